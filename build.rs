@@ -0,0 +1,8 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // tonic-build generates the messages as well as the service
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/risk.proto")?;
+    #[cfg(all(feature = "protobuf", not(feature = "grpc")))]
+    prost_build::compile_protos(&["proto/risk.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use crate::input::RequestOptions;
+use crate::latency::LatencyStats;
+use crate::price_sources::PriceSources;
+use crate::prices::{PriceCache, PriceTick};
+use crate::quarantine::Origin;
+use crate::RiskCheckResponse;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+use trading_base::{OrderType, TradeIntent};
+
+/// A trade intent and everything needed to answer it once it has been priced.
+pub struct PendingIntent {
+    pub intent: TradeIntent,
+    pub options: RequestOptions,
+    pub correlation_id: Option<String>,
+    pub origin: Option<Origin>,
+    pub reply: Option<oneshot::Sender<RiskCheckResponse>>,
+    pub received_at: DateTime<Utc>,
+    /// Last trade price of the ticker, for market orders whose lookup succeeded
+    pub last_price: Option<PriceTick>,
+}
+
+/// One task per ticker that looks up the prices of its intents, so that a slow lookup for one
+/// ticker doesn't hold up the others. Each ticker's intents come back in the order they were
+/// dispatched. The intents are still checked one at a time by the main loop, since buying power
+/// and exposure are shared by every ticker.
+pub struct TickerActors {
+    mailboxes: HashMap<String, mpsc::UnboundedSender<PendingIntent>>,
+    priced_tx: mpsc::UnboundedSender<PendingIntent>,
+    priced_rx: mpsc::UnboundedReceiver<PendingIntent>,
+    sources: Arc<PriceSources>,
+    prices: PriceCache,
+    latency: Arc<LatencyStats>,
+    /// Intents dispatched that haven't come back yet
+    in_flight: usize,
+}
+
+impl TickerActors {
+    pub fn new(sources: Arc<PriceSources>, prices: PriceCache, latency: Arc<LatencyStats>) -> Self {
+        let (priced_tx, priced_rx) = mpsc::unbounded_channel();
+        Self {
+            mailboxes: HashMap::new(),
+            priced_tx,
+            priced_rx,
+            sources,
+            prices,
+            latency,
+            in_flight: 0,
+        }
+    }
+
+    /// Hands an intent to the actor of its ticker, starting one if needed.
+    pub fn dispatch(&mut self, pending: PendingIntent) {
+        let Self {
+            mailboxes,
+            priced_tx,
+            sources,
+            prices,
+            latency,
+            in_flight,
+            ..
+        } = self;
+        let ticker = pending.intent.ticker.clone();
+        let mailbox = mailboxes.entry(ticker.clone()).or_insert_with(|| {
+            debug!(%ticker, "Starting ticker actor");
+            let (tx, rx) = mpsc::unbounded_channel();
+            let actor = TickerActor {
+                ticker,
+                sources: sources.clone(),
+                prices: prices.clone(),
+                latency: latency.clone(),
+            };
+            tokio::spawn(actor.run(rx, priced_tx.clone()));
+            tx
+        });
+        // The actors only stop once `priced_rx` is dropped, and with it `self`
+        let _ = mailbox.send(pending);
+        *in_flight += 1;
+    }
+
+    /// Waits for the next priced intent of any ticker.
+    pub async fn next(&mut self) -> Option<PendingIntent> {
+        let pending = self.priced_rx.recv().await?;
+        self.in_flight -= 1;
+        Some(pending)
+    }
+
+    /// Whether every dispatched intent has come back.
+    pub fn is_idle(&self) -> bool {
+        self.in_flight == 0
+    }
+}
+
+struct TickerActor {
+    ticker: String,
+    sources: Arc<PriceSources>,
+    prices: PriceCache,
+    latency: Arc<LatencyStats>,
+}
+
+impl TickerActor {
+    async fn run(
+        self,
+        mut mailbox: mpsc::UnboundedReceiver<PendingIntent>,
+        priced: mpsc::UnboundedSender<PendingIntent>,
+    ) {
+        while let Some(mut pending) = mailbox.recv().await {
+            // Cached prices are read by the check itself
+            let cached = self.prices.get(&self.ticker, Utc::now()).is_some();
+            if matches!(pending.intent.order_type, OrderType::Market) && !cached {
+                // If the lookup fails, the check looks the price up again and reports the error
+                match self.last_price().await {
+                    Ok(price) => pending.last_price = Some(price),
+                    Err(e) => warn!(ticker = %self.ticker, ?e, "Failed to look up last price"),
+                }
+            }
+            if priced.send(pending).is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn last_price(&self) -> Result<PriceTick> {
+        let (sources, ticker) = (self.sources.clone(), self.ticker.clone());
+        let start = Instant::now();
+        // Price sources block, so they are kept off the runtime's threads
+        let (tick, source) =
+            tokio::task::spawn_blocking(move || sources.last_price(&ticker)).await??;
+        self.latency.price_lookup.observe(start.elapsed());
+        debug!(ticker = %self.ticker, %source, "Priced");
+        self.prices.record_lookup(&self.ticker, tick, Utc::now());
+        Ok(tick)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn pending(intent: TradeIntent) -> PendingIntent {
+        PendingIntent {
+            intent,
+            options: RequestOptions::default(),
+            correlation_id: None,
+            origin: None,
+            reply: None,
+            received_at: Utc::now(),
+            last_price: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn ticker_actors() {
+        let _m = mockito::mock("GET", "/last/TSLA").with_body("700").create();
+        let sources = PriceSources::datastore(mockito::server_url()).unwrap();
+        let mut actors =
+            TickerActors::new(Arc::new(sources), PriceCache::default(), Default::default());
+        let limit = TradeIntent::new("TSLA", 1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(650, 0),
+        });
+        let market = TradeIntent::new("TSLA", 2);
+        actors.dispatch(pending(market.clone()));
+        actors.dispatch(pending(limit.clone()));
+
+        // Intents for the same ticker come back in order, and only market orders are priced
+        let first = actors.next().await.unwrap();
+        assert_eq!(first.intent, market);
+        assert_eq!(
+            first.last_price.map(|tick| tick.price),
+            Some(Decimal::new(700, 0))
+        );
+        assert!(!actors.is_idle());
+        let second = actors.next().await.unwrap();
+        assert_eq!(second.intent, limit);
+        assert_eq!(second.last_price, None);
+        assert!(actors.is_idle());
+    }
+}
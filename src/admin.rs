@@ -0,0 +1,100 @@
+use crate::control::{ControlCommand, TradingHalts};
+use crate::holdings::Holdings;
+use crate::input::RequestOptions;
+use crate::limits::RiskLimits;
+use crate::sharding::ShardState;
+use crate::{PortfolioSnapshot, RiskCheckResponse, RiskManager};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+use trading_base::TradeIntent;
+
+/// Requests from the admin API that need access to the risk manager's state. They are handled
+/// by the main loop between messages, so they never race with a risk check.
+#[derive(Debug)]
+pub enum AdminCommand {
+    ExportHoldings(oneshot::Sender<Holdings>),
+    ImportHoldings(Holdings, oneshot::Sender<()>),
+    /// Asks for the current state, with up to the given number of recent decisions
+    InspectState(usize, oneshot::Sender<StateView>),
+    Control(ControlCommand, oneshot::Sender<TradingHalts>),
+    /// Checks an intent like one received from Kafka. Not handled by `handle_admin_command`,
+    /// since the decision goes through the main loop's post-processing.
+    RiskCheck(
+        TradeIntent,
+        RequestOptions,
+        oneshot::Sender<RiskCheckResponse>,
+    ),
+    Portfolio(oneshot::Sender<PortfolioSnapshot>),
+    /// The latest state of another instance, when sharding by ticker
+    PeerState(ShardState),
+}
+
+/// Live state of the risk manager, as returned by `GET /admin/state`.
+#[derive(Debug, Serialize)]
+pub struct StateView {
+    pub holdings: Holdings,
+    pub reserved_buying_power: Decimal,
+    pub limits: RiskLimits,
+    pub halts: TradingHalts,
+    pub recent_decisions: Vec<RiskCheckResponse>,
+}
+
+pub type AdminSender = mpsc::Sender<AdminCommand>;
+pub type AdminReceiver = mpsc::Receiver<AdminCommand>;
+
+pub fn channel() -> (AdminSender, AdminReceiver) {
+    mpsc::channel(16)
+}
+
+impl RiskManager {
+    /// Returns a description of the change if the command replaced any state.
+    pub fn handle_admin_command(&mut self, command: AdminCommand) -> Option<String> {
+        match command {
+            AdminCommand::ExportHoldings(reply) => {
+                let _ = reply.send(self.export_holdings());
+                None
+            }
+            AdminCommand::ImportHoldings(holdings, reply) => {
+                warn!(
+                    cash = %holdings.cash,
+                    positions = holdings.positions.len(),
+                    "Replacing holdings from import"
+                );
+                self.import_holdings(holdings);
+                info!("Holdings imported");
+                let _ = reply.send(());
+                Some("Holdings imported via admin API".into())
+            }
+            AdminCommand::InspectState(decisions, reply) => {
+                let _ = reply.send(StateView {
+                    holdings: self.export_holdings(),
+                    reserved_buying_power: self.reserved_buying_power(),
+                    limits: (*self.limits()).clone(),
+                    halts: self.halts().clone(),
+                    recent_decisions: self.recent_decisions(decisions),
+                });
+                None
+            }
+            AdminCommand::Control(command, reply) => {
+                warn!(?command, "Applying control command from admin API");
+                self.apply_control(&command);
+                let _ = reply.send(self.halts().clone());
+                None
+            }
+            AdminCommand::Portfolio(reply) => {
+                let _ = reply.send(self.portfolio_snapshot());
+                None
+            }
+            AdminCommand::PeerState(state) => {
+                self.update_peer(state);
+                None
+            }
+            AdminCommand::RiskCheck(intent, ..) => {
+                error!(id = %intent.id, "Risk checks must be handled by the main loop");
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,264 @@
+#[cfg(feature = "reqwest")]
+use crate::price_sources::blocking_client;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::{debug, warn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetStatus {
+    Active,
+    #[serde(other)]
+    Inactive,
+}
+
+/// What the broker allows for an asset, as returned by its assets endpoint.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Asset {
+    pub symbol: String,
+    pub status: AssetStatus,
+    pub tradable: bool,
+    pub marginable: bool,
+    pub shortable: bool,
+    pub easy_to_borrow: bool,
+    #[serde(default)]
+    pub fractionable: bool,
+}
+
+impl Asset {
+    pub fn is_tradable(&self) -> bool {
+        self.tradable && self.status == AssetStatus::Active
+    }
+
+    /// Whether `qty` can be ordered, i.e. it is whole or the asset is fractionable.
+    pub fn allows_qty(&self, qty: rust_decimal::Decimal) -> bool {
+        self.fractionable || qty.fract().is_zero()
+    }
+}
+
+/// Where asset records are looked up.
+pub trait AssetSource: Send + Sync {
+    fn asset(&self, ticker: &str) -> Result<Asset>;
+}
+
+/// Alpaca's assets endpoint, using the trading account's credentials.
+#[cfg(feature = "reqwest")]
+pub struct AlpacaAssetSource {
+    client: reqwest::blocking::Client,
+    base_url: String,
+    key_id: String,
+    secret_key: String,
+}
+
+#[cfg(feature = "reqwest")]
+impl AlpacaAssetSource {
+    pub fn new(
+        base_url: String,
+        key_id: String,
+        secret_key: String,
+        timeout: std::time::Duration,
+    ) -> Result<Self> {
+        let client = blocking_client(timeout)?;
+        Ok(Self {
+            client,
+            base_url,
+            key_id,
+            secret_key,
+        })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl AssetSource for AlpacaAssetSource {
+    fn asset(&self, ticker: &str) -> Result<Asset> {
+        let url = format!("{}/v2/assets/{}", self.base_url, ticker);
+        Ok(self
+            .client
+            .get(url)
+            .header("APCA-API-KEY-ID", &self.key_id)
+            .header("APCA-API-SECRET-KEY", &self.secret_key)
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+}
+
+/// How long after a failed lookup an asset is looked up again
+const FAILURE_BACKOFF_SECS: i64 = 60;
+
+#[derive(Default)]
+struct Cached {
+    /// The last record fetched, and when
+    asset: Option<(Asset, DateTime<Utc>)>,
+    /// When the last lookup failed, if it did since the record was fetched
+    failed_at: Option<DateTime<Utc>>,
+}
+
+/// Asset records, looked up ahead of the checks that read them, and again once they are older
+/// than the refresh interval. Without a source, nothing is known about any asset.
+#[derive(Clone, Default)]
+pub struct AssetCache {
+    source: Option<Arc<dyn AssetSource>>,
+    refresh: Duration,
+    assets: Arc<Mutex<HashMap<String, Cached>>>,
+}
+
+impl AssetCache {
+    pub fn new(source: impl AssetSource + 'static, refresh: Duration) -> Self {
+        Self {
+            source: Some(Arc::new(source)),
+            refresh,
+            assets: Default::default(),
+        }
+    }
+
+    /// The last asset record of `ticker` fetched, if any. Checks are evaluated synchronously,
+    /// so this never looks the asset up itself.
+    pub fn get(&self, ticker: &str) -> Option<Asset> {
+        let assets = self.assets.lock().expect("Asset cache lock poisoned");
+        let (asset, _) = assets.get(ticker)?.asset.as_ref()?;
+        Some(asset.clone())
+    }
+
+    /// Looks up `ticker` unless its record is still fresh, or the last lookup failed less than
+    /// a minute ago. The lookup runs on a blocking thread, without locking the cache. A failed
+    /// lookup keeps the last record, if any.
+    pub async fn refresh(&self, ticker: &str, now: DateTime<Utc>) {
+        let source = match self.source.as_ref() {
+            Some(source) if self.is_due(ticker, now) => source.clone(),
+            _ => return,
+        };
+        let symbol = ticker.to_string();
+        let fetched = tokio::task::spawn_blocking(move || source.asset(&symbol))
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|fetched| fetched);
+        let mut assets = self.assets.lock().expect("Asset cache lock poisoned");
+        let cached = assets.entry(ticker.to_string()).or_default();
+        match fetched {
+            Ok(asset) => {
+                debug!(%ticker, ?asset, "Fetched asset");
+                cached.asset = Some((asset, now));
+                cached.failed_at = None;
+            }
+            Err(e) => {
+                warn!(%ticker, ?e, "Failed to fetch asset");
+                cached.failed_at = Some(now);
+            }
+        }
+    }
+
+    fn is_due(&self, ticker: &str, now: DateTime<Utc>) -> bool {
+        let assets = self.assets.lock().expect("Asset cache lock poisoned");
+        match assets.get(ticker) {
+            None => true,
+            Some(Cached {
+                failed_at: Some(failed_at),
+                ..
+            }) if now - *failed_at < Duration::seconds(FAILURE_BACKOFF_SECS) => false,
+            Some(Cached { asset: None, .. }) => true,
+            Some(Cached {
+                asset: Some((_, fetched_at)),
+                ..
+            }) => now - *fetched_at >= self.refresh,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Source(Arc<AtomicUsize>);
+
+    impl AssetSource for Source {
+        fn asset(&self, ticker: &str) -> Result<Asset> {
+            if self.0.fetch_add(1, Ordering::SeqCst) > 1 {
+                anyhow::bail!("Unavailable");
+            }
+            Ok(Asset {
+                symbol: ticker.into(),
+                status: AssetStatus::Active,
+                tradable: true,
+                marginable: true,
+                shortable: true,
+                easy_to_borrow: true,
+                fractionable: false,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn asset_cache() {
+        let now = Utc::now();
+        let unknown = AssetCache::default();
+        unknown.refresh("AAPL", now).await;
+        assert!(unknown.get("AAPL").is_none());
+
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let cache = AssetCache::new(Source(lookups.clone()), Duration::hours(1));
+        // Nothing is looked up until the asset is refreshed
+        assert!(cache.get("AAPL").is_none());
+        cache.refresh("AAPL", now).await;
+        assert!(cache.get("AAPL").unwrap().is_tradable());
+        cache.refresh("AAPL", now + Duration::minutes(30)).await;
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        cache.refresh("AAPL", now + Duration::hours(2)).await;
+        assert_eq!(lookups.load(Ordering::SeqCst), 2);
+        // The last record is kept when a refresh fails, and the failure is remembered for a
+        // while
+        let later = now + Duration::hours(4);
+        cache.refresh("AAPL", later).await;
+        assert!(cache.get("AAPL").is_some());
+        cache.refresh("AAPL", later + Duration::seconds(30)).await;
+        assert_eq!(lookups.load(Ordering::SeqCst), 3);
+        cache.refresh("AAPL", later + Duration::minutes(2)).await;
+        assert_eq!(lookups.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    #[cfg(feature = "reqwest")]
+    fn alpaca() {
+        let _m = mockito::mock("GET", "/v2/assets/AAPL")
+            .match_header("APCA-API-KEY-ID", "key")
+            .match_header("APCA-API-SECRET-KEY", "secret")
+            .with_body(r#"{"id":"b0b6dd9d-8b9b-48a9-ba46-b9d54906e415","class":"us_equity","exchange":"NASDAQ","symbol":"AAPL","name":"Apple Inc.","status":"active","tradable":true,"marginable":true,"shortable":true,"easy_to_borrow":true,"fractionable":true}"#)
+            .create();
+        let _m = mockito::mock("GET", "/v2/assets/NOPE")
+            .with_status(404)
+            .create();
+        let source = AlpacaAssetSource::new(
+            mockito::server_url(),
+            "key".into(),
+            "secret".into(),
+            std::time::Duration::from_secs(1),
+        )
+        .unwrap();
+        let asset = source.asset("AAPL").unwrap();
+        assert_eq!(asset.symbol, "AAPL");
+        assert!(asset.is_tradable() && asset.fractionable);
+        assert!(source.asset("NOPE").is_err());
+    }
+
+    #[test]
+    fn asset_status() {
+        let asset: Asset = serde_json::from_str(
+            r#"{"id":"b0b6dd9d-8b9b-48a9-ba46-b9d54906e415","class":"us_equity","exchange":"NASDAQ","symbol":"AAPL","name":"Apple Inc.","status":"delisted","tradable":true,"marginable":true,"shortable":true,"easy_to_borrow":true,"fractionable":true}"#,
+        )
+        .unwrap();
+        assert_eq!(asset.status, AssetStatus::Inactive);
+        assert!(!asset.is_tradable());
+        assert!(asset.allows_qty(rust_decimal::Decimal::new(15, 1)));
+        let whole = Asset {
+            fractionable: false,
+            ..asset
+        };
+        assert!(whole.allows_qty(rust_decimal::Decimal::new(2, 0)));
+        assert!(!whole.allows_qty(rust_decimal::Decimal::new(15, 1)));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::clock::{Clock, SharedClock};
+use crate::error::{Result, RiskManagerError};
+use crate::holdings::Holdings;
+use crate::limits::SharedLimits;
+use crate::rules::RiskRule;
+use crate::settings::RiskSettings;
+use crate::RiskManager;
+#[cfg(feature = "alpaca")]
+use alpaca::Client;
+
+/// Builds a risk manager, checking that everything it needs was given before initializing it.
+///
+/// The starting account comes from the broker if an Alpaca client is given. Starting holdings
+/// replace the broker's, and are required without a client.
+#[derive(Default)]
+pub struct RiskManagerBuilder {
+    settings: RiskSettings,
+    price_source: Option<String>,
+    #[cfg(feature = "alpaca")]
+    alpaca: Option<Client>,
+    holdings: Option<Holdings>,
+    limits: Option<SharedLimits>,
+    rules: Vec<Box<dyn RiskRule>>,
+    clock: Option<SharedClock>,
+}
+
+impl RiskManager {
+    pub fn builder() -> RiskManagerBuilder {
+        RiskManagerBuilder::default()
+    }
+}
+
+impl RiskManagerBuilder {
+    pub fn settings(mut self, settings: RiskSettings) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Base URL of the datastore that market orders are priced from.
+    pub fn price_source(mut self, url: impl Into<String>) -> Self {
+        self.price_source = Some(url.into());
+        self
+    }
+
+    #[cfg(feature = "alpaca")]
+    pub fn alpaca(mut self, client: Client) -> Self {
+        self.alpaca = Some(client);
+        self
+    }
+
+    pub fn holdings(mut self, holdings: Holdings) -> Self {
+        self.holdings = Some(holdings);
+        self
+    }
+
+    pub fn limits(mut self, limits: SharedLimits) -> Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(SharedClock::new(clock));
+        self
+    }
+
+    /// Appends a custom rule to the end of the pipeline.
+    pub fn rule(mut self, rule: Box<dyn RiskRule>) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Names of the components that are required but missing.
+    fn missing(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if cfg!(feature = "reqwest") && self.price_source.is_none() {
+            missing.push("price source");
+        }
+        #[cfg(feature = "alpaca")]
+        let has_account = self.alpaca.is_some() || self.holdings.is_some();
+        #[cfg(not(feature = "alpaca"))]
+        let has_account = self.holdings.is_some();
+        if !has_account {
+            missing.push("Alpaca client or starting holdings");
+        }
+        missing
+    }
+
+    /// Checks that the required components were given, then builds the risk manager and loads
+    /// the starting account.
+    pub async fn build(self) -> Result<RiskManager> {
+        let missing = self.missing();
+        if !missing.is_empty() {
+            return Err(RiskManagerError::Configuration(format!(
+                "Can't build the risk manager without: {}",
+                missing.join(", ")
+            )));
+        }
+        let Self {
+            settings,
+            price_source,
+            #[cfg(feature = "alpaca")]
+            alpaca,
+            holdings,
+            limits,
+            rules,
+            clock,
+        } = self;
+        let mut risk_manager = RiskManager::new(price_source.unwrap_or_default(), settings);
+        if let Some(limits) = limits {
+            risk_manager.bind_limits(limits);
+        }
+        for rule in rules {
+            risk_manager.add_rule(rule);
+        }
+        if let Some(clock) = clock {
+            risk_manager.bind_clock(clock);
+        }
+        #[cfg(feature = "alpaca")]
+        if let Some(client) = alpaca {
+            risk_manager.bind_alpaca_client(client);
+            risk_manager.initialize().await?;
+        }
+        if let Some(holdings) = holdings {
+            risk_manager.import_holdings(holdings);
+        }
+        Ok(risk_manager)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::holdings::Holding;
+    use rust_decimal::Decimal;
+
+    #[tokio::test]
+    async fn validation() {
+        let e = RiskManager::builder().build().await.err().unwrap();
+        assert!(e.to_string().contains("Alpaca client or starting holdings"));
+
+        let holdings = Holdings {
+            cash: Decimal::new(1000, 0),
+            positions: vec![Holding {
+                ticker: "AAPL".into(),
+                shares: Decimal::new(10, 0),
+                price: Decimal::new(100, 0),
+            }],
+        };
+        let risk_manager = RiskManager::builder()
+            .price_source("http://localhost:8080")
+            .holdings(holdings)
+            .build()
+            .await
+            .unwrap();
+        let snapshot = risk_manager.portfolio_snapshot();
+        assert_eq!(snapshot.report.cash, Decimal::new(1000, 0));
+        assert_eq!(snapshot.report.long_market_exposure, Decimal::new(1000, 0));
+    }
+}
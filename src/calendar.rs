@@ -0,0 +1,153 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc, Weekday};
+
+/// Returns `true` if US equity markets are open on `date`, a weekday that isn't an exchange
+/// holiday.
+pub fn is_business_day(date: NaiveDate) -> bool {
+    !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) && !is_holiday(date)
+}
+
+/// The business day `days` business days after `date`.
+pub fn add_business_days(date: NaiveDate, days: usize) -> NaiveDate {
+    let mut date = date;
+    let mut remaining = days;
+    while remaining > 0 {
+        date += Duration::days(1);
+        if is_business_day(date) {
+            remaining -= 1;
+        }
+    }
+    date
+}
+
+/// The date in New York, where US equity markets trade, at `at`.
+pub fn exchange_date(at: DateTime<Utc>) -> NaiveDate {
+    // Daylight saving time starts at 2am on the second Sunday of March, and ends at 2am on the
+    // first Sunday of November
+    let year = at.year();
+    let starts = nth_weekday(year, 3, Weekday::Sun, 2).and_hms(7, 0, 0);
+    let ends = nth_weekday(year, 11, Weekday::Sun, 1).and_hms(6, 0, 0);
+    let naive = at.naive_utc();
+    let offset = if naive >= starts && naive < ends {
+        4
+    } else {
+        5
+    };
+    (naive - Duration::hours(offset)).date()
+}
+
+fn is_holiday(date: NaiveDate) -> bool {
+    let year = date.year();
+    // New Year's Day falling on a Saturday isn't made up on the Friday before
+    let new_year = NaiveDate::from_ymd(year, 1, 1);
+    let new_year = if new_year.weekday() == Weekday::Sun {
+        new_year.succ()
+    } else {
+        new_year
+    };
+    let mut holidays = vec![
+        new_year,
+        nth_weekday(year, 1, Weekday::Mon, 3),
+        nth_weekday(year, 2, Weekday::Mon, 3),
+        easter(year) - Duration::days(2),
+        last_weekday(year, 5, Weekday::Mon),
+        observed(NaiveDate::from_ymd(year, 7, 4)),
+        nth_weekday(year, 9, Weekday::Mon, 1),
+        nth_weekday(year, 11, Weekday::Thu, 4),
+        observed(NaiveDate::from_ymd(year, 12, 25)),
+    ];
+    if year >= 2022 {
+        holidays.push(observed(NaiveDate::from_ymd(year, 6, 19)));
+    }
+    holidays.contains(&date)
+}
+
+/// Holidays falling on a weekend are observed on the closest weekday.
+fn observed(date: NaiveDate) -> NaiveDate {
+    match date.weekday() {
+        Weekday::Sat => date.pred(),
+        Weekday::Sun => date.succ(),
+        _ => date,
+    }
+}
+
+fn nth_weekday(year: i32, month: u32, weekday: Weekday, n: u32) -> NaiveDate {
+    let first = NaiveDate::from_ymd(year, month, 1);
+    let offset = (7 + weekday.num_days_from_monday() - first.weekday().num_days_from_monday()) % 7;
+    first + Duration::days((offset + 7 * (n - 1)) as i64)
+}
+
+fn last_weekday(year: i32, month: u32, weekday: Weekday) -> NaiveDate {
+    let last = NaiveDate::from_ymd(year, month + 1, 1).pred();
+    let offset = (7 + last.weekday().num_days_from_monday() - weekday.num_days_from_monday()) % 7;
+    last - Duration::days(offset as i64)
+}
+
+/// Easter Sunday, by the anonymous Gregorian algorithm.
+fn easter(year: i32) -> NaiveDate {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd(year, month as u32, day as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd(y, m, d)
+    }
+
+    #[test]
+    fn holidays() {
+        for holiday in [
+            date(2021, 1, 1),
+            date(2021, 1, 18),
+            date(2021, 2, 15),
+            date(2021, 4, 2),
+            date(2021, 5, 31),
+            // Independence Day on a Sunday
+            date(2021, 7, 5),
+            date(2021, 9, 6),
+            date(2021, 11, 25),
+            // Christmas on a Saturday
+            date(2021, 12, 24),
+            // Juneteenth on a Sunday
+            date(2022, 6, 20),
+        ]
+        .iter()
+        {
+            assert!(!is_business_day(*holiday), "{}", holiday);
+        }
+        assert!(is_business_day(date(2021, 6, 18)));
+        assert!(!is_business_day(date(2021, 6, 19)));
+    }
+
+    #[test]
+    fn business_days() {
+        // Over a weekend and Independence Day
+        assert_eq!(add_business_days(date(2021, 6, 30), 5), date(2021, 7, 8));
+        assert_eq!(add_business_days(date(2021, 6, 3), 5), date(2021, 6, 10));
+    }
+
+    #[test]
+    fn new_york_dates() {
+        let at = |m, d, h| Utc.ymd(2021, m, d).and_hms(h, 0, 0);
+        // 8pm in New York, during and outside of daylight saving time
+        assert_eq!(exchange_date(at(6, 2, 0)), date(2021, 6, 1));
+        assert_eq!(exchange_date(at(12, 2, 1)), date(2021, 12, 1));
+        assert_eq!(exchange_date(at(12, 2, 5)), date(2021, 12, 2));
+    }
+}
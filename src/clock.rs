@@ -0,0 +1,92 @@
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Source of the current time for everything the risk manager decides by date or age, such as
+/// day trades, settlement and how long cached decisions stay fresh.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    fn today(&self) -> NaiveDate {
+        self.now().date().naive_utc()
+    }
+}
+
+/// The system clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to, for tests. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock(Arc<Mutex<DateTime<Utc>>>);
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self(Arc::new(Mutex::new(now)))
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.0.lock().expect("Clock lock poisoned") = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.0.lock().expect("Clock lock poisoned");
+        *now = *now + by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().expect("Clock lock poisoned")
+    }
+}
+
+/// The clock the risk manager was given, the system clock unless replaced.
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: impl Clock + 'static) -> Self {
+        Self(Arc::new(clock))
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl Clock for SharedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0.now()
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedClock({})", self.now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn manual() {
+        let clock = ManualClock::new(Utc.ymd(2021, 6, 4).and_hms(23, 0, 0));
+        let shared = SharedClock::new(clock.clone());
+        clock.advance(Duration::hours(2));
+        assert_eq!(shared.today(), NaiveDate::from_ymd(2021, 6, 5));
+        clock.set(Utc.ymd(2021, 6, 7).and_hms(9, 30, 0));
+        assert_eq!(shared.now(), Utc.ymd(2021, 6, 7).and_hms(9, 30, 0));
+    }
+}
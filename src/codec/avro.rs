@@ -0,0 +1,370 @@
+use anyhow::{anyhow, Context, Result};
+use avro_rs::schema::Schema;
+use avro_rs::types::Value;
+use avro_rs::{from_avro_datum, to_avro_datum, Decimal as AvroDecimal};
+use chrono::{DateTime, TimeZone, Utc};
+use reqwest::Client;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use serde_json::{Map, Number, Value as Json};
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use tracing::debug;
+
+/// First byte of every message in the Confluent wire format.
+const MAGIC_BYTE: u8 = 0;
+
+#[derive(Deserialize)]
+struct RegisteredSchema {
+    #[serde(default)]
+    id: Option<u32>,
+    schema: String,
+}
+
+/// Splits a Confluent-framed payload into its schema id and Avro datum.
+fn unframe(payload: &[u8]) -> Result<(u32, &[u8])> {
+    match payload {
+        [MAGIC_BYTE, a, b, c, d, datum @ ..] => Ok((u32::from_be_bytes([*a, *b, *c, *d]), datum)),
+        _ => Err(anyhow!("Payload is not in the Confluent Avro wire format")),
+    }
+}
+
+fn frame(id: u32, datum: Vec<u8>) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(datum.len() + 5);
+    payload.push(MAGIC_BYTE);
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend(datum);
+    payload
+}
+
+fn timestamp(value: &Json, scale: i64) -> Result<i64> {
+    match value {
+        Json::String(s) => {
+            let dt: DateTime<Utc> = s.parse()?;
+            Ok(match scale {
+                1_000 => dt.timestamp_millis(),
+                _ => dt.timestamp_nanos() / 1_000,
+            })
+        }
+        value => value
+            .as_i64()
+            .ok_or_else(|| anyhow!("Expected a timestamp, got {}", value)),
+    }
+}
+
+fn number(value: &Json) -> Result<f64> {
+    match value {
+        // Decimals are serialized as strings, and would lose precision as floating point
+        Json::String(s) => Err(anyhow!(
+            "Expected a number, got {:?}. Use a string or decimal schema for decimals",
+            s
+        )),
+        value => value
+            .as_f64()
+            .ok_or_else(|| anyhow!("Expected a number, got {}", value)),
+    }
+}
+
+/// Encodes a decimal as the unscaled two's complement integer of an Avro decimal with `scale`.
+fn to_avro_decimal(value: &Json, scale: usize) -> Result<AvroDecimal> {
+    let decimal: Decimal = match value {
+        Json::String(s) => s.parse()?,
+        Json::Number(n) => n.to_string().parse()?,
+        value => return Err(anyhow!("Expected a decimal, got {}", value)),
+    };
+    let mut scaled = decimal;
+    scaled.rescale(scale.try_into()?);
+    if scaled != decimal {
+        return Err(anyhow!(
+            "{} doesn't fit in a decimal of scale {}",
+            decimal,
+            scale
+        ));
+    }
+    Ok(AvroDecimal::from(scaled.mantissa().to_be_bytes()))
+}
+
+fn from_avro_decimal(decimal: &AvroDecimal, scale: usize) -> Result<Decimal> {
+    let bytes = Vec::<u8>::try_from(decimal)?;
+    if bytes.len() > 16 {
+        return Err(anyhow!("Decimal of {} bytes is too large", bytes.len()));
+    }
+    let negative = bytes.first().map_or(false, |byte| byte & 0x80 != 0);
+    let mut unscaled = if negative { [0xff; 16] } else { [0; 16] };
+    unscaled[16 - bytes.len()..].copy_from_slice(&bytes);
+    let unscaled = i128::from_be_bytes(unscaled);
+    if unscaled.unsigned_abs() >= 1 << 96 {
+        return Err(anyhow!("Decimal {} is out of range", unscaled));
+    }
+    Ok(Decimal::from_i128_with_scale(unscaled, scale.try_into()?))
+}
+
+fn integer(value: &Json) -> Result<i64> {
+    match value {
+        Json::String(s) => Ok(s.parse()?),
+        value => value
+            .as_i64()
+            .ok_or_else(|| anyhow!("Expected an integer, got {}", value)),
+    }
+}
+
+/// Converts serde's JSON form of a message into an Avro value matching `schema`.
+fn to_avro(value: &Json, schema: &Schema) -> Result<Value> {
+    Ok(match schema {
+        Schema::Null => match value {
+            Json::Null => Value::Null,
+            _ => return Err(anyhow!("Expected null, got {}", value)),
+        },
+        Schema::Boolean => Value::Boolean(
+            value
+                .as_bool()
+                .ok_or_else(|| anyhow!("Expected a boolean, got {}", value))?,
+        ),
+        Schema::Int => Value::Int(integer(value)?.try_into()?),
+        Schema::Long => Value::Long(integer(value)?),
+        Schema::Float => Value::Float(number(value)? as f32),
+        Schema::Double => Value::Double(number(value)?),
+        Schema::Decimal { scale, .. } => Value::Decimal(to_avro_decimal(value, *scale)?),
+        Schema::String => match value {
+            Json::String(s) => Value::String(s.clone()),
+            value => Value::String(value.to_string()),
+        },
+        Schema::Uuid => Value::Uuid(
+            value
+                .as_str()
+                .ok_or_else(|| anyhow!("Expected a UUID, got {}", value))?
+                .parse()?,
+        ),
+        Schema::TimestampMillis => Value::TimestampMillis(timestamp(value, 1_000)?),
+        Schema::TimestampMicros => Value::TimestampMicros(timestamp(value, 1_000_000)?),
+        Schema::Array(items) => Value::Array(
+            value
+                .as_array()
+                .ok_or_else(|| anyhow!("Expected an array, got {}", value))?
+                .iter()
+                .map(|item| to_avro(item, items))
+                .collect::<Result<_>>()?,
+        ),
+        Schema::Map(values) => Value::Map(
+            value
+                .as_object()
+                .ok_or_else(|| anyhow!("Expected an object, got {}", value))?
+                .iter()
+                .map(|(key, item)| Ok((key.clone(), to_avro(item, values)?)))
+                .collect::<Result<_>>()?,
+        ),
+        Schema::Enum { symbols, .. } => {
+            let symbol = value
+                .as_str()
+                .ok_or_else(|| anyhow!("Expected an enum symbol, got {}", value))?;
+            let index = symbols
+                .iter()
+                .position(|s| s == symbol)
+                .ok_or_else(|| anyhow!("Unknown enum symbol {}", symbol))?;
+            Value::Enum(index as i32, symbol.to_string())
+        }
+        Schema::Union(union) => {
+            let variant = union
+                .variants()
+                .iter()
+                .find_map(|variant| to_avro(value, variant).ok())
+                .ok_or_else(|| anyhow!("No union variant matches {}", value))?;
+            Value::Union(Box::new(variant))
+        }
+        Schema::Record { name, fields, .. } => {
+            let object = value
+                .as_object()
+                .ok_or_else(|| anyhow!("Expected an object for {}, got {}", name.name, value))?;
+            Value::Record(
+                fields
+                    .iter()
+                    .map(|field| {
+                        let item = object
+                            .get(&field.name)
+                            .or_else(|| field.default.as_ref())
+                            .unwrap_or(&Json::Null);
+                        let item = to_avro(item, &field.schema)
+                            .with_context(|| format!("Invalid field {}", field.name))?;
+                        Ok((field.name.clone(), item))
+                    })
+                    .collect::<Result<_>>()?,
+            )
+        }
+        schema => return Err(anyhow!("Unsupported Avro schema {:?}", schema)),
+    })
+}
+
+/// Converts a decoded Avro value into the JSON form the message types deserialize from. The
+/// schema is needed for the scale of decimals, which are converted to strings like serde's.
+fn to_json(value: Value, schema: &Schema) -> Result<Json> {
+    Ok(match (value, schema) {
+        (Value::Null, _) => Json::Null,
+        (Value::Boolean(b), _) => Json::Bool(b),
+        (Value::Int(i), _) => Json::from(i),
+        (Value::Long(i), _) => Json::from(i),
+        (Value::Float(f), _) => Number::from_f64(f as f64)
+            .map(Json::Number)
+            .ok_or_else(|| anyhow!("Invalid float {}", f))?,
+        (Value::Double(f), _) => Number::from_f64(f)
+            .map(Json::Number)
+            .ok_or_else(|| anyhow!("Invalid double {}", f))?,
+        (Value::Decimal(decimal), Schema::Decimal { scale, .. }) => {
+            Json::String(from_avro_decimal(&decimal, *scale)?.to_string())
+        }
+        (Value::String(s), _) | (Value::Enum(_, s), _) => Json::String(s),
+        (Value::Uuid(uuid), _) => Json::String(uuid.to_string()),
+        (Value::TimestampMillis(ms), _) => Json::String(Utc.timestamp_millis(ms).to_rfc3339()),
+        (Value::TimestampMicros(us), _) => {
+            Json::String(Utc.timestamp_nanos(us * 1_000).to_rfc3339())
+        }
+        (Value::Union(value), Schema::Union(union)) => {
+            let variant = union
+                .variants()
+                .iter()
+                .find(|variant| value.validate(variant))
+                .ok_or_else(|| anyhow!("No union variant matches {:?}", value))?;
+            to_json(*value, variant)?
+        }
+        (Value::Array(items), Schema::Array(schema)) => Json::Array(
+            items
+                .into_iter()
+                .map(|item| to_json(item, schema))
+                .collect::<Result<_>>()?,
+        ),
+        (Value::Map(items), Schema::Map(schema)) => Json::Object(
+            items
+                .into_iter()
+                .map(|(key, item)| Ok((key, to_json(item, schema)?)))
+                .collect::<Result<Map<_, _>>>()?,
+        ),
+        (Value::Record(items), Schema::Record { fields, .. }) => Json::Object(
+            items
+                .into_iter()
+                .zip(fields)
+                .map(|((key, item), field)| Ok((key, to_json(item, &field.schema)?)))
+                .collect::<Result<Map<_, _>>>()?,
+        ),
+        (value, _) => return Err(anyhow!("Unsupported Avro value {:?}", value)),
+    })
+}
+
+/// The schema caches only ever gain complete entries, so a panic while one was held can't have
+/// left it inconsistent.
+fn cache<T>(cache: &Mutex<T>) -> MutexGuard<T> {
+    cache.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Avro codec backed by a Confluent schema registry. Messages are encoded with the latest
+/// schema registered under `<topic>-value`, and decoded with the schema their id refers to.
+#[derive(Clone)]
+pub struct AvroCodec {
+    client: Client,
+    registry_url: String,
+    by_id: Arc<Mutex<HashMap<u32, Arc<Schema>>>>,
+    by_topic: Arc<Mutex<HashMap<String, (u32, Arc<Schema>)>>>,
+}
+
+impl AvroCodec {
+    pub fn new(registry_url: &str) -> Self {
+        Self {
+            client: Client::new(),
+            registry_url: registry_url.trim_end_matches('/').to_string(),
+            by_id: Default::default(),
+            by_topic: Default::default(),
+        }
+    }
+
+    async fn fetch(&self, path: &str) -> Result<(Option<u32>, Arc<Schema>)> {
+        let url = format!("{}/{}", self.registry_url, path);
+        debug!(%url, "Fetching Avro schema");
+        let registered: RegisteredSchema = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let schema = Schema::parse_str(&registered.schema)?;
+        Ok((registered.id, Arc::new(schema)))
+    }
+
+    async fn schema_by_id(&self, id: u32) -> Result<Arc<Schema>> {
+        if let Some(schema) = cache(&self.by_id).get(&id) {
+            return Ok(schema.clone());
+        }
+        let (_, schema) = self.fetch(&format!("schemas/ids/{}", id)).await?;
+        cache(&self.by_id).insert(id, schema.clone());
+        Ok(schema)
+    }
+
+    async fn schema_for_topic(&self, topic: &str) -> Result<(u32, Arc<Schema>)> {
+        if let Some(entry) = cache(&self.by_topic).get(topic) {
+            return Ok(entry.clone());
+        }
+        let path = format!("subjects/{}-value/versions/latest", topic);
+        let (id, schema) = self.fetch(&path).await?;
+        let id = id.ok_or_else(|| anyhow!("Schema registry didn't return an id for {}", topic))?;
+        cache(&self.by_topic).insert(topic.to_string(), (id, schema.clone()));
+        Ok((id, schema))
+    }
+
+    pub async fn decode(&self, payload: &[u8]) -> Result<Json> {
+        let (id, mut datum) = unframe(payload)?;
+        let schema = self.schema_by_id(id).await?;
+        to_json(from_avro_datum(&schema, &mut datum, None)?, &schema)
+    }
+
+    pub async fn encode(&self, topic: &str, message: Json) -> Result<Vec<u8>> {
+        let (id, schema) = self.schema_for_topic(topic).await?;
+        let value = to_avro(&message, &schema)?;
+        Ok(frame(id, to_avro_datum(&schema, value)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let schema = Schema::parse_str(
+            r#"{
+                "type": "record",
+                "name": "Lot",
+                "fields": [
+                    {"name": "ticker", "type": "string"},
+                    {
+                        "name": "price",
+                        "type": {"type": "bytes", "logicalType": "decimal", "precision": 18, "scale": 4}
+                    },
+                    {"name": "fill_time", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+                    {"name": "note", "type": ["null", "string"], "default": null}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let message = serde_json::json!({
+            "ticker": "AAPL",
+            "price": "100.5",
+            "fill_time": "2021-11-01T14:30:00+00:00",
+        });
+        let datum = to_avro_datum(&schema, to_avro(&message, &schema).unwrap()).unwrap();
+        let payload = frame(7, datum);
+        let (id, mut datum) = unframe(&payload).unwrap();
+        assert_eq!(id, 7);
+        let decoded = from_avro_datum(&schema, &mut datum, None).unwrap();
+        let decoded = to_json(decoded, &schema).unwrap();
+        assert_eq!(
+            decoded,
+            serde_json::json!({
+                "ticker": "AAPL",
+                "price": "100.5000",
+                "fill_time": "2021-11-01T14:30:00+00:00",
+                "note": null,
+            })
+        );
+        assert!(unframe(b"{}").is_err());
+    }
+}
@@ -0,0 +1,255 @@
+#[cfg(feature = "avro")]
+mod avro;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+
+use crate::input::Input;
+use crate::input::CORRELATION_ID_HEADER;
+use crate::latency::LatencyStats;
+use crate::settings::{CodecFormat, CodecSettings, ProducerRetrySettings};
+use crate::supervisor::{classify, Backoff, ErrorClass};
+use crate::transport::{MessageHeaders, MessageSink};
+use crate::RiskCheckResponse;
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{error, warn};
+
+/// Wire format of Kafka payloads. JSON unless configured otherwise.
+#[derive(Clone)]
+pub enum Codec {
+    Json,
+    /// Avro in the Confluent wire format, with schemas from a schema registry
+    #[cfg(feature = "avro")]
+    Avro(avro::AvroCodec),
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::Json
+    }
+}
+
+impl Codec {
+    pub fn new(settings: &CodecSettings) -> Result<Self> {
+        match settings.format {
+            CodecFormat::Json => Ok(Codec::Json),
+            #[cfg(feature = "avro")]
+            CodecFormat::Avro => {
+                let url = settings
+                    .schema_registry_url
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Avro requires a schema registry URL"))?;
+                Ok(Codec::Avro(avro::AvroCodec::new(url)))
+            }
+            #[cfg(not(feature = "avro"))]
+            CodecFormat::Avro => Err(anyhow!("risk-manager was built without the `avro` feature")),
+            #[cfg(feature = "protobuf")]
+            CodecFormat::Protobuf => Ok(Codec::Protobuf),
+            #[cfg(not(feature = "protobuf"))]
+            CodecFormat::Protobuf => Err(anyhow!(
+                "risk-manager was built without the `protobuf` feature"
+            )),
+        }
+    }
+
+    pub async fn decode_input(&self, payload: &[u8]) -> Result<Input> {
+        match self {
+            #[cfg(feature = "protobuf")]
+            Codec::Protobuf => protobuf::decode_input(payload),
+            _ => self.decode(payload).await,
+        }
+    }
+
+    /// Decodes a self-describing message. Protobuf messages aren't, so they are decoded as JSON.
+    pub async fn decode<T: DeserializeOwned>(&self, payload: &[u8]) -> Result<T> {
+        match self {
+            // Avro is converted to JSON first so that untagged inputs are told apart the same way
+            #[cfg(feature = "avro")]
+            Codec::Avro(codec) => Ok(serde_json::from_value(codec.decode(payload).await?)?),
+            _ => Ok(serde_json::from_slice(payload)?),
+        }
+    }
+
+    /// Encodes a message for `topic`, whose schema is used for Avro.
+    #[cfg_attr(not(feature = "avro"), allow(unused_variables))]
+    pub async fn encode<T: Serialize>(&self, topic: &str, message: &T) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "avro")]
+            Codec::Avro(codec) => codec.encode(topic, serde_json::to_value(message)?).await,
+            _ => Ok(serde_json::to_vec(message)?),
+        }
+    }
+
+    /// Encodes a decision for `topic`. Unlike other outputs, decisions have a protobuf form.
+    pub async fn encode_decision(
+        &self,
+        topic: &str,
+        response: &RiskCheckResponse,
+    ) -> Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "protobuf")]
+            Codec::Protobuf => Ok(protobuf::encode_decision(response)),
+            _ => self.encode(topic, response).await,
+        }
+    }
+}
+
+/// Counts of retried and failed sends.
+#[derive(Debug, Default)]
+pub struct ProducerStats {
+    retries: AtomicU64,
+    /// Sends that failed after exhausting their retries
+    failures: AtomicU64,
+}
+
+impl ProducerStats {
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut metrics = String::new();
+        let counters = [
+            (
+                "retries",
+                "Sends to Kafka that were retried",
+                self.retries(),
+            ),
+            (
+                "failures",
+                "Sends to Kafka that failed after exhausting their retries",
+                self.failures(),
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            let _ = writeln!(metrics, "# HELP risk_manager_produce_{} {}", name, help);
+            let _ = writeln!(metrics, "# TYPE risk_manager_produce_{} counter", name);
+            let _ = writeln!(metrics, "risk_manager_produce_{} {}", name, value);
+        }
+        metrics
+    }
+}
+
+pub fn correlation_headers(correlation_id: Option<&str>) -> MessageHeaders {
+    correlation_id
+        .map(|correlation_id| (CORRELATION_ID_HEADER.to_string(), correlation_id.into()))
+        .into_iter()
+        .collect()
+}
+
+/// Producer that encodes messages with the configured codec.
+#[derive(Clone)]
+pub struct Producer {
+    sink: Arc<dyn MessageSink>,
+    codec: Codec,
+    latency: Arc<LatencyStats>,
+    retry: ProducerRetrySettings,
+    stats: Arc<ProducerStats>,
+}
+
+impl Producer {
+    pub fn new(
+        sink: Arc<dyn MessageSink>,
+        codec: Codec,
+        latency: Arc<LatencyStats>,
+        retry: ProducerRetrySettings,
+    ) -> Self {
+        Self {
+            sink,
+            codec,
+            latency,
+            retry,
+            stats: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Arc<ProducerStats> {
+        self.stats.clone()
+    }
+
+    /// Sends a message, with the correlation id of the input it responds to if there is one.
+    pub async fn send<T: Serialize>(
+        &self,
+        topic: &str,
+        key: &str,
+        message: &T,
+        correlation_id: Option<&str>,
+    ) -> Result<()> {
+        let payload = self.codec.encode(topic, message).await?;
+        let headers = correlation_headers(correlation_id);
+        self.send_raw(topic, key.as_bytes(), &payload, &headers)
+            .await
+    }
+
+    /// Sends an already encoded payload, e.g. a message forwarded to the dead-letter topic.
+    /// Transient failures are retried with exponential backoff. Errors are only returned once
+    /// the retries run out, or straight away if retrying can't help.
+    pub async fn send_raw(
+        &self,
+        topic: &str,
+        key: &[u8],
+        payload: &[u8],
+        headers: &[(String, Vec<u8>)],
+    ) -> Result<()> {
+        let mut backoff = Backoff::with_bounds(
+            Duration::from_millis(self.retry.initial_backoff_ms),
+            Duration::from_millis(self.retry.max_backoff_ms),
+        );
+        let mut attempt = 0;
+        loop {
+            let start = Instant::now();
+            let e = match self.sink.send(topic, key, payload, headers).await {
+                Ok(()) => {
+                    self.latency.produce.observe(start.elapsed());
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            if attempt == self.retry.max_retries || classify(&e) == ErrorClass::Fatal {
+                self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                error!(topic, attempts = attempt + 1, ?e, "Giving up on send");
+                return Err(e);
+            }
+            attempt += 1;
+            self.stats.retries.fetch_add(1, Ordering::Relaxed);
+            let delay = backoff.next_jittered_delay();
+            warn!(topic, attempt, ?delay, ?e, "Send failed, retrying");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::Input;
+
+    #[tokio::test]
+    async fn json() {
+        let codec = Codec::default();
+        let payload = codec
+            .encode(
+                "time",
+                &serde_json::json!({"state": "closed", "next_open": 60}),
+            )
+            .await
+            .unwrap();
+        let input = codec.decode_input(&payload).await.unwrap();
+        assert!(matches!(
+            input,
+            Input::Time(crate::input::State::Closed { next_open: 60 })
+        ));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::input::{Input, State};
+use crate::proto::decimal;
+use crate::proto::generated as proto;
+use crate::{
+    ControlCommand, ControlRequest, Lot, MultiLegIntent, OrderAmendment, OrderCancellation, Query,
+    QueryRequest, RiskCheckResponse,
+};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use prost::Message;
+use std::convert::TryInto;
+use uuid::Uuid;
+
+fn uuid(field: &str, value: &str) -> Result<Uuid> {
+    Uuid::parse_str(value).map_err(|e| anyhow!("Invalid {}: {}", field, e))
+}
+
+impl proto::Lot {
+    pub fn into_lot(self) -> Result<Lot> {
+        Ok(Lot {
+            id: uuid("id", &self.id)?,
+            order_id: uuid("order_id", &self.order_id)?,
+            ticker: self.ticker,
+            fill_time: DateTime::parse_from_rfc3339(&self.fill_time)
+                .map_err(|e| anyhow!("Invalid fill_time: {}", e))?
+                .with_timezone(&Utc),
+            price: decimal("price", &self.price)?,
+            shares: decimal("shares", &self.shares)?,
+        })
+    }
+}
+
+impl proto::MultiLegIntent {
+    pub fn into_multi_leg(self) -> Result<MultiLegIntent> {
+        Ok(MultiLegIntent {
+            id: uuid("id", &self.id)?,
+            legs: self
+                .legs
+                .into_iter()
+                .map(proto::TradeIntent::into_intent)
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl proto::OrderAmendment {
+    pub fn into_amendment(self) -> Result<OrderAmendment> {
+        let limit_price = match self.limit_price.as_str() {
+            "" => None,
+            price => Some(decimal("limit_price", price)?),
+        };
+        Ok(OrderAmendment {
+            id: uuid("id", &self.id)?,
+            amends: uuid("amends", &self.amends)?,
+            qty: self.qty.try_into().map_err(|_| anyhow!("Invalid qty"))?,
+            limit_price,
+        })
+    }
+}
+
+impl proto::QueryRequest {
+    pub fn into_query(self) -> Result<QueryRequest> {
+        use proto::query_request::Query as Kind;
+        let query = match Kind::from_i32(self.query) {
+            Some(Kind::BuyingPower) => Query::BuyingPower,
+            Some(Kind::Position) if self.ticker.is_empty() => {
+                return Err(anyhow!("Position queries need a ticker"))
+            }
+            Some(Kind::Position) => Query::Position {
+                ticker: self.ticker,
+            },
+            Some(Kind::Portfolio) => Query::Portfolio,
+            Some(Kind::RiskReport) => Query::RiskReport,
+            None => return Err(anyhow!("Invalid query")),
+        };
+        Ok(QueryRequest {
+            id: uuid("id", &self.id)?,
+            query,
+        })
+    }
+}
+
+impl proto::ControlRequest {
+    pub fn into_control(self) -> Result<ControlRequest> {
+        use proto::control_request::Command;
+        let ticker = || match self.ticker.as_str() {
+            "" => Err(anyhow!("Ticker commands need a ticker")),
+            ticker => Ok(ticker.to_string()),
+        };
+        let command = match Command::from_i32(self.command) {
+            Some(Command::PauseAll) => ControlCommand::PauseAll,
+            Some(Command::PauseTicker) => ControlCommand::PauseTicker(ticker()?),
+            Some(Command::Resume) => ControlCommand::Resume,
+            Some(Command::ResumeTicker) => ControlCommand::ResumeTicker(ticker()?),
+            Some(Command::KillSwitch) => ControlCommand::KillSwitch,
+            Some(Command::ClearKillSwitch) => ControlCommand::ClearKillSwitch,
+            None => return Err(anyhow!("Invalid command")),
+        };
+        Ok(ControlRequest {
+            id: uuid("id", &self.id)?,
+            command,
+            issued_at: DateTime::parse_from_rfc3339(&self.issued_at)
+                .map_err(|e| anyhow!("Invalid issued_at: {}", e))?
+                .with_timezone(&Utc),
+            signature: self.signature,
+        })
+    }
+}
+
+impl proto::RiskInput {
+    pub fn into_input(self) -> Result<Input> {
+        use proto::market_state::State as MarketState;
+        use proto::risk_input::Input as RiskInput;
+        match self.input {
+            Some(RiskInput::TradeIntent(intent)) => Ok(Input::TradeIntent(intent.into_intent()?)),
+            Some(RiskInput::Lot(lot)) => Ok(Input::Lot(lot.into_lot()?)),
+            Some(RiskInput::Time(time)) => match time.state {
+                Some(MarketState::NextClose(next_close)) => Ok(Input::Time(State::Open {
+                    next_close: next_close as usize,
+                })),
+                Some(MarketState::NextOpen(next_open)) => Ok(Input::Time(State::Closed {
+                    next_open: next_open as usize,
+                })),
+                None => Err(anyhow!("Missing market state")),
+            },
+            Some(RiskInput::PriceUpdate(update)) => Ok(Input::PriceUpdate {
+                ticker: update.ticker,
+                price: decimal("price", &update.price)?,
+                timestamp: DateTime::parse_from_rfc3339(&update.timestamp)
+                    .map_err(|e| anyhow!("Invalid timestamp: {}", e))?
+                    .with_timezone(&Utc),
+            }),
+            Some(RiskInput::BorrowRate(rate)) => Ok(Input::BorrowRate {
+                ticker: rate.ticker,
+                borrow_rate: decimal("borrow_rate", &rate.borrow_rate)?,
+            }),
+            Some(RiskInput::Greeks(greeks)) => Ok(Input::Greeks {
+                ticker: greeks.ticker,
+                delta: decimal("delta", &greeks.delta)?,
+            }),
+            Some(RiskInput::MultiLeg(intent)) => Ok(Input::MultiLeg(intent.into_multi_leg()?)),
+            Some(RiskInput::Amendment(amendment)) => {
+                Ok(Input::Amendment(amendment.into_amendment()?))
+            }
+            Some(RiskInput::Cancellation(cancellation)) => {
+                Ok(Input::Cancellation(OrderCancellation {
+                    cancels: uuid("cancels", &cancellation.cancels)?,
+                }))
+            }
+            Some(RiskInput::Query(query)) => Ok(Input::Query(query.into_query()?)),
+            Some(RiskInput::WhatIf(intent)) => Ok(Input::WhatIf(intent.into_intent()?)),
+            Some(RiskInput::Control(control)) => Ok(Input::Control(control.into_control()?)),
+            // Also what an input added to the envelope after this version decodes to
+            None => Err(anyhow!("Empty or unsupported input")),
+        }
+    }
+}
+
+/// Decodes a `RiskInput` envelope.
+pub fn decode_input(payload: &[u8]) -> Result<Input> {
+    proto::RiskInput::decode(payload)?.into_input()
+}
+
+pub fn encode_decision(response: &RiskCheckResponse) -> Vec<u8> {
+    proto::RiskCheckResponse::from(response).encode_to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal::Decimal;
+    use trading_base::{OrderType, TradeIntent};
+
+    #[test]
+    fn inputs() {
+        let intent = TradeIntent::new("AAPL", 10).order_type(OrderType::StopLimit {
+            stop_price: Decimal::new(99, 0),
+            limit_price: Decimal::new(100, 0),
+        });
+        let envelope = proto::RiskInput {
+            input: Some(proto::risk_input::Input::TradeIntent((&intent).into())),
+        };
+        match decode_input(&envelope.encode_to_vec()).unwrap() {
+            Input::TradeIntent(decoded) => assert_eq!(decoded, intent),
+            _ => panic!("Expected a trade intent"),
+        }
+
+        let envelope = proto::RiskInput {
+            input: Some(proto::risk_input::Input::Time(proto::MarketState {
+                state: Some(proto::market_state::State::NextOpen(60)),
+            })),
+        };
+        assert!(matches!(
+            decode_input(&envelope.encode_to_vec()).unwrap(),
+            Input::Time(State::Closed { next_open: 60 })
+        ));
+        let envelope = proto::RiskInput {
+            input: Some(proto::risk_input::Input::PriceUpdate(proto::PriceUpdate {
+                ticker: "AAPL".into(),
+                price: "150.1".into(),
+                timestamp: "2021-06-01T14:30:00Z".into(),
+            })),
+        };
+        match decode_input(&envelope.encode_to_vec()).unwrap() {
+            Input::PriceUpdate { ticker, price, .. } => {
+                assert_eq!((ticker.as_str(), price), ("AAPL", Decimal::new(1501, 1)))
+            }
+            _ => panic!("Expected a price update"),
+        }
+        assert!(decode_input(&[]).is_err());
+    }
+
+    #[test]
+    fn other_inputs() {
+        let decode = |input| {
+            let envelope = proto::RiskInput { input: Some(input) };
+            decode_input(&envelope.encode_to_vec())
+        };
+        let id = Uuid::new_v4();
+        let amendment = proto::OrderAmendment {
+            id: id.to_string(),
+            amends: id.to_string(),
+            qty: 5,
+            limit_price: "101.5".into(),
+        };
+        match decode(proto::risk_input::Input::Amendment(amendment)).unwrap() {
+            Input::Amendment(amendment) => {
+                assert_eq!(amendment.qty, 5);
+                assert_eq!(amendment.limit_price, Some(Decimal::new(1015, 1)));
+            }
+            _ => panic!("Expected an amendment"),
+        }
+        let cancellation = proto::OrderCancellation {
+            cancels: id.to_string(),
+        };
+        assert!(matches!(
+            decode(proto::risk_input::Input::Cancellation(cancellation)).unwrap(),
+            Input::Cancellation(OrderCancellation { cancels }) if cancels == id
+        ));
+        let rate = proto::BorrowRate {
+            ticker: "GME".into(),
+            borrow_rate: "0.25".into(),
+        };
+        assert!(matches!(
+            decode(proto::risk_input::Input::BorrowRate(rate)).unwrap(),
+            Input::BorrowRate { borrow_rate, .. } if borrow_rate == Decimal::new(25, 2)
+        ));
+        let query = proto::QueryRequest {
+            id: id.to_string(),
+            query: proto::query_request::Query::Position as i32,
+            ticker: "AAPL".into(),
+        };
+        match decode(proto::risk_input::Input::Query(query)).unwrap() {
+            Input::Query(request) => assert_eq!(
+                request.query,
+                Query::Position {
+                    ticker: "AAPL".into()
+                }
+            ),
+            _ => panic!("Expected a query"),
+        }
+
+        // Ticker commands can't be sent without one
+        let control = proto::ControlRequest {
+            id: id.to_string(),
+            command: proto::control_request::Command::PauseTicker as i32,
+            ticker: String::new(),
+            signature: "sha256=00".into(),
+            issued_at: "2021-06-01T14:30:00Z".into(),
+        };
+        assert!(decode(proto::risk_input::Input::Control(control)).is_err());
+        let legs = proto::MultiLegIntent {
+            id: id.to_string(),
+            legs: vec![(&TradeIntent::new("AAPL", 10)).into()],
+        };
+        match decode(proto::risk_input::Input::MultiLeg(legs)).unwrap() {
+            Input::MultiLeg(intent) => assert_eq!(intent.legs.len(), 1),
+            _ => panic!("Expected a multi-leg intent"),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use crate::settings::{ComplianceFailurePolicy, ComplianceHookSettings};
+use crate::{DenyReason, PortfolioSnapshot, RiskCheckResponse};
+use anyhow::Result;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, warn};
+use trading_base::TradeIntent;
+
+#[derive(Serialize)]
+struct ComplianceRequest<'a> {
+    intent: &'a TradeIntent,
+    portfolio: &'a PortfolioSnapshot,
+}
+
+/// Verdict returned by the external compliance service.
+#[derive(Debug, Deserialize)]
+pub struct ComplianceVerdict {
+    pub approved: bool,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// Asks an external compliance service, such as a centralized restricted-list service, to
+/// review intents the rule pipeline would grant.
+pub struct ComplianceHook {
+    client: Client,
+    settings: ComplianceHookSettings,
+}
+
+impl ComplianceHook {
+    pub fn new(settings: ComplianceHookSettings) -> Self {
+        Self {
+            client: Client::new(),
+            settings,
+        }
+    }
+
+    async fn verdict(
+        &self,
+        intent: &TradeIntent,
+        portfolio: &PortfolioSnapshot,
+    ) -> Result<ComplianceVerdict> {
+        let verdict = self
+            .client
+            .post(&self.settings.url)
+            .timeout(Duration::from_millis(self.settings.timeout_ms))
+            .json(&ComplianceRequest { intent, portfolio })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(verdict)
+    }
+
+    /// Merges the service's verdict into the decision. Denied intents aren't sent for review.
+    pub async fn review(
+        &self,
+        response: RiskCheckResponse,
+        portfolio: &PortfolioSnapshot,
+    ) -> RiskCheckResponse {
+        if let RiskCheckResponse::Denied { .. } = response {
+            return response;
+        }
+        let intent = match response.granted_intent() {
+            Some(intent) => intent,
+            None => return response,
+        };
+        match self.verdict(&intent, portfolio).await {
+            Ok(verdict) if verdict.approved => {
+                debug!(id = %intent.id, "Compliance service approved intent");
+                response
+            }
+            Ok(verdict) => {
+                debug!(id = %intent.id, ?verdict, "Compliance service rejected intent");
+                response.into_denied(vec![DenyReason::ComplianceRejected {
+                    reason: verdict.reason,
+                }])
+            }
+            Err(e) => {
+                warn!(id = %intent.id, ?e, policy = ?self.settings.failure_policy, "Compliance service unavailable");
+                match self.settings.failure_policy {
+                    ComplianceFailurePolicy::Open => response,
+                    ComplianceFailurePolicy::Closed => {
+                        response.into_denied(vec![DenyReason::ComplianceUnavailable])
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::RiskManager;
+    use mockito::mock;
+
+    fn hook(failure_policy: ComplianceFailurePolicy) -> ComplianceHook {
+        ComplianceHook::new(ComplianceHookSettings {
+            url: format!("{}/compliance", mockito::server_url()),
+            timeout_ms: 500,
+            failure_policy,
+        })
+    }
+
+    fn granted() -> RiskCheckResponse {
+        RiskCheckResponse::Granted {
+            intent: TradeIntent::new("AAPL", 10),
+            buffered_price: None,
+            price_source: None,
+            score: None,
+            metrics: None,
+            timing: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn review() {
+        let portfolio = RiskManager::default().portfolio_snapshot();
+
+        let m = mock("POST", "/compliance")
+            .with_body(r#"{"approved": true}"#)
+            .create();
+        let response = hook(ComplianceFailurePolicy::Closed)
+            .review(granted(), &portfolio)
+            .await;
+        assert!(matches!(response, RiskCheckResponse::Granted { .. }));
+        drop(m);
+
+        let m = mock("POST", "/compliance")
+            .with_body(r#"{"approved": false, "reason": "Restricted"}"#)
+            .create();
+        let response = hook(ComplianceFailurePolicy::Open)
+            .review(granted(), &portfolio)
+            .await;
+        match response {
+            RiskCheckResponse::Denied { reasons, .. } => assert_eq!(
+                reasons,
+                vec![DenyReason::ComplianceRejected {
+                    reason: Some("Restricted".into())
+                }]
+            ),
+            _ => panic!("Expected denial, got {:?}", response),
+        }
+        drop(m);
+
+        let m = mock("POST", "/compliance").with_status(500).create();
+        let response = hook(ComplianceFailurePolicy::Open)
+            .review(granted(), &portfolio)
+            .await;
+        assert!(matches!(response, RiskCheckResponse::Granted { .. }));
+        let response = hook(ComplianceFailurePolicy::Closed)
+            .review(granted(), &portfolio)
+            .await;
+        assert!(matches!(
+            response,
+            RiskCheckResponse::Denied { reasons, .. }
+                if reasons == vec![DenyReason::ComplianceUnavailable]
+        ));
+        drop(m);
+    }
+}
@@ -0,0 +1,295 @@
+use crate::settings::ControlSettings;
+use crate::signature::{constant_time_eq, sign};
+use crate::{DenyReason, RiskManager};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Operational commands consumed from the `risk-admin` topic.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ControlCommand {
+    PauseAll,
+    PauseTicker(String),
+    /// Lifts every pause, but not the kill switch
+    Resume,
+    ResumeTicker(String),
+    /// Denies every intent until cleared
+    KillSwitch,
+    ClearKillSwitch,
+}
+
+/// A command signed with the shared secret from the `control` settings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ControlRequest {
+    pub id: Uuid,
+    pub command: ControlCommand,
+    /// When the request was signed. Requests older than `ControlSettings::max_age_secs` are
+    /// rejected, so that an old request can't be replayed.
+    pub issued_at: DateTime<Utc>,
+    /// HMAC-SHA256 of `<id>:<issued_at as milliseconds since the epoch>:<command as JSON>`,
+    /// formatted like webhook signatures
+    pub signature: String,
+}
+
+impl ControlRequest {
+    pub fn signing_payload(
+        id: Uuid,
+        issued_at: DateTime<Utc>,
+        command: &ControlCommand,
+    ) -> Result<String> {
+        Ok(format!(
+            "{}:{}:{}",
+            id,
+            issued_at.timestamp_millis(),
+            serde_json::to_string(command)?
+        ))
+    }
+
+    pub fn new(
+        id: Uuid,
+        command: ControlCommand,
+        issued_at: DateTime<Utc>,
+        secret: &str,
+    ) -> Result<Self> {
+        let payload = Self::signing_payload(id, issued_at, &command)?;
+        Ok(Self {
+            id,
+            command,
+            issued_at,
+            signature: sign(secret, payload.as_bytes()),
+        })
+    }
+
+    /// Checks the signature, and that the request was issued no more than `max_age` from `now`.
+    pub fn verify(&self, secret: &str, now: DateTime<Utc>, max_age: Duration) -> Result<()> {
+        let payload = Self::signing_payload(self.id, self.issued_at, &self.command)?;
+        let expected = sign(secret, payload.as_bytes());
+        if !constant_time_eq(expected.as_bytes(), self.signature.as_bytes()) {
+            return Err(anyhow!("Invalid signature"));
+        }
+        // Allow for clocks running ahead as well as behind
+        if now - self.issued_at > max_age || self.issued_at - now > max_age {
+            return Err(anyhow!(
+                "Request was issued at {}, too long ago",
+                self.issued_at
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Control requests applied recently enough that they could still pass the age check, so that
+/// none is applied twice.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct AppliedControls(HashMap<Uuid, DateTime<Utc>>);
+
+impl AppliedControls {
+    /// Records a request, forgetting those issued before `oldest`. Returns `false` if it was
+    /// already applied.
+    fn insert(&mut self, request: &ControlRequest, oldest: DateTime<Utc>) -> bool {
+        self.0.retain(|_, issued_at| *issued_at >= oldest);
+        self.0.insert(request.id, request.issued_at).is_none()
+    }
+}
+
+/// Acknowledgement published on the admin response topic for every control request.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ControlAck {
+    pub id: Uuid,
+    pub command: ControlCommand,
+    pub accepted: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// Trading restrictions put in place by control commands.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct TradingHalts {
+    pub kill_switch: bool,
+    pub paused_all: bool,
+    pub paused_tickers: HashSet<String>,
+}
+
+impl TradingHalts {
+    pub fn apply(&mut self, command: &ControlCommand) {
+        match command {
+            ControlCommand::PauseAll => self.paused_all = true,
+            ControlCommand::PauseTicker(ticker) => {
+                self.paused_tickers.insert(ticker.clone());
+            }
+            ControlCommand::Resume => {
+                self.paused_all = false;
+                self.paused_tickers.clear();
+            }
+            ControlCommand::ResumeTicker(ticker) => {
+                self.paused_tickers.remove(ticker);
+            }
+            ControlCommand::KillSwitch => self.kill_switch = true,
+            ControlCommand::ClearKillSwitch => self.kill_switch = false,
+        }
+    }
+
+    pub fn denial(&self, ticker: &str) -> Option<DenyReason> {
+        if self.kill_switch {
+            Some(DenyReason::KillSwitchEngaged)
+        } else if self.paused_all || self.paused_tickers.contains(ticker) {
+            Some(DenyReason::TradingPaused)
+        } else {
+            None
+        }
+    }
+}
+
+/// Blocks the broker has placed on the account. Any of them stops all trading.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccountBlocks {
+    pub trading_blocked: bool,
+    pub account_blocked: bool,
+    pub trade_suspended_by_user: bool,
+}
+
+impl AccountBlocks {
+    pub fn is_blocked(&self) -> bool {
+        self.trading_blocked || self.account_blocked || self.trade_suspended_by_user
+    }
+
+    pub fn denial(&self) -> Option<DenyReason> {
+        if self.is_blocked() {
+            Some(DenyReason::AccountRestricted)
+        } else {
+            None
+        }
+    }
+}
+
+impl RiskManager {
+    /// Verifies and applies a control request. Requests are rejected if control commands aren't
+    /// configured, and if they are too old or were already applied.
+    pub fn handle_control(
+        &mut self,
+        request: &ControlRequest,
+        settings: Option<&ControlSettings>,
+    ) -> ControlAck {
+        let now = self.clock.now();
+        let result = settings
+            .ok_or_else(|| anyhow!("Control commands are not enabled"))
+            .and_then(|settings| {
+                let max_age = Duration::seconds(settings.max_age_secs as i64);
+                request.verify(&settings.secret, now, max_age)?;
+                if !self.applied_controls.insert(request, now - max_age) {
+                    return Err(anyhow!("Request was already applied"));
+                }
+                Ok(())
+            });
+        let error = match result {
+            Ok(()) => {
+                warn!(command = ?request.command, "Applying control command");
+                self.apply_control(&request.command);
+                None
+            }
+            Err(e) => {
+                warn!(?e, id = %request.id, "Rejected control command");
+                Some(e.to_string())
+            }
+        };
+        ControlAck {
+            id: request.id,
+            command: request.command.clone(),
+            accepted: error.is_none(),
+            error,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use trading_base::TradeIntent;
+
+    fn settings() -> ControlSettings {
+        ControlSettings {
+            secret: "s3cret".into(),
+            max_age_secs: 60,
+        }
+    }
+
+    #[test]
+    fn signatures() {
+        let now = Utc::now();
+        let max_age = Duration::seconds(60);
+        let request =
+            ControlRequest::new(Uuid::new_v4(), ControlCommand::KillSwitch, now, "s3cret").unwrap();
+        assert!(request.verify("s3cret", now, max_age).is_ok());
+        assert!(request.verify("guess", now, max_age).is_err());
+        let tampered = ControlRequest {
+            command: ControlCommand::ClearKillSwitch,
+            ..request.clone()
+        };
+        assert!(tampered.verify("s3cret", now, max_age).is_err());
+        let backdated = ControlRequest {
+            issued_at: now + Duration::minutes(5),
+            ..request.clone()
+        };
+        assert!(backdated.verify("s3cret", now, max_age).is_err());
+        // Requests only stay valid for the maximum age
+        let later = now + Duration::seconds(61);
+        assert!(request.verify("s3cret", later, max_age).is_err());
+    }
+
+    #[test]
+    fn halts() {
+        let mut manager = RiskManager::default();
+        let settings = settings();
+        let secret = Some(&settings);
+        let send = |manager: &mut RiskManager, command| {
+            let now = manager.clock().now();
+            let request = ControlRequest::new(Uuid::new_v4(), command, now, "s3cret").unwrap();
+            assert!(manager.handle_control(&request, secret).accepted);
+        };
+        let denial = |manager: &RiskManager, ticker| match manager
+            .risk_check(&TradeIntent::new(ticker, 1))
+            .unwrap()
+        {
+            crate::RiskCheckResponse::Denied { reasons, .. } => Some(reasons),
+            _ => None,
+        };
+
+        send(&mut manager, ControlCommand::PauseTicker("TSLA".into()));
+        assert_eq!(
+            denial(&manager, "TSLA"),
+            Some(vec![DenyReason::TradingPaused])
+        );
+        send(&mut manager, ControlCommand::KillSwitch);
+        send(&mut manager, ControlCommand::Resume);
+        assert_eq!(
+            denial(&manager, "AAPL"),
+            Some(vec![DenyReason::KillSwitchEngaged])
+        );
+
+        let now = manager.clock().now();
+        let clear = |secret| {
+            ControlRequest::new(Uuid::new_v4(), ControlCommand::ClearKillSwitch, now, secret)
+                .unwrap()
+        };
+        let request = clear("guess");
+        let ack = manager.handle_control(&request, secret);
+        assert!(!ack.accepted);
+        assert!(manager.halts().kill_switch);
+        let ack = manager.handle_control(&request, None);
+        assert_eq!(
+            ack.error.as_deref(),
+            Some("Control commands are not enabled")
+        );
+
+        // A request that was already applied can't be replayed, e.g. to lift a later kill switch
+        let request = clear("s3cret");
+        assert!(manager.handle_control(&request, secret).accepted);
+        send(&mut manager, ControlCommand::KillSwitch);
+        let ack = manager.handle_control(&request, secret);
+        assert_eq!(ack.error.as_deref(), Some("Request was already applied"));
+        assert!(manager.halts().kill_switch);
+    }
+}
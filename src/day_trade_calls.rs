@@ -0,0 +1,165 @@
+use crate::calendar::add_business_days;
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Number of business days the account has to meet a day-trading call.
+const DUE_DAYS: usize = 5;
+
+/// Issued when the exposure of the day's trades exceeds day-trading buying power.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DayTradeCall {
+    pub issued_on: NaiveDate,
+    pub due_on: NaiveDate,
+    /// Cash to deposit to meet the call
+    pub amount: Decimal,
+    /// Cash deposited since the call was issued
+    #[serde(default)]
+    pub deposited: Decimal,
+}
+
+impl DayTradeCall {
+    pub fn is_past_due(&self, today: NaiveDate) -> bool {
+        today > self.due_on
+    }
+}
+
+/// Follows the day-trading call of the account. Until deposits meet the call, day-trading buying
+/// power is restricted to twice the excess equity, and to the excess equity once the call is past
+/// due.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DayTradeCalls {
+    call: Option<DayTradeCall>,
+    /// Day of the last call, so that there is at most one call per day
+    last_issued_on: Option<NaiveDate>,
+}
+
+impl DayTradeCalls {
+    /// Records the exposure of the positions opened during the day against the day-trading
+    /// limit, the excess equity times the multiplier. Returns the call if this issues one.
+    pub fn observe(
+        &mut self,
+        today: NaiveDate,
+        daytrade_exposure: Decimal,
+        limit: Decimal,
+        multiplier: Decimal,
+    ) -> Option<DayTradeCall> {
+        if self.call.is_some()
+            || self.last_issued_on == Some(today)
+            || daytrade_exposure <= limit
+            || multiplier.is_zero()
+        {
+            return None;
+        }
+        let amount = (daytrade_exposure - limit) / multiplier;
+        let call = DayTradeCall {
+            issued_on: today,
+            due_on: add_business_days(today, DUE_DAYS),
+            amount,
+            deposited: Decimal::ZERO,
+        };
+        warn!(
+            %daytrade_exposure,
+            %limit,
+            %amount,
+            due_on = %call.due_on,
+            "Day-trading buying power exceeded, call issued"
+        );
+        self.last_issued_on = Some(today);
+        self.call = Some(call.clone());
+        Some(call)
+    }
+
+    /// Credits a deposit to the outstanding call, which is met once deposits cover it. Gains on
+    /// positions don't meet a call.
+    pub fn deposit(&mut self, amount: Decimal) {
+        if let Some(call) = self.call.as_mut() {
+            call.deposited += amount;
+            if call.deposited >= call.amount {
+                info!(issued_on = %call.issued_on, deposited = %call.deposited, "Day-trading call met");
+                self.call = None;
+            }
+        }
+    }
+
+    /// The call that hasn't been met yet, if any.
+    pub fn outstanding(&self) -> Option<&DayTradeCall> {
+        self.call.as_ref()
+    }
+
+    /// Multiplier of the excess equity allowed for day trading, given the account's own.
+    pub fn restrict(&self, multiplier: Decimal, today: NaiveDate) -> Decimal {
+        match self.call.as_ref() {
+            Some(call) if call.is_past_due(today) => multiplier.min(Decimal::ONE),
+            Some(_) => multiplier.min(Decimal::new(2, 0)),
+            None => multiplier,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn day_trade_call() {
+        // A Thursday
+        let today = NaiveDate::from_ymd(2021, 6, 3);
+        let mut calls = DayTradeCalls::default();
+        let four = Decimal::new(4, 0);
+        assert!(calls
+            .observe(
+                today,
+                Decimal::new(100000, 0),
+                Decimal::new(120000, 0),
+                four
+            )
+            .is_none());
+        assert_eq!(calls.restrict(four, today), four);
+
+        let call = calls
+            .observe(
+                today,
+                Decimal::new(140000, 0),
+                Decimal::new(120000, 0),
+                four,
+            )
+            .unwrap();
+        assert_eq!(call.amount, Decimal::new(5000, 0));
+        assert_eq!(call.due_on, NaiveDate::from_ymd(2021, 6, 10));
+        assert_eq!(calls.restrict(four, today), Decimal::new(2, 0));
+        assert_eq!(
+            calls.restrict(four, NaiveDate::from_ymd(2021, 6, 11)),
+            Decimal::ONE
+        );
+        // Only one call at a time
+        let tomorrow = today.succ();
+        assert!(calls
+            .observe(
+                tomorrow,
+                Decimal::new(150000, 0),
+                Decimal::new(60000, 0),
+                Decimal::new(2, 0)
+            )
+            .is_none());
+
+        // Met once deposits cover it
+        calls.deposit(Decimal::new(3000, 0));
+        assert!(calls.outstanding().is_some());
+        calls.deposit(Decimal::new(2000, 0));
+        assert!(calls.outstanding().is_none());
+        assert_eq!(calls.restrict(four, tomorrow), four);
+    }
+
+    #[test]
+    fn due_on_business_days() {
+        // The Wednesday before Independence Day
+        let today = NaiveDate::from_ymd(2021, 6, 30);
+        let mut calls = DayTradeCalls::default();
+        let call = calls
+            .observe(today, Decimal::new(2, 0), Decimal::ONE, Decimal::ONE)
+            .unwrap();
+        assert_eq!(call.due_on, NaiveDate::from_ymd(2021, 7, 8));
+    }
+}
@@ -0,0 +1,100 @@
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::trace;
+
+/// Number of business days in the rolling window used to count day trades.
+const WINDOW_DAYS: usize = 5;
+
+/// Number of day trades allowed in the rolling window for accounts under the PDT equity threshold.
+pub const MAX_DAY_TRADES: usize = 3;
+
+fn window_start(today: NaiveDate) -> NaiveDate {
+    let mut date = today;
+    let mut remaining = WINDOW_DAYS - 1;
+    while remaining > 0 {
+        date -= Duration::days(1);
+        if !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) {
+            remaining -= 1;
+        }
+    }
+    date
+}
+
+/// Counts round trips (an opening fill followed by a closing fill in the same symbol on the same
+/// day) over a rolling five business day window.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DayTradeTracker {
+    /// Day trades reported by the broker at startup. The broker doesn't tell us when these
+    /// happened, so they are assumed to stay in the window for the lifetime of the process.
+    baseline: usize,
+    opened: HashMap<String, NaiveDate>,
+    day_trades: Vec<NaiveDate>,
+}
+
+impl DayTradeTracker {
+    pub fn new(baseline: usize) -> Self {
+        Self {
+            baseline,
+            ..Default::default()
+        }
+    }
+
+    pub fn record_open(&mut self, ticker: &str, date: NaiveDate) {
+        self.opened.insert(ticker.to_string(), date);
+    }
+
+    pub fn record_close(&mut self, ticker: &str, date: NaiveDate) {
+        if self.opened.remove(ticker) == Some(date) {
+            trace!(%ticker, %date, "Day trade recorded");
+            self.day_trades.push(date);
+        }
+    }
+
+    /// Returns `true` if closing `ticker` on `today` would complete a day trade.
+    pub fn is_day_trade(&self, ticker: &str, today: NaiveDate) -> bool {
+        self.opened.get(ticker) == Some(&today)
+    }
+
+    pub fn count(&self, today: NaiveDate) -> usize {
+        let start = window_start(today);
+        self.baseline
+            + self
+                .day_trades
+                .iter()
+                .filter(|date| **date >= start && **date <= today)
+                .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd(y, m, d)
+    }
+
+    #[test]
+    fn rolling_window() {
+        let monday = date(2021, 11, 1);
+        let mut tracker = DayTradeTracker::new(1);
+        tracker.record_open("AAPL", monday);
+        tracker.record_open("AAPL", monday);
+        assert!(tracker.is_day_trade("AAPL", monday));
+        assert!(!tracker.is_day_trade("AAPL", date(2021, 11, 2)));
+        tracker.record_close("AAPL", monday);
+        // Only the first closing fill completes the round trip
+        tracker.record_close("AAPL", monday);
+        assert_eq!(tracker.count(monday), 2);
+        // Friday is still inside the window
+        assert_eq!(tracker.count(date(2021, 11, 5)), 2);
+        // But the next Monday isn't
+        assert_eq!(tracker.count(date(2021, 11, 8)), 1);
+
+        // Positions opened on a previous day don't count
+        tracker.record_open("TSLA", monday);
+        tracker.record_close("TSLA", date(2021, 11, 2));
+        assert_eq!(tracker.count(date(2021, 11, 2)), 2);
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+const DEFAULT_CAPACITY: usize = 10_000;
+
+/// A bounded map that evicts the least recently inserted entry once full. Used to remember recent
+/// decisions so that redelivered messages get the same answer instead of being evaluated twice.
+#[derive(Clone, Debug)]
+pub struct RecentCache<K, V> {
+    capacity: usize,
+    order: VecDeque<K>,
+    entries: HashMap<K, V>,
+}
+
+impl<K: Clone + Eq + Hash, V> RecentCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            entries: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)
+    }
+
+    /// Entries from most to least recently inserted.
+    pub fn latest(&self) -> impl Iterator<Item = &V> {
+        self.order
+            .iter()
+            .rev()
+            .filter_map(move |key| self.entries.get(key))
+    }
+
+    /// Entries from least to most recently inserted.
+    pub fn entries(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.order
+            .iter()
+            .filter_map(move |key| Some((key, self.entries.get(key)?)))
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key.clone(), value).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash, V> Default for RecentCache<K, V> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest() {
+        let mut cache = RecentCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        cache.insert(1, "c");
+        assert_eq!(cache.get(&1), Some(&"c"));
+        cache.insert(3, "d");
+        assert_eq!(cache.latest().collect::<Vec<_>>(), vec![&"d", &"b"]);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.get(&2), Some(&"b"));
+        assert_eq!(cache.get(&3), Some(&"d"));
+    }
+}
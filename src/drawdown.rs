@@ -0,0 +1,97 @@
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+/// Follows the highest equity of the day, and trips once equity gives back more than a fraction
+/// of it. Stays tripped for the rest of the day.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct DrawdownBreaker {
+    day: Option<NaiveDate>,
+    peak: Decimal,
+    tripped: bool,
+}
+
+impl DrawdownBreaker {
+    /// Records the current equity. Returns the drawdown from the peak, as a fraction of it, if
+    /// this trips the breaker.
+    pub fn observe(
+        &mut self,
+        equity: Decimal,
+        today: NaiveDate,
+        max_drawdown: Decimal,
+    ) -> Option<Decimal> {
+        if self.day != Some(today) {
+            *self = Self {
+                day: Some(today),
+                peak: equity,
+                tripped: false,
+            };
+        }
+        self.peak = self.peak.max(equity);
+        if self.tripped || !self.peak.is_sign_positive() || self.peak.is_zero() {
+            return None;
+        }
+        let drawdown = (self.peak - equity) / self.peak;
+        if drawdown > max_drawdown {
+            self.tripped = true;
+            Some(drawdown)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the breaker tripped today.
+    pub fn is_tripped(&self, today: NaiveDate) -> bool {
+        self.tripped && self.day == Some(today)
+    }
+
+    /// Highest equity of the day.
+    pub fn peak(&self) -> Decimal {
+        self.peak
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn drawdown() {
+        let today = NaiveDate::from_ymd(2021, 6, 1);
+        let max_drawdown = Decimal::new(5, 2);
+        let mut breaker = DrawdownBreaker::default();
+        assert_eq!(
+            breaker.observe(Decimal::new(1000, 0), today, max_drawdown),
+            None
+        );
+        assert_eq!(
+            breaker.observe(Decimal::new(1200, 0), today, max_drawdown),
+            None
+        );
+        // Still above the opening equity, but 6% off the peak
+        assert_eq!(
+            breaker.observe(Decimal::new(1128, 0), today, max_drawdown),
+            Some(Decimal::new(6, 2))
+        );
+        assert!(breaker.is_tripped(today));
+        // Only trips once, and recovering doesn't reset it
+        assert_eq!(
+            breaker.observe(Decimal::new(1000, 0), today, max_drawdown),
+            None
+        );
+        assert_eq!(
+            breaker.observe(Decimal::new(1300, 0), today, max_drawdown),
+            None
+        );
+        assert!(breaker.is_tripped(today));
+
+        // The next day starts over
+        let tomorrow = today.succ();
+        assert!(!breaker.is_tripped(tomorrow));
+        assert_eq!(
+            breaker.observe(Decimal::new(1250, 0), tomorrow, max_drawdown),
+            None
+        );
+        assert_eq!(breaker.peak(), Decimal::new(1250, 0));
+    }
+}
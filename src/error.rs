@@ -0,0 +1,42 @@
+use thiserror::Error;
+
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+/// What went wrong in the risk manager, so that callers can tell errors worth retrying apart from
+/// requests that will never succeed.
+#[derive(Debug, Error)]
+pub enum RiskManagerError {
+    #[cfg(feature = "service")]
+    #[error("Kafka error: {0}")]
+    Kafka(#[from] rdkafka::error::KafkaError),
+    /// The broker couldn't be reached, or rejected the request
+    #[error("Broker error: {0}")]
+    Broker(#[source] Source),
+    #[error("No price for {ticker}: {source}")]
+    PriceSource {
+        ticker: String,
+        #[source]
+        source: Source,
+    },
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The intent can't be evaluated, e.g. because of its order type
+    #[error("Rule evaluation failed: {0}")]
+    RuleEvaluation(String),
+    /// Something the risk manager needs is missing, or wasn't built in
+    #[error("Configuration error: {0}")]
+    Configuration(String),
+}
+
+impl RiskManagerError {
+    pub(crate) fn price_source(ticker: &str, source: impl Into<Source>) -> Self {
+        Self::PriceSource {
+            ticker: ticker.to_string(),
+            source: source.into(),
+        }
+    }
+}
+
+pub type Result<T, E = RiskManagerError> = std::result::Result<T, E>;
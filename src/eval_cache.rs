@@ -0,0 +1,148 @@
+use crate::dedup::RecentCache;
+use crate::settings::EvaluationCacheSettings;
+use crate::RiskCheckResponse;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Everything a risk check depends on. Intents with equal keys get the same decision.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EvaluationKey {
+    pub ticker: String,
+    /// Signed, so this also captures the side
+    pub qty: isize,
+    pub order_type: String,
+    pub allow_partial: bool,
+    pub today: NaiveDate,
+    /// Changes whenever the portfolio, rules or limits change. Marks don't change it, and are
+    /// only picked up once the entry expires.
+    pub state: (u64, u64),
+}
+
+#[derive(Debug, Default)]
+pub struct EvaluationCacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl EvaluationCacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        let (hits, misses) = (self.hits(), self.misses());
+        if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        }
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut metrics = String::new();
+        let counters = [
+            ("hits", "Risk checks answered from the cache", self.hits()),
+            (
+                "misses",
+                "Risk checks that ran the rule pipeline",
+                self.misses(),
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            let _ = writeln!(
+                metrics,
+                "# HELP risk_manager_evaluation_cache_{}_total {}",
+                name, help
+            );
+            let _ = writeln!(
+                metrics,
+                "# TYPE risk_manager_evaluation_cache_{}_total counter",
+                name
+            );
+            let _ = writeln!(
+                metrics,
+                "risk_manager_evaluation_cache_{}_total {}",
+                name, value
+            );
+        }
+        let _ = writeln!(
+            metrics,
+            "# HELP risk_manager_evaluation_cache_hit_rate Fraction of risk checks answered from the cache"
+        );
+        let _ = writeln!(
+            metrics,
+            "# TYPE risk_manager_evaluation_cache_hit_rate gauge"
+        );
+        let _ = writeln!(
+            metrics,
+            "risk_manager_evaluation_cache_hit_rate {}",
+            self.hit_rate()
+        );
+        metrics
+    }
+}
+
+/// Remembers recent decisions so that bursts of identical intents, common with retrying
+/// upstreams, skip the price fetch and the rule pipeline. Entries expire after a short time since
+/// market prices move even when the portfolio doesn't.
+#[derive(Debug)]
+pub struct EvaluationCache {
+    enabled: bool,
+    ttl: Duration,
+    entries: Mutex<RecentCache<EvaluationKey, (DateTime<Utc>, RiskCheckResponse)>>,
+    stats: Arc<EvaluationCacheStats>,
+}
+
+impl EvaluationCache {
+    pub fn new(settings: &EvaluationCacheSettings) -> Self {
+        Self {
+            enabled: settings.enabled,
+            ttl: Duration::milliseconds(settings.ttl_ms as i64),
+            entries: Mutex::new(RecentCache::new(settings.capacity)),
+            stats: Default::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn stats(&self) -> Arc<EvaluationCacheStats> {
+        self.stats.clone()
+    }
+
+    pub fn get(&self, key: &EvaluationKey, now: DateTime<Utc>) -> Option<RiskCheckResponse> {
+        let entries = self.entries.lock().expect("Evaluation cache lock poisoned");
+        let hit = entries
+            .get(key)
+            .filter(|(inserted, _)| now - *inserted < self.ttl)
+            .map(|(_, response)| response.clone());
+        let counter = if hit.is_some() {
+            &self.stats.hits
+        } else {
+            &self.stats.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        hit
+    }
+
+    pub fn insert(&self, key: EvaluationKey, response: RiskCheckResponse, now: DateTime<Utc>) {
+        self.entries
+            .lock()
+            .expect("Evaluation cache lock poisoned")
+            .insert(key, (now, response));
+    }
+}
+
+impl Default for EvaluationCache {
+    fn default() -> Self {
+        Self::new(&Default::default())
+    }
+}
@@ -0,0 +1,104 @@
+use crate::margin_call::MarginAlert;
+use crate::report::Position;
+use crate::RiskCheckResponse;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+const CHANNEL_CAPACITY: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Decisions,
+    Exposures,
+    Alerts,
+}
+
+impl std::str::FromStr for Topic {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "decisions" => Ok(Self::Decisions),
+            "exposures" => Ok(Self::Exposures),
+            "alerts" => Ok(Self::Alerts),
+            _ => Err(anyhow::anyhow!("Unknown topic: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ExposureUpdate {
+    pub cash: Decimal,
+    pub equity: Decimal,
+    pub long_market_exposure: Decimal,
+    pub short_market_exposure: Decimal,
+    pub gross_market_exposure: Decimal,
+    pub net_market_exposure: Decimal,
+    pub buying_power: Decimal,
+    #[serde(default)]
+    pub positions: Vec<Position>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertLevel {
+    Warning,
+    Critical,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Alert {
+    pub level: AlertLevel,
+    pub message: String,
+}
+
+/// Live events pushed to dashboards.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "topic", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    Decision(RiskCheckResponse),
+    Exposure(ExposureUpdate),
+    Alert(Alert),
+    MarginAlert(MarginAlert),
+}
+
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Event::Decision(_) => Topic::Decisions,
+            Event::Exposure(_) => Topic::Exposures,
+            Event::Alert(_) | Event::MarginAlert(_) => Topic::Alerts,
+        }
+    }
+}
+
+/// Fan-out of live events to any number of subscribers. Publishing never blocks; subscribers that
+/// fall too far behind miss events rather than slowing down the risk checks.
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    pub fn publish(&self, event: Event) {
+        // An error only means that nobody is listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,254 @@
+use crate::settings::{FixMode, FixSettings};
+use crate::RiskCheckResponse;
+use anyhow::Result;
+use chrono::Utc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tracing::{debug, error, info, warn};
+use trading_base::OrderType;
+use uuid::Uuid;
+
+const SOH: char = '\x01';
+const BEGIN_STRING: &str = "FIX.4.4";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+fn timestamp() -> String {
+    Utc::now().format("%Y%m%d-%H:%M:%S%.3f").to_string()
+}
+
+/// A FIX message under construction. Header fields that depend on the session (sender, target,
+/// sequence number) are added when the message is encoded.
+pub struct FixMessage {
+    msg_type: &'static str,
+    fields: Vec<(u32, String)>,
+}
+
+impl FixMessage {
+    pub fn new(msg_type: &'static str) -> Self {
+        Self {
+            msg_type,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn field<T: ToString>(mut self, tag: u32, value: T) -> Self {
+        self.fields.push((tag, value.to_string()));
+        self
+    }
+
+    pub fn encode(&self, sender: &str, target: &str, seq_num: u64, sending_time: &str) -> String {
+        let mut body = format!(
+            "35={}{soh}49={}{soh}56={}{soh}34={}{soh}52={}{soh}",
+            self.msg_type,
+            sender,
+            target,
+            seq_num,
+            sending_time,
+            soh = SOH
+        );
+        for (tag, value) in &self.fields {
+            body.push_str(&format!("{}={}{}", tag, value, SOH));
+        }
+        let mut message = format!(
+            "8={}{soh}9={}{soh}{}",
+            BEGIN_STRING,
+            body.len(),
+            body,
+            soh = SOH
+        );
+        let checksum = message.bytes().fold(0u32, |sum, b| sum + b as u32) % 256;
+        message.push_str(&format!("10={:03}{}", checksum, SOH));
+        message
+    }
+}
+
+/// Converts a risk-check decision into an ExecutionReport drop copy. Granted intents are
+/// reported as new orders and denied intents as rejections. Partially approved intents are
+/// reported as new orders for the approved quantity.
+pub fn execution_report(response: &RiskCheckResponse) -> FixMessage {
+    let (intent, denial) = match response {
+        RiskCheckResponse::Granted { intent, .. } => (intent.clone(), None),
+        RiskCheckResponse::Denied {
+            intent, reasons, ..
+        } => (intent.clone(), Some(reasons)),
+        RiskCheckResponse::Amended {
+            original,
+            approved_qty,
+            ..
+        } => {
+            let mut intent = original.clone();
+            intent.qty = *approved_qty;
+            (intent, None)
+        }
+    };
+    let side = if intent.qty > 0 { 1 } else { 2 };
+    let qty = intent.qty.abs();
+    let (ord_type, price) = match intent.order_type {
+        OrderType::Market => (1, None),
+        OrderType::Limit { limit_price } => (2, Some(limit_price)),
+        // The risk manager only decides on market and limit orders
+        _ => (1, None),
+    };
+    let mut message = FixMessage::new("8")
+        .field(37, intent.id)
+        .field(11, intent.id)
+        .field(17, Uuid::new_v4())
+        .field(55, &intent.ticker)
+        .field(54, side)
+        .field(38, qty)
+        .field(40, ord_type);
+    if let Some(price) = price {
+        message = message.field(44, price);
+    }
+    message = match denial {
+        None => message.field(150, 0).field(39, 0).field(151, qty),
+        Some(reasons) => message
+            .field(150, 8)
+            .field(39, 8)
+            .field(151, 0)
+            .field(103, 99)
+            .field(
+                58,
+                reasons
+                    .iter()
+                    .map(|reason| format!("{:?}", reason))
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            ),
+    };
+    message.field(14, 0).field(6, 0).field(60, timestamp())
+}
+
+struct Session<'a> {
+    settings: &'a FixSettings,
+    stream: TcpStream,
+    seq_num: u64,
+}
+
+impl<'a> Session<'a> {
+    async fn send(&mut self, message: &FixMessage) -> Result<()> {
+        self.seq_num += 1;
+        let encoded = message.encode(
+            &self.settings.sender_comp_id,
+            &self.settings.target_comp_id,
+            self.seq_num,
+            &timestamp(),
+        );
+        self.stream.write_all(encoded.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn run(&mut self, receiver: &mut UnboundedReceiver<RiskCheckResponse>) -> Result<()> {
+        let logon = FixMessage::new("A")
+            .field(98, 0)
+            .field(108, self.settings.heartbeat_interval_secs)
+            .field(141, "Y");
+        self.send(&logon).await?;
+        let mut heartbeat =
+            tokio::time::interval(Duration::from_secs(self.settings.heartbeat_interval_secs));
+        let mut buf = [0u8; 4096];
+        loop {
+            tokio::select! {
+                response = receiver.recv() => match response {
+                    Some(response) => self.send(&execution_report(&response)).await?,
+                    None => {
+                        self.send(&FixMessage::new("5")).await?;
+                        return Ok(());
+                    }
+                },
+                _ = heartbeat.tick() => self.send(&FixMessage::new("0")).await?,
+                // Drop copies are one-way; inbound traffic is only read to detect disconnects
+                read = self.stream.read(&mut buf) => {
+                    if read? == 0 {
+                        return Err(anyhow::anyhow!("Counterparty closed the connection"));
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn connect(settings: &FixSettings, listener: Option<&TcpListener>) -> Result<TcpStream> {
+    match listener {
+        Some(listener) => {
+            let (stream, addr) = listener.accept().await?;
+            info!(%addr, "Accepted FIX connection");
+            Ok(stream)
+        }
+        None => {
+            let stream = TcpStream::connect(&settings.address).await?;
+            info!(address = %settings.address, "Connected to FIX counterparty");
+            Ok(stream)
+        }
+    }
+}
+
+async fn run_session(
+    settings: FixSettings,
+    mut receiver: UnboundedReceiver<RiskCheckResponse>,
+) -> Result<()> {
+    let listener = match settings.mode {
+        FixMode::Acceptor => Some(TcpListener::bind(&settings.address).await?),
+        FixMode::Initiator => None,
+    };
+    loop {
+        match connect(&settings, listener.as_ref()).await {
+            Ok(stream) => {
+                let mut session = Session {
+                    settings: &settings,
+                    stream,
+                    seq_num: 0,
+                };
+                match session.run(&mut receiver).await {
+                    Ok(()) => {
+                        debug!("FIX drop-copy session finished");
+                        return Ok(());
+                    }
+                    Err(e) => warn!(?e, "FIX drop-copy session ended"),
+                }
+            }
+            Err(e) => warn!(?e, "Failed to establish FIX session"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Emits ExecutionReport drop copies of risk-check decisions over a FIX 4.4 session.
+#[derive(Clone)]
+pub struct FixDropCopy {
+    sender: UnboundedSender<RiskCheckResponse>,
+}
+
+impl FixDropCopy {
+    pub fn spawn(settings: FixSettings) -> Self {
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(e) = run_session(settings, receiver).await {
+                error!(?e, "FIX drop-copy session failed")
+            }
+        });
+        Self { sender }
+    }
+
+    pub fn notify(&self, response: &RiskCheckResponse) {
+        if self.sender.send(response.clone()).is_err() {
+            warn!("FIX drop-copy session is no longer running")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let message = FixMessage::new("0").encode("RISK", "OMS", 1, "20211101-14:30:00.000");
+        assert_eq!(
+            message.replace(SOH, "|"),
+            "8=FIX.4.4|9=50|35=0|49=RISK|56=OMS|34=1|52=20211101-14:30:00.000|10=217|"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Decisions recorded with the state they were made in, to be replayed as golden tests.
+//!
+//! In shadow mode, setting `SHADOW__FIXTURES` appends every decision to a file. Replaying the
+//! file checks that a change to the rules doesn't change any of the decisions:
+//!
+//! ```ignore
+//! let fixtures = fixtures::read_fixtures(BufReader::new(File::open("fixtures.jsonl")?))?;
+//! let mismatches = fixtures::mismatches(&fixtures, &RiskSettings::default());
+//! assert!(mismatches.is_empty(), "{:#?}", mismatches);
+//! ```
+use crate::clock::ManualClock;
+use crate::error::Result;
+use crate::handoff::HandoffState;
+use crate::input::RequestOptions;
+use crate::json_lines::read_json_lines;
+use crate::limits::{RiskLimits, SharedLimits};
+use crate::prices::PriceTick;
+use crate::settings::RiskSettings;
+use crate::{RiskCheckResponse, RiskManager};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Write};
+use std::sync::Mutex;
+use trading_base::TradeIntent;
+use uuid::Uuid;
+
+/// An intent, the decision it got and everything the decision depended on.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DecisionFixture {
+    /// The account when the intent was checked. Its `saved_at` is the time of the decision.
+    pub state: HandoffState,
+    pub limits: RiskLimits,
+    pub intent: TradeIntent,
+    /// Last trade price the intent was checked at, if it was priced before the check
+    pub last_price: Option<Decimal>,
+    /// When the last price was traded at, unless its source doesn't say or the fixture was
+    /// recorded before prices were timestamped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traded_at: Option<DateTime<Utc>>,
+    pub decision: RiskCheckResponse,
+}
+
+impl DecisionFixture {
+    /// Captures a decision the risk manager has just made, before it is recorded.
+    pub fn capture(
+        risk_manager: &RiskManager,
+        intent: &TradeIntent,
+        last_price: Option<PriceTick>,
+        decision: &RiskCheckResponse,
+    ) -> Self {
+        let mut state = risk_manager.handoff_state();
+        // Only needed to answer redelivered intents
+        state.recent_decisions.clear();
+        Self {
+            state,
+            limits: (*risk_manager.limits()).clone(),
+            intent: intent.clone(),
+            last_price: last_price.map(|tick| tick.price),
+            traded_at: last_price.and_then(|tick| tick.timestamp),
+            decision: comparable(decision.clone()),
+        }
+    }
+
+    /// Decides the intent again, in the recorded state and at the recorded time.
+    pub fn replay(&self, settings: &RiskSettings) -> Result<RiskCheckResponse> {
+        let mut risk_manager = RiskManager::new(String::new(), settings.clone());
+        risk_manager.bind_clock(ManualClock::new(self.state.saved_at));
+        risk_manager.restore(self.state.clone());
+        risk_manager.bind_limits(SharedLimits::new(self.limits.clone()));
+        let last_price = self.last_price.map(|price| PriceTick {
+            price,
+            timestamp: self.traded_at,
+            source: None,
+        });
+        let decision =
+            risk_manager.risk_check_priced(&self.intent, RequestOptions::default(), last_price)?;
+        Ok(comparable(decision))
+    }
+}
+
+/// The decision without its timing and price source, which a replay can't reproduce.
+fn comparable(mut decision: RiskCheckResponse) -> RiskCheckResponse {
+    match &mut decision {
+        RiskCheckResponse::Granted {
+            timing,
+            price_source,
+            ..
+        }
+        | RiskCheckResponse::Denied {
+            timing,
+            price_source,
+            ..
+        }
+        | RiskCheckResponse::Amended {
+            timing,
+            price_source,
+            ..
+        } => {
+            *timing = None;
+            *price_source = None;
+        }
+    }
+    decision
+}
+
+/// Reads fixtures from JSON lines, skipping blank lines.
+pub fn read_fixtures(reader: impl BufRead) -> Result<Vec<DecisionFixture>> {
+    read_json_lines(reader)
+}
+
+/// A recorded decision that came out differently when replayed.
+#[derive(Debug, PartialEq)]
+pub struct GoldenMismatch {
+    pub intent_id: Uuid,
+    pub expected: RiskCheckResponse,
+    /// The error if the intent could no longer be decided
+    pub actual: std::result::Result<RiskCheckResponse, String>,
+}
+
+/// Replays every fixture, returning those whose decision changed.
+pub fn mismatches(fixtures: &[DecisionFixture], settings: &RiskSettings) -> Vec<GoldenMismatch> {
+    fixtures
+        .iter()
+        .filter_map(|fixture| {
+            let actual = fixture.replay(settings).map_err(|e| e.to_string());
+            if actual.as_ref() == Ok(&fixture.decision) {
+                return None;
+            }
+            Some(GoldenMismatch {
+                intent_id: fixture.intent.id,
+                expected: fixture.decision.clone(),
+                actual,
+            })
+        })
+        .collect()
+}
+
+/// Appends fixtures to a file as JSON lines.
+#[derive(Debug)]
+pub struct FixtureRecorder(Mutex<File>);
+
+impl FixtureRecorder {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self(Mutex::new(file)))
+    }
+
+    pub fn record(&self, fixture: &DecisionFixture) -> Result<()> {
+        let mut line = serde_json::to_vec(fixture)?;
+        line.push(b'\n');
+        // A single write per line, so that lines stay whole if several recorders share a file
+        self.0
+            .lock()
+            .expect("Fixture file lock poisoned")
+            .write_all(&line)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Price, Shares};
+    use chrono::{TimeZone, Utc};
+    use std::io::BufReader;
+    use trading_base::OrderType;
+
+    #[test]
+    fn golden() {
+        let mut risk_manager = RiskManager::default();
+        risk_manager.bind_clock(ManualClock::new(Utc.ymd(2021, 6, 4).and_hms(14, 0, 0)));
+        risk_manager.update_cash(Decimal::new(10000, 0));
+        risk_manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(10, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        risk_manager.bind_limits(SharedLimits::new(RiskLimits {
+            max_notional: Some(Decimal::new(2000, 0)),
+            ..Default::default()
+        }));
+        let limit = || OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        };
+        let granted = TradeIntent::new("AAPL", 10).order_type(limit());
+        let denied = TradeIntent::new("MSFT", 30).order_type(limit());
+        let price = Some(PriceTick {
+            price: Decimal::new(100, 0),
+            timestamp: Some(Utc::now()),
+            source: None,
+        });
+
+        let path = std::env::temp_dir().join(format!("fixtures-{}.jsonl", Uuid::new_v4()));
+        let recorder = FixtureRecorder::open(path.to_str().unwrap()).unwrap();
+        for (intent, last_price) in [(&granted, price), (&denied, None)].iter() {
+            let decision = risk_manager
+                .risk_check_priced(intent, RequestOptions::default(), *last_price)
+                .unwrap()
+                .timed(Utc::now());
+            let fixture = DecisionFixture::capture(&risk_manager, intent, *last_price, &decision);
+            recorder.record(&fixture).unwrap();
+        }
+        let fixtures = read_fixtures(BufReader::new(File::open(&path).unwrap())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(fixtures.len(), 2);
+        assert!(mismatches(&fixtures, &RiskSettings::default()).is_empty());
+
+        // Any change in the decisions is reported
+        let settings = RiskSettings {
+            partial_approvals: true,
+            ..Default::default()
+        };
+        let changed = mismatches(&fixtures, &settings);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].intent_id, denied.id);
+        assert!(matches!(
+            changed[0].actual,
+            Ok(RiskCheckResponse::Amended {
+                approved_qty: 20,
+                ..
+            })
+        ));
+    }
+}
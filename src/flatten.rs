@@ -0,0 +1,112 @@
+use crate::clock::Clock;
+use crate::settings::FlattenSettings;
+use crate::RiskManager;
+use chrono::NaiveDate;
+use std::collections::HashSet;
+use tracing::info;
+use trading_base::TradeIntent;
+
+/// Flattens the tickers that must be flat overnight once the market is about to close.
+pub struct Flattener {
+    minutes_before_close: u64,
+    tickers: HashSet<String>,
+    /// Day the positions were last closed, so that they are only closed once per day
+    flattened_on: Option<NaiveDate>,
+}
+
+impl Flattener {
+    pub fn new(settings: FlattenSettings) -> Self {
+        Self {
+            minutes_before_close: settings.minutes_before_close,
+            tickers: settings.strategies.into_values().flatten().collect(),
+            flattened_on: None,
+        }
+    }
+
+    /// Opens the flatten window once the close is `next_close` seconds away or less, returning
+    /// intents that close the remaining positions the first time on a given day.
+    pub fn market_open(
+        &mut self,
+        next_close: usize,
+        risk_manager: &mut RiskManager,
+    ) -> Vec<TradeIntent> {
+        if next_close as u64 > self.minutes_before_close * 60 {
+            risk_manager.set_flatten_window(HashSet::new());
+            return Vec::new();
+        }
+        risk_manager.set_flatten_window(self.tickers.clone());
+        let today = risk_manager.clock().today();
+        if self.flattened_on == Some(today) {
+            return Vec::new();
+        }
+        self.flattened_on = Some(today);
+        let intents: Vec<_> = risk_manager
+            .positions()
+            .filter(|position| self.tickers.contains(&position.ticker))
+            .filter_map(|position| position.closing_intent())
+            .collect();
+        info!(positions = intents.len(), "Flattening before the close");
+        intents
+    }
+
+    /// Closes the flatten window.
+    pub fn market_closed(&self, risk_manager: &mut RiskManager) {
+        risk_manager.set_flatten_window(HashSet::new());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DenyReason, Price, RiskCheckResponse, Shares};
+    use rust_decimal::Decimal;
+    use std::collections::HashMap;
+    use trading_base::OrderType;
+
+    #[test]
+    fn flatten() {
+        let mut strategies = HashMap::new();
+        strategies.insert("intraday".to_string(), vec!["AAPL".to_string()]);
+        let mut flattener = Flattener::new(FlattenSettings {
+            minutes_before_close: 15,
+            strategies,
+        });
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(10000, 0));
+        for ticker in ["AAPL", "TSLA"].iter() {
+            manager.update_holdings(
+                *ticker,
+                Shares(Decimal::new(10, 0)),
+                Price(Decimal::new(100, 0)),
+            );
+        }
+        assert!(flattener.market_open(3600, &mut manager).is_empty());
+
+        let intents = flattener.market_open(900, &mut manager);
+        assert_eq!(intents.len(), 1);
+        assert_eq!(intents[0].ticker, "AAPL");
+        assert_eq!(intents[0].qty, -10);
+        // Only once per day
+        assert!(flattener.market_open(600, &mut manager).is_empty());
+
+        let limit = || OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        };
+        let opening = TradeIntent::new("AAPL", 1).order_type(limit());
+        assert!(matches!(
+            manager.risk_check(&opening).unwrap(),
+            RiskCheckResponse::Denied { reasons, .. } if reasons == vec![DenyReason::FlattenWindow]
+        ));
+        let other = TradeIntent::new("TSLA", 1).order_type(limit());
+        assert!(matches!(
+            manager.risk_check(&other).unwrap(),
+            RiskCheckResponse::Granted { .. }
+        ));
+
+        flattener.market_closed(&mut manager);
+        assert!(matches!(
+            manager.risk_check(&opening).unwrap(),
+            RiskCheckResponse::Granted { .. }
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::admin::{AdminCommand, AdminSender};
+use crate::input::RequestOptions;
+use crate::proto::generated::{
+    self as proto,
+    risk_check_server::{RiskCheck, RiskCheckServer},
+};
+use crate::settings::GrpcSettings;
+use crate::signature::constant_time_eq;
+use tokio::sync::oneshot;
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+
+/// Forwards requests to the main loop through the admin channel, so gRPC checks are serialized
+/// with the ones from Kafka and decided the same way, reserving buying power when granted.
+struct RiskCheckService {
+    admin: AdminSender,
+}
+
+impl RiskCheckService {
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> AdminCommand,
+    ) -> Result<T, Status> {
+        let (tx, rx) = oneshot::channel();
+        self.admin
+            .send(command(tx))
+            .await
+            .map_err(|_| Status::unavailable("Risk manager is not running"))?;
+        rx.await
+            .map_err(|_| Status::internal("Request failed, see risk manager logs"))
+    }
+}
+
+#[tonic::async_trait]
+impl RiskCheck for RiskCheckService {
+    async fn check(
+        &self,
+        request: Request<proto::TradeIntent>,
+    ) -> Result<Response<proto::RiskCheckResponse>, Status> {
+        let intent = request
+            .into_inner()
+            .into_intent()
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let response = self
+            .request(|tx| AdminCommand::RiskCheck(intent, RequestOptions::default(), tx))
+            .await?;
+        Ok(Response::new((&response).into()))
+    }
+
+    async fn get_portfolio(
+        &self,
+        _request: Request<proto::GetPortfolioRequest>,
+    ) -> Result<Response<proto::Portfolio>, Status> {
+        let snapshot = self.request(AdminCommand::Portfolio).await?;
+        Ok(Response::new(snapshot.into()))
+    }
+}
+
+/// Rejects calls without the admin bearer token. Without a configured token, every call is
+/// rejected, like the admin endpoints of the web server.
+fn authorize(token: Option<&str>, request: Request<()>) -> Result<Request<()>, Status> {
+    let token = token.ok_or_else(|| Status::permission_denied("No admin token configured"))?;
+    let presented = request
+        .metadata()
+        .get("authorization")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+        .ok_or_else(|| Status::unauthenticated("Missing admin token"))?;
+    if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+        Ok(request)
+    } else {
+        Err(Status::permission_denied("Invalid admin token"))
+    }
+}
+
+pub async fn serve(settings: GrpcSettings, admin_token: Option<String>, admin: AdminSender) {
+    info!(port = settings.port, "Starting gRPC server");
+    if admin_token.is_none() {
+        warn!("No admin token configured, gRPC calls are disabled");
+    }
+    let service = RiskCheckServer::with_interceptor(RiskCheckService { admin }, move |request| {
+        authorize(admin_token.as_deref(), request)
+    });
+    let result = tonic::transport::Server::builder()
+        .add_service(service)
+        .serve(([0, 0, 0, 0], settings.port).into())
+        .await;
+    if let Err(e) = result {
+        error!(?e, "gRPC server failed")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(header: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(header) = header {
+            request
+                .metadata_mut()
+                .insert("authorization", header.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn admin_token() {
+        let token = Some("secret");
+        assert!(authorize(token, request(Some("Bearer secret"))).is_ok());
+        let missing = authorize(token, request(None)).unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::Unauthenticated);
+        let wrong = authorize(token, request(Some("Bearer guess"))).unwrap_err();
+        assert_eq!(wrong.code(), tonic::Code::PermissionDenied);
+        // Calls are disabled without a token
+        let disabled = authorize(None, request(Some("Bearer secret"))).unwrap_err();
+        assert_eq!(disabled.code(), tonic::Code::PermissionDenied);
+    }
+}
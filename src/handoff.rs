@@ -0,0 +1,133 @@
+use crate::control::{AccountBlocks, AppliedControls, TradingHalts};
+use crate::day_trade_calls::DayTradeCalls;
+use crate::day_trades::DayTradeTracker;
+use crate::drawdown::DrawdownBreaker;
+use crate::orders::WorkingOrders;
+use crate::settlement::SettlementLedger;
+use crate::tax_lots::TaxLots;
+use crate::units::{Price, Shares};
+use crate::RiskCheckResponse;
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+#[cfg(feature = "service")]
+pub use store::Handoff;
+use uuid::Uuid;
+
+/// Everything the risk manager has learned since it started, for the instance replacing it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HandoffState {
+    pub saved_at: DateTime<Utc>,
+    pub cash: Decimal,
+    /// Shares and last known price by ticker
+    pub holdings: HashMap<String, (Shares, Price)>,
+    /// Average cost of the holdings by ticker
+    #[serde(default)]
+    pub avg_costs: HashMap<String, Price>,
+    /// Missing from states saved before lots were tracked
+    #[serde(default)]
+    pub tax_lots: Option<TaxLots>,
+    #[serde(default)]
+    pub drawdown: DrawdownBreaker,
+    #[serde(default)]
+    pub day_trade_calls: DayTradeCalls,
+    pub is_pattern_day_trader: bool,
+    #[serde(default)]
+    pub account_multiplier: Option<Decimal>,
+    #[serde(default)]
+    pub shorting_disabled: bool,
+    #[serde(default)]
+    pub account_blocks: AccountBlocks,
+    #[serde(default)]
+    pub session_day: Option<NaiveDate>,
+    pub last_equity: Decimal,
+    pub last_maintenance_margin: Decimal,
+    pub is_cash_account: bool,
+    pub settlement_ledger: SettlementLedger,
+    pub day_trades: DayTradeTracker,
+    pub working_orders: WorkingOrders,
+    /// Least recent first, so that they can be replayed into the cache in order
+    pub recent_decisions: Vec<(Uuid, RiskCheckResponse)>,
+    pub halts: TradingHalts,
+    /// So that control requests applied before the handoff can't be replayed after it
+    #[serde(default)]
+    pub applied_controls: AppliedControls,
+    /// Annualized borrow rates from the borrow rates topic, by ticker
+    #[serde(default)]
+    pub borrow_rates: HashMap<String, Decimal>,
+}
+
+/// Saving and loading states in Redis.
+#[cfg(feature = "service")]
+mod store {
+    use super::HandoffState;
+    use crate::redis_pool::{RedisPool, RedisPoolStats};
+    use crate::settings::{HandoffSettings, RedisPoolSettings};
+    use anyhow::Result;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tracing::{info, warn};
+
+    const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Where the outgoing instance leaves its state. A state can only be taken once, so that a
+    /// later restart doesn't pick up a state that has gone stale.
+    pub struct Handoff {
+        pool: RedisPool,
+        settings: HandoffSettings,
+    }
+
+    impl Handoff {
+        pub fn new(settings: HandoffSettings, pool: RedisPoolSettings) -> Result<Self> {
+            let pool = RedisPool::new("handoff", &settings.redis_url, pool)?;
+            Ok(Self { pool, settings })
+        }
+
+        pub fn pool_stats(&self) -> Arc<RedisPoolStats> {
+            self.pool.stats()
+        }
+
+        pub async fn save(&self, state: &HandoffState) -> Result<()> {
+            let payload = serde_json::to_string(state)?;
+            let ttl = self.settings.ttl_secs as usize;
+            self.pool
+                .query::<()>(
+                    redis::cmd("SETEX")
+                        .arg(&self.settings.key)
+                        .arg(ttl)
+                        .arg(payload),
+                )
+                .await?;
+            info!(key = %self.settings.key, "State saved for handoff");
+            Ok(())
+        }
+
+        /// Takes the state left by the previous instance, waiting for it to be saved for up to the
+        /// configured time.
+        pub async fn take(&self) -> Result<Option<HandoffState>> {
+            let deadline = Instant::now() + Duration::from_secs(self.settings.wait_secs);
+            let payload = loop {
+                let payload: Option<String> = self
+                    .pool
+                    .query(redis::cmd("GET").arg(&self.settings.key))
+                    .await?;
+                match payload {
+                    Some(payload) => break payload,
+                    None if Instant::now() < deadline => tokio::time::sleep(POLL_INTERVAL).await,
+                    None => return Ok(None),
+                }
+            };
+            self.pool
+                .query::<()>(redis::cmd("DEL").arg(&self.settings.key))
+                .await?;
+            match serde_json::from_str(&payload) {
+                Ok(state) => Ok(Some(state)),
+                Err(e) => {
+                    warn!(?e, "Discarding undecodable handoff state");
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::transport::MessageSource;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How often an idle main loop reports that it is still alive.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+struct HealthState {
+    initialized: AtomicBool,
+    last_progress_ms: AtomicU64,
+    liveness_timeout: Duration,
+}
+
+/// Progress of the main loop, shared with the liveness and readiness probes.
+#[derive(Clone)]
+pub struct Health(Arc<HealthState>);
+
+impl Health {
+    pub fn new(liveness_timeout: Duration) -> Self {
+        Self(Arc::new(HealthState {
+            initialized: AtomicBool::new(false),
+            last_progress_ms: AtomicU64::new(now_millis()),
+            liveness_timeout,
+        }))
+    }
+
+    pub fn mark_initialized(&self) {
+        self.0.initialized.store(true, Ordering::Relaxed)
+    }
+
+    /// Records that the main loop made progress.
+    pub fn heartbeat(&self) {
+        self.0
+            .last_progress_ms
+            .store(now_millis(), Ordering::Relaxed)
+    }
+
+    pub fn is_alive(&self) -> bool {
+        let elapsed = now_millis().saturating_sub(self.0.last_progress_ms.load(Ordering::Relaxed));
+        Duration::from_millis(elapsed) <= self.0.liveness_timeout
+    }
+
+    /// Ready once initialized and assigned at least one partition. Returns why it isn't otherwise.
+    pub fn readiness(&self, source: &dyn MessageSource) -> Result<(), &'static str> {
+        if !self.0.initialized.load(Ordering::Relaxed) {
+            return Err("Not initialized");
+        }
+        match source.is_assigned() {
+            Ok(true) => Ok(()),
+            Ok(_) => Err("No partitions assigned"),
+            Err(_) => Err("Failed to fetch partition assignment"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn liveness() {
+        let health = Health::new(Duration::from_millis(20));
+        assert!(health.is_alive());
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(!health.is_alive());
+        health.heartbeat();
+        assert!(health.is_alive());
+    }
+}
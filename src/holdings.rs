@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::Write;
+
+const HEADER: &str = "ticker,shares,price";
+/// Pseudo-ticker of the row holding the cash balance, with the amount in the `shares` column.
+pub const CASH_TICKER: &str = "$CASH";
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Holding {
+    pub ticker: String,
+    pub shares: Decimal,
+    pub price: Decimal,
+}
+
+/// Cash and positions in a broker-neutral form that can be written to and read from CSV.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Holdings {
+    pub cash: Decimal,
+    pub positions: Vec<Holding>,
+}
+
+impl Holdings {
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::new();
+        let _ = writeln!(csv, "{}", HEADER);
+        let _ = writeln!(csv, "{},{},1", CASH_TICKER, self.cash);
+        for holding in &self.positions {
+            let _ = writeln!(
+                csv,
+                "{},{},{}",
+                holding.ticker, holding.shares, holding.price
+            );
+        }
+        csv
+    }
+
+    /// Parses and validates holdings. Every problem is reported with its line number, and the
+    /// file must contain exactly one cash row.
+    pub fn from_csv(csv: &str) -> Result<Self> {
+        let mut lines = csv
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty());
+        match lines.next() {
+            Some((_, header)) if header.replace(' ', "") == HEADER => (),
+            _ => return Err(anyhow!("Expected header `{}`", HEADER)),
+        }
+        let mut cash = None;
+        let mut positions = Vec::new();
+        let mut tickers = HashSet::new();
+        for (line_number, line) in lines {
+            let holding = parse_row(line).map_err(|e| anyhow!("Line {}: {}", line_number, e))?;
+            if !tickers.insert(holding.ticker.clone()) {
+                return Err(anyhow!(
+                    "Line {}: duplicate ticker {}",
+                    line_number,
+                    holding.ticker
+                ));
+            }
+            if holding.ticker == CASH_TICKER {
+                cash = Some(holding.shares);
+            } else {
+                positions.push(holding);
+            }
+        }
+        Ok(Self {
+            cash: cash.ok_or_else(|| anyhow!("Missing {} row", CASH_TICKER))?,
+            positions,
+        })
+    }
+}
+
+fn parse_row(line: &str) -> Result<Holding> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let (ticker, shares, price) = match fields.as_slice() {
+        [ticker, shares, price] => (*ticker, *shares, *price),
+        _ => return Err(anyhow!("expected 3 fields, found {}", fields.len())),
+    };
+    let shares: Decimal = shares
+        .parse()
+        .map_err(|_| anyhow!("invalid shares `{}`", shares))?;
+    let price: Decimal = price
+        .parse()
+        .map_err(|_| anyhow!("invalid price `{}`", price))?;
+    if ticker == CASH_TICKER {
+        if price != Decimal::ONE {
+            return Err(anyhow!("cash must have a price of 1"));
+        }
+    } else {
+        let valid_ticker = !ticker.is_empty()
+            && ticker
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '.' || c == '-');
+        if !valid_ticker {
+            return Err(anyhow!("invalid ticker `{}`", ticker));
+        }
+        if shares.is_zero() {
+            return Err(anyhow!("position in {} has no shares", ticker));
+        }
+        if price <= Decimal::ZERO {
+            return Err(anyhow!("price of {} must be positive", ticker));
+        }
+    }
+    Ok(Holding {
+        ticker: ticker.to_string(),
+        shares,
+        price,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let holdings = Holdings {
+            cash: Decimal::new(100050, 2),
+            positions: vec![
+                Holding {
+                    ticker: "AAPL".into(),
+                    shares: Decimal::new(10, 0),
+                    price: Decimal::new(15025, 2),
+                },
+                Holding {
+                    ticker: "BRK.B".into(),
+                    shares: Decimal::new(-5, 0),
+                    price: Decimal::new(300, 0),
+                },
+            ],
+        };
+        let csv = holdings.to_csv();
+        assert_eq!(
+            csv,
+            "ticker,shares,price\n$CASH,1000.50,1\nAAPL,10,150.25\nBRK.B,-5,300\n"
+        );
+        assert_eq!(Holdings::from_csv(&csv).unwrap(), holdings);
+    }
+
+    #[test]
+    fn validation() {
+        let errors = [
+            ("AAPL,10,150\n", "Expected header"),
+            ("ticker,shares,price\nAAPL,10,150\n", "Missing $CASH row"),
+            ("ticker,shares,price\n$CASH,10,2\n", "Line 2: cash"),
+            (
+                "ticker,shares,price\n$CASH,10,1\naapl,1,1\n",
+                "Line 3: invalid ticker",
+            ),
+            (
+                "ticker,shares,price\n$CASH,10,1\nAAPL,1\n",
+                "Line 3: expected 3",
+            ),
+            (
+                "ticker,shares,price\n$CASH,10,1\nAAPL,x,1\n",
+                "Line 3: invalid shares",
+            ),
+            (
+                "ticker,shares,price\n$CASH,10,1\nAAPL,0,1\n",
+                "Line 3: position",
+            ),
+            (
+                "ticker,shares,price\n$CASH,10,1\nAAPL,1,-1\n",
+                "Line 3: price",
+            ),
+            (
+                "ticker,shares,price\n$CASH,10,1\nAAPL,1,1\nAAPL,1,1\n",
+                "Line 4: duplicate",
+            ),
+        ];
+        for (csv, error) in errors.iter() {
+            let e = Holdings::from_csv(csv).unwrap_err().to_string();
+            assert!(e.starts_with(error), "{} doesn't start with {}", e, error);
+        }
+    }
+}
@@ -1,10 +1,11 @@
-use crate::RiskManager;
-use anyhow::{anyhow, Result};
+// Inputs are only read from Kafka by the service
+#![cfg_attr(not(feature = "service"), allow(dead_code))]
+
+use crate::control::ControlRequest;
+use crate::query::QueryRequest;
 use chrono::{DateTime, Utc};
-use rdkafka::Message;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use tracing::debug;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use trading_base::TradeIntent;
 use uuid::Uuid;
 
@@ -20,10 +21,83 @@ pub enum State {
 #[allow(clippy::large_enum_variant)]
 pub enum Input {
     Lot(Lot),
+    /// A new market price for marking positions, from the prices topic
+    PriceUpdate {
+        ticker: String,
+        price: Decimal,
+        timestamp: DateTime<Utc>,
+    },
+    /// Annualized borrow rate of a ticker, from the borrow rates topic
+    BorrowRate {
+        ticker: String,
+        borrow_rate: Decimal,
+    },
+    /// Delta of an option contract, from the greeks topic
+    Greeks {
+        ticker: String,
+        delta: Decimal,
+    },
+    MultiLeg(MultiLegIntent),
     Time(State),
+    Amendment(OrderAmendment),
+    Cancellation(OrderCancellation),
+    Query(QueryRequest),
+    /// Asks what would happen to an intent without acting on it. Sent as `{"what_if": intent}`.
+    #[serde(
+        deserialize_with = "deserialize_what_if",
+        serialize_with = "serialize_what_if"
+    )]
+    WhatIf(TradeIntent),
+    Control(ControlRequest),
     TradeIntent(TradeIntent),
 }
 
+#[derive(Deserialize, Serialize)]
+struct WhatIf<T> {
+    what_if: T,
+}
+
+fn deserialize_what_if<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<TradeIntent, D::Error> {
+    WhatIf::deserialize(deserializer).map(|what_if| what_if.what_if)
+}
+
+fn serialize_what_if<S: Serializer>(
+    intent: &TradeIntent,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    WhatIf { what_if: intent }.serialize(serializer)
+}
+
+/// Request to resize or reprice a working order that was granted earlier.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OrderAmendment {
+    pub id: Uuid,
+    /// Id of the intent that was granted for the working order
+    pub amends: Uuid,
+    pub qty: isize,
+    /// New limit price. Leaves the order type unchanged if not set.
+    #[serde(default)]
+    pub limit_price: Option<Decimal>,
+}
+
+/// Notice that a working order was canceled or expired before it was completely filled, which
+/// releases what is left of its reservation.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OrderCancellation {
+    /// Id of the intent that was granted for the working order
+    pub cancels: Uuid,
+}
+
+/// Legs that are granted or denied as a unit, e.g. the two sides of a spread or a pairs trade.
+/// Each leg gets its own response on the responses topic, like any other intent.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MultiLegIntent {
+    pub id: Uuid,
+    pub legs: Vec<TradeIntent>,
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Lot {
     pub id: Uuid,
@@ -34,18 +108,78 @@ pub struct Lot {
     pub shares: Decimal,
 }
 
-impl RiskManager {
-    #[tracing::instrument(skip(self))]
-    pub async fn receive_message(&mut self) -> Result<Input> {
-        match self.kafka_consumer.as_ref() {
-            Some(consumer) => {
-                let message = consumer.recv().await;
-                let message = message?;
-                debug!("Message received from kafka");
-                let payload = message.payload().ok_or_else(|| anyhow!("Empty payload"))?;
-                Ok(serde_json::from_slice(payload)?)
-            }
-            None => Err(anyhow!("Consumer not initialized")),
+/// Header carrying an id that is copied onto every message produced in response, so requests can
+/// be matched to responses across services.
+pub const CORRELATION_ID_HEADER: &str = "correlation-id";
+
+/// Per-request options, passed as Kafka message headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RequestOptions {
+    /// Overrides `partial_approvals` from the settings. Set with the `allow-partial` header.
+    pub allow_partial: Option<bool>,
+}
+
+impl RequestOptions {
+    /// Parses the value of an `allow-partial` header.
+    pub fn parse_allow_partial(value: &[u8]) -> Option<bool> {
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn what_if_input() {
+        let intent = TradeIntent::new("AAPL", 10);
+        let plain = serde_json::to_string(&intent).unwrap();
+        let what_if = format!(r#"{{"what_if":{}}}"#, plain);
+        assert!(matches!(
+            serde_json::from_str(&plain).unwrap(),
+            Input::TradeIntent(_)
+        ));
+        match serde_json::from_str(&what_if).unwrap() {
+            Input::WhatIf(parsed) => assert_eq!(parsed, intent),
+            _ => panic!("Expected a what-if input"),
+        }
+        let round_trip = serde_json::to_string(&Input::WhatIf(intent)).unwrap();
+        assert_eq!(round_trip, what_if);
+    }
+
+    #[test]
+    fn price_update_input() {
+        let update = r#"{"ticker":"AAPL","price":"150.1","timestamp":"2021-06-01T14:30:00Z"}"#;
+        assert!(matches!(
+            serde_json::from_str(update).unwrap(),
+            Input::PriceUpdate { .. }
+        ));
+        let lot = r#"{"id":"c8b0f8a4-4a3b-4a52-9d3a-3b0f5a9d7c11","order_id":"c8b0f8a4-4a3b-4a52-9d3a-3b0f5a9d7c12","ticker":"AAPL","fill_time":"2021-06-01T14:30:00Z","price":"150.1","shares":"10"}"#;
+        assert!(matches!(serde_json::from_str(lot).unwrap(), Input::Lot(_)));
+        let legs = MultiLegIntent {
+            id: Uuid::new_v4(),
+            legs: vec![TradeIntent::new("KO", 10), TradeIntent::new("PEP", -10)],
+        };
+        match serde_json::from_str(&serde_json::to_string(&legs).unwrap()).unwrap() {
+            Input::MultiLeg(parsed) => assert_eq!(parsed, legs),
+            _ => panic!("Expected a multi-leg input"),
         }
+        let greeks = r#"{"ticker":"AAPL240119C00150000","delta":"0.45"}"#;
+        assert!(matches!(
+            serde_json::from_str(greeks).unwrap(),
+            Input::Greeks { .. }
+        ));
+        let borrow_rate = r#"{"ticker":"GME","borrow_rate":"0.35"}"#;
+        assert!(matches!(
+            serde_json::from_str(borrow_rate).unwrap(),
+            Input::BorrowRate { .. }
+        ));
+        let cancellation = r#"{"cancels":"c8b0f8a4-4a3b-4a52-9d3a-3b0f5a9d7c11"}"#;
+        assert!(matches!(
+            serde_json::from_str(cancellation).unwrap(),
+            Input::Cancellation(_)
+        ));
     }
 }
@@ -0,0 +1,15 @@
+use crate::error::Result;
+use serde::de::DeserializeOwned;
+use std::io::BufRead;
+
+/// Reads values from JSON lines, skipping blank lines.
+pub(crate) fn read_json_lines<T: DeserializeOwned>(reader: impl BufRead) -> Result<Vec<T>> {
+    let mut values = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            values.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(values)
+}
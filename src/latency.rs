@@ -0,0 +1,127 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 13] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Cumulative histogram of durations, in the shape Prometheus expects.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    /// Observations that fit in each bucket but not the previous one
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Runs `f`, recording how long it took.
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.observe(start.elapsed());
+        result
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, metrics: &mut String, name: &str, stage: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                metrics,
+                "{}_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                name, stage, bound, cumulative
+            );
+        }
+        let count = self.count();
+        let _ = writeln!(
+            metrics,
+            "{}_bucket{{stage=\"{}\",le=\"+Inf\"}} {}",
+            name, stage, count
+        );
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(metrics, "{}_sum{{stage=\"{}\"}} {}", name, stage, sum);
+        let _ = writeln!(metrics, "{}_count{{stage=\"{}\"}} {}", name, stage, count);
+    }
+}
+
+/// How long each stage of handling a message takes.
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    pub deserialization: Histogram,
+    /// Fetching the last price of market orders from the price sources
+    pub price_lookup: Histogram,
+    pub rule_evaluation: Histogram,
+    pub produce: Histogram,
+}
+
+impl LatencyStats {
+    /// Renders the histograms in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let name = "risk_manager_stage_duration_seconds";
+        let mut metrics = String::new();
+        let _ = writeln!(
+            metrics,
+            "# HELP {} Time spent in each stage of a risk check",
+            name
+        );
+        let _ = writeln!(metrics, "# TYPE {} histogram", name);
+        let stages = [
+            ("deserialization", &self.deserialization),
+            ("price_lookup", &self.price_lookup),
+            ("rule_evaluation", &self.rule_evaluation),
+            ("produce", &self.produce),
+        ];
+        for (stage, histogram) in stages.iter() {
+            histogram.render(&mut metrics, name, stage);
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn histogram() {
+        let stats = LatencyStats::default();
+        stats.price_lookup.observe(Duration::from_micros(800));
+        stats.price_lookup.observe(Duration::from_millis(30));
+        stats.price_lookup.observe(Duration::from_secs(10));
+        assert_eq!(stats.rule_evaluation.time(|| 1 + 1), 2);
+        assert_eq!(stats.rule_evaluation.count(), 1);
+
+        let metrics = stats.render_metrics();
+        let name = "risk_manager_stage_duration_seconds";
+        assert!(metrics.contains(&format!(
+            "{}_bucket{{stage=\"price_lookup\",le=\"0.0005\"}} 0",
+            name
+        )));
+        assert!(metrics.contains(&format!(
+            "{}_bucket{{stage=\"price_lookup\",le=\"0.05\"}} 2",
+            name
+        )));
+        assert!(metrics.contains(&format!(
+            "{}_bucket{{stage=\"price_lookup\",le=\"+Inf\"}} 3",
+            name
+        )));
+        assert!(metrics.contains(&format!("{}_sum{{stage=\"price_lookup\"}} 10.0308", name)));
+    }
+}
@@ -1,63 +1,176 @@
+#[cfg(feature = "service")]
+mod actors;
+#[cfg(feature = "service")]
+mod admin;
+mod assets;
+mod builder;
+mod calendar;
+mod clock;
+#[cfg(feature = "service")]
+mod codec;
+#[cfg(feature = "service")]
+mod compliance_hook;
+mod control;
+mod day_trade_calls;
+mod day_trades;
+mod dedup;
+mod drawdown;
+mod error;
+mod eval_cache;
+mod events;
+mod fix;
+pub mod fixtures;
+#[cfg(feature = "service")]
+mod flatten;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handoff;
+#[cfg(feature = "service")]
+mod health;
+mod holdings;
 mod input;
+mod json_lines;
+mod latency;
+#[cfg(feature = "service")]
+mod lifecycle;
+mod limits;
+#[cfg(feature = "service")]
+mod liquidation;
+mod margin;
+mod margin_call;
+mod money;
+#[cfg(feature = "service")]
+mod monitor;
+#[cfg(feature = "service")]
+mod notifier;
+#[cfg(feature = "service")]
+mod offsets;
+mod options;
+mod orders;
+#[cfg(feature = "service")]
+mod pipeline;
+#[cfg(feature = "service")]
+mod preflight;
+#[cfg(feature = "service")]
+mod price_feed;
+mod price_sources;
+mod prices;
+#[cfg(any(feature = "grpc", feature = "protobuf"))]
+mod proto;
+#[cfg(feature = "service")]
+mod quarantine;
+mod query;
+#[cfg(feature = "service")]
+mod redis_pool;
+#[cfg(feature = "service")]
+mod replay;
+mod report;
 mod risk_manager;
+pub mod rules;
+#[cfg(feature = "service")]
+mod server;
+#[cfg(feature = "service")]
+mod service;
 mod settings;
-pub use crate::risk_manager::{DenyReason, Price, RiskCheckResponse, RiskManager, Shares};
-use alpaca::Client;
-use anyhow::{anyhow, Result};
-pub use input::Lot;
-use kafka_settings::{consumer, producer};
-use rdkafka::producer::FutureRecord;
+mod settlement;
+mod sharding;
+mod shutdown;
+mod signature;
+pub mod simulation;
+#[cfg(feature = "service")]
+mod snapshot;
+#[cfg(feature = "service")]
+mod stop_loss;
+#[cfg(feature = "service")]
+mod supervisor;
+mod symbols;
+mod tax_lots;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+#[cfg(feature = "tui")]
+pub mod top;
+#[cfg(feature = "service")]
+pub mod transport;
+mod units;
+mod validation;
+#[cfg(feature = "service")]
+mod webhook;
+pub use crate::risk_manager::{
+    DecisionTiming, DenyReason, Explanation, MultiLegResponse, PortfolioSnapshot,
+    RiskCheckResponse, RiskManager, TradeMetrics, WhatIfResponse,
+};
+#[cfg(feature = "reqwest")]
+pub use assets::AlpacaAssetSource;
+pub use assets::{Asset, AssetCache, AssetSource, AssetStatus};
+pub use builder::RiskManagerBuilder;
+pub use clock::{Clock, ManualClock, SharedClock, SystemClock};
+#[cfg(feature = "service")]
+pub use compliance_hook::{ComplianceHook, ComplianceVerdict};
+pub use control::{
+    AccountBlocks, AppliedControls, ControlAck, ControlCommand, ControlRequest, TradingHalts,
+};
+pub use day_trade_calls::{DayTradeCall, DayTradeCalls};
+pub use error::{Result, RiskManagerError};
+pub use eval_cache::EvaluationCacheStats;
+pub use events::{Alert, AlertLevel, Event, EventBus, ExposureUpdate, Topic};
+pub use fix::FixDropCopy;
+pub use handoff::HandoffState;
+pub use holdings::{Holding, Holdings};
+pub use input::{Lot, MultiLegIntent, OrderAmendment, OrderCancellation};
+#[cfg(feature = "service")]
+pub use lifecycle::{LifecycleEvent, LifecycleStage};
+pub use limits::{LimitsFile, RiskLimits, SharedLimits, TickerLimits};
+#[cfg(feature = "service")]
+pub use liquidation::{LiquidationPlan, LiquidationSuggestion};
+pub use margin::{
+    ConcentrationAddOn, ConcentrationBasis, MarginMethod, MarginSchedule, PortfolioMarginSettings,
+    SymbolMargin,
+};
+pub use margin_call::{MarginAlert, MarginAlertLevel};
+pub use money::{RoundingMode, RoundingPolicy};
+#[cfg(feature = "service")]
+pub use offsets::PartitionOffsets;
+pub use options::{OptionContract, OptionRight, OptionsSettings};
+#[cfg(feature = "service")]
+pub use preflight::{check_settings, preflight, ConfigProblem};
+#[cfg(feature = "service")]
+pub use price_sources::RedisSource;
+#[cfg(feature = "reqwest")]
+pub use price_sources::{AlpacaDataSource, DatastoreSource, PolygonSource};
+pub use price_sources::{CircuitBreaker, PriceSource, PriceSourceStats, PriceSources};
+pub use prices::{PriceCache, PriceTick};
+pub use query::{Query, QueryRequest, QueryResponse, QueryResult};
+#[cfg(feature = "service")]
+pub use replay::{replay, ReplayBound, ReplayRange};
+pub use report::{Position, PositionSide, RiskReport};
+#[cfg(feature = "service")]
+pub use service::{run, run_with};
+#[cfg(feature = "aws")]
+pub use settings::AwsSettings;
+#[cfg(feature = "grpc")]
+pub use settings::GrpcSettings;
+#[cfg(feature = "service")]
 pub use settings::Settings;
-use tracing::{error, info, trace};
-
-pub async fn run(settings: Settings) -> Result<()> {
-    info!("Running RiskManager");
-    let consumer = consumer(&settings.kafka)?;
-    let producer = producer(&settings.kafka)?;
-    let client = Client::new(
-        settings.alpaca.base_url,
-        settings.alpaca.key_id,
-        settings.alpaca.secret_key,
-    );
-    let mut risk_manager = RiskManager::new(settings.datastore.base_url);
-    risk_manager.bind_consumer(consumer);
-    if let Ok(client) = client {
-        risk_manager.bind_alpaca_client(client);
-    }
-    risk_manager.initialize().await?;
-    loop {
-        let message = risk_manager.receive_message().await?;
-        match message {
-            input::Input::Lot(lot) => {
-                trace!("Lot received");
-                risk_manager.update_holdings(lot.ticker, Shares(lot.shares), Price(lot.price));
-            }
-            input::Input::TradeIntent(trade_intent) => {
-                trace!("TradeIntent received");
-                let response = risk_manager.risk_check(&trade_intent);
-                match response {
-                    Ok(response) => {
-                        let payload = serde_json::to_string(&response)?;
-                        let record = FutureRecord::to("risk-check-response")
-                            .key(&trade_intent.ticker)
-                            .payload(&payload);
-                        producer
-                            .send(record, std::time::Duration::from_secs(0))
-                            .await
-                            .map_err(|(e, m)| anyhow!("{} - {:?}", e, m))?;
-                    }
-                    Err(e) => error!(?e),
-                }
-            }
-            input::Input::Time(input::State::Open { .. }) => (),
-            input::Input::Time(input::State::Closed { next_open }) => {
-                // Only want to shut down in post-market, not pre-market. We achieve this by
-                // checking if next open is at least 12 hours away.
-                if next_open > 60 * 60 * 12 {
-                    info!("Market closed, shutting down");
-                    return Ok(());
-                }
-            }
-        }
-    }
-}
+pub use settings::{
+    AssetSettings, BorrowFeeSettings, CodecFormat, CodecSettings, ComplianceFailurePolicy,
+    ComplianceHookSettings, ControlSettings, CryptoSettings, DenialMonitorSettings,
+    EvaluationCacheSettings, FixMode, FixSettings, FlattenSettings, GoodFaithViolationPolicy,
+    HandoffSettings, LagMonitorSettings, LimitsSettings, LiquidationSettings, MarginCallSettings,
+    MultiplierSettings, NotificationEndpoint, NotificationFormat, NotificationSettings,
+    PipelineSettings, PolygonSettings, PriceCacheSettings, PriceFeedSettings, PriceSourceKind,
+    PriceSourcesSettings, ProducerRetrySettings, QuarantineSettings, RedisPoolSettings,
+    RiskSettings, RuleEvaluationMode, ShadowSettings, ShardingSettings, SnapshotSettings,
+    StalePricePolicy, StopLossSettings, SupervisorSettings, TopicSettings, ValidationSettings,
+    WebServerSettings, WebhookFilter, WebhookSettings,
+};
+pub use settlement::SettlementLedger;
+pub use sharding::ShardState;
+pub use shutdown::ShutdownPolicy;
+#[cfg(feature = "service")]
+pub use snapshot::{RiskSnapshot, SnapshotTrigger};
+pub use symbols::{SymbolInfo, SymbolMetadata};
+pub use tax_lots::{ClosedLot, TaxLot, TaxLots};
+pub use units::{Price, Shares};
+pub use validation::{DecisionSampler, ValidationSample};
+#[cfg(feature = "service")]
+pub use webhook::WebhookSink;
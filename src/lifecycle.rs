@@ -0,0 +1,117 @@
+use crate::codec::Producer;
+use crate::RiskManager;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LifecycleStage {
+    Starting,
+    Initialized,
+    /// Risk checks are failing, so intents aren't getting decisions
+    Degraded,
+    /// Risk checks are succeeding again after being degraded
+    Recovered,
+    /// State was replaced from outside, e.g. by a holdings import
+    Reconciled,
+    ShuttingDown,
+    Stopped,
+}
+
+/// Machine-readable record of when the risk manager was and wasn't producing decisions.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LifecycleEvent {
+    pub stage: LifecycleStage,
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Hash of cash and positions at the time of the event, when they are known
+    #[serde(default)]
+    pub state_hash: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
+impl RiskManager {
+    /// SHA-256 of the holdings in their CSV export form, which is ordered by ticker.
+    pub fn state_hash(&self) -> String {
+        hex::encode(Sha256::digest(self.export_holdings().to_csv().as_bytes()))
+    }
+}
+
+pub struct Lifecycle<'a> {
+    producer: &'a Producer,
+    topic: &'a str,
+    degraded: bool,
+}
+
+impl<'a> Lifecycle<'a> {
+    pub fn new(producer: &'a Producer, topic: &'a str) -> Self {
+        Self {
+            producer,
+            topic,
+            degraded: false,
+        }
+    }
+
+    /// Publishes an event. Failures are logged rather than returned, since lifecycle events are
+    /// often published while already handling an error.
+    pub async fn publish(
+        &self,
+        stage: LifecycleStage,
+        reason: Option<String>,
+        risk_manager: Option<&RiskManager>,
+    ) {
+        let event = LifecycleEvent {
+            stage,
+            reason,
+            state_hash: risk_manager.map(RiskManager::state_hash),
+            timestamp: Utc::now(),
+        };
+        info!(?event, "Lifecycle event");
+        if let Err(e) = self
+            .producer
+            .send(self.topic, "risk-manager", &event, None)
+            .await
+        {
+            error!(?e, "Failed to publish lifecycle event");
+        }
+    }
+
+    /// Publishes `Degraded` the first time a risk check fails.
+    pub async fn degraded(&mut self, reason: String, risk_manager: &RiskManager) {
+        if !self.degraded {
+            self.degraded = true;
+            self.publish(LifecycleStage::Degraded, Some(reason), Some(risk_manager))
+                .await
+        }
+    }
+
+    /// Publishes `Recovered` if risk checks were failing.
+    pub async fn healthy(&mut self, risk_manager: &RiskManager) {
+        if self.degraded {
+            self.degraded = false;
+            self.publish(LifecycleStage::Recovered, None, Some(risk_manager))
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Price, Shares};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn state_hash() {
+        let mut manager = RiskManager::default();
+        let empty = manager.state_hash();
+        assert_eq!(empty.len(), 64);
+        manager.update_holdings("AAPL", Shares(Decimal::ONE), Price(Decimal::ONE));
+        let hash = manager.state_hash();
+        assert_ne!(hash, empty);
+        manager.update_price("AAPL", Price(Decimal::ONE));
+        assert_eq!(manager.state_hash(), hash);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::settings::LimitsSettings;
+use anyhow::Result;
+use config::{Config, File};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, error, info};
+
+/// Per-ticker replacements for the global limits. Unset values fall back to the global ones.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TickerLimits {
+    pub max_notional: Option<Decimal>,
+    pub max_concentration: Option<Decimal>,
+    /// Replaces `risk.market_order_slippage`
+    pub slippage: Option<Decimal>,
+    /// Replaces `risk.max_price_age_secs`
+    pub max_price_age_secs: Option<u64>,
+    /// Replaces `risk.borrow_fees.max_rate`
+    pub max_borrow_rate: Option<Decimal>,
+}
+
+/// Thresholds used by the limit rules. Every limit is optional, and an empty file disables them
+/// all.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RiskLimits {
+    /// Largest notional value of a single opening intent
+    pub max_notional: Option<Decimal>,
+    /// Largest gross market exposure the account may reach after the intent
+    pub max_gross_exposure: Option<Decimal>,
+    /// Largest absolute net market exposure the account may reach after the intent
+    pub max_net_exposure: Option<Decimal>,
+    /// Largest fraction of equity a single position may make up after the intent. Only enforced
+    /// by the concentration rule, so preflight rejects it unless that rule is in the pipeline.
+    pub max_concentration: Option<Decimal>,
+    /// Tickers that may not be opened or added to
+    pub blocklist: HashSet<String>,
+    /// Overrides for individual tickers, e.g. a tighter notional cap on illiquid names
+    pub tickers: HashMap<String, TickerLimits>,
+}
+
+impl RiskLimits {
+    /// Reads limits from a TOML, YAML or JSON file, depending on its extension.
+    pub fn load(path: &str) -> Result<Self> {
+        let mut c = Config::new();
+        c.merge(File::with_name(path))?;
+        Ok(c.try_into()?)
+    }
+
+    fn ticker<T>(&self, ticker: &str, limit: impl Fn(&TickerLimits) -> Option<T>) -> Option<T> {
+        self.tickers.get(ticker).and_then(limit)
+    }
+
+    pub fn max_notional(&self, ticker: &str) -> Option<Decimal> {
+        self.ticker(ticker, |limits| limits.max_notional)
+            .or(self.max_notional)
+    }
+
+    pub fn max_concentration(&self, ticker: &str) -> Option<Decimal> {
+        self.ticker(ticker, |limits| limits.max_concentration)
+            .or(self.max_concentration)
+    }
+
+    /// Unlike the limits above, the global values of these three live in the risk settings.
+    pub fn slippage(&self, ticker: &str, global: Decimal) -> Decimal {
+        self.ticker(ticker, |limits| limits.slippage)
+            .unwrap_or(global)
+    }
+
+    pub fn max_price_age_secs(&self, ticker: &str, global: Option<u64>) -> Option<u64> {
+        self.ticker(ticker, |limits| limits.max_price_age_secs)
+            .or(global)
+    }
+
+    pub fn max_borrow_rate(&self, ticker: &str, global: Option<Decimal>) -> Option<Decimal> {
+        self.ticker(ticker, |limits| limits.max_borrow_rate)
+            .or(global)
+    }
+}
+
+/// Limits shared between the risk manager and whatever reloads them, along with a generation
+/// that changes on every reload.
+#[derive(Clone, Default)]
+pub struct SharedLimits(Arc<RwLock<(u64, Arc<RiskLimits>)>>);
+
+impl SharedLimits {
+    pub fn new(limits: RiskLimits) -> Self {
+        Self(Arc::new(RwLock::new((0, Arc::new(limits)))))
+    }
+
+    pub fn current(&self) -> Arc<RiskLimits> {
+        self.0.read().expect("Limits lock poisoned").1.clone()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.0.read().expect("Limits lock poisoned").0
+    }
+
+    pub fn replace(&self, limits: RiskLimits) {
+        let mut guard = self.0.write().expect("Limits lock poisoned");
+        *guard = (guard.0 + 1, Arc::new(limits));
+    }
+}
+
+/// Limits backed by a file on disk, which can be reloaded on demand or watched for changes.
+#[derive(Clone)]
+pub struct LimitsFile {
+    path: String,
+    reload_interval: Duration,
+    limits: SharedLimits,
+}
+
+impl LimitsFile {
+    pub fn load(settings: LimitsSettings) -> Result<Self> {
+        let limits = RiskLimits::load(&settings.path)?;
+        info!(path = %settings.path, ?limits, "Loaded risk limits");
+        Ok(Self {
+            path: settings.path,
+            reload_interval: Duration::from_secs(settings.reload_interval_secs.max(1)),
+            limits: SharedLimits::new(limits),
+        })
+    }
+
+    pub fn limits(&self) -> SharedLimits {
+        self.limits.clone()
+    }
+
+    /// Re-reads the file. On error the current limits are kept.
+    pub fn reload(&self) -> Result<Arc<RiskLimits>> {
+        let limits = RiskLimits::load(&self.path)?;
+        info!(path = %self.path, ?limits, "Reloaded risk limits");
+        self.limits.replace(limits);
+        Ok(self.limits.current())
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Polls the file's modification time and reloads it whenever it changes.
+    pub async fn watch(self) {
+        let mut last_modified = self.modified();
+        let mut interval = tokio::time::interval(self.reload_interval);
+        loop {
+            interval.tick().await;
+            let modified = self.modified();
+            if modified == last_modified {
+                continue;
+            }
+            debug!(path = %self.path, "Limits file changed");
+            last_modified = modified;
+            if let Err(e) = self.reload() {
+                error!(?e, path = %self.path, "Failed to reload risk limits");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reload_limits() {
+        let path = std::env::temp_dir().join(format!("limits-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            "max_notional = 10000\nblocklist = [\"GME\"]\n\
+             [tickers.AMC]\nmax_notional = 500\nslippage = 0.05\nmax_price_age_secs = 5\n",
+        )
+        .unwrap();
+        let file = LimitsFile::load(LimitsSettings {
+            path: path.to_str().unwrap().into(),
+            reload_interval_secs: 1,
+        })
+        .unwrap();
+        let limits = file.limits();
+        assert_eq!(
+            *limits.current(),
+            RiskLimits {
+                max_notional: Some(Decimal::new(10000, 0)),
+                blocklist: vec!["GME".to_string()].into_iter().collect(),
+                tickers: vec![(
+                    "AMC".to_string(),
+                    TickerLimits {
+                        max_notional: Some(Decimal::new(500, 0)),
+                        slippage: Some(Decimal::new(5, 2)),
+                        max_price_age_secs: Some(5),
+                        ..Default::default()
+                    }
+                )]
+                .into_iter()
+                .collect(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            limits.current().max_notional("AMC"),
+            Some(Decimal::new(500, 0))
+        );
+        assert_eq!(
+            limits.current().max_notional("AAPL"),
+            Some(Decimal::new(10000, 0))
+        );
+        let global = Decimal::new(3, 2);
+        assert_eq!(limits.current().slippage("AMC", global), Decimal::new(5, 2));
+        assert_eq!(limits.current().slippage("AAPL", global), global);
+        assert_eq!(
+            limits.current().max_price_age_secs("AMC", Some(60)),
+            Some(5)
+        );
+        assert_eq!(
+            limits.current().max_price_age_secs("AAPL", Some(60)),
+            Some(60)
+        );
+
+        std::fs::write(&path, "max_gross_exposure = 50000\n").unwrap();
+        file.reload().unwrap();
+        assert_eq!(
+            *limits.current(),
+            RiskLimits {
+                max_gross_exposure: Some(Decimal::new(50000, 0)),
+                ..Default::default()
+            }
+        );
+
+        std::fs::write(&path, "max_notional = \"lots\"\n").unwrap();
+        assert!(file.reload().is_err());
+        assert_eq!(
+            limits.current().max_gross_exposure,
+            Some(Decimal::new(50000, 0))
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+use crate::clock::Clock;
+use crate::settings::LiquidationSettings;
+use crate::symbols::SymbolMetadata;
+use crate::{Price, RiskManager, Shares};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Part or all of a position to close to release maintenance margin.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LiquidationSuggestion {
+    pub ticker: String,
+    /// Quantity to trade, on the other side of the position
+    pub qty: Decimal,
+    pub notional: Decimal,
+    /// Maintenance margin released by trading `qty`
+    pub margin_relief: Decimal,
+    /// Maintenance margin released per dollar closed
+    pub relief_per_dollar: Decimal,
+    /// Value traded in the ticker on an average day, if known
+    pub dollar_volume: Option<Decimal>,
+}
+
+/// Positions to close to bring maintenance margin back under equity with a buffer to spare, most
+/// effective first.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct LiquidationPlan {
+    pub timestamp: DateTime<Utc>,
+    pub equity: Decimal,
+    pub maintenance_margin: Decimal,
+    /// Maintenance margin that leaves the buffer of equity
+    pub target_margin: Decimal,
+    pub suggestions: Vec<LiquidationSuggestion>,
+    /// Margin relief still missing after every suggestion, when closing everything isn't enough
+    pub shortfall: Decimal,
+}
+
+impl LiquidationPlan {
+    /// Closes the positions releasing the most margin per dollar first, and the most liquid ones
+    /// among those, until maintenance margin is down to `1 - buffer` of equity. Trades are sized
+    /// from the margin schedule, and the margin they release is measured on the portfolio
+    /// projected with them, so that offsets between positions are accounted for.
+    pub fn new(risk_manager: &RiskManager, buffer: Decimal, symbols: &SymbolMetadata) -> Self {
+        let equity = risk_manager.equity();
+        let maintenance_margin = risk_manager.maintenance_margin();
+        let schedule = &risk_manager.settings().margin;
+        let target_margin = (equity * (Decimal::ONE - buffer)).max(Decimal::ZERO);
+        let mut candidates: Vec<_> = risk_manager
+            .positions()
+            .into_iter()
+            .filter(|position| !position.shares.is_zero() && !position.market_price.is_zero())
+            .map(|position| {
+                let factor = schedule.maintenance(
+                    &position.ticker,
+                    Shares(position.shares),
+                    Price(position.market_price),
+                );
+                let dollar_volume = symbols
+                    .avg_daily_volume(&position.ticker)
+                    .map(|volume| volume * position.market_price);
+                (position, factor, dollar_volume)
+            })
+            .filter(|(_, factor, _)| !factor.is_zero())
+            .collect();
+        candidates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| b.2.cmp(&a.2)));
+        let mut fills: Vec<(String, Decimal, Decimal)> = Vec::new();
+        let mut margin = maintenance_margin;
+        let mut suggestions = Vec::new();
+        for (position, factor, dollar_volume) in candidates {
+            let needed = margin - target_margin;
+            if needed <= Decimal::ZERO {
+                break;
+            }
+            let shares = (needed / (position.market_price * factor))
+                .ceil()
+                .min(position.shares.abs());
+            let qty = if position.shares.is_sign_negative() {
+                shares
+            } else {
+                -shares
+            };
+            fills.push((position.ticker.clone(), qty, position.market_price));
+            let projected = risk_manager.maintenance_margin_after(
+                &fills
+                    .iter()
+                    .map(|(ticker, qty, price)| (ticker.as_str(), *qty, *price))
+                    .collect::<Vec<_>>(),
+            );
+            let margin_relief = margin - projected;
+            margin = projected;
+            let notional = shares * position.market_price;
+            suggestions.push(LiquidationSuggestion {
+                ticker: position.ticker,
+                qty,
+                notional,
+                margin_relief,
+                relief_per_dollar: margin_relief / notional,
+                dollar_volume,
+            });
+        }
+        Self {
+            timestamp: risk_manager.clock().now(),
+            equity,
+            maintenance_margin,
+            target_margin,
+            suggestions,
+            shortfall: (margin - target_margin).max(Decimal::ZERO),
+        }
+    }
+}
+
+/// Suggests liquidations when maintenance margin rises above equity, or when a margin call is
+/// raised. There is only one plan per breach; the monitor re-arms once margin is back under
+/// equity.
+pub struct LiquidationMonitor {
+    settings: LiquidationSettings,
+    breached: bool,
+}
+
+impl LiquidationMonitor {
+    pub fn new(settings: LiquidationSettings) -> Self {
+        Self {
+            settings,
+            breached: false,
+        }
+    }
+
+    /// Plans liquidations for a new breach. `margin_called` is set when a margin call was just
+    /// raised, which calls for a plan even while margin is still under equity.
+    pub fn check(
+        &mut self,
+        risk_manager: &RiskManager,
+        symbols: &SymbolMetadata,
+        margin_called: bool,
+    ) -> Option<LiquidationPlan> {
+        let equity = risk_manager.equity();
+        let maintenance_margin = risk_manager.maintenance_margin();
+        if maintenance_margin <= equity && !margin_called {
+            self.breached = false;
+            return None;
+        }
+        if self.breached {
+            return None;
+        }
+        self.breached = true;
+        warn!(%equity, %maintenance_margin, margin_called, "Planning liquidations");
+        Some(LiquidationPlan::new(
+            risk_manager,
+            self.settings.buffer,
+            symbols,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::symbols::SymbolInfo;
+    use std::collections::HashMap;
+
+    #[test]
+    fn liquidation_plan() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
+        // Maintenance margin of 30% on AAPL and GME, and 100% on the penny stock
+        for (ticker, shares, price) in
+            [("AAPL", 100, 50), ("GME", 100, 50), ("PENNY", 500, 2)].iter()
+        {
+            manager.update_holdings(
+                *ticker,
+                Shares(Decimal::new(*shares, 0)),
+                Price(Decimal::new(*price, 0)),
+            );
+        }
+        manager.update_price("AAPL", Price(Decimal::new(40, 0)));
+        manager.update_price("GME", Price(Decimal::new(40, 0)));
+        manager.update_price("PENNY", Price(Decimal::ONE));
+        // Cash of -10000 and positions worth 8500
+        assert_eq!(manager.equity(), Decimal::new(-1500, 0));
+
+        let mut symbols = HashMap::new();
+        symbols.insert(
+            "AAPL".to_string(),
+            SymbolInfo {
+                avg_daily_volume: Some(Decimal::new(1_000_000, 0)),
+                ..Default::default()
+            },
+        );
+        let symbols = SymbolMetadata::new(symbols);
+        let mut monitor = LiquidationMonitor::new(LiquidationSettings {
+            buffer: Decimal::new(1, 1),
+        });
+        let plan = monitor.check(&manager, &symbols, false).unwrap();
+        assert_eq!(plan.maintenance_margin, Decimal::new(2900, 0));
+        assert_eq!(plan.target_margin, Decimal::ZERO);
+        // The penny stock releases the most margin per dollar, then the more liquid AAPL
+        let tickers: Vec<_> = plan.suggestions.iter().map(|s| s.ticker.as_str()).collect();
+        assert_eq!(tickers, vec!["PENNY", "AAPL", "GME"]);
+        assert_eq!(plan.suggestions[0].qty, Decimal::new(-500, 0));
+        assert_eq!(plan.suggestions[0].margin_relief, Decimal::new(500, 0));
+        // Equity is negative, so nothing short of closing everything is enough
+        assert_eq!(plan.shortfall, Decimal::ZERO);
+        assert_eq!(plan.suggestions[2].qty, Decimal::new(-100, 0));
+
+        // Only one plan per breach
+        assert!(monitor.check(&manager, &symbols, false).is_none());
+    }
+
+    #[test]
+    fn margin_call() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(5000, 0));
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(100, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        // Cash of -5000, for equity of 5000 and maintenance margin of 3000
+        let symbols = SymbolMetadata::default();
+        let mut monitor = LiquidationMonitor::new(LiquidationSettings {
+            buffer: Decimal::new(5, 1),
+        });
+        assert!(monitor.check(&manager, &symbols, false).is_none());
+        // A margin call asks for a plan to restore the buffer
+        let plan = monitor.check(&manager, &symbols, true).unwrap();
+        assert_eq!(plan.target_margin, Decimal::new(2500, 0));
+        let suggestion = &plan.suggestions[0];
+        assert_eq!(suggestion.qty, Decimal::new(-17, 0));
+        assert_eq!(suggestion.margin_relief, Decimal::new(510, 0));
+        assert_eq!(plan.shortfall, Decimal::ZERO);
+    }
+}
@@ -1,17 +1,270 @@
-use anyhow::Result;
-use risk_manager::run;
-use risk_manager::Settings;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use risk_manager::{
+    check_settings, preflight, run, Holdings, PriceTick, ReplayBound, ReplayRange, RiskLimits,
+    RiskManager, RiskSettings, Settings, SharedLimits, TradingHalts,
+};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
 use tracing::subscriber::set_global_default;
 use tracing_subscriber::EnvFilter;
+use trading_base::TradeIntent;
+
+/// Risk checks for trade intents.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "risk-manager")]
+struct Cli {
+    /// Runs the service if not given
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Run the service
+    Run,
+    /// Check the configuration and the services it points to, then exit
+    ValidateConfig {
+        /// Only check the settings themselves, without reaching Redis or the broker
+        #[structopt(long)]
+        offline: bool,
+    },
+    /// Print the running risk manager's state as JSON
+    Snapshot {
+        #[structopt(flatten)]
+        admin: AdminArgs,
+        /// Number of recent decisions to include
+        #[structopt(long)]
+        decisions: Option<usize>,
+    },
+    /// Run the inputs produced in a time range through the risk manager without publishing
+    /// anything, writing its decisions to a file as JSON lines
+    Replay {
+        /// Start of the range, in RFC 3339
+        #[structopt(long, parse(try_from_str = parse_time), conflicts_with = "from-offset")]
+        from: Option<DateTime<Utc>>,
+        /// Start of the range, as an offset in every partition of the input topics
+        #[structopt(long)]
+        from_offset: Option<i64>,
+        /// End of the range, in RFC 3339. Defaults to the inputs produced before the replay
+        #[structopt(long, parse(try_from_str = parse_time), conflicts_with = "until-offset")]
+        until: Option<DateTime<Utc>>,
+        /// Last offset to replay in every partition of the input topics
+        #[structopt(long)]
+        until_offset: Option<i64>,
+        #[structopt(long, short, default_value = "decisions.jsonl")]
+        output: PathBuf,
+    },
+    /// Decide an intent against a saved portfolio without connecting to anything, and show what
+    /// each rule made of it
+    Check {
+        /// Trade intent, as JSON
+        #[structopt(long)]
+        intent: PathBuf,
+        /// Portfolio, as printed by `snapshot`
+        #[structopt(long)]
+        portfolio: PathBuf,
+        /// Last price of the ticker, for market orders. Defaults to the price in the portfolio
+        #[structopt(long)]
+        price: Option<Decimal>,
+    },
+    /// Export or import the running risk manager's holdings as CSV
+    Holdings(HoldingsCommand),
+    /// Watch the running risk manager's exposure in the terminal
+    Top {
+        #[structopt(long, default_value = "ws://localhost:8080/ws")]
+        url: String,
+        /// Taken from the server's own setting if not given
+        #[structopt(long, env = "WEBSERVER__ADMIN_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum HoldingsCommand {
+    /// Print the holdings as CSV
+    Export {
+        #[structopt(flatten)]
+        admin: AdminArgs,
+    },
+    /// Replace the holdings with the contents of a CSV file
+    Import {
+        file: PathBuf,
+        #[structopt(flatten)]
+        admin: AdminArgs,
+    },
+}
+
+// How to reach the admin API of a running risk manager. Not a doc comment, which would replace
+// the about of the commands it is flattened into.
+#[derive(Debug, StructOpt)]
+struct AdminArgs {
+    #[structopt(long, default_value = "http://localhost:8080")]
+    url: String,
+    /// Taken from the server's own setting if not given
+    #[structopt(long, env = "WEBSERVER__ADMIN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+/// The parts of the output of `snapshot` that decisions depend on.
+#[derive(Debug, Deserialize)]
+struct Portfolio {
+    holdings: Holdings,
+    #[serde(default)]
+    limits: RiskLimits,
+    #[serde(default)]
+    halts: TradingHalts,
+}
+
+impl AdminArgs {
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let url = format!("{}{}", self.url.trim_end_matches('/'), path);
+        let request = reqwest::Client::new().request(method, url);
+        match self.token.as_ref() {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    Ok(DateTime::parse_from_rfc3339(time)?.with_timezone(&Utc))
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
+    match Cli::from_args().command.unwrap_or(Command::Run) {
+        Command::Run => {
+            init_tracing()?;
+            run(Settings::new()?).await
+        }
+        Command::ValidateConfig { offline } => validate_config(offline).await,
+        Command::Snapshot { admin, decisions } => {
+            let mut request = admin.request(reqwest::Method::GET, "/admin/state");
+            if let Some(decisions) = decisions {
+                request = request.query(&[("decisions", decisions)]);
+            }
+            print_response(request).await
+        }
+        Command::Replay {
+            from,
+            from_offset,
+            until,
+            until_offset,
+            output,
+        } => {
+            init_tracing()?;
+            let from = match (from, from_offset) {
+                (Some(from), _) => ReplayBound::Time(from),
+                (None, Some(offset)) => ReplayBound::Offset(offset),
+                (None, None) => return Err(anyhow!("Either --from or --from-offset is needed")),
+            };
+            let until = until
+                .map(ReplayBound::Time)
+                .or_else(|| until_offset.map(ReplayBound::Offset));
+            let range = ReplayRange { from, until };
+            let summary = risk_manager::replay(Settings::new()?, range, &output).await?;
+            println!(
+                "{} granted, {} amended, {} denied, {} errors",
+                summary.granted, summary.amended, summary.denied, summary.errors
+            );
+            Ok(())
+        }
+        Command::Check {
+            intent,
+            portfolio,
+            price,
+        } => check(&intent, &portfolio, price),
+        Command::Holdings(HoldingsCommand::Export { admin }) => {
+            print_response(admin.request(reqwest::Method::GET, "/admin/holdings")).await
+        }
+        Command::Holdings(HoldingsCommand::Import { file, admin }) => {
+            let csv = std::fs::read_to_string(file)?;
+            print_response(
+                admin
+                    .request(reqwest::Method::POST, "/admin/holdings")
+                    .body(csv),
+            )
+            .await
+        }
+        Command::Top { url, token } => top(url, token).await,
+    }
+}
+
+fn init_tracing() -> Result<()> {
     let subscriber = tracing_subscriber::fmt()
         .json()
         .with_env_filter(EnvFilter::from_default_env())
         .finish();
     set_global_default(subscriber)?;
+    Ok(())
+}
+
+async fn validate_config(offline: bool) -> Result<()> {
     let settings = Settings::new()?;
-    run(settings).await
+    if !offline {
+        preflight(&settings).await?;
+    } else {
+        let problems = check_settings(&settings);
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+            return Err(anyhow!(
+                "Invalid configuration:\n  {}",
+                problems.join("\n  ")
+            ));
+        }
+    }
+    println!("Configuration is valid");
+    Ok(())
+}
+
+fn check(intent: &Path, portfolio: &Path, price: Option<Decimal>) -> Result<()> {
+    let intent: TradeIntent = serde_json::from_str(&std::fs::read_to_string(intent)?)?;
+    let portfolio: Portfolio = serde_json::from_str(&std::fs::read_to_string(portfolio)?)?;
+    let price = price.or_else(|| {
+        portfolio
+            .holdings
+            .positions
+            .iter()
+            .find(|holding| holding.ticker == intent.ticker)
+            .map(|holding| holding.price)
+    });
+    let mut risk_manager = RiskManager::new(String::new(), RiskSettings::load()?);
+    risk_manager.import_holdings(portfolio.holdings);
+    risk_manager.bind_limits(SharedLimits::new(portfolio.limits));
+    risk_manager.bind_halts(portfolio.halts);
+    // A price given on the command line is taken as current
+    let price = price.map(|price| PriceTick {
+        price,
+        timestamp: Some(Utc::now()),
+        source: None,
+    });
+    let explanation = risk_manager.explain(&intent, price)?;
+    println!("{}", serde_json::to_string_pretty(&explanation)?);
+    Ok(())
+}
+
+async fn print_response(request: reqwest::RequestBuilder) -> Result<()> {
+    let response = request.send().await?;
+    let status = response.status();
+    let body = response.text().await?;
+    if status.is_success() {
+        print!("{}", body);
+        Ok(())
+    } else {
+        Err(anyhow!("{}: {}", status, body.trim()))
+    }
+}
+
+#[cfg(feature = "tui")]
+async fn top(url: String, token: Option<String>) -> Result<()> {
+    risk_manager::top::run(url, token).await
+}
+
+#[cfg(not(feature = "tui"))]
+async fn top(_url: String, _token: Option<String>) -> Result<()> {
+    Err(anyhow!("risk-manager was built without the `tui` feature"))
 }
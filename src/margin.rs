@@ -0,0 +1,358 @@
+use crate::symbols::SymbolMetadata;
+use crate::units::{Price, Shares};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+fn default_initial() -> Decimal {
+    Decimal::new(5, 1)
+}
+
+fn default_maintenance() -> Decimal {
+    Decimal::new(3, 1)
+}
+
+fn default_min_long_price() -> Decimal {
+    Decimal::new(25, 1)
+}
+
+fn default_min_short_price() -> Decimal {
+    Decimal::new(5, 0)
+}
+
+/// Margin requirements of a symbol that replace the schedule's.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct SymbolMargin {
+    pub initial: Option<Decimal>,
+    pub maintenance: Option<Decimal>,
+}
+
+/// What the size of a position is compared to when checking whether it is concentrated.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConcentrationBasis {
+    GrossExposure,
+    Equity,
+}
+
+impl Default for ConcentrationBasis {
+    fn default() -> Self {
+        Self::GrossExposure
+    }
+}
+
+/// Extra maintenance requirement of positions larger than `threshold` of the basis.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ConcentrationAddOn {
+    pub threshold: Decimal,
+    #[serde(default)]
+    pub basis: ConcentrationBasis,
+    /// Fraction of market value added to the maintenance requirement
+    pub add_on: Decimal,
+}
+
+impl ConcentrationAddOn {
+    /// Add-on of a position with the given market value, in an account with the given gross
+    /// exposure and equity.
+    pub fn add_on(
+        &self,
+        market_value: Decimal,
+        gross_exposure: Decimal,
+        equity: Decimal,
+    ) -> Decimal {
+        let basis = match self.basis {
+            ConcentrationBasis::GrossExposure => gross_exposure,
+            ConcentrationBasis::Equity => equity,
+        };
+        if basis > Decimal::ZERO && market_value.abs() > basis * self.threshold {
+            self.add_on
+        } else {
+            Decimal::ZERO
+        }
+    }
+}
+
+/// How margin requirements are computed.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarginMethod {
+    /// Fixed fractions of each position's market value
+    RegT,
+    /// Largest loss of the positions on each underlier under price shocks, for accounts approved
+    /// for portfolio margin
+    Portfolio,
+}
+
+impl Default for MarginMethod {
+    fn default() -> Self {
+        Self::RegT
+    }
+}
+
+fn default_shock() -> Decimal {
+    Decimal::new(15, 2)
+}
+
+fn default_scenarios() -> u32 {
+    10
+}
+
+/// Stress test of portfolio margin. Each underlier is moved up and down in even steps up to its
+/// shock, and the positions on it are netted in every scenario.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PortfolioMarginSettings {
+    #[serde(default = "default_shock")]
+    pub shock: Decimal,
+    /// Shocks of particular underliers that replace `shock`
+    #[serde(default)]
+    pub shocks: HashMap<String, Decimal>,
+    /// Number of steps tested on each side
+    #[serde(default = "default_scenarios")]
+    pub scenarios: u32,
+    /// Underlier of tickers that track another, e.g. QQQ for TQQQ. Other tickers are their own.
+    #[serde(default)]
+    pub underliers: HashMap<String, String>,
+}
+
+impl Default for PortfolioMarginSettings {
+    fn default() -> Self {
+        Self {
+            shock: default_shock(),
+            shocks: HashMap::new(),
+            scenarios: default_scenarios(),
+            underliers: HashMap::new(),
+        }
+    }
+}
+
+/// Fractions of market value required as margin to open and to hold positions. Positions priced
+/// under the minimum price of their side are fully margined, and the maintenance requirement of
+/// leveraged ETFs is multiplied by their leverage.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MarginSchedule {
+    #[serde(default = "default_initial")]
+    pub initial: Decimal,
+    #[serde(default = "default_maintenance")]
+    pub long_maintenance: Decimal,
+    #[serde(default = "default_maintenance")]
+    pub short_maintenance: Decimal,
+    #[serde(default = "default_min_long_price")]
+    pub min_long_price: Decimal,
+    #[serde(default = "default_min_short_price")]
+    pub min_short_price: Decimal,
+    #[serde(default)]
+    pub symbols: HashMap<String, SymbolMargin>,
+    /// Leverage of leveraged and inverse ETFs by ticker, along with those in the symbol metadata
+    #[serde(default)]
+    pub leverage: HashMap<String, Decimal>,
+    pub concentration: Option<ConcentrationAddOn>,
+    #[serde(default)]
+    pub method: MarginMethod,
+    #[serde(default)]
+    pub portfolio: PortfolioMarginSettings,
+}
+
+impl Default for MarginSchedule {
+    fn default() -> Self {
+        Self {
+            initial: default_initial(),
+            long_maintenance: default_maintenance(),
+            short_maintenance: default_maintenance(),
+            min_long_price: default_min_long_price(),
+            min_short_price: default_min_short_price(),
+            symbols: HashMap::new(),
+            leverage: HashMap::new(),
+            concentration: None,
+            method: Default::default(),
+            portfolio: Default::default(),
+        }
+    }
+}
+
+impl MarginSchedule {
+    /// Adds the leverage of the ETFs in the symbol metadata, unless configured here already.
+    pub fn with_leverage(mut self, symbols: &SymbolMetadata) -> Self {
+        for (ticker, leverage) in symbols.leveraged() {
+            self.leverage.entry(ticker.to_string()).or_insert(leverage);
+        }
+        self
+    }
+
+    /// Portfolio margin requirement of positions given by ticker and signed market value: the
+    /// largest loss of the positions on each underlier over its scenarios, summed. Leveraged ETFs
+    /// move by their leverage, and long positions lose at most their value.
+    pub fn portfolio_requirement<'a>(
+        &self,
+        positions: impl Iterator<Item = (&'a str, Decimal)>,
+    ) -> Decimal {
+        let settings = &self.portfolio;
+        let mut underliers: HashMap<&str, Vec<(Decimal, Decimal)>> = HashMap::new();
+        for (ticker, market_value) in positions {
+            let underlier = settings
+                .underliers
+                .get(ticker)
+                .map(String::as_str)
+                .unwrap_or(ticker);
+            let leverage = self.leverage.get(ticker).copied().unwrap_or(Decimal::ONE);
+            underliers
+                .entry(underlier)
+                .or_default()
+                .push((market_value, leverage));
+        }
+        let steps = settings.scenarios.max(1) as i64;
+        underliers
+            .into_iter()
+            .map(|(underlier, positions)| {
+                let shock = settings
+                    .shocks
+                    .get(underlier)
+                    .copied()
+                    .unwrap_or(settings.shock);
+                (-steps..=steps)
+                    .map(|step| {
+                        let change = shock * Decimal::new(step, 0) / Decimal::new(steps, 0);
+                        let pnl: Decimal = positions
+                            .iter()
+                            .map(|(market_value, leverage)| {
+                                let pnl = *market_value * *leverage * change;
+                                if market_value.is_sign_positive() {
+                                    pnl.max(-*market_value)
+                                } else {
+                                    pnl
+                                }
+                            })
+                            .sum();
+                        -pnl
+                    })
+                    .fold(Decimal::ZERO, Decimal::max)
+            })
+            .sum()
+    }
+
+    /// Fraction of a position's market value required to open it.
+    pub fn initial(&self, ticker: &str) -> Decimal {
+        self.symbols
+            .get(ticker)
+            .and_then(|symbol| symbol.initial)
+            .unwrap_or(self.initial)
+    }
+
+    /// Fraction of a position's market value required to hold it.
+    pub fn maintenance(&self, ticker: &str, shares: Shares, price: Price) -> Decimal {
+        if let Some(maintenance) = self.symbols.get(ticker).and_then(|s| s.maintenance) {
+            return maintenance;
+        }
+        let (maintenance, min_price) = if shares.is_short() {
+            (self.short_maintenance, self.min_short_price)
+        } else {
+            (self.long_maintenance, self.min_long_price)
+        };
+        if price.0 < min_price {
+            return Decimal::ONE;
+        }
+        match self.leverage.get(ticker) {
+            Some(leverage) => (maintenance * leverage.abs().max(Decimal::ONE)).min(Decimal::ONE),
+            None => maintenance,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn margin_schedule() {
+        let mut schedule = MarginSchedule::default();
+        schedule.symbols.insert(
+            "GME".into(),
+            SymbolMargin {
+                initial: Some(Decimal::ONE),
+                maintenance: Some(Decimal::ONE),
+            },
+        );
+        let long = Shares(Decimal::new(10, 0));
+        let short = Shares(Decimal::new(-10, 0));
+        let price = |p| Price(Decimal::new(p, 1));
+
+        assert_eq!(schedule.initial("AAPL"), Decimal::new(5, 1));
+        assert_eq!(schedule.initial("GME"), Decimal::ONE);
+        assert_eq!(
+            schedule.maintenance("AAPL", long, price(25)),
+            Decimal::new(3, 1)
+        );
+        assert_eq!(schedule.maintenance("AAPL", long, price(24)), Decimal::ONE);
+        assert_eq!(schedule.maintenance("AAPL", short, price(40)), Decimal::ONE);
+        assert_eq!(
+            schedule.maintenance("AAPL", short, price(50)),
+            Decimal::new(3, 1)
+        );
+        assert_eq!(schedule.maintenance("GME", long, price(500)), Decimal::ONE);
+
+        let mut symbols = HashMap::new();
+        for (ticker, leverage) in [("TQQQ", 3), ("SQQQ", -3), ("UPRO", 4)].iter() {
+            symbols.insert(
+                ticker.to_string(),
+                crate::symbols::SymbolInfo {
+                    leverage: Some(Decimal::new(*leverage, 0)),
+                    ..Default::default()
+                },
+            );
+        }
+        let schedule = schedule.with_leverage(&SymbolMetadata::new(symbols));
+        assert_eq!(
+            schedule.maintenance("TQQQ", long, price(500)),
+            Decimal::new(9, 1)
+        );
+        assert_eq!(
+            schedule.maintenance("SQQQ", short, price(500)),
+            Decimal::new(9, 1)
+        );
+        assert_eq!(schedule.maintenance("UPRO", long, price(500)), Decimal::ONE);
+    }
+
+    #[test]
+    fn portfolio_margin() {
+        let mut schedule = MarginSchedule::default();
+        schedule.leverage.insert("TQQQ".into(), Decimal::new(3, 0));
+        schedule
+            .portfolio
+            .underliers
+            .insert("TQQQ".into(), "QQQ".into());
+        let requirement = |schedule: &MarginSchedule, positions: &[(&'static str, i64)]| {
+            schedule.portfolio_requirement(
+                positions
+                    .iter()
+                    .map(|(ticker, value)| (*ticker, Decimal::new(*value, 0))),
+            )
+        };
+
+        assert_eq!(
+            requirement(&schedule, &[("AAPL", 10000)]),
+            Decimal::new(1500, 0)
+        );
+        assert_eq!(
+            requirement(&schedule, &[("AAPL", 10000), ("MSFT", -10000)]),
+            Decimal::new(3000, 0)
+        );
+        // Short TQQQ hedges long QQQ, since they share an underlier
+        assert_eq!(
+            requirement(&schedule, &[("QQQ", 9000), ("TQQQ", -3000)]),
+            Decimal::ZERO
+        );
+        assert_eq!(
+            requirement(&schedule, &[("QQQ", 9000), ("TQQQ", -2000)]),
+            Decimal::new(450, 0)
+        );
+
+        // A long position can't lose more than its value
+        schedule
+            .portfolio
+            .shocks
+            .insert("QQQ".into(), Decimal::new(4, 1));
+        assert_eq!(
+            requirement(&schedule, &[("TQQQ", 1000)]),
+            Decimal::new(1000, 0)
+        );
+    }
+}
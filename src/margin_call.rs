@@ -0,0 +1,146 @@
+use crate::clock::Clock;
+use crate::events::{Alert, AlertLevel};
+use crate::settings::MarginCallSettings;
+use crate::RiskManager;
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MarginAlertLevel {
+    Warning,
+    Breach,
+}
+
+/// Raised when the equity left over maintenance margin drops below one of the thresholds.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct MarginAlert {
+    pub level: MarginAlertLevel,
+    pub timestamp: DateTime<Utc>,
+    pub equity: Decimal,
+    pub maintenance_margin: Decimal,
+    /// Equity in excess of maintenance margin, as a fraction of equity. -1 once equity is gone.
+    pub cushion: Decimal,
+    /// Threshold the cushion dropped below
+    pub threshold: Decimal,
+}
+
+impl From<&MarginAlert> for Alert {
+    fn from(alert: &MarginAlert) -> Self {
+        let (level, what) = match alert.level {
+            MarginAlertLevel::Warning => (AlertLevel::Warning, "Margin cushion is low"),
+            MarginAlertLevel::Breach => (AlertLevel::Critical, "Margin call"),
+        };
+        Alert {
+            level,
+            message: format!(
+                "{}: equity of {} over maintenance margin of {} is a cushion of {}, below {}",
+                what,
+                alert.equity.round_dp(2),
+                alert.maintenance_margin.round_dp(2),
+                alert.cushion.round_dp(4),
+                alert.threshold
+            ),
+        }
+    }
+}
+
+/// Compares equity to maintenance margin as positions are marked. Alerts once each time the
+/// cushion drops to a worse level; recovering above a threshold re-arms it.
+pub struct MarginCallMonitor {
+    settings: MarginCallSettings,
+    level: Option<MarginAlertLevel>,
+}
+
+impl MarginCallMonitor {
+    pub fn new(settings: MarginCallSettings) -> Self {
+        Self {
+            settings,
+            level: None,
+        }
+    }
+
+    fn cushion(equity: Decimal, maintenance_margin: Decimal) -> Decimal {
+        if equity <= Decimal::ZERO {
+            return -Decimal::ONE;
+        }
+        (equity - maintenance_margin) / equity
+    }
+
+    pub fn check(&mut self, risk_manager: &RiskManager) -> Option<MarginAlert> {
+        let equity = risk_manager.equity();
+        let maintenance_margin = risk_manager.maintenance_margin();
+        let cushion = Self::cushion(equity, maintenance_margin);
+        let (level, threshold) = if maintenance_margin.is_zero() {
+            (None, Decimal::ZERO)
+        } else if cushion < self.settings.breach_cushion {
+            (Some(MarginAlertLevel::Breach), self.settings.breach_cushion)
+        } else if cushion < self.settings.warning_cushion {
+            (
+                Some(MarginAlertLevel::Warning),
+                self.settings.warning_cushion,
+            )
+        } else {
+            (None, Decimal::ZERO)
+        };
+        let previous = std::mem::replace(&mut self.level, level);
+        let level = level.filter(|level| Some(*level) > previous)?;
+        warn!(?level, %equity, %maintenance_margin, %cushion, "Margin cushion dropped");
+        Some(MarginAlert {
+            level,
+            timestamp: risk_manager.clock().now(),
+            equity,
+            maintenance_margin,
+            cushion,
+            threshold,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Price, Shares};
+
+    #[test]
+    fn margin_call() {
+        let mut monitor = MarginCallMonitor::new(MarginCallSettings {
+            warning_cushion: Decimal::new(5, 1),
+            breach_cushion: Decimal::new(2, 1),
+        });
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(8000, 0));
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(100, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        // Equity of 8000 and maintenance margin of 3000
+        assert!(monitor.check(&manager).is_none());
+
+        // Equity of 2000 and maintenance margin of 1200
+        manager.update_price("AAPL", Price(Decimal::new(40, 0)));
+        let alert = monitor.check(&manager).unwrap();
+        assert_eq!(alert.level, MarginAlertLevel::Warning);
+        assert_eq!(alert.cushion, Decimal::new(4, 1));
+        assert!(monitor.check(&manager).is_none());
+
+        // Equity of 1000 and maintenance margin of 900
+        manager.update_price("AAPL", Price(Decimal::new(30, 0)));
+        let alert = monitor.check(&manager).unwrap();
+        assert_eq!(alert.level, MarginAlertLevel::Breach);
+        assert_eq!(alert.threshold, Decimal::new(2, 1));
+        assert_eq!(Alert::from(&alert).level, AlertLevel::Critical);
+
+        // Recovering to a warning re-arms the breach
+        manager.update_price("AAPL", Price(Decimal::new(40, 0)));
+        assert!(monitor.check(&manager).is_none());
+        manager.update_price("AAPL", Price(Decimal::new(30, 0)));
+        assert_eq!(
+            monitor.check(&manager).unwrap().level,
+            MarginAlertLevel::Breach
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoundingMode {
+    /// Round midpoints to the nearest even digit
+    Bankers,
+    /// Round midpoints away from zero
+    HalfUp,
+}
+
+impl Default for RoundingMode {
+    fn default() -> Self {
+        Self::HalfUp
+    }
+}
+
+impl RoundingMode {
+    fn strategy(self) -> RoundingStrategy {
+        match self {
+            RoundingMode::Bankers => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        }
+    }
+}
+
+fn default_money_scale() -> u32 {
+    2
+}
+
+fn default_price_scale() -> u32 {
+    4
+}
+
+fn default_quantity_scale() -> u32 {
+    9
+}
+
+/// How monetary amounts, prices and quantities are rounded. All exposure, margin and P&L
+/// calculations go through this so they agree with the broker to the penny.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct RoundingPolicy {
+    #[serde(default)]
+    pub mode: RoundingMode,
+    /// Decimal places kept for cash, market values, margins and buying power
+    #[serde(default = "default_money_scale")]
+    pub money_scale: u32,
+    /// Decimal places kept for per-share prices
+    #[serde(default = "default_price_scale")]
+    pub price_scale: u32,
+    /// Decimal places kept for share quantities
+    #[serde(default = "default_quantity_scale")]
+    pub quantity_scale: u32,
+}
+
+impl Default for RoundingPolicy {
+    fn default() -> Self {
+        Self {
+            mode: Default::default(),
+            money_scale: default_money_scale(),
+            price_scale: default_price_scale(),
+            quantity_scale: default_quantity_scale(),
+        }
+    }
+}
+
+impl RoundingPolicy {
+    pub fn money(&self, amount: Decimal) -> Decimal {
+        amount.round_dp_with_strategy(self.money_scale, self.mode.strategy())
+    }
+
+    pub fn price(&self, price: Decimal) -> Decimal {
+        price.round_dp_with_strategy(self.price_scale, self.mode.strategy())
+    }
+
+    pub fn quantity(&self, quantity: Decimal) -> Decimal {
+        quantity.round_dp_with_strategy(self.quantity_scale, self.mode.strategy())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rounding() {
+        let half_up = RoundingPolicy::default();
+        let bankers = RoundingPolicy {
+            mode: RoundingMode::Bankers,
+            ..Default::default()
+        };
+        assert_eq!(half_up.money(Decimal::new(1225, 3)), Decimal::new(123, 2));
+        assert_eq!(bankers.money(Decimal::new(1225, 3)), Decimal::new(122, 2));
+        assert_eq!(half_up.money(Decimal::new(-1225, 3)), Decimal::new(-123, 2));
+        assert_eq!(bankers.money(Decimal::new(1235, 3)), Decimal::new(124, 2));
+        assert_eq!(
+            half_up.price(Decimal::new(1030005, 5)),
+            Decimal::new(103001, 4)
+        );
+        assert_eq!(bankers.quantity(Decimal::new(15, 10)), Decimal::new(2, 9));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::events::{Alert, AlertLevel};
+use crate::settings::DenialMonitorSettings;
+use crate::RiskCheckResponse;
+use std::collections::VecDeque;
+
+/// Tracks the share of recent decisions that were denials. A sudden rise usually means our state
+/// has drifted from the broker's or a strategy is misbehaving.
+pub struct DenialMonitor {
+    settings: DenialMonitorSettings,
+    /// Whether each of the most recent decisions was a denial, newest last
+    decisions: VecDeque<bool>,
+    alerting: bool,
+}
+
+impl DenialMonitor {
+    pub fn new(settings: DenialMonitorSettings) -> Self {
+        Self {
+            decisions: VecDeque::with_capacity(settings.window),
+            settings,
+            alerting: false,
+        }
+    }
+
+    /// Denial rate over the window, once it holds enough decisions to be meaningful.
+    pub fn denial_rate(&self) -> Option<f64> {
+        if self.decisions.is_empty() || self.decisions.len() < self.settings.min_decisions {
+            return None;
+        }
+        let denials = self.decisions.iter().filter(|denied| **denied).count();
+        Some(denials as f64 / self.decisions.len() as f64)
+    }
+
+    /// Records a decision, returning an alert when the denial rate rises above the threshold.
+    /// There is only one alert per excursion; the monitor re-arms once the rate drops back.
+    pub fn record(&mut self, response: &RiskCheckResponse) -> Option<Alert> {
+        if self.settings.window == 0 {
+            return None;
+        }
+        if self.decisions.len() == self.settings.window {
+            self.decisions.pop_front();
+        }
+        self.decisions
+            .push_back(matches!(response, RiskCheckResponse::Denied { .. }));
+        let rate = self.denial_rate()?;
+        if rate <= self.settings.threshold {
+            self.alerting = false;
+            return None;
+        }
+        if self.alerting {
+            return None;
+        }
+        self.alerting = true;
+        Some(Alert {
+            level: AlertLevel::Warning,
+            message: format!(
+                "Denial rate is {:.1}% over the last {} decisions, above the threshold of {:.1}%",
+                rate * 100.0,
+                self.decisions.len(),
+                self.settings.threshold * 100.0
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::DenyReason;
+    use trading_base::TradeIntent;
+
+    fn decision(denied: bool) -> RiskCheckResponse {
+        let response = RiskCheckResponse::Granted {
+            intent: TradeIntent::new("AAPL", 1),
+            buffered_price: None,
+            price_source: None,
+            score: None,
+            metrics: None,
+            timing: None,
+        };
+        if denied {
+            response.into_denied(vec![DenyReason::Blocklisted])
+        } else {
+            response
+        }
+    }
+
+    #[test]
+    fn denial_rate() {
+        let mut monitor = DenialMonitor::new(DenialMonitorSettings {
+            window: 4,
+            threshold: 0.5,
+            min_decisions: 2,
+        });
+        assert!(monitor.record(&decision(true)).is_none());
+        assert_eq!(monitor.denial_rate(), None);
+        assert!(monitor.record(&decision(false)).is_none());
+        assert_eq!(monitor.denial_rate(), Some(0.5));
+        let alert = monitor.record(&decision(true)).unwrap();
+        assert_eq!(alert.level, AlertLevel::Warning);
+        // Still above the threshold, but already alerted
+        assert!(monitor.record(&decision(true)).is_none());
+        assert_eq!(monitor.denial_rate(), Some(0.75));
+        // The oldest decision falls out of the window, and the rate drops back to the threshold
+        assert!(monitor.record(&decision(false)).is_none());
+        assert_eq!(monitor.denial_rate(), Some(0.5));
+        assert!(monitor.record(&decision(true)).is_some());
+    }
+}
@@ -0,0 +1,158 @@
+use crate::events::{Alert, AlertLevel, Event};
+use crate::margin_call::MarginAlertLevel;
+use crate::settings::{NotificationEndpoint, NotificationFormat, NotificationSettings};
+use crate::webhook::with_retries;
+use anyhow::Result;
+use reqwest::Client;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, warn};
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// Allows at most `limit` notifications in any window of `RATE_LIMIT_WINDOW`, so a flapping
+/// condition can't flood a channel.
+struct RateLimiter {
+    limit: usize,
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            sent: VecDeque::with_capacity(limit),
+        }
+    }
+
+    fn allow(&mut self, now: Instant) -> bool {
+        while let Some(sent) = self.sent.front() {
+            if now.duration_since(*sent) < RATE_LIMIT_WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= self.limit {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
+
+fn payload(format: NotificationFormat, alert: &Alert) -> serde_json::Value {
+    match format {
+        NotificationFormat::Json => serde_json::json!(alert),
+        NotificationFormat::Slack => {
+            serde_json::json!({ "text": format!(":rotating_light: {}", alert.message) })
+        }
+    }
+}
+
+/// Sends critical alerts, such as the kill switch being engaged, to people. Unlike decision
+/// webhooks, these are meant for chat channels and pagers.
+pub struct Notifier {
+    client: Client,
+    settings: Arc<NotificationSettings>,
+    rate_limiter: RateLimiter,
+}
+
+impl Notifier {
+    pub fn new(settings: NotificationSettings) -> Self {
+        Self {
+            client: Client::new(),
+            rate_limiter: RateLimiter::new(settings.max_per_minute),
+            settings: Arc::new(settings),
+        }
+    }
+
+    /// Notifies every endpoint of each critical alert on the event bus.
+    pub async fn run(mut self, mut events: broadcast::Receiver<Event>) {
+        loop {
+            match events.recv().await {
+                Ok(Event::Alert(alert)) if alert.level == AlertLevel::Critical => {
+                    self.notify(alert)
+                }
+                Ok(Event::MarginAlert(alert)) if alert.level == MarginAlertLevel::Breach => {
+                    self.notify(Alert::from(&alert))
+                }
+                Ok(_) => (),
+                Err(RecvError::Lagged(n)) => warn!(n, "Notifier lagged, alerts may be missed"),
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+
+    fn notify(&mut self, alert: Alert) {
+        if !self.rate_limiter.allow(Instant::now()) {
+            warn!(message = %alert.message, "Notification rate limit reached, dropping alert");
+            return;
+        }
+        for (name, endpoint) in self.settings.endpoints.iter() {
+            let client = self.client.clone();
+            let settings = self.settings.clone();
+            let name = name.clone();
+            let endpoint = endpoint.clone();
+            let payload = payload(endpoint.format, &alert);
+            tokio::spawn(async move {
+                let backoff = Duration::from_millis(settings.initial_backoff_ms);
+                let send = || post(&client, &endpoint, &payload, settings.timeout_ms);
+                if with_retries(&name, settings.max_retries, backoff, send).await {
+                    debug!(endpoint = %name, "Notification delivered");
+                } else {
+                    error!(endpoint = %name, "Notification retries exhausted, dropping alert");
+                }
+            });
+        }
+    }
+}
+
+async fn post(
+    client: &Client,
+    endpoint: &NotificationEndpoint,
+    payload: &serde_json::Value,
+    timeout_ms: u64,
+) -> Result<()> {
+    client
+        .post(&endpoint.url)
+        .json(payload)
+        .timeout(Duration::from_millis(timeout_ms))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limiter() {
+        let mut limiter = RateLimiter::new(2);
+        let start = Instant::now();
+        assert!(limiter.allow(start));
+        assert!(limiter.allow(start + Duration::from_secs(1)));
+        assert!(!limiter.allow(start + Duration::from_secs(2)));
+        assert!(limiter.allow(start + RATE_LIMIT_WINDOW));
+        assert!(!limiter.allow(start + RATE_LIMIT_WINDOW));
+    }
+
+    #[test]
+    fn slack_payload() {
+        let alert = Alert {
+            level: AlertLevel::Critical,
+            message: "Kill switch engaged".into(),
+        };
+        assert_eq!(
+            payload(NotificationFormat::Slack, &alert),
+            serde_json::json!({ "text": ":rotating_light: Kill switch engaged" })
+        );
+        assert_eq!(
+            payload(NotificationFormat::Json, &alert)["level"],
+            "critical"
+        );
+    }
+}
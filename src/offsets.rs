@@ -0,0 +1,174 @@
+use crate::events::{Alert, AlertLevel, Event, EventBus};
+use crate::settings::LagMonitorSettings;
+use crate::transport::MessageSource;
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// Progress of the consumer on one of its assigned input partitions.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PartitionOffsets {
+    pub topic: String,
+    pub partition: i32,
+    /// Offset of the next message the consumer will read
+    pub position: Option<i64>,
+    pub committed: Option<i64>,
+    pub high_watermark: i64,
+    /// Messages left to read before the consumer is caught up
+    pub lag: Option<i64>,
+}
+
+/// Metric name, help text and how to read the value from the offsets
+type Gauge = (
+    &'static str,
+    &'static str,
+    fn(&PartitionOffsets) -> Option<i64>,
+);
+
+/// Renders offsets in the Prometheus text exposition format.
+pub fn render_metrics(offsets: &[PartitionOffsets]) -> String {
+    let mut metrics = String::new();
+    let gauges: [Gauge; 4] = [
+        ("consumer_position", "Next offset to be consumed", |o| {
+            o.position
+        }),
+        ("consumer_committed_offset", "Last committed offset", |o| {
+            o.committed
+        }),
+        (
+            "consumer_high_watermark",
+            "Partition high-water mark",
+            |o| Some(o.high_watermark),
+        ),
+        ("consumer_lag", "Messages not yet consumed", |o| o.lag),
+    ];
+    for (name, help, value) in gauges.iter() {
+        let _ = writeln!(metrics, "# HELP risk_manager_{} {}", name, help);
+        let _ = writeln!(metrics, "# TYPE risk_manager_{} gauge", name);
+        for o in offsets {
+            if let Some(value) = value(o) {
+                let _ = writeln!(
+                    metrics,
+                    "risk_manager_{}{{topic=\"{}\",partition=\"{}\"}} {}",
+                    name, o.topic, o.partition, value
+                );
+            }
+        }
+    }
+    metrics
+}
+
+/// Alerts when the consumer falls too far behind on any partition. Decisions made on stale
+/// inputs are worse than none, so this is worth a warning. There is only one alert per
+/// excursion; the alarm re-arms once the lag drops back.
+struct LagAlarm {
+    threshold: i64,
+    alerting: bool,
+}
+
+impl LagAlarm {
+    fn check(&mut self, offsets: &[PartitionOffsets]) -> Option<Alert> {
+        let worst = offsets
+            .iter()
+            .filter(|o| o.lag.is_some())
+            .max_by_key(|o| o.lag)?;
+        let lag = worst.lag?;
+        if lag <= self.threshold {
+            self.alerting = false;
+            return None;
+        }
+        if self.alerting {
+            return None;
+        }
+        self.alerting = true;
+        Some(Alert {
+            level: AlertLevel::Warning,
+            message: format!(
+                "Consumer is {} messages behind on {}/{}, above the threshold of {}",
+                lag, worst.topic, worst.partition, self.threshold
+            ),
+        })
+    }
+}
+
+/// Periodically checks the consumer's lag, publishing an alert when it exceeds the threshold.
+pub async fn monitor_lag(
+    source: Arc<dyn MessageSource>,
+    settings: LagMonitorSettings,
+    events: EventBus,
+) {
+    let mut alarm = LagAlarm {
+        threshold: settings.threshold,
+        alerting: false,
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let source = source.clone();
+        let offsets = tokio::task::spawn_blocking(move || source.partition_offsets()).await;
+        match offsets {
+            Ok(Ok(offsets)) => {
+                if let Some(alert) = alarm.check(&offsets) {
+                    warn!(message = %alert.message, "Consumer lag alert");
+                    events.publish(Event::Alert(alert));
+                }
+            }
+            Ok(Err(e)) => error!(?e, "Failed to fetch consumer offsets"),
+            Err(e) => error!(?e, "Consumer offsets task failed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn metrics() {
+        let offsets = vec![PartitionOffsets {
+            topic: "lots".into(),
+            partition: 0,
+            position: Some(10),
+            committed: None,
+            high_watermark: 12,
+            lag: Some(2),
+        }];
+        let metrics = render_metrics(&offsets);
+        assert!(
+            metrics.contains("risk_manager_consumer_position{topic=\"lots\",partition=\"0\"} 10\n")
+        );
+        assert!(metrics.contains("risk_manager_consumer_lag{topic=\"lots\",partition=\"0\"} 2\n"));
+        assert!(!metrics.contains("risk_manager_consumer_committed_offset{"));
+    }
+
+    #[test]
+    fn lag_alarm() {
+        let offsets = |lags: &[Option<i64>]| -> Vec<PartitionOffsets> {
+            lags.iter()
+                .enumerate()
+                .map(|(partition, lag)| PartitionOffsets {
+                    topic: "risk-check-request".into(),
+                    partition: partition as i32,
+                    position: None,
+                    committed: None,
+                    high_watermark: 100,
+                    lag: *lag,
+                })
+                .collect()
+        };
+        let mut alarm = LagAlarm {
+            threshold: 10,
+            alerting: false,
+        };
+        assert!(alarm.check(&offsets(&[None, Some(10)])).is_none());
+        let alert = alarm.check(&offsets(&[Some(2), Some(11)])).unwrap();
+        assert_eq!(alert.level, AlertLevel::Warning);
+        assert!(alert.message.contains("risk-check-request/1"));
+        // Still behind, but already alerted
+        assert!(alarm.check(&offsets(&[Some(20), Some(2)])).is_none());
+        assert!(alarm.check(&offsets(&[Some(0), Some(0)])).is_none());
+        assert!(alarm.check(&offsets(&[Some(0), Some(12)])).is_some());
+    }
+}
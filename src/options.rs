@@ -0,0 +1,223 @@
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptionRight {
+    Call,
+    Put,
+}
+
+/// An equity option contract, as given by its OCC symbol.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OptionContract {
+    pub underlying: String,
+    pub expiration: NaiveDate,
+    pub right: OptionRight,
+    pub strike: Decimal,
+}
+
+impl OptionContract {
+    /// Parses an OCC symbol such as `AAPL240119C00150000`: the root, the expiration as YYMMDD,
+    /// C or P, and the strike in thousandths of a dollar over 8 digits. The root may be padded
+    /// with spaces to 6 characters.
+    pub fn parse(symbol: &str) -> Option<Self> {
+        if !symbol.is_ascii() || symbol.len() < 16 {
+            return None;
+        }
+        let (root, rest) = symbol.split_at(symbol.len() - 15);
+        let underlying = root.trim_end();
+        if underlying.is_empty() || underlying.len() > 6 {
+            return None;
+        }
+        let (date, rest) = rest.split_at(6);
+        let (right, strike) = rest.split_at(1);
+        if !date
+            .bytes()
+            .chain(strike.bytes())
+            .all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        let expiration = NaiveDate::parse_from_str(date, "%y%m%d").ok()?;
+        let right = match right {
+            "C" => OptionRight::Call,
+            "P" => OptionRight::Put,
+            _ => return None,
+        };
+        Some(Self {
+            underlying: underlying.to_string(),
+            expiration,
+            right,
+            strike: Decimal::new(strike.parse().ok()?, 3).normalize(),
+        })
+    }
+
+    /// Amount per share by which the option is out of the money at `underlying_price`.
+    pub fn out_of_the_money(&self, underlying_price: Decimal) -> Decimal {
+        let otm = match self.right {
+            OptionRight::Call => self.strike - underlying_price,
+            OptionRight::Put => underlying_price - self.strike,
+        };
+        otm.max(Decimal::ZERO)
+    }
+}
+
+fn default_multiplier() -> Decimal {
+    Decimal::new(100, 0)
+}
+
+fn default_naked_rate() -> Decimal {
+    Decimal::new(2, 1)
+}
+
+fn default_min_naked_rate() -> Decimal {
+    Decimal::new(1, 1)
+}
+
+fn default_cash_secured_puts() -> bool {
+    true
+}
+
+/// Options trading. Tickers that parse as OCC symbols are treated as option contracts, and are
+/// margined by the standard option requirements rather than the margin schedule: long options
+/// are paid in full, calls covered by shares of the underlying need nothing on top of the shares,
+/// and other short options are either cash-secured or margined as naked.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OptionsSettings {
+    /// Shares of the underlying per contract
+    #[serde(default = "default_multiplier")]
+    pub multiplier: Decimal,
+    /// Fraction of the underlying's price required on top of the premium of naked options, less
+    /// the amount out of the money
+    #[serde(default = "default_naked_rate")]
+    pub naked_rate: Decimal,
+    /// Smallest fraction of the underlying's price, or the strike of puts, required on top of the
+    /// premium of naked options
+    #[serde(default = "default_min_naked_rate")]
+    pub min_naked_rate: Decimal,
+    /// Short puts hold their strike in cash rather than being margined as naked
+    #[serde(default = "default_cash_secured_puts")]
+    pub cash_secured_puts: bool,
+    /// Deltas of contracts by OCC symbol, until the greeks topic has one
+    #[serde(default)]
+    pub deltas: HashMap<String, Decimal>,
+}
+
+impl Default for OptionsSettings {
+    fn default() -> Self {
+        Self {
+            multiplier: default_multiplier(),
+            naked_rate: default_naked_rate(),
+            min_naked_rate: default_min_naked_rate(),
+            cash_secured_puts: default_cash_secured_puts(),
+            deltas: HashMap::new(),
+        }
+    }
+}
+
+impl OptionsSettings {
+    /// Requirement per share of a naked short option.
+    pub fn naked(
+        &self,
+        contract: &OptionContract,
+        premium: Decimal,
+        underlying_price: Decimal,
+    ) -> Decimal {
+        let standard =
+            self.naked_rate * underlying_price - contract.out_of_the_money(underlying_price);
+        let minimum = self.min_naked_rate
+            * match contract.right {
+                OptionRight::Call => underlying_price,
+                OptionRight::Put => contract.strike,
+            };
+        premium + standard.max(minimum)
+    }
+
+    /// Requirement of a position of `contracts` contracts, negative if short, priced at `premium`
+    /// per share. `covered_contracts` of a short call are covered by shares of the underlying.
+    pub fn requirement(
+        &self,
+        contract: &OptionContract,
+        contracts: Decimal,
+        premium: Decimal,
+        underlying_price: Decimal,
+        covered_contracts: Decimal,
+    ) -> Decimal {
+        let per_share = if contracts.is_sign_positive() {
+            premium * contracts
+        } else {
+            let contracts = contracts.abs();
+            match contract.right {
+                OptionRight::Call => {
+                    let uncovered = (contracts - covered_contracts).max(Decimal::ZERO);
+                    self.naked(contract, premium, underlying_price) * uncovered
+                }
+                OptionRight::Put if self.cash_secured_puts => contract.strike * contracts,
+                OptionRight::Put => self.naked(contract, premium, underlying_price) * contracts,
+            }
+        };
+        per_share * self.multiplier
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn occ_symbol() {
+        let contract = OptionContract::parse("AAPL240119C00150000").unwrap();
+        assert_eq!(contract.underlying, "AAPL");
+        assert_eq!(contract.expiration, NaiveDate::from_ymd(2024, 1, 19));
+        assert_eq!(contract.right, OptionRight::Call);
+        assert_eq!(contract.strike, Decimal::new(150, 0));
+        let padded = OptionContract::parse("SPY   211217P00452500").unwrap();
+        assert_eq!(padded.underlying, "SPY");
+        assert_eq!(padded.strike, Decimal::new(4525, 1));
+
+        assert!(OptionContract::parse("AAPL").is_none());
+        assert!(OptionContract::parse("AAPL241319C00150000").is_none());
+        assert!(OptionContract::parse("AAPL240119X00150000").is_none());
+    }
+
+    #[test]
+    fn option_requirements() {
+        let settings = OptionsSettings::default();
+        let call = OptionContract::parse("AAPL240119C00150000").unwrap();
+        let put = OptionContract::parse("AAPL240119P00140000").unwrap();
+        let (premium, price) = (Decimal::new(2, 0), Decimal::new(145, 0));
+
+        // Long options are paid in full
+        assert_eq!(
+            settings.requirement(&call, Decimal::ONE, premium, price, Decimal::ZERO),
+            Decimal::new(200, 0)
+        );
+        // Covered calls need nothing besides the shares
+        assert_eq!(
+            settings.requirement(&call, -Decimal::ONE, premium, price, Decimal::ONE),
+            Decimal::ZERO
+        );
+        // Naked calls: 2 + 20% of 145 - 5 out of the money
+        assert_eq!(
+            settings.requirement(&call, -Decimal::ONE, premium, price, Decimal::ZERO),
+            Decimal::new(2600, 0)
+        );
+        assert_eq!(
+            settings.requirement(&put, -Decimal::new(2, 0), premium, price, Decimal::ZERO),
+            Decimal::new(28000, 0)
+        );
+        // Naked puts: 2 + 10% of the 140 strike, as 10% of 145 less 5 out of the money is less
+        let naked = OptionsSettings {
+            cash_secured_puts: false,
+            naked_rate: Decimal::new(1, 1),
+            ..Default::default()
+        };
+        assert_eq!(
+            naked.requirement(&put, -Decimal::ONE, premium, price, Decimal::ZERO),
+            Decimal::new(1600, 0)
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use trading_base::{TimeInForce, TradeIntent};
+use uuid::Uuid;
+
+/// A granted intent that hasn't been completely filled yet.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WorkingOrder {
+    pub intent: TradeIntent,
+    /// Price the intent was granted at. Closing trades aren't priced.
+    pub price: Option<Decimal>,
+    pub filled: Decimal,
+}
+
+impl WorkingOrder {
+    /// Buying power set aside for the part of the order that hasn't been filled yet, with
+    /// `multiplier` shares per contract.
+    pub fn reserved(&self, multiplier: Decimal) -> Decimal {
+        let qty = Decimal::from_isize(self.intent.qty).unwrap_or_default();
+        let remaining = (qty.abs() - self.filled).max(Decimal::ZERO);
+        self.price
+            .map(|price| price * remaining * multiplier)
+            .unwrap_or_default()
+    }
+}
+
+/// Granted intents keyed by intent id, kept until they are filled, canceled or expire so that
+/// amendments can be checked against the original reservation.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct WorkingOrders(HashMap<Uuid, WorkingOrder>);
+
+impl WorkingOrders {
+    pub fn insert(&mut self, id: Uuid, intent: TradeIntent, price: Option<Decimal>) {
+        let filled = self
+            .0
+            .get(&id)
+            .map(|order| order.filled)
+            .unwrap_or_default();
+        self.0.insert(
+            id,
+            WorkingOrder {
+                intent,
+                price,
+                filled,
+            },
+        );
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<&WorkingOrder> {
+        self.0.get(id)
+    }
+
+    /// Buying power set aside for all working orders, given the shares per contract by ticker.
+    pub fn total_reserved(&self, multiplier: impl Fn(&str) -> Decimal) -> Decimal {
+        self.0
+            .values()
+            .map(|order| order.reserved(multiplier(&order.intent.ticker)))
+            .sum()
+    }
+
+    /// Forgets a canceled order, returning it if it was working.
+    pub fn remove(&mut self, id: &Uuid) -> Option<WorkingOrder> {
+        self.0.remove(id)
+    }
+
+    /// Forgets the day orders, which expire at the close.
+    pub fn expire_day_orders(&mut self) -> usize {
+        let before = self.0.len();
+        self.0
+            .retain(|_, order| !matches!(order.intent.time_in_force, TimeInForce::Day));
+        before - self.0.len()
+    }
+
+    /// Records a fill against an order, forgetting the order once it is completely filled.
+    pub fn fill(&mut self, id: &Uuid, shares: Decimal) {
+        if let Some(order) = self.0.get_mut(id) {
+            order.filled += shares.abs();
+            if order.filled >= Decimal::from_isize(order.intent.qty.abs()).unwrap_or_default() {
+                self.0.remove(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn working_orders() {
+        let mut orders = WorkingOrders::default();
+        let intent = TradeIntent::new("AAPL", -10);
+        let id = intent.id;
+        orders.insert(id, intent.clone(), Some(Decimal::new(100, 0)));
+        assert_eq!(
+            orders.get(&id).unwrap().reserved(Decimal::ONE),
+            Decimal::new(1000, 0)
+        );
+        assert_eq!(
+            orders.total_reserved(|_| Decimal::ONE),
+            Decimal::new(1000, 0)
+        );
+        // Contracts reserve the value of the shares they control
+        let contracts = |_: &str| Decimal::new(100, 0);
+        assert_eq!(orders.total_reserved(contracts), Decimal::new(100000, 0));
+
+        orders.fill(&id, Decimal::new(-4, 0));
+        orders.insert(id, intent.clone(), Some(Decimal::new(90, 0)));
+        assert_eq!(orders.get(&id).unwrap().filled, Decimal::new(4, 0));
+        // Only what is left to fill stays reserved
+        assert_eq!(
+            orders.total_reserved(|_| Decimal::ONE),
+            Decimal::new(540, 0)
+        );
+        orders.fill(&id, Decimal::new(-6, 0));
+        assert!(orders.get(&id).is_none());
+    }
+
+    #[test]
+    fn cancellation_and_expiry() {
+        let mut orders = WorkingOrders::default();
+        let day = TradeIntent::new("AAPL", 10).time_in_force(TimeInForce::Day);
+        let gtc = TradeIntent::new("MSFT", 10).time_in_force(TimeInForce::GoodTilCancelled);
+        orders.insert(day.id, day.clone(), Some(Decimal::new(100, 0)));
+        orders.insert(gtc.id, gtc.clone(), Some(Decimal::new(100, 0)));
+        assert_eq!(orders.expire_day_orders(), 1);
+        assert!(orders.get(&day.id).is_none());
+        assert!(orders.remove(&gtc.id).is_some());
+        assert_eq!(orders.total_reserved(|_| Decimal::ONE), Decimal::ZERO);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::codec::{correlation_headers, Codec, Producer};
+use crate::input::{Input, RequestOptions, CORRELATION_ID_HEADER};
+use crate::latency::LatencyStats;
+use crate::quarantine::{Origin, Quarantine, Undecodable};
+use crate::settings::{PipelineSettings, SupervisorSettings};
+use crate::supervisor::Backoff;
+use crate::transport::{header, MessageHeaders, MessageSource};
+use crate::RiskCheckResponse;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// An input and where it came from.
+pub struct Received {
+    pub input: Input,
+    pub options: RequestOptions,
+    pub correlation_id: Option<String>,
+    /// Not set for inputs that didn't come from the input topics
+    pub origin: Option<Origin>,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Number of messages waiting between the stages of the pipeline.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    inputs: AtomicUsize,
+    outputs: AtomicUsize,
+    input_capacity: usize,
+    output_capacity: usize,
+}
+
+impl QueueStats {
+    pub fn new(settings: &PipelineSettings) -> Self {
+        Self {
+            input_capacity: settings.input_capacity,
+            output_capacity: settings.output_capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Renders the queue depths in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut metrics = String::new();
+        let queues = [
+            ("inputs", &self.inputs, self.input_capacity),
+            ("outputs", &self.outputs, self.output_capacity),
+        ];
+        let _ = writeln!(
+            metrics,
+            "# HELP risk_manager_queue_depth Messages waiting between pipeline stages"
+        );
+        let _ = writeln!(metrics, "# TYPE risk_manager_queue_depth gauge");
+        for (queue, depth, _) in queues.iter() {
+            let depth = depth.load(Ordering::Relaxed);
+            let _ = writeln!(
+                metrics,
+                "risk_manager_queue_depth{{queue=\"{}\"}} {}",
+                queue, depth
+            );
+        }
+        let _ = writeln!(
+            metrics,
+            "# HELP risk_manager_queue_capacity Messages a queue holds before applying backpressure"
+        );
+        let _ = writeln!(metrics, "# TYPE risk_manager_queue_capacity gauge");
+        for (queue, _, capacity) in queues.iter() {
+            let _ = writeln!(
+                metrics,
+                "risk_manager_queue_capacity{{queue=\"{}\"}} {}",
+                queue, capacity
+            );
+        }
+        metrics
+    }
+}
+
+fn correlation_id(headers: &[(String, Vec<u8>)]) -> Option<String> {
+    let value = header(headers, CORRELATION_ID_HEADER)?;
+    std::str::from_utf8(value).ok().map(String::from)
+}
+
+/// Parses the per-request options from the message headers.
+fn request_options(headers: &[(String, Vec<u8>)]) -> RequestOptions {
+    let mut options = RequestOptions::default();
+    for (name, value) in headers {
+        if name == "allow-partial" {
+            options.allow_partial = RequestOptions::parse_allow_partial(value);
+        }
+    }
+    options
+}
+
+/// Returns the next input, with its options, correlation id and where it was read from.
+/// Messages that can't be decoded are returned as an `Undecodable` error.
+pub async fn receive(
+    source: &dyn MessageSource,
+    codec: &Codec,
+    latency: &LatencyStats,
+) -> Result<(Input, RequestOptions, Option<String>, Origin)> {
+    let message = source.recv().await?;
+    debug!(topic = %message.topic, "Message received");
+    let origin = Origin {
+        topic: message.topic,
+        partition: message.partition,
+        offset: message.offset,
+        key: message.key,
+        payload: message.payload.clone().unwrap_or_default(),
+    };
+    if message.payload.is_none() {
+        let error = "Empty payload".to_string();
+        return Err(Undecodable { origin, error }.into());
+    }
+    let options = request_options(&message.headers);
+    let correlation_id = correlation_id(&message.headers);
+    let start = std::time::Instant::now();
+    let input = match codec.decode_input(&origin.payload).await {
+        Ok(input) => input,
+        Err(e) => {
+            let error = e.to_string();
+            return Err(Undecodable { origin, error }.into());
+        }
+    };
+    latency.deserialization.observe(start.elapsed());
+    Ok((input, options, correlation_id, origin))
+}
+
+/// Inputs read and decoded by the consumer task, waiting to be evaluated.
+pub struct Inputs {
+    receiver: mpsc::Receiver<Received>,
+    stats: Arc<QueueStats>,
+}
+
+impl Inputs {
+    pub async fn recv(&mut self) -> Result<Received> {
+        let received = self
+            .receiver
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("Consumer task stopped"))?;
+        self.stats.inputs.fetch_sub(1, Ordering::Relaxed);
+        Ok(received)
+    }
+
+    /// Stops the consumer task from queueing more inputs. Inputs already queued can still be
+    /// received, after which `recv` fails.
+    pub fn close(&mut self) {
+        self.receiver.close();
+    }
+}
+
+/// Reads and decodes inputs in a task of its own, so that it can read ahead while an input is
+/// evaluated. Undecodable messages are quarantined here, since only the consumer can seek back
+/// to them. Once the queue is full, the task stops reading until the evaluator catches up.
+pub fn consume(
+    source: Arc<dyn MessageSource>,
+    codec: Codec,
+    latency: Arc<LatencyStats>,
+    mut quarantine: Quarantine,
+    supervisor: &SupervisorSettings,
+    stats: Arc<QueueStats>,
+    errors: mpsc::UnboundedSender<anyhow::Error>,
+) -> Inputs {
+    let (sender, receiver) = mpsc::channel(stats.input_capacity.max(1));
+    let mut backoff = Backoff::new(supervisor);
+    let inputs = Inputs {
+        receiver,
+        stats: stats.clone(),
+    };
+    tokio::spawn(async move {
+        loop {
+            let received = receive(&*source, &codec, &latency).await;
+            let error = match received {
+                Ok((input, options, correlation_id, origin)) => {
+                    backoff.reset();
+                    let received = Received {
+                        input,
+                        options,
+                        correlation_id,
+                        origin: Some(origin),
+                        received_at: Utc::now(),
+                    };
+                    stats.inputs.fetch_add(1, Ordering::Relaxed);
+                    if sender.send(received).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Err(e) => match e.downcast::<Undecodable>() {
+                    Ok(Undecodable { origin, error }) => {
+                        match quarantine.failed(origin, &error).await {
+                            Ok(()) => continue,
+                            Err(e) => e,
+                        }
+                    }
+                    Err(e) => e,
+                },
+            };
+            // Consumer errors used to end the run loop, so they still go to the supervisor
+            if errors.send(error).is_err() {
+                return;
+            }
+            tokio::time::sleep(backoff.next_delay()).await;
+        }
+    });
+    inputs
+}
+
+/// A message to produce, already encoded.
+struct Outgoing {
+    topic: String,
+    key: String,
+    payload: Vec<u8>,
+    headers: MessageHeaders,
+}
+
+/// Queues messages for the producer task, so that a stalled broker doesn't hold up evaluating
+/// inputs until the queue is full.
+pub struct Outbox {
+    sender: mpsc::Sender<Outgoing>,
+    codec: Codec,
+    stats: Arc<QueueStats>,
+}
+
+impl Outbox {
+    /// Starts the producer task. Sends that fail after exhausting their retries are reported on
+    /// `errors`. The task stops, once every queued message has been sent, when the outbox is
+    /// dropped.
+    pub fn new(
+        producer: Producer,
+        codec: Codec,
+        stats: Arc<QueueStats>,
+        errors: mpsc::UnboundedSender<anyhow::Error>,
+    ) -> (Self, JoinHandle<()>) {
+        let (sender, mut receiver) = mpsc::channel::<Outgoing>(stats.output_capacity.max(1));
+        let task_stats = stats.clone();
+        let task = tokio::spawn(async move {
+            while let Some(outgoing) = receiver.recv().await {
+                task_stats.outputs.fetch_sub(1, Ordering::Relaxed);
+                let Outgoing {
+                    topic,
+                    key,
+                    payload,
+                    headers,
+                } = outgoing;
+                if let Err(e) = producer
+                    .send_raw(&topic, key.as_bytes(), &payload, &headers)
+                    .await
+                {
+                    let _ = errors.send(e);
+                }
+            }
+        });
+        let outbox = Self {
+            sender,
+            codec,
+            stats,
+        };
+        (outbox, task)
+    }
+
+    /// Queues a message, with the correlation id of the input it responds to if there is one.
+    pub async fn send<T: Serialize>(
+        &self,
+        topic: &str,
+        key: &str,
+        message: &T,
+        correlation_id: Option<&str>,
+    ) -> Result<()> {
+        let payload = self.codec.encode(topic, message).await?;
+        self.enqueue(topic, key, payload, correlation_id).await
+    }
+
+    pub async fn send_decision(
+        &self,
+        topic: &str,
+        key: &str,
+        response: &RiskCheckResponse,
+        correlation_id: Option<&str>,
+    ) -> Result<()> {
+        let payload = self.codec.encode_decision(topic, response).await?;
+        self.enqueue(topic, key, payload, correlation_id).await
+    }
+
+    async fn enqueue(
+        &self,
+        topic: &str,
+        key: &str,
+        payload: Vec<u8>,
+        correlation_id: Option<&str>,
+    ) -> Result<()> {
+        let outgoing = Outgoing {
+            topic: topic.to_string(),
+            key: key.to_string(),
+            payload,
+            headers: correlation_headers(correlation_id),
+        };
+        self.stats.outputs.fetch_add(1, Ordering::Relaxed);
+        self.sender
+            .send(outgoing)
+            .await
+            .map_err(|_| anyhow!("Producer task stopped"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn queue_metrics() {
+        let stats = QueueStats::new(&PipelineSettings {
+            input_capacity: 10,
+            output_capacity: 20,
+        });
+        stats.inputs.fetch_add(3, Ordering::Relaxed);
+        let metrics = stats.render_metrics();
+        assert!(metrics.contains("risk_manager_queue_depth{queue=\"inputs\"} 3\n"));
+        assert!(metrics.contains("risk_manager_queue_depth{queue=\"outputs\"} 0\n"));
+        assert!(metrics.contains("risk_manager_queue_capacity{queue=\"outputs\"} 20\n"));
+    }
+}
@@ -0,0 +1,633 @@
+use crate::error::RiskManagerError;
+use crate::limits::RiskLimits;
+use crate::rules::RuleKind;
+use crate::settings::{
+    CodecFormat, PipelineSettings, PriceSourceKind, ProducerRetrySettings, RiskSettings,
+    SupervisorSettings, TopicSettings,
+};
+use crate::Settings;
+use alpaca::{rest::account::GetAccount, Client};
+use reqwest::Url;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+use tracing::info;
+
+/// How long to wait for each external service to answer.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A setting that can't work, named by its path in the configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigProblem {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<ConfigProblem>);
+
+impl Problems {
+    fn push(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push(ConfigProblem {
+            field: field.into(),
+            message: message.into(),
+        })
+    }
+
+    fn url(&mut self, field: impl Into<String>, url: &str) {
+        if let Err(e) = Url::parse(url) {
+            self.push(field, format!("`{}` is not a valid URL: {}", url, e))
+        }
+    }
+
+    fn non_empty(&mut self, field: impl Into<String>, value: &str) {
+        if value.trim().is_empty() {
+            self.push(field, "must not be empty")
+        }
+    }
+
+    fn positive(&mut self, field: impl Into<String>, value: u64) {
+        if value == 0 {
+            self.push(field, "must be greater than 0")
+        }
+    }
+
+    fn file(&mut self, field: impl Into<String>, path: &str) {
+        if !Path::new(path).is_file() {
+            self.push(field, format!("`{}` does not exist", path))
+        }
+    }
+
+    fn backoff(&mut self, field: &str, initial_ms: u64, max_ms: u64) {
+        self.positive(format!("{}.initial_backoff_ms", field), initial_ms);
+        if max_ms < initial_ms {
+            self.push(
+                format!("{}.max_backoff_ms", field),
+                "must not be less than initial_backoff_ms",
+            )
+        }
+    }
+}
+
+fn check_topics(problems: &mut Problems, topics: &TopicSettings) {
+    let names = [
+        ("requests", &topics.requests),
+        ("responses", &topics.responses),
+        ("lots", &topics.lots),
+        ("time", &topics.time),
+        ("admin", &topics.admin),
+        ("admin_responses", &topics.admin_responses),
+        ("query_responses", &topics.query_responses),
+        ("what_if_responses", &topics.what_if_responses),
+        ("lifecycle", &topics.lifecycle),
+        ("snapshots", &topics.snapshots),
+        ("alerts", &topics.alerts),
+        ("dead_letters", &topics.dead_letters),
+        ("account_state", &topics.account_state),
+        ("liquidations", &topics.liquidations),
+    ];
+    for (name, topic) in names.iter() {
+        problems.non_empty(format!("topics.{}", name), topic);
+    }
+    if let Some(prices) = topics.prices.as_deref() {
+        problems.non_empty("topics.prices", prices);
+    }
+}
+
+fn check_risk(problems: &mut Problems, risk: &RiskSettings) {
+    if risk.market_order_slippage.is_sign_negative() {
+        problems.push("risk.market_order_slippage", "must not be negative");
+    }
+    if let Some(max_drawdown) = risk.max_drawdown {
+        if max_drawdown <= Decimal::ZERO || max_drawdown >= Decimal::ONE {
+            problems.push("risk.max_drawdown", "must be between 0 and 1");
+        }
+    }
+    if let Some(max_rate) = risk.borrow_fees.max_rate {
+        if max_rate.is_sign_negative() {
+            problems.push("risk.borrow_fees.max_rate", "must not be negative");
+        }
+    }
+    for (ticker, rate) in risk.borrow_fees.rates.iter() {
+        if rate.is_sign_negative() {
+            problems.push(
+                format!("risk.borrow_fees.rates.{}", ticker),
+                "must not be negative",
+            );
+        }
+    }
+    if let Some(crypto) = risk.crypto.as_ref() {
+        if crypto.market_order_slippage.is_sign_negative() {
+            problems.push("risk.crypto.market_order_slippage", "must not be negative");
+        }
+    }
+    if let Some(options) = risk.options.as_ref() {
+        if options.multiplier <= Decimal::ZERO {
+            problems.push("risk.options.multiplier", "must be positive");
+        }
+        for (field, rate) in [
+            ("risk.options.naked_rate", options.naked_rate),
+            ("risk.options.min_naked_rate", options.min_naked_rate),
+        ]
+        .iter()
+        {
+            if rate.is_sign_negative() || *rate > Decimal::ONE {
+                problems.push(*field, "must be between 0 and 1");
+            }
+        }
+        for (ticker, delta) in &options.deltas {
+            if delta.abs() > Decimal::ONE {
+                problems.push(
+                    "risk.options.deltas",
+                    format!("delta of {} must be between -1 and 1", ticker),
+                );
+            }
+        }
+    }
+    if risk.multiplier.min_margin_equity <= Decimal::ZERO
+        || risk.multiplier.min_margin_equity > risk.multiplier.pdt_equity
+    {
+        problems.push(
+            "risk.multiplier.min_margin_equity",
+            "must be positive and not greater than pdt_equity",
+        );
+    }
+    let margin = &risk.margin;
+    let requirements = [
+        ("risk.margin.initial".to_string(), Some(margin.initial)),
+        (
+            "risk.margin.long_maintenance".to_string(),
+            Some(margin.long_maintenance),
+        ),
+        (
+            "risk.margin.short_maintenance".to_string(),
+            Some(margin.short_maintenance),
+        ),
+    ];
+    let overrides = margin.symbols.iter().flat_map(|(ticker, symbol)| {
+        vec![
+            (
+                format!("risk.margin.symbols.{}.initial", ticker),
+                symbol.initial,
+            ),
+            (
+                format!("risk.margin.symbols.{}.maintenance", ticker),
+                symbol.maintenance,
+            ),
+        ]
+    });
+    for (field, requirement) in requirements.iter().cloned().chain(overrides) {
+        if let Some(requirement) = requirement {
+            if requirement <= Decimal::ZERO || requirement > Decimal::ONE {
+                problems.push(field, "must be greater than 0 and at most 1");
+            }
+        }
+    }
+    let shocks =
+        margin.portfolio.shocks.iter().map(|(underlier, shock)| {
+            (format!("risk.margin.portfolio.shocks.{}", underlier), shock)
+        });
+    for (field, shock) in std::iter::once((
+        "risk.margin.portfolio.shock".to_string(),
+        &margin.portfolio.shock,
+    ))
+    .chain(shocks)
+    {
+        if *shock <= Decimal::ZERO || *shock >= Decimal::ONE {
+            problems.push(field, "must be between 0 and 1");
+        }
+    }
+    problems.positive(
+        "risk.margin.portfolio.scenarios",
+        margin.portfolio.scenarios as u64,
+    );
+    if let Some(concentration) = margin.concentration.as_ref() {
+        if concentration.threshold <= Decimal::ZERO {
+            problems.push(
+                "risk.margin.concentration.threshold",
+                "must be greater than 0",
+            );
+        }
+        if concentration.add_on <= Decimal::ZERO || concentration.add_on > Decimal::ONE {
+            problems.push(
+                "risk.margin.concentration.add_on",
+                "must be greater than 0 and at most 1",
+            );
+        }
+    }
+    problems.positive(
+        "risk.duplicate_intent_cache_size",
+        risk.duplicate_intent_cache_size as u64,
+    );
+    if risk.evaluation_cache.enabled {
+        problems.positive(
+            "risk.evaluation_cache.capacity",
+            risk.evaluation_cache.capacity as u64,
+        );
+    }
+}
+
+fn check_limits(problems: &mut Problems, limits: &RiskLimits, rules: &[RuleKind]) {
+    // The concentration rule isn't in the default pipeline, so the limit would silently only
+    // scale scores
+    let concentration = limits.max_concentration.is_some()
+        || limits
+            .tickers
+            .values()
+            .any(|ticker_limits| ticker_limits.max_concentration.is_some());
+    if concentration && !rules.contains(&RuleKind::Concentration) {
+        problems.push(
+            "limits.max_concentration",
+            "is only enforced with `concentration` in risk.rules",
+        );
+    }
+    for (ticker, ticker_limits) in limits.tickers.iter() {
+        if ticker_limits
+            .slippage
+            .map_or(false, |s| s.is_sign_negative())
+        {
+            problems.push(
+                format!("limits.tickers.{}.slippage", ticker),
+                "must not be negative",
+            );
+        }
+        if ticker_limits
+            .max_borrow_rate
+            .map_or(false, |r| r.is_sign_negative())
+        {
+            problems.push(
+                format!("limits.tickers.{}.max_borrow_rate", ticker),
+                "must not be negative",
+            );
+        }
+    }
+}
+
+fn check_pipeline(problems: &mut Problems, pipeline: &PipelineSettings) {
+    problems.positive("pipeline.input_capacity", pipeline.input_capacity as u64);
+    problems.positive("pipeline.output_capacity", pipeline.output_capacity as u64);
+}
+
+fn check_retries(
+    problems: &mut Problems,
+    supervisor: &SupervisorSettings,
+    producer_retry: &ProducerRetrySettings,
+) {
+    problems.backoff(
+        "supervisor",
+        supervisor.initial_backoff_ms,
+        supervisor.max_backoff_ms,
+    );
+    problems.backoff(
+        "producer_retry",
+        producer_retry.initial_backoff_ms,
+        producer_retry.max_backoff_ms,
+    );
+}
+
+fn fraction(value: f64) -> bool {
+    (0.0..=1.0).contains(&value)
+}
+
+/// Checks the settings that can be checked without reaching any external service.
+pub fn check_settings(settings: &Settings) -> Vec<ConfigProblem> {
+    let mut problems = Problems::default();
+    problems.url("alpaca.base_url", &settings.alpaca.base_url);
+    problems.non_empty("alpaca.key_id", &settings.alpaca.key_id);
+    problems.non_empty("alpaca.secret_key", &settings.alpaca.secret_key);
+    if let Some(datastore) = settings.datastore.as_ref() {
+        problems.url("datastore.base_url", &datastore.base_url);
+    }
+    check_topics(&mut problems, &settings.topics);
+    check_risk(&mut problems, &settings.risk);
+    check_pipeline(&mut problems, &settings.pipeline);
+    check_retries(
+        &mut problems,
+        &settings.supervisor,
+        &settings.producer_retry,
+    );
+    problems.positive(
+        "quarantine.max_attempts",
+        settings.quarantine.max_attempts as u64,
+    );
+    for (name, webhook) in settings.webhooks.iter() {
+        problems.url(format!("webhooks.{}.url", name), &webhook.url);
+        problems.positive(format!("webhooks.{}.timeout_ms", name), webhook.timeout_ms);
+    }
+    if let Some(hook) = settings.compliance_hook.as_ref() {
+        problems.url("compliance_hook.url", &hook.url);
+        problems.positive("compliance_hook.timeout_ms", hook.timeout_ms);
+    }
+    if let Some(notifications) = settings.notifications.as_ref() {
+        for (name, endpoint) in notifications.endpoints.iter() {
+            problems.url(
+                format!("notifications.endpoints.{}.url", name),
+                &endpoint.url,
+            );
+        }
+        problems.positive(
+            "notifications.max_per_minute",
+            notifications.max_per_minute as u64,
+        );
+    }
+    if let Some(fix) = settings.fix.as_ref() {
+        problems.non_empty("fix.address", &fix.address);
+    }
+    if let Some(validation) = settings.validation.as_ref() {
+        problems.non_empty("validation.topic", &validation.topic);
+        if !fraction(validation.sample_rate) {
+            problems.push("validation.sample_rate", "must be between 0 and 1");
+        }
+    }
+    if let Some(limits) = settings.limits.as_ref() {
+        problems.file("limits.path", &limits.path);
+        if Path::new(&limits.path).is_file() {
+            match RiskLimits::load(&limits.path) {
+                Ok(limits) => check_limits(&mut problems, &limits, &settings.risk.rules),
+                Err(e) => problems.push("limits.path", format!("can't be parsed: {}", e)),
+            }
+        }
+    }
+    if let Some(path) = settings.holdings_file.as_deref() {
+        problems.file("holdings_file", path);
+    }
+    if let Some(path) = settings.symbols_file.as_deref() {
+        problems.file("symbols_file", path);
+    }
+    if let Some(shadow) = settings.shadow.as_ref() {
+        problems.non_empty("shadow.topic", &shadow.topic);
+    }
+    if let Some(control) = settings.control.as_ref() {
+        problems.non_empty("control.secret", &control.secret);
+    }
+    if let Some(stop_loss) = settings.stop_loss.as_ref() {
+        let stops = stop_loss
+            .default_stop
+            .iter()
+            .map(|stop| ("stop_loss.default_stop".to_string(), stop))
+            .chain(
+                stop_loss
+                    .stops
+                    .iter()
+                    .map(|(ticker, stop)| (format!("stop_loss.stops.{}", ticker), stop)),
+            );
+        for (field, stop) in stops {
+            if *stop <= Decimal::ZERO {
+                problems.push(field, "must be greater than 0");
+            }
+        }
+    }
+    if let Some(liquidation) = settings.liquidation.as_ref() {
+        if liquidation.buffer.is_sign_negative() || liquidation.buffer >= Decimal::ONE {
+            problems.push("liquidation.buffer", "must be at least 0 and less than 1");
+        }
+    }
+    if let Some(margin_call) = settings.margin_call.as_ref() {
+        let cushions = [
+            ("margin_call.warning_cushion", margin_call.warning_cushion),
+            ("margin_call.breach_cushion", margin_call.breach_cushion),
+        ];
+        for (field, cushion) in cushions.iter() {
+            if cushion.is_sign_negative() || *cushion >= Decimal::ONE {
+                problems.push(*field, "must be at least 0 and less than 1");
+            }
+        }
+        if margin_call.breach_cushion > margin_call.warning_cushion {
+            problems.push(
+                "margin_call.breach_cushion",
+                "must not be greater than warning_cushion",
+            );
+        }
+    }
+    if let Some(assets) = settings.assets.as_ref() {
+        problems.positive("assets.refresh_secs", assets.refresh_secs);
+        problems.positive("assets.timeout_ms", assets.timeout_ms);
+    }
+    if let Some(flatten) = settings.flatten.as_ref() {
+        problems.positive("flatten.minutes_before_close", flatten.minutes_before_close);
+    }
+    if let Some(monitor) = settings.denial_monitor.as_ref() {
+        problems.positive("denial_monitor.window", monitor.window as u64);
+        if !fraction(monitor.threshold) {
+            problems.push("denial_monitor.threshold", "must be between 0 and 1");
+        }
+    }
+    if let Some(monitor) = settings.lag_monitor.as_ref() {
+        problems.positive("lag_monitor.interval_secs", monitor.interval_secs);
+        if monitor.threshold <= 0 {
+            problems.push("lag_monitor.threshold", "must be greater than 0");
+        }
+    }
+    if let Some(feed) = settings.price_feed.as_ref() {
+        problems.url("price_feed.redis_url", &feed.redis_url);
+        if feed.channel.is_none() && feed.key_prefix.is_none() {
+            problems.push("price_feed", "needs a channel or a key_prefix to follow");
+        }
+    }
+    let sources = &settings.price_sources;
+    if sources.order.is_empty() {
+        problems.push("price_sources.order", "needs at least one source");
+    }
+    problems.positive("price_sources.timeout_ms", sources.timeout_ms);
+    problems.positive(
+        "price_sources.breaker_failures",
+        sources.breaker_failures as u64,
+    );
+    if sources.order.contains(&PriceSourceKind::Redis) {
+        match sources.redis_url.as_deref() {
+            Some(url) => problems.url("price_sources.redis_url", url),
+            None => problems.push("price_sources.redis_url", "is needed by the redis source"),
+        }
+    }
+    if sources.order.contains(&PriceSourceKind::Datastore) && settings.datastore.is_none() {
+        problems.push("datastore", "is needed by the datastore price source");
+    }
+    if sources.order.contains(&PriceSourceKind::Alpaca) {
+        problems.url("price_sources.alpaca_data_url", &sources.alpaca_data_url);
+    }
+    if sources.order.contains(&PriceSourceKind::Polygon) {
+        match sources.polygon.as_ref() {
+            Some(polygon) => {
+                problems.non_empty("price_sources.polygon.api_key", &polygon.api_key);
+                problems.url("price_sources.polygon.base_url", &polygon.base_url);
+            }
+            None => problems.push("price_sources.polygon", "is needed by the polygon source"),
+        }
+    }
+    let polygon_stream = sources
+        .polygon
+        .as_ref()
+        .and_then(|polygon| polygon.websocket_url.as_deref());
+    if let Some(url) = polygon_stream {
+        problems.url("price_sources.polygon.websocket_url", url);
+    }
+    if let Some(cache) = settings.price_cache.as_ref() {
+        problems.positive("price_cache.ttl_ms", cache.ttl_ms);
+        if let Some(interval) = cache.prefetch_interval_ms {
+            problems.positive("price_cache.prefetch_interval_ms", interval);
+        }
+    }
+    if let Some(sharding) = settings.sharding.as_ref() {
+        problems.non_empty("sharding.instance_id", &sharding.instance_id);
+    }
+    if let Some(handoff) = settings.handoff.as_ref() {
+        problems.non_empty("handoff.key", &handoff.key);
+        problems.positive("handoff.ttl_secs", handoff.ttl_secs);
+        problems.positive(
+            "redis_pool.max_connections",
+            settings.redis_pool.max_connections as u64,
+        );
+    }
+    #[cfg(feature = "aws")]
+    if let Some(aws) = settings.aws.as_ref() {
+        if aws.region.parse::<rusoto_core::Region>().is_err() {
+            problems.push(
+                "aws.region",
+                format!("`{}` is not an AWS region", aws.region),
+            );
+        }
+        problems.url("aws.queue_url_prefix", &aws.queue_url_prefix);
+        problems.non_empty("aws.topic_arn_prefix", &aws.topic_arn_prefix);
+        if !(0..=20).contains(&aws.wait_time_secs) {
+            problems.push("aws.wait_time_secs", "must be between 0 and 20");
+        }
+        if settings.codec.format != CodecFormat::Json {
+            problems.push(
+                "codec.format",
+                "must be json, as SQS and SNS only carry text",
+            );
+        }
+    }
+    if settings.codec.format == CodecFormat::Avro {
+        match settings.codec.schema_registry_url.as_deref() {
+            Some(url) => problems.url("codec.schema_registry_url", url),
+            None => problems.push("codec.schema_registry_url", "is required for Avro"),
+        }
+    }
+    problems.0
+}
+
+/// Checks that Redis can be reached and that the broker accepts the Alpaca credentials.
+pub async fn check_services(settings: &Settings) -> Vec<ConfigProblem> {
+    let mut problems = Problems::default();
+    if let Some(handoff) = settings.handoff.as_ref() {
+        if let Err(e) = ping_redis(&handoff.redis_url).await {
+            problems.push("handoff.redis_url", format!("Redis is unreachable: {}", e));
+        }
+    }
+    let alpaca = &settings.alpaca;
+    let account = async {
+        let client = Client::new(
+            alpaca.base_url.clone(),
+            alpaca.key_id.clone(),
+            alpaca.secret_key.clone(),
+        )
+        .map_err(|e| e.to_string())?;
+        client
+            .send(GetAccount)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    };
+    match tokio::time::timeout(CONNECT_TIMEOUT, account).await {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => problems.push("alpaca", format!("The broker rejected the account: {}", e)),
+        Err(_) => problems.push("alpaca", "The broker didn't answer in time"),
+    }
+    problems.0
+}
+
+async fn ping_redis(url: &str) -> Result<(), String> {
+    let ping = async {
+        let client = redis::Client::open(url)?;
+        let mut connection = client.get_async_connection().await?;
+        redis::cmd("PING")
+            .query_async::<_, String>(&mut connection)
+            .await
+    };
+    match tokio::time::timeout(CONNECT_TIMEOUT, ping).await {
+        Ok(result) => result.map(|_| ()).map_err(|e| e.to_string()),
+        Err(_) => Err("timed out".into()),
+    }
+}
+
+/// Checks the settings, then the services they point to, reporting every problem found at once.
+/// Services are only checked if the settings are valid.
+pub async fn preflight(settings: &Settings) -> Result<(), RiskManagerError> {
+    let mut problems = check_settings(settings);
+    if problems.is_empty() {
+        problems = check_services(settings).await;
+    }
+    if problems.is_empty() {
+        info!("Configuration checked");
+        return Ok(());
+    }
+    let problems: Vec<String> = problems.iter().map(ToString::to_string).collect();
+    Err(RiskManagerError::Configuration(format!(
+        "Invalid configuration:\n  {}",
+        problems.join("\n  ")
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    fn fields(problems: Problems) -> Vec<String> {
+        problems.0.into_iter().map(|p| p.field).collect()
+    }
+
+    #[test]
+    fn problems() {
+        let mut problems = Problems::default();
+        let topics = TopicSettings {
+            lots: "".into(),
+            alerts: " ".into(),
+            ..Default::default()
+        };
+        check_topics(&mut problems, &topics);
+        let risk = RiskSettings {
+            market_order_slippage: Decimal::new(-1, 2),
+            ..Default::default()
+        };
+        check_risk(&mut problems, &risk);
+        let pipeline = PipelineSettings {
+            input_capacity: 0,
+            ..Default::default()
+        };
+        check_pipeline(&mut problems, &pipeline);
+        let supervisor = SupervisorSettings {
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 10,
+        };
+        check_retries(&mut problems, &supervisor, &Default::default());
+        problems.url("datastore.base_url", "datastore/prices");
+        problems.url("alpaca.base_url", "https://paper-api.alpaca.markets");
+        let limits = RiskLimits {
+            max_concentration: Some(Decimal::new(2, 1)),
+            ..Default::default()
+        };
+        check_limits(&mut problems, &limits, &RuleKind::default_pipeline());
+        check_limits(&mut problems, &limits, &[RuleKind::Concentration]);
+        assert_eq!(
+            fields(problems),
+            vec![
+                "topics.lots",
+                "topics.alerts",
+                "risk.market_order_slippage",
+                "pipeline.input_capacity",
+                "supervisor.max_backoff_ms",
+                "datastore.base_url",
+                "limits.max_concentration",
+            ]
+        );
+    }
+}
@@ -0,0 +1,336 @@
+use crate::latency::LatencyStats;
+use crate::price_sources::PriceSources;
+use crate::prices::{PriceCache, PriceTick};
+use crate::redis_pool::RedisPool;
+use crate::settings::{PolygonSettings, PriceFeedSettings, RedisPoolSettings};
+use crate::supervisor::Backoff;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{SinkExt, StreamExt};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::{debug, error, info, warn};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A price tick as published on the pub/sub channels.
+#[derive(Debug, Deserialize)]
+struct PublishedTick {
+    ticker: String,
+    price: Decimal,
+    timestamp: DateTime<Utc>,
+}
+
+/// What a message on one of the subscribed channels means.
+#[derive(Debug, PartialEq)]
+enum Update {
+    Tick(String, PriceTick),
+    /// The key holding a ticker's price was written
+    KeyChanged {
+        key: String,
+        ticker: String,
+    },
+}
+
+fn parse(settings: &PriceFeedSettings, channel: &str, payload: &str) -> Result<Option<Update>> {
+    if channel.starts_with("__keyspace@") {
+        // Channels are named `__keyspace@<db>__:<key>`, and only writes change the price
+        let key = channel
+            .splitn(2, ':')
+            .nth(1)
+            .ok_or_else(|| anyhow!("Unexpected keyspace channel {}", channel))?;
+        let prefix = settings.key_prefix.as_deref().unwrap_or_default();
+        return Ok(match (payload, key.strip_prefix(prefix)) {
+            ("set", Some(ticker)) => Some(Update::KeyChanged {
+                key: key.to_string(),
+                ticker: ticker.to_string(),
+            }),
+            _ => None,
+        });
+    }
+    let tick: PublishedTick = serde_json::from_str(payload)?;
+    let update = PriceTick {
+        price: tick.price,
+        timestamp: Some(tick.timestamp),
+        source: Some("price_feed"),
+    };
+    Ok(Some(Update::Tick(tick.ticker, update)))
+}
+
+/// Subscribes to the price updates in Redis, keeping the cache up to date until the task is
+/// dropped. Lost connections are re-established with exponential backoff.
+pub async fn follow(settings: PriceFeedSettings, pool: RedisPoolSettings, cache: PriceCache) {
+    let pool = match RedisPool::new("prices", &settings.redis_url, pool) {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!(?e, "Invalid price feed Redis URL, prices will be looked up");
+            return;
+        }
+    };
+    let mut backoff = Backoff::with_bounds(INITIAL_BACKOFF, MAX_BACKOFF);
+    loop {
+        if let Err(e) = subscribe(&settings, &pool, &cache, &mut backoff).await {
+            let delay = backoff.next_jittered_delay();
+            warn!(?e, ?delay, "Price feed disconnected, resubscribing");
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+async fn subscribe(
+    settings: &PriceFeedSettings,
+    pool: &RedisPool,
+    cache: &PriceCache,
+    backoff: &mut Backoff,
+) -> Result<()> {
+    let client = redis::Client::open(settings.redis_url.as_str())?;
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    if let Some(channel) = settings.channel.as_deref() {
+        pubsub.psubscribe(channel).await?;
+    }
+    if let Some(prefix) = settings.key_prefix.as_deref() {
+        pubsub
+            .psubscribe(format!("__keyspace@*__:{}*", prefix))
+            .await?;
+    }
+    info!("Following price updates");
+    backoff.reset();
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = match message.get_payload() {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!(?e, "Ignoring undecodable price update");
+                continue;
+            }
+        };
+        let update = match parse(settings, message.get_channel_name(), &payload) {
+            Ok(Some(update)) => update,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!(?e, %payload, "Ignoring undecodable price update");
+                continue;
+            }
+        };
+        match update {
+            Update::Tick(ticker, tick) => {
+                cache.update(&ticker, tick, Utc::now());
+            }
+            Update::KeyChanged { key, ticker } => {
+                if !cache.is_watched(&ticker) {
+                    continue;
+                }
+                // The notification doesn't carry the value, so it is read. The key doesn't say
+                // when the price was traded at either.
+                let price: Option<String> = pool.query(redis::cmd("GET").arg(&key)).await?;
+                match price.as_deref().map(str::parse::<Decimal>) {
+                    Some(Ok(price)) => {
+                        let tick = PriceTick {
+                            price,
+                            timestamp: None,
+                            source: Some("price_feed"),
+                        };
+                        cache.update(&ticker, tick, Utc::now());
+                    }
+                    Some(Err(e)) => warn!(?e, %key, "Ignoring undecodable price"),
+                    None => debug!(%key, "Price key removed"),
+                }
+            }
+        }
+    }
+    Err(anyhow!("Price feed subscription ended"))
+}
+
+/// A message on Polygon's websocket. They arrive in JSON arrays.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "ev")]
+enum PolygonEvent {
+    #[serde(rename = "status")]
+    Status { status: String, message: String },
+    #[serde(rename = "T")]
+    Trade {
+        #[serde(rename = "sym")]
+        ticker: String,
+        #[serde(rename = "p")]
+        price: Decimal,
+        /// SIP timestamp, in milliseconds since the epoch
+        #[serde(rename = "t")]
+        timestamp: i64,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Follows trades on Polygon's websocket, keeping the prices of watched tickers in the cache up
+/// to date until the task is dropped. Lost connections are re-established with exponential
+/// backoff.
+pub async fn follow_polygon(settings: PolygonSettings, cache: PriceCache) {
+    let url = match settings.websocket_url.as_deref() {
+        Some(url) => url,
+        None => return,
+    };
+    let mut backoff = Backoff::with_bounds(INITIAL_BACKOFF, MAX_BACKOFF);
+    loop {
+        if let Err(e) = stream_trades(url, &settings.api_key, &cache, &mut backoff).await {
+            let delay = backoff.next_jittered_delay();
+            warn!(
+                ?e,
+                ?delay,
+                "Polygon trade stream disconnected, reconnecting"
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+async fn stream_trades(
+    url: &str,
+    api_key: &str,
+    cache: &PriceCache,
+    backoff: &mut Backoff,
+) -> Result<()> {
+    let (mut stream, _) = connect_async(url).await?;
+    let auth = serde_json::json!({"action": "auth", "params": api_key});
+    stream.send(Message::Text(auth.to_string())).await?;
+    while let Some(message) = stream.next().await {
+        let text = match message? {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let events: Vec<PolygonEvent> = match serde_json::from_str(&text) {
+            Ok(events) => events,
+            Err(e) => {
+                warn!(?e, %text, "Ignoring undecodable Polygon message");
+                continue;
+            }
+        };
+        for event in events {
+            match event {
+                PolygonEvent::Status { status, message } => match status.as_str() {
+                    "auth_success" => {
+                        // Only the watched tickers are kept, but which those are changes with
+                        // every trade, so all trades are followed
+                        let subscribe = serde_json::json!({"action": "subscribe", "params": "T.*"});
+                        stream.send(Message::Text(subscribe.to_string())).await?;
+                        info!("Following Polygon trades");
+                        backoff.reset();
+                    }
+                    "auth_failed" => {
+                        return Err(anyhow!("Polygon rejected the API key: {}", message))
+                    }
+                    _ => debug!(%status, %message, "Polygon status"),
+                },
+                PolygonEvent::Trade {
+                    ticker,
+                    price,
+                    timestamp,
+                } => {
+                    let tick = PriceTick {
+                        price,
+                        timestamp: Some(Utc.timestamp_millis(timestamp)),
+                        source: Some("polygon_stream"),
+                    };
+                    cache.update(&ticker, tick, Utc::now());
+                }
+                PolygonEvent::Other => {}
+            }
+        }
+    }
+    Err(anyhow!("Polygon trade stream ended"))
+}
+
+/// Refreshes the prices of the held tickers from the price sources every `period`, so that
+/// market orders for them find a fresh price in the cache.
+pub async fn prefetch(
+    sources: Arc<PriceSources>,
+    cache: PriceCache,
+    period: Duration,
+    latency: Arc<LatencyStats>,
+) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let (sources, cache, latency) = (sources.clone(), cache.clone(), latency.clone());
+        // Price sources block, so they are kept off the runtime's threads
+        let refreshed = tokio::task::spawn_blocking(move || {
+            for ticker in cache.held() {
+                let start = Instant::now();
+                let price = sources.last_price(&ticker);
+                latency.price_lookup.observe(start.elapsed());
+                match price {
+                    Ok((tick, _)) => cache.record_lookup(&ticker, tick, Utc::now()),
+                    Err(e) => warn!(%ticker, ?e, "Failed to prefetch last price"),
+                }
+            }
+        });
+        if let Err(e) = refreshed.await {
+            error!(?e, "Prefetching prices panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn updates() {
+        let settings = PriceFeedSettings {
+            redis_url: "redis://localhost".into(),
+            channel: Some("prices.*".into()),
+            key_prefix: Some("price:".into()),
+        };
+        let payload = r#"{"ticker":"AAPL","price":"150.1","timestamp":"2021-06-01T14:30:00Z"}"#;
+        let tick = PriceTick {
+            price: Decimal::new(1501, 1),
+            timestamp: Some("2021-06-01T14:30:00Z".parse().unwrap()),
+            source: Some("price_feed"),
+        };
+        assert_eq!(
+            parse(&settings, "prices.AAPL", payload).unwrap(),
+            Some(Update::Tick("AAPL".into(), tick))
+        );
+        assert_eq!(
+            parse(&settings, "__keyspace@0__:price:AAPL", "set").unwrap(),
+            Some(Update::KeyChanged {
+                key: "price:AAPL".into(),
+                ticker: "AAPL".into()
+            })
+        );
+        assert_eq!(
+            parse(&settings, "__keyspace@0__:price:AAPL", "expired").unwrap(),
+            None
+        );
+        assert!(parse(&settings, "prices.AAPL", "150.1").is_err());
+    }
+
+    #[test]
+    fn polygon_events() {
+        let text = r#"[
+            {"ev":"status","status":"auth_success","message":"authenticated"},
+            {"ev":"T","sym":"AAPL","i":"1","x":4,"p":150.1,"s":100,"t":1622557800000,"q":1,"z":3},
+            {"ev":"Q","sym":"AAPL","bp":150,"ap":150.2,"t":1622557800000}
+        ]"#;
+        let events: Vec<PolygonEvent> = serde_json::from_str(text).unwrap();
+        assert_eq!(
+            events,
+            vec![
+                PolygonEvent::Status {
+                    status: "auth_success".into(),
+                    message: "authenticated".into()
+                },
+                PolygonEvent::Trade {
+                    ticker: "AAPL".into(),
+                    price: Decimal::new(1501, 1),
+                    timestamp: 1622557800000
+                },
+                PolygonEvent::Other
+            ]
+        );
+    }
+}
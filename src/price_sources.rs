@@ -0,0 +1,657 @@
+use crate::prices::PriceTick;
+#[cfg(feature = "service")]
+use crate::redis_pool::RedisPool;
+#[cfg(feature = "reqwest")]
+use crate::settings::PolygonSettings;
+#[cfg(feature = "service")]
+use crate::settings::{PriceSourceKind, RedisPoolSettings, Settings};
+use anyhow::{anyhow, Result};
+#[cfg(feature = "reqwest")]
+use chrono::{DateTime, TimeZone, Utc};
+#[cfg(feature = "reqwest")]
+use rust_decimal::Decimal;
+#[cfg(feature = "reqwest")]
+use serde::Deserialize;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+#[cfg(feature = "service")]
+use tokio::runtime::Handle;
+use tracing::warn;
+
+/// How long a lookup may take when the timeout isn't configured
+#[cfg(feature = "reqwest")]
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A blocking HTTP client whose requests give up after `timeout`. Fails if the TLS backend can't
+/// be initialized.
+#[cfg(feature = "reqwest")]
+pub(crate) fn blocking_client(timeout: Duration) -> Result<reqwest::blocking::Client> {
+    Ok(reqwest::blocking::Client::builder()
+        .timeout(timeout)
+        .build()?)
+}
+
+/// Somewhere the last trade price of a ticker can be looked up. Lookups block, and should give up
+/// after the source's timeout.
+pub trait PriceSource: Send + Sync {
+    /// Names the source in logs, metrics and decisions.
+    fn name(&self) -> &'static str;
+    fn last_price(&self, ticker: &str) -> Result<PriceTick>;
+}
+
+/// The datastore service, which serves the last price of a ticker at `/last/{ticker}`.
+#[cfg(feature = "reqwest")]
+pub struct DatastoreSource {
+    client: reqwest::blocking::Client,
+    base_url: String,
+}
+
+#[cfg(feature = "reqwest")]
+impl DatastoreSource {
+    pub fn new(base_url: String, timeout: Duration) -> Result<Self> {
+        let client = blocking_client(timeout)?;
+        Ok(Self { client, base_url })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl PriceSource for DatastoreSource {
+    fn name(&self) -> &'static str {
+        "datastore"
+    }
+
+    fn last_price(&self, ticker: &str) -> Result<PriceTick> {
+        let url = format!("{}/last/{}", self.base_url, ticker);
+        let price: Decimal = self.client.get(url).send()?.error_for_status()?.json()?;
+        // The datastore doesn't say when the price was traded at
+        Ok(PriceTick {
+            price,
+            timestamp: None,
+            source: None,
+        })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+#[derive(Deserialize)]
+struct PolygonLastTrade {
+    results: PolygonTrade,
+}
+
+#[cfg(feature = "reqwest")]
+#[derive(Deserialize)]
+struct PolygonTrade {
+    #[serde(rename = "p")]
+    price: Decimal,
+    /// SIP timestamp, in nanoseconds since the epoch
+    #[serde(rename = "t")]
+    timestamp: i64,
+}
+
+/// Polygon.io's last trade endpoint, for deployments without the datastore service.
+#[cfg(feature = "reqwest")]
+pub struct PolygonSource {
+    client: reqwest::blocking::Client,
+    settings: PolygonSettings,
+}
+
+#[cfg(feature = "reqwest")]
+impl PolygonSource {
+    pub fn new(settings: PolygonSettings, timeout: Duration) -> Result<Self> {
+        let client = blocking_client(timeout)?;
+        Ok(Self { client, settings })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl PriceSource for PolygonSource {
+    fn name(&self) -> &'static str {
+        "polygon"
+    }
+
+    fn last_price(&self, ticker: &str) -> Result<PriceTick> {
+        let url = format!("{}/v2/last/trade/{}", self.settings.base_url, ticker);
+        let last: PolygonLastTrade = self
+            .client
+            .get(url)
+            // In a header rather than the query, so that it doesn't end up in logged URLs
+            .bearer_auth(&self.settings.api_key)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(PriceTick {
+            price: last.results.price,
+            timestamp: Some(Utc.timestamp_nanos(last.results.timestamp)),
+            source: None,
+        })
+    }
+}
+
+#[cfg(feature = "reqwest")]
+#[derive(Deserialize)]
+struct AlpacaLatestTrade {
+    trade: AlpacaTrade,
+}
+
+#[cfg(feature = "reqwest")]
+#[derive(Deserialize)]
+struct AlpacaTrade {
+    #[serde(rename = "p")]
+    price: Decimal,
+    #[serde(rename = "t")]
+    timestamp: DateTime<Utc>,
+}
+
+#[cfg(feature = "reqwest")]
+#[derive(Deserialize)]
+struct AlpacaLatestQuote {
+    quote: AlpacaQuote,
+}
+
+#[cfg(feature = "reqwest")]
+#[derive(Deserialize)]
+struct AlpacaQuote {
+    #[serde(rename = "ap")]
+    ask: Decimal,
+    #[serde(rename = "bp")]
+    bid: Decimal,
+    #[serde(rename = "t")]
+    timestamp: DateTime<Utc>,
+}
+
+/// Alpaca's market data API, using the trading account's credentials. The latest trade is used,
+/// or the midpoint of the latest quote when the trade can't be read.
+#[cfg(feature = "reqwest")]
+pub struct AlpacaDataSource {
+    client: reqwest::blocking::Client,
+    data_url: String,
+    key_id: String,
+    secret_key: String,
+}
+
+#[cfg(feature = "reqwest")]
+impl AlpacaDataSource {
+    pub fn new(
+        data_url: String,
+        key_id: String,
+        secret_key: String,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let client = blocking_client(timeout)?;
+        Ok(Self {
+            client,
+            data_url,
+            key_id,
+            secret_key,
+        })
+    }
+
+    fn get<T: serde::de::DeserializeOwned>(&self, ticker: &str, endpoint: &str) -> Result<T> {
+        let url = format!("{}/v2/stocks/{}/{}/latest", self.data_url, ticker, endpoint);
+        Ok(self
+            .client
+            .get(url)
+            .header("APCA-API-KEY-ID", &self.key_id)
+            .header("APCA-API-SECRET-KEY", &self.secret_key)
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl PriceSource for AlpacaDataSource {
+    fn name(&self) -> &'static str {
+        "alpaca"
+    }
+
+    fn last_price(&self, ticker: &str) -> Result<PriceTick> {
+        let trade_error = match self.get::<AlpacaLatestTrade>(ticker, "trades") {
+            Ok(latest) => {
+                return Ok(PriceTick {
+                    price: latest.trade.price,
+                    timestamp: Some(latest.trade.timestamp),
+                    source: None,
+                })
+            }
+            Err(e) => e,
+        };
+        let quote = self
+            .get::<AlpacaLatestQuote>(ticker, "quotes")
+            .map_err(|e| anyhow!("{}, and then {}", trade_error, e))?
+            .quote;
+        // A side without orders is quoted at zero
+        if quote.ask <= Decimal::ZERO || quote.bid <= Decimal::ZERO {
+            return Err(anyhow!("{}, and the quote is one-sided", trade_error));
+        }
+        Ok(PriceTick {
+            price: (quote.ask + quote.bid) / Decimal::new(2, 0),
+            timestamp: Some(quote.timestamp),
+            source: None,
+        })
+    }
+}
+
+/// Keys in Redis holding the last price of each ticker, e.g. `price:AAPL`, read through a
+/// connection pool.
+#[cfg(feature = "service")]
+pub struct RedisSource {
+    pool: RedisPool,
+    key_prefix: String,
+    /// The runtime the pool's connections are driven by, since lookups block
+    runtime: Handle,
+}
+
+#[cfg(feature = "service")]
+impl RedisSource {
+    /// Must be called from within the runtime.
+    pub fn new(url: &str, key_prefix: String, pool: RedisPoolSettings) -> Result<Self> {
+        Ok(Self {
+            pool: RedisPool::new("price_source", url, pool)?,
+            key_prefix,
+            runtime: Handle::current(),
+        })
+    }
+}
+
+#[cfg(feature = "service")]
+impl PriceSource for RedisSource {
+    fn name(&self) -> &'static str {
+        "redis"
+    }
+
+    fn last_price(&self, ticker: &str) -> Result<PriceTick> {
+        let key = format!("{}{}", self.key_prefix, ticker);
+        // Lookups happen both on the blocking threads and in checks on the runtime's threads
+        let price: Option<String> = tokio::task::block_in_place(|| {
+            self.runtime
+                .block_on(self.pool.query(redis::cmd("GET").arg(&key)))
+        })?;
+        let price = price.ok_or_else(|| anyhow!("No price at {}", key))?;
+        Ok(PriceTick {
+            price: price.parse()?,
+            // Like the price feed, Redis doesn't say when the price was traded at
+            timestamp: None,
+            source: None,
+        })
+    }
+}
+
+#[derive(Default)]
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Stops trying a source after it failed `failure_threshold` times in a row, until `cooldown` has
+/// passed, so that checks don't each wait for a source that is down. After the cooldown, the
+/// source is tried again, and a single failure opens the breaker again.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Whether the source may be tried at `now`.
+    pub fn allows(&self, now: Instant) -> bool {
+        let state = self.state.lock().expect("Breaker lock poisoned");
+        !matches!(state.open_until, Some(until) if now < until)
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.allows(Instant::now())
+    }
+
+    pub fn record_success(&self) {
+        *self.state.lock().expect("Breaker lock poisoned") = BreakerState::default();
+    }
+
+    pub fn record_failure(&self, now: Instant) {
+        let mut state = self.state.lock().expect("Breaker lock poisoned");
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.failure_threshold {
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(5, Duration::from_secs(30))
+    }
+}
+
+/// The breaker of a source and what came of its lookups.
+struct SourceHealth {
+    name: &'static str,
+    breaker: CircuitBreaker,
+    served: AtomicU64,
+    failed: AtomicU64,
+    /// Lookups that skipped the source because its breaker was open
+    skipped: AtomicU64,
+}
+
+struct Guarded {
+    source: Box<dyn PriceSource>,
+    health: Arc<SourceHealth>,
+}
+
+/// Price sources tried in order, falling through to the next one when a source fails or its
+/// breaker is open.
+#[derive(Default)]
+pub struct PriceSources {
+    sources: Vec<Guarded>,
+}
+
+impl PriceSources {
+    /// Adds a source, tried after the ones already added.
+    pub fn with(mut self, source: impl PriceSource + 'static, breaker: CircuitBreaker) -> Self {
+        let health = Arc::new(SourceHealth {
+            name: source.name(),
+            breaker,
+            served: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        });
+        self.sources.push(Guarded {
+            source: Box::new(source),
+            health,
+        });
+        self
+    }
+
+    /// Only the datastore, as before price sources could be chained.
+    #[cfg(feature = "reqwest")]
+    pub fn datastore(base_url: String) -> Result<Self> {
+        let source = DatastoreSource::new(base_url, DEFAULT_TIMEOUT)?;
+        Ok(Self::default().with(source, CircuitBreaker::default()))
+    }
+
+    /// The sources configured, in order.
+    #[cfg(feature = "service")]
+    pub fn from_settings(all: &Settings) -> Result<Self> {
+        let settings = &all.price_sources;
+        let timeout = Duration::from_millis(settings.timeout_ms);
+        let breaker = || {
+            CircuitBreaker::new(
+                settings.breaker_failures,
+                Duration::from_secs(settings.breaker_cooldown_secs),
+            )
+        };
+        let mut sources = Self::default();
+        for kind in settings.order.iter() {
+            sources = match kind {
+                PriceSourceKind::Redis => {
+                    let url = settings
+                        .redis_url
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("The redis price source needs a redis_url"))?;
+                    // Lookups are given the source timeout rather than the pool's
+                    let pool = RedisPoolSettings {
+                        connect_timeout_ms: settings.timeout_ms,
+                        command_timeout_ms: settings.timeout_ms,
+                        ..all.redis_pool.clone()
+                    };
+                    let source = RedisSource::new(url, settings.redis_key_prefix.clone(), pool)?;
+                    sources.with(source, breaker())
+                }
+                PriceSourceKind::Datastore => {
+                    let datastore = all
+                        .datastore
+                        .as_ref()
+                        .ok_or_else(|| anyhow!("The datastore price source needs its settings"))?;
+                    let source = DatastoreSource::new(datastore.base_url.clone(), timeout)?;
+                    sources.with(source, breaker())
+                }
+                PriceSourceKind::Alpaca => {
+                    let source = AlpacaDataSource::new(
+                        settings.alpaca_data_url.clone(),
+                        all.alpaca.key_id.clone(),
+                        all.alpaca.secret_key.clone(),
+                        timeout,
+                    )?;
+                    sources.with(source, breaker())
+                }
+                PriceSourceKind::Polygon => {
+                    let polygon = settings
+                        .polygon
+                        .clone()
+                        .ok_or_else(|| anyhow!("The polygon price source needs its settings"))?;
+                    sources.with(PolygonSource::new(polygon, timeout)?, breaker())
+                }
+            };
+        }
+        Ok(sources)
+    }
+
+    /// Looks the price of a ticker up in each source in turn, returning it along with the name of
+    /// the source that served it, which the tick also carries.
+    pub fn last_price(&self, ticker: &str) -> Result<(PriceTick, &'static str)> {
+        let mut errors = Vec::new();
+        for Guarded { source, health } in self.sources.iter() {
+            if !health.breaker.allows(Instant::now()) {
+                health.skipped.fetch_add(1, Ordering::Relaxed);
+                errors.push(format!("{}: circuit open", health.name));
+                continue;
+            }
+            match source.last_price(ticker) {
+                Ok(tick) => {
+                    health.breaker.record_success();
+                    health.served.fetch_add(1, Ordering::Relaxed);
+                    let tick = PriceTick {
+                        source: Some(health.name),
+                        ..tick
+                    };
+                    return Ok((tick, health.name));
+                }
+                Err(e) => {
+                    warn!(source = %health.name, %ticker, ?e, "Price lookup failed");
+                    health.breaker.record_failure(Instant::now());
+                    health.failed.fetch_add(1, Ordering::Relaxed);
+                    errors.push(format!("{}: {}", health.name, e));
+                }
+            }
+        }
+        if errors.is_empty() {
+            Err(anyhow!("No price source is configured"))
+        } else {
+            Err(anyhow!("Every price source failed ({})", errors.join("; ")))
+        }
+    }
+
+    pub fn stats(&self) -> PriceSourceStats {
+        PriceSourceStats(self.sources.iter().map(|s| s.health.clone()).collect())
+    }
+}
+
+/// Lookups and breakers of every price source, exposed at `/metrics`.
+#[derive(Clone, Default)]
+pub struct PriceSourceStats(Vec<Arc<SourceHealth>>);
+
+impl PriceSourceStats {
+    /// Renders the counters and gauges in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut metrics = String::new();
+        if self.0.is_empty() {
+            return metrics;
+        }
+        let _ = writeln!(
+            metrics,
+            "# HELP risk_manager_price_source_lookups_total Price lookups by source and result"
+        );
+        let _ = writeln!(
+            metrics,
+            "# TYPE risk_manager_price_source_lookups_total counter"
+        );
+        for health in self.0.iter() {
+            let results = [
+                ("served", &health.served),
+                ("failed", &health.failed),
+                ("skipped", &health.skipped),
+            ];
+            for (result, value) in results.iter() {
+                let _ = writeln!(
+                    metrics,
+                    "risk_manager_price_source_lookups_total{{source=\"{}\",result=\"{}\"}} {}",
+                    health.name,
+                    result,
+                    value.load(Ordering::Relaxed)
+                );
+            }
+        }
+        let _ = writeln!(
+            metrics,
+            "# HELP risk_manager_price_source_open Whether the breaker of the price source is open"
+        );
+        let _ = writeln!(metrics, "# TYPE risk_manager_price_source_open gauge");
+        for health in self.0.iter() {
+            let _ = writeln!(
+                metrics,
+                "risk_manager_price_source_open{{source=\"{}\"}} {}",
+                health.name,
+                health.breaker.is_open() as u8
+            );
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rust_decimal::Decimal;
+
+    struct Fixed(&'static str, Option<i64>);
+
+    impl PriceSource for Fixed {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn last_price(&self, _ticker: &str) -> Result<PriceTick> {
+            let price = self.1.ok_or_else(|| anyhow!("down"))?;
+            Ok(PriceTick {
+                price: Decimal::new(price, 0),
+                timestamp: Some(chrono::Utc::now()),
+                source: None,
+            })
+        }
+    }
+
+    #[test]
+    fn failover() {
+        let sources = PriceSources::default()
+            .with(
+                Fixed("redis", None),
+                CircuitBreaker::new(2, Duration::from_secs(60)),
+            )
+            .with(Fixed("datastore", Some(100)), CircuitBreaker::default());
+        let (tick, source) = sources.last_price("AAPL").unwrap();
+        assert_eq!((tick.price, source), (Decimal::new(100, 0), "datastore"));
+        assert_eq!(tick.source, Some("datastore"));
+
+        // The failing source is skipped once its breaker opens
+        sources.last_price("AAPL").unwrap();
+        sources.last_price("AAPL").unwrap();
+        let metrics = sources.stats().render_metrics();
+        assert!(metrics.contains(
+            "risk_manager_price_source_lookups_total{source=\"redis\",result=\"failed\"} 2\n"
+        ));
+        assert!(metrics.contains(
+            "risk_manager_price_source_lookups_total{source=\"redis\",result=\"skipped\"} 1\n"
+        ));
+        assert!(metrics.contains("risk_manager_price_source_open{source=\"redis\"} 1\n"));
+        assert!(metrics.contains(
+            "risk_manager_price_source_lookups_total{source=\"datastore\",result=\"served\"} 3\n"
+        ));
+
+        let down = PriceSources::default().with(Fixed("redis", None), CircuitBreaker::default());
+        assert!(down.last_price("AAPL").is_err());
+        assert!(PriceSources::default().last_price("AAPL").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "reqwest")]
+    fn polygon() {
+        let _m = mockito::mock("GET", "/v2/last/trade/AAPL")
+            .match_header("Authorization", "Bearer secret")
+            .with_body(
+                r#"{"status":"OK","request_id":"1","results":{"T":"AAPL","p":150.1,"s":100,"t":1622557800000000000,"x":4}}"#,
+            )
+            .create();
+        let settings = PolygonSettings {
+            api_key: "secret".into(),
+            base_url: mockito::server_url(),
+            websocket_url: None,
+        };
+        let tick = PolygonSource::new(settings, Duration::from_secs(1))
+            .unwrap()
+            .last_price("AAPL")
+            .unwrap();
+        assert_eq!(tick.price, Decimal::new(1501, 1));
+        assert_eq!(
+            tick.timestamp,
+            Some("2021-06-01T14:30:00Z".parse().unwrap())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "reqwest")]
+    fn alpaca() {
+        let _m = mockito::mock("GET", "/v2/stocks/AAPL/trades/latest")
+            .match_header("APCA-API-KEY-ID", "key")
+            .with_body(r#"{"symbol":"AAPL","trade":{"t":"2021-06-01T14:30:00Z","x":"V","p":150.1,"s":100,"c":["@"],"i":1,"z":"C"}}"#)
+            .create();
+        let _m = mockito::mock("GET", "/v2/stocks/TSLA/trades/latest")
+            .with_status(404)
+            .create();
+        let _m = mockito::mock("GET", "/v2/stocks/TSLA/quotes/latest")
+            .with_body(r#"{"symbol":"TSLA","quote":{"t":"2021-06-01T14:30:00Z","ax":"V","ap":701,"as":1,"bx":"V","bp":699,"bs":2,"c":["R"],"z":"C"}}"#)
+            .create();
+        let source = AlpacaDataSource::new(
+            mockito::server_url(),
+            "key".into(),
+            "secret".into(),
+            Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(
+            source.last_price("AAPL").unwrap().price,
+            Decimal::new(1501, 1)
+        );
+        // Without a trade, the quote's midpoint is used
+        assert_eq!(
+            source.last_price("TSLA").unwrap().price,
+            Decimal::new(700, 0)
+        );
+    }
+
+    #[test]
+    fn circuit_breaker() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+        breaker.record_failure(now);
+        assert!(breaker.allows(now));
+        breaker.record_failure(now);
+        assert!(!breaker.allows(now + Duration::from_secs(29)));
+
+        // After the cooldown, a single failure opens it again
+        let later = now + Duration::from_secs(30);
+        assert!(breaker.allows(later));
+        breaker.record_failure(later);
+        assert!(!breaker.allows(later));
+        breaker.record_success();
+        assert!(breaker.allows(later));
+    }
+}
@@ -0,0 +1,220 @@
+use crate::dedup::RecentCache;
+use chrono::{DateTime, Duration, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+/// How many recently traded tickers, besides the held ones, have their prices kept
+const RECENT_TICKERS: usize = 1_000;
+
+/// A last trade price, and when it was traded at.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PriceTick {
+    pub price: Decimal,
+    /// Unknown when the source doesn't say, in which case the price may be of any age
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Where the price came from, e.g. the price source that served the lookup
+    #[serde(skip)]
+    pub source: Option<&'static str>,
+}
+
+#[derive(Clone, Copy)]
+struct Cached {
+    tick: PriceTick,
+    cached_at: DateTime<Utc>,
+}
+
+struct Prices {
+    ticks: HashMap<String, Cached>,
+    held: HashSet<String>,
+    traded: RecentCache<String, ()>,
+    /// How long prices are used for after being cached. Without it, they are used until replaced
+    /// and lookups aren't cached.
+    ttl: Option<Duration>,
+}
+
+impl Prices {
+    fn is_watched(&self, ticker: &str) -> bool {
+        self.held.contains(ticker) || self.traded.get(&ticker.to_string()).is_some()
+    }
+}
+
+impl Default for Prices {
+    fn default() -> Self {
+        Self {
+            ticks: HashMap::new(),
+            held: HashSet::new(),
+            traded: RecentCache::new(RECENT_TICKERS),
+            ttl: None,
+        }
+    }
+}
+
+/// The latest prices of the held and recently traded tickers, kept up to date by a price feed or
+/// by caching lookups, so that market orders can be priced without a lookup. Clones share the
+/// same prices.
+#[derive(Clone, Default)]
+pub struct PriceCache(Arc<RwLock<Prices>>);
+
+impl PriceCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caches the results of lookups too, using every price for `ttl` after it was cached.
+    pub fn with_ttl(ttl: Duration) -> Self {
+        let cache = Self::default();
+        cache.0.write().expect("Prices lock poisoned").ttl = Some(ttl);
+        cache
+    }
+
+    /// The cached price of a ticker, unless it has expired.
+    pub fn get(&self, ticker: &str, now: DateTime<Utc>) -> Option<PriceTick> {
+        let prices = self.0.read().expect("Prices lock poisoned");
+        let cached = prices.ticks.get(ticker)?;
+        match prices.ttl {
+            Some(ttl) if now - cached.cached_at > ttl => None,
+            _ => Some(cached.tick),
+        }
+    }
+
+    /// Records a price of a watched ticker received at `now`, unless a later one is already
+    /// known. Prices of unknown age replace any other, as they arrived later. Returns whether the
+    /// price was kept.
+    pub fn update(&self, ticker: &str, tick: PriceTick, now: DateTime<Utc>) -> bool {
+        let mut prices = self.0.write().expect("Prices lock poisoned");
+        if !prices.is_watched(ticker) {
+            return false;
+        }
+        let outdated = prices.ticks.get(ticker).map_or(false, |cached| {
+            matches!(
+                (cached.tick.timestamp, tick.timestamp),
+                (Some(cached), Some(timestamp)) if cached > timestamp
+            )
+        });
+        if outdated {
+            return false;
+        }
+        let cached = Cached {
+            tick,
+            cached_at: now,
+        };
+        prices.ticks.insert(ticker.to_string(), cached);
+        true
+    }
+
+    /// Caches the price a lookup returned at `now`, if lookups are cached.
+    pub fn record_lookup(&self, ticker: &str, tick: PriceTick, now: DateTime<Utc>) {
+        if self.caches_lookups() {
+            self.update(ticker, tick, now);
+        }
+    }
+
+    pub fn caches_lookups(&self) -> bool {
+        self.0.read().expect("Prices lock poisoned").ttl.is_some()
+    }
+
+    /// The held tickers, whose prices are refreshed ahead of time.
+    pub fn held(&self) -> Vec<String> {
+        let prices = self.0.read().expect("Prices lock poisoned");
+        prices.held.iter().cloned().collect()
+    }
+
+    /// Whether the price of a ticker is kept, because it is held or was recently traded.
+    pub fn is_watched(&self, ticker: &str) -> bool {
+        self.0
+            .read()
+            .expect("Prices lock poisoned")
+            .is_watched(ticker)
+    }
+
+    /// Replaces the held tickers, dropping the prices of tickers no longer watched.
+    pub fn set_held<'a>(&self, tickers: impl IntoIterator<Item = &'a String>) {
+        let mut prices = self.0.write().expect("Prices lock poisoned");
+        prices.held = tickers.into_iter().cloned().collect();
+        let Prices {
+            ticks,
+            held,
+            traded,
+            ..
+        } = &mut *prices;
+        ticks.retain(|ticker, _| held.contains(ticker) || traded.get(ticker).is_some());
+    }
+
+    /// Starts watching a ticker an intent was received for.
+    pub fn traded(&self, ticker: &str) {
+        let ticker = ticker.to_string();
+        if self
+            .0
+            .read()
+            .expect("Prices lock poisoned")
+            .traded
+            .get(&ticker)
+            .is_some()
+        {
+            return;
+        }
+        self.0
+            .write()
+            .expect("Prices lock poisoned")
+            .traded
+            .insert(ticker, ());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn watched_tickers() {
+        let cache = PriceCache::new();
+        let now = Utc::now();
+        let tick = |price| PriceTick {
+            price: Decimal::new(price, 0),
+            timestamp: Some(now),
+            source: None,
+        };
+        assert!(!cache.update("AAPL", tick(150), now));
+        cache.set_held(&["AAPL".to_string()]);
+        cache.traded("TSLA");
+        assert!(cache.update("AAPL", tick(150), now));
+        assert!(cache.update("TSLA", tick(700), now));
+        assert_eq!(cache.get("AAPL", now), Some(tick(150)));
+
+        // Prices older than the cached one are ignored
+        let stale = PriceTick {
+            price: Decimal::new(140, 0),
+            timestamp: Some(now - Duration::seconds(1)),
+            source: None,
+        };
+        assert!(!cache.update("AAPL", stale, now));
+        assert_eq!(cache.get("AAPL", now), Some(tick(150)));
+
+        // Selling out of a ticker drops its price, unless it was recently traded
+        cache.set_held(std::iter::empty());
+        assert_eq!(cache.get("AAPL", now), None);
+        assert_eq!(cache.get("TSLA", now), Some(tick(700)));
+        // Without a TTL, prices are kept until replaced, and lookups aren't cached
+        assert_eq!(cache.get("TSLA", now + Duration::days(1)), Some(tick(700)));
+        cache.record_lookup("TSLA", tick(710), now);
+        assert_eq!(cache.get("TSLA", now), Some(tick(700)));
+    }
+
+    #[test]
+    fn ttl() {
+        let cache = PriceCache::with_ttl(Duration::seconds(5));
+        let now = Utc::now();
+        cache.traded("AAPL");
+        let tick = PriceTick {
+            price: Decimal::new(150, 0),
+            timestamp: Some(now),
+            source: None,
+        };
+        cache.record_lookup("AAPL", tick, now);
+        let cached = cache.get("AAPL", now + Duration::seconds(5)).unwrap();
+        assert_eq!(cached.price, Decimal::new(150, 0));
+        assert_eq!(cache.get("AAPL", now + Duration::seconds(6)), None);
+    }
+}
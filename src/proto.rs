@@ -0,0 +1,249 @@
+//! Protobuf messages generated from `proto/risk.proto`, and conversions to and from the
+//! domain types.
+use crate::{DenyReason, PortfolioSnapshot, RiskCheckResponse, TradeMetrics};
+use anyhow::{anyhow, Result};
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::str::FromStr;
+use trading_base::{OrderType, TimeInForce, TradeIntent};
+use uuid::Uuid;
+
+pub mod generated {
+    include!(concat!(env!("OUT_DIR"), "/risk.rs"));
+}
+
+use generated as proto;
+
+pub(crate) fn decimal(field: &str, value: &str) -> Result<Decimal> {
+    Decimal::from_str(value).map_err(|e| anyhow!("Invalid {}: {}", field, e))
+}
+
+impl proto::TradeIntent {
+    pub fn into_intent(self) -> Result<TradeIntent> {
+        let order_type = match proto::OrderType::from_i32(self.order_type) {
+            Some(proto::OrderType::Market) => OrderType::Market,
+            Some(proto::OrderType::Limit) => OrderType::Limit {
+                limit_price: decimal("limit_price", &self.limit_price)?,
+            },
+            Some(proto::OrderType::Stop) => OrderType::Stop {
+                stop_price: decimal("stop_price", &self.stop_price)?,
+            },
+            Some(proto::OrderType::StopLimit) => OrderType::StopLimit {
+                stop_price: decimal("stop_price", &self.stop_price)?,
+                limit_price: decimal("limit_price", &self.limit_price)?,
+            },
+            None => return Err(anyhow!("Invalid order_type")),
+        };
+        let time_in_force = match proto::TimeInForce::from_i32(self.time_in_force) {
+            Some(proto::TimeInForce::Day) => TimeInForce::Day,
+            Some(proto::TimeInForce::GoodTilCancelled) => TimeInForce::GoodTilCancelled,
+            None => return Err(anyhow!("Invalid time_in_force")),
+        };
+        let qty = self.qty.try_into().map_err(|_| anyhow!("Invalid qty"))?;
+        let mut intent = TradeIntent::new(self.ticker, qty)
+            .order_type(order_type)
+            .time_in_force(time_in_force);
+        if !self.id.is_empty() {
+            intent.id = Uuid::parse_str(&self.id).map_err(|e| anyhow!("Invalid id: {}", e))?;
+        }
+        Ok(intent)
+    }
+}
+
+impl From<&DenyReason> for proto::DenyReason {
+    /// Uses the JSON form of the reason, so names and details match the Kafka decisions.
+    fn from(reason: &DenyReason) -> Self {
+        match serde_json::to_value(reason) {
+            Ok(serde_json::Value::Object(object)) => {
+                let (reason, details) = object.into_iter().next().unwrap_or_default();
+                let details = match details {
+                    serde_json::Value::Object(details) => details
+                        .into_iter()
+                        .map(|(key, value)| match value {
+                            serde_json::Value::String(value) => (key, value),
+                            value => (key, value.to_string()),
+                        })
+                        .collect(),
+                    _ => HashMap::new(),
+                };
+                Self { reason, details }
+            }
+            Ok(serde_json::Value::String(reason)) => Self {
+                reason,
+                details: HashMap::new(),
+            },
+            _ => Self {
+                reason: format!("{:?}", reason),
+                details: HashMap::new(),
+            },
+        }
+    }
+}
+
+impl From<&RiskCheckResponse> for proto::RiskCheckResponse {
+    fn from(response: &RiskCheckResponse) -> Self {
+        use proto::risk_check_response::Decision;
+        let intent = response.intent();
+        let timing = response.timing();
+        let (decision, reasons) = match response {
+            RiskCheckResponse::Granted { .. } => (Decision::Granted, vec![]),
+            RiskCheckResponse::Denied { reasons, .. } => {
+                (Decision::Denied, reasons.iter().map(Into::into).collect())
+            }
+            RiskCheckResponse::Amended { .. } => (Decision::Amended, vec![]),
+        };
+        Self {
+            decision: decision as i32,
+            intent_id: intent.id.to_string(),
+            ticker: intent.ticker.clone(),
+            qty: intent.qty as i64,
+            approved_qty: response
+                .granted_intent()
+                .map(|intent| intent.qty as i64)
+                .unwrap_or_default(),
+            reasons,
+            intent: Some(intent.into()),
+            buffered_price: response
+                .buffered_price()
+                .map(|price| price.to_string())
+                .unwrap_or_default(),
+            price_source: response.price_source().unwrap_or_default().to_string(),
+            metrics: response.metrics().map(Into::into),
+            received_at: timing
+                .map(|timing| timing.received_at.to_rfc3339())
+                .unwrap_or_default(),
+            decided_at: timing
+                .map(|timing| timing.decided_at.to_rfc3339())
+                .unwrap_or_default(),
+            latency_ms: timing.map(|timing| timing.latency_ms).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&TradeMetrics> for proto::TradeMetrics {
+    fn from(metrics: &TradeMetrics) -> Self {
+        Self {
+            buying_power_before: metrics.buying_power_before.to_string(),
+            buying_power_after: metrics.buying_power_after.to_string(),
+            projected_position: metrics.projected_position.to_string(),
+            projected_gross_exposure: metrics.projected_gross_exposure.to_string(),
+            projected_net_exposure: metrics.projected_net_exposure.to_string(),
+            margin_utilization: metrics
+                .margin_utilization
+                .map(|utilization| utilization.to_string())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl From<PortfolioSnapshot> for proto::Portfolio {
+    fn from(snapshot: PortfolioSnapshot) -> Self {
+        let report = snapshot.report;
+        Self {
+            cash: report.cash.to_string(),
+            equity: report.equity.to_string(),
+            long_market_exposure: report.long_market_exposure.to_string(),
+            short_market_exposure: report.short_market_exposure.to_string(),
+            gross_market_exposure: report.gross_market_exposure.to_string(),
+            net_market_exposure: report.net_market_exposure.to_string(),
+            buying_power: report.buying_power.to_string(),
+            realized_pnl: report.realized_pnl.to_string(),
+            unrealized_pnl: report.unrealized_pnl.to_string(),
+            is_pattern_day_trader: snapshot.is_pattern_day_trader,
+            positions: snapshot
+                .positions
+                .into_iter()
+                .map(|position| proto::Position {
+                    ticker: position.ticker,
+                    shares: position.shares.to_string(),
+                    price: position.market_price.to_string(),
+                    market_value: position.market_value.to_string(),
+                    avg_cost: position.avg_cost.to_string(),
+                    unrealized_pnl: position.unrealized_pnl.to_string(),
+                    realized_pnl: position.realized_pnl.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&TradeIntent> for proto::TradeIntent {
+    fn from(intent: &TradeIntent) -> Self {
+        let (order_type, limit_price, stop_price) = match intent.order_type {
+            OrderType::Market => (proto::OrderType::Market, None, None),
+            OrderType::Limit { limit_price } => (proto::OrderType::Limit, Some(limit_price), None),
+            OrderType::Stop { stop_price } => (proto::OrderType::Stop, None, Some(stop_price)),
+            OrderType::StopLimit {
+                stop_price,
+                limit_price,
+            } => (
+                proto::OrderType::StopLimit,
+                Some(limit_price),
+                Some(stop_price),
+            ),
+        };
+        let time_in_force = match intent.time_in_force {
+            TimeInForce::Day => proto::TimeInForce::Day,
+            TimeInForce::GoodTilCancelled => proto::TimeInForce::GoodTilCancelled,
+        };
+        Self {
+            id: intent.id.to_string(),
+            ticker: intent.ticker.clone(),
+            qty: intent.qty as i64,
+            order_type: order_type as i32,
+            limit_price: limit_price.map(|p| p.to_string()).unwrap_or_default(),
+            stop_price: stop_price.map(|p| p.to_string()).unwrap_or_default(),
+            time_in_force: time_in_force as i32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        let request = proto::TradeIntent {
+            ticker: "AAPL".into(),
+            qty: -10,
+            order_type: proto::OrderType::Limit as i32,
+            limit_price: "101.5".into(),
+            ..Default::default()
+        };
+        let intent = request.into_intent().unwrap();
+        assert_eq!(
+            intent.order_type,
+            OrderType::Limit {
+                limit_price: Decimal::new(1015, 1)
+            }
+        );
+        assert_eq!(intent.qty, -10);
+
+        let response = RiskCheckResponse::Denied {
+            intent: intent.clone(),
+            reasons: vec![
+                DenyReason::Blocklisted,
+                DenyReason::NotionalLimitExceeded {
+                    max_notional: Decimal::new(500, 0),
+                },
+            ],
+            buffered_price: None,
+            price_source: None,
+            score: None,
+            timing: None,
+        };
+        let response = proto::RiskCheckResponse::from(&response);
+        assert_eq!(response.approved_qty, 0);
+        assert_eq!(response.reasons[0].reason, "blocklisted");
+        assert_eq!(response.reasons[1].reason, "notional_limit_exceeded");
+        assert!(response.reasons[1].details.contains_key("max_notional"));
+
+        let missing_price = proto::TradeIntent {
+            order_type: proto::OrderType::Limit as i32,
+            ..Default::default()
+        };
+        assert!(missing_price.into_intent().is_err());
+    }
+}
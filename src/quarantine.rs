@@ -0,0 +1,290 @@
+use crate::codec::Producer;
+use crate::settings::QuarantineSettings;
+use crate::transport::MessageSource;
+use anyhow::Result;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::{error, warn};
+
+/// Where a message was read from, and its raw payload, so that it can be re-read or
+/// quarantined.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+/// A message that couldn't be decoded.
+#[derive(Debug)]
+pub struct Undecodable {
+    pub origin: Origin,
+    pub error: String,
+}
+
+impl fmt::Display for Undecodable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to decode message at {}/{}@{}: {}",
+            self.origin.topic, self.origin.partition, self.origin.offset, self.error
+        )
+    }
+}
+
+impl std::error::Error for Undecodable {}
+
+/// Runs a message handler, turning a panic into an error so that the message can be quarantined
+/// instead of taking the service down.
+pub fn catch_panic<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|panic| panic_message(&*panic))
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => format!("Handler panicked: {}", message),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => format!("Handler panicked: {}", message),
+            None => "Handler panicked".into(),
+        },
+    }
+}
+
+/// Counts of messages that failed processing.
+#[derive(Debug, Default)]
+pub struct QuarantineStats {
+    failures: AtomicU64,
+    /// Messages that were skipped and sent to the dead-letter topic
+    quarantined: AtomicU64,
+}
+
+impl QuarantineStats {
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub fn quarantined(&self) -> u64 {
+        self.quarantined.load(Ordering::Relaxed)
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut metrics = String::new();
+        let counters = [
+            (
+                "message_failures",
+                "Attempts to process a message that failed",
+                self.failures(),
+            ),
+            (
+                "quarantined_messages",
+                "Messages skipped and sent to the dead-letter topic",
+                self.quarantined(),
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            let _ = writeln!(metrics, "# HELP risk_manager_{} {}", name, help);
+            let _ = writeln!(metrics, "# TYPE risk_manager_{} counter", name);
+            let _ = writeln!(metrics, "risk_manager_{} {}", name, value);
+        }
+        metrics
+    }
+}
+
+/// What to do with a message that failed processing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Verdict {
+    Retry,
+    Quarantine { attempts: usize },
+}
+
+/// Counts failed attempts at the current message of each partition.
+#[derive(Clone, Debug, Default)]
+struct Attempts {
+    max_attempts: usize,
+    /// Offset of the failing message and how many times it failed, by topic and partition
+    failing: HashMap<(String, i32), (i64, usize)>,
+}
+
+impl Attempts {
+    fn record(&mut self, origin: &Origin) -> Verdict {
+        let partition = (origin.topic.clone(), origin.partition);
+        let (offset, attempts) = self.failing.entry(partition).or_insert((origin.offset, 0));
+        if *offset != origin.offset {
+            *offset = origin.offset;
+            *attempts = 0;
+        }
+        *attempts += 1;
+        if *attempts < self.max_attempts {
+            return Verdict::Retry;
+        }
+        let attempts = *attempts;
+        self.failing
+            .remove(&(origin.topic.clone(), origin.partition));
+        Verdict::Quarantine { attempts }
+    }
+}
+
+/// Retries messages that fail processing, and moves them to the dead-letter topic once they have
+/// failed too often, so that one bad message can't stall or crash the pipeline.
+#[derive(Clone)]
+pub struct Quarantine {
+    producer: Producer,
+    topic: String,
+    source: Arc<dyn MessageSource>,
+    attempts: Attempts,
+    stats: Arc<QuarantineStats>,
+}
+
+impl Quarantine {
+    pub fn new(
+        producer: Producer,
+        topic: String,
+        source: Arc<dyn MessageSource>,
+        settings: QuarantineSettings,
+    ) -> Self {
+        Self {
+            producer,
+            topic,
+            source,
+            attempts: Attempts {
+                max_attempts: settings.max_attempts,
+                failing: HashMap::new(),
+            },
+            stats: Default::default(),
+        }
+    }
+
+    pub fn stats(&self) -> Arc<QuarantineStats> {
+        self.stats.clone()
+    }
+
+    /// Records a failed attempt at consuming a message, seeking back to it so that it is read
+    /// again. Only the consumer may call this, as messages read after it would be read twice.
+    pub async fn failed(&mut self, origin: Origin, error: &str) -> Result<()> {
+        self.stats.failures.fetch_add(1, Ordering::Relaxed);
+        let (topic, partition, offset) = (&origin.topic, origin.partition, origin.offset);
+        match self.attempts.record(&origin) {
+            Verdict::Retry => {
+                warn!(%topic, partition, offset, error, "Failed to process message, retrying");
+                self.source.seek(topic, partition, offset)?;
+            }
+            Verdict::Quarantine { attempts } => {
+                let (topic, partition, offset) = (topic.clone(), partition, offset);
+                self.dead_letter(Some(origin), attempts, error).await?;
+                // Never reaches the run loop, so it is acknowledged once forwarded
+                self.source.ack(&topic, partition, offset).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a message handler until it stops panicking, up to the maximum number of attempts.
+    /// Handlers can't mutate what they capture, so a panicking attempt can't leave behind
+    /// half-applied changes for the next one to run on.
+    pub fn retry<T>(&self, handler: impl Fn() -> T) -> Result<T, String> {
+        let mut attempt = 1;
+        loop {
+            match catch_panic(&handler) {
+                Ok(result) => return Ok(result),
+                Err(error) => {
+                    self.stats.failures.fetch_add(1, Ordering::Relaxed);
+                    if attempt >= self.attempts.max_attempts {
+                        return Err(error);
+                    }
+                    warn!(attempt, error = %error, "Failed to process message, retrying");
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Forwards a message that failed `attempts` times to the dead-letter topic. Messages that
+    /// didn't come from the input topics are only logged.
+    pub async fn dead_letter(
+        &self,
+        origin: Option<Origin>,
+        attempts: usize,
+        error: &str,
+    ) -> Result<()> {
+        let origin = match origin {
+            Some(origin) => origin,
+            None => {
+                error!(attempts, error, "Failed to process message");
+                return Ok(());
+            }
+        };
+        let (topic, partition, offset) = (&origin.topic, origin.partition, origin.offset);
+        error!(%topic, partition, offset, attempts, error, "Quarantining message");
+        self.stats.quarantined.fetch_add(1, Ordering::Relaxed);
+        let headers = vec![
+            ("dlq-topic".to_string(), topic.clone().into_bytes()),
+            (
+                "dlq-partition".to_string(),
+                partition.to_string().into_bytes(),
+            ),
+            ("dlq-offset".to_string(), offset.to_string().into_bytes()),
+            (
+                "dlq-attempts".to_string(),
+                attempts.to_string().into_bytes(),
+            ),
+            ("dlq-error".to_string(), error.as_bytes().to_vec()),
+        ];
+        let key = origin.key.as_deref().unwrap_or_default();
+        self.producer
+            .send_raw(&self.topic, key, &origin.payload, &headers)
+            .await
+    }
+
+    pub fn max_attempts(&self) -> usize {
+        self.attempts.max_attempts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn origin(partition: i32, offset: i64) -> Origin {
+        Origin {
+            topic: "risk-check-request".into(),
+            partition,
+            offset,
+            key: None,
+            payload: b"{".to_vec(),
+        }
+    }
+
+    #[test]
+    fn attempts() {
+        let mut attempts = Attempts {
+            max_attempts: 2,
+            failing: HashMap::new(),
+        };
+        assert_eq!(attempts.record(&origin(0, 10)), Verdict::Retry);
+        // Failures on other partitions are counted separately
+        assert_eq!(attempts.record(&origin(1, 10)), Verdict::Retry);
+        assert_eq!(
+            attempts.record(&origin(0, 10)),
+            Verdict::Quarantine { attempts: 2 }
+        );
+        // A later message starts from scratch
+        assert_eq!(attempts.record(&origin(1, 11)), Verdict::Retry);
+        assert_eq!(attempts.record(&origin(0, 10)), Verdict::Retry);
+    }
+
+    #[test]
+    fn panics() {
+        assert_eq!(catch_panic(|| 1), Ok(1));
+        let message = catch_panic(|| -> u8 { panic!("bad {}", "intent") }).unwrap_err();
+        assert_eq!(message, "Handler panicked: bad intent");
+        let message = catch_panic(|| -> u8 { panic!("bad intent") }).unwrap_err();
+        assert_eq!(message, "Handler panicked: bad intent");
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{PortfolioSnapshot, Position, RiskManager, RiskReport};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Query {
+    BuyingPower,
+    Position { ticker: String },
+    Portfolio,
+    RiskReport,
+}
+
+/// Question about the risk manager's view of the book, answered on the query-response topic.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct QueryRequest {
+    pub id: Uuid,
+    pub query: Query,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum QueryResult {
+    BuyingPower {
+        buying_power: Decimal,
+    },
+    /// `position` is empty if there is no position in the ticker
+    Position {
+        ticker: String,
+        position: Option<Position>,
+    },
+    Portfolio {
+        portfolio: PortfolioSnapshot,
+    },
+    RiskReport {
+        report: RiskReport,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct QueryResponse {
+    /// Id of the request being answered
+    pub id: Uuid,
+    pub result: QueryResult,
+}
+
+impl RiskManager {
+    pub fn answer(&self, request: &QueryRequest) -> QueryResponse {
+        let result = match &request.query {
+            Query::BuyingPower => QueryResult::BuyingPower {
+                buying_power: self.buying_power(),
+            },
+            Query::Position { ticker } => QueryResult::Position {
+                ticker: ticker.clone(),
+                position: self.position(ticker),
+            },
+            Query::Portfolio => QueryResult::Portfolio {
+                portfolio: self.portfolio_snapshot(),
+            },
+            Query::RiskReport => QueryResult::RiskReport {
+                report: self.risk_report(),
+            },
+        };
+        QueryResponse {
+            id: request.id,
+            result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{PositionSide, Price, Shares};
+
+    #[test]
+    fn queries() {
+        let mut manager = RiskManager::default();
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(2, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        manager.update_cash(Decimal::new(800, 0));
+        let request: QueryRequest = serde_json::from_str(&format!(
+            r#"{{"id":"{}","query":{{"type":"position","ticker":"AAPL"}}}}"#,
+            Uuid::new_v4()
+        ))
+        .unwrap();
+        assert_eq!(
+            manager.answer(&request).result,
+            QueryResult::Position {
+                ticker: "AAPL".into(),
+                position: Some(Position {
+                    ticker: "AAPL".into(),
+                    shares: Decimal::new(2, 0),
+                    avg_cost: Decimal::new(100, 0),
+                    market_price: Decimal::new(100, 0),
+                    market_value: Decimal::new(200, 0),
+                    unrealized_pnl: Decimal::ZERO,
+                    realized_pnl: Decimal::ZERO,
+                    side: PositionSide::Long,
+                })
+            }
+        );
+        let request = QueryRequest {
+            id: Uuid::new_v4(),
+            query: Query::BuyingPower,
+        };
+        assert_eq!(
+            manager.answer(&request).result,
+            QueryResult::BuyingPower {
+                buying_power: Decimal::new(1800, 0)
+            }
+        );
+        let request = QueryRequest {
+            id: Uuid::new_v4(),
+            query: Query::RiskReport,
+        };
+        assert_eq!(
+            manager.answer(&request).result,
+            QueryResult::RiskReport {
+                report: manager.risk_report()
+            }
+        );
+    }
+}
@@ -0,0 +1,259 @@
+use crate::settings::RedisPoolSettings;
+use anyhow::{anyhow, Result};
+use redis::aio::Connection;
+use redis::{Cmd, FromRedisValue};
+use std::fmt::Write;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+/// Async Redis connections, opened on demand up to the maximum and reused between calls.
+/// Connections that fail or time out are closed rather than reused, so that the next call
+/// reconnects.
+#[derive(Clone)]
+pub struct RedisPool(Arc<Inner>);
+
+struct Inner {
+    client: redis::Client,
+    idle: Mutex<Vec<Connection>>,
+    permits: Arc<Semaphore>,
+    settings: RedisPoolSettings,
+    stats: Arc<RedisPoolStats>,
+}
+
+impl RedisPool {
+    /// `name` labels the pool's metrics.
+    pub fn new(name: &str, url: &str, settings: RedisPoolSettings) -> Result<Self> {
+        let client = redis::Client::open(url)?;
+        let stats = Arc::new(RedisPoolStats {
+            name: name.to_string(),
+            max: settings.max_connections,
+            permits: Arc::new(Semaphore::new(settings.max_connections)),
+            idle: AtomicU64::new(0),
+            opened: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            wait_timeouts: AtomicU64::new(0),
+        });
+        Ok(Self(Arc::new(Inner {
+            client,
+            idle: Mutex::new(Vec::new()),
+            permits: stats.permits.clone(),
+            settings,
+            stats,
+        })))
+    }
+
+    pub fn stats(&self) -> Arc<RedisPoolStats> {
+        self.0.stats.clone()
+    }
+
+    /// Waits for a free connection, reusing an idle one or opening a new one.
+    pub async fn get(&self) -> Result<PooledConnection> {
+        let (inner, stats) = (&self.0, &self.0.stats);
+        let wait = Duration::from_millis(inner.settings.wait_timeout_ms);
+        let permit = match tokio::time::timeout(wait, inner.permits.clone().acquire_owned()).await {
+            Ok(permit) => permit?,
+            Err(_) => {
+                stats.wait_timeouts.fetch_add(1, Ordering::Relaxed);
+                return Err(anyhow!("Timed out waiting for a Redis connection"));
+            }
+        };
+        let idle = inner
+            .idle
+            .lock()
+            .expect("Idle connections lock poisoned")
+            .pop();
+        let connection = match idle {
+            Some(connection) => {
+                stats.idle.fetch_sub(1, Ordering::Relaxed);
+                connection
+            }
+            None => {
+                let timeout = Duration::from_millis(inner.settings.connect_timeout_ms);
+                let connection =
+                    tokio::time::timeout(timeout, inner.client.get_async_connection()).await;
+                match connection {
+                    Ok(Ok(connection)) => {
+                        stats.opened.fetch_add(1, Ordering::Relaxed);
+                        connection
+                    }
+                    Ok(Err(e)) => {
+                        stats.failures.fetch_add(1, Ordering::Relaxed);
+                        return Err(e.into());
+                    }
+                    Err(_) => {
+                        stats.failures.fetch_add(1, Ordering::Relaxed);
+                        return Err(anyhow!("Timed out connecting to Redis"));
+                    }
+                }
+            }
+        };
+        Ok(PooledConnection {
+            connection: Some(connection),
+            pool: self.0.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// Runs a command on a pooled connection, giving up after the command timeout.
+    pub async fn query<T: FromRedisValue>(&self, cmd: &Cmd) -> Result<T> {
+        let mut connection = self.get().await?;
+        let timeout = Duration::from_millis(self.0.settings.command_timeout_ms);
+        match tokio::time::timeout(timeout, cmd.query_async(&mut *connection)).await {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => {
+                if e.is_io_error() || e.is_connection_dropped() {
+                    connection.discard();
+                }
+                Err(e.into())
+            }
+            Err(_) => {
+                // The reply may still arrive later, and be read as the reply to the next command
+                connection.discard();
+                Err(anyhow!("Redis command timed out"))
+            }
+        }
+    }
+}
+
+/// A connection taken from the pool, returned to it when dropped.
+pub struct PooledConnection {
+    connection: Option<Connection>,
+    pool: Arc<Inner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledConnection {
+    /// Closes the connection instead of returning it to the pool.
+    pub fn discard(&mut self) {
+        if self.connection.take().is_some() {
+            warn!(pool = %self.pool.stats.name, "Closing broken Redis connection");
+            self.pool.stats.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.connection
+            .as_ref()
+            .expect("Connection used after being discarded")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.connection
+            .as_mut()
+            .expect("Connection used after being discarded")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            let mut idle = self
+                .pool
+                .idle
+                .lock()
+                .expect("Idle connections lock poisoned");
+            idle.push(connection);
+            self.pool.stats.idle.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Connections of a pool, exposed at `/metrics`.
+pub struct RedisPoolStats {
+    name: String,
+    max: usize,
+    permits: Arc<Semaphore>,
+    idle: AtomicU64,
+    opened: AtomicU64,
+    /// Connections that couldn't be opened, and connections closed after failing
+    failures: AtomicU64,
+    wait_timeouts: AtomicU64,
+}
+
+impl RedisPoolStats {
+    pub fn in_use(&self) -> usize {
+        self.max - self.permits.available_permits()
+    }
+
+    /// Renders the gauges and counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let mut metrics = String::new();
+        let name = &self.name;
+        let _ = writeln!(
+            metrics,
+            "# HELP risk_manager_redis_connections Redis connections of the pool"
+        );
+        let _ = writeln!(metrics, "# TYPE risk_manager_redis_connections gauge");
+        let gauges = [
+            ("in_use", self.in_use() as u64),
+            ("idle", self.idle.load(Ordering::Relaxed)),
+            ("max", self.max as u64),
+        ];
+        for (state, value) in gauges.iter() {
+            let _ = writeln!(
+                metrics,
+                "risk_manager_redis_connections{{pool=\"{}\",state=\"{}\"}} {}",
+                name, state, value
+            );
+        }
+        let counters = [
+            (
+                "redis_connections_opened",
+                "Redis connections opened",
+                self.opened.load(Ordering::Relaxed),
+            ),
+            (
+                "redis_connection_failures",
+                "Redis connections that failed to open or were closed after failing",
+                self.failures.load(Ordering::Relaxed),
+            ),
+            (
+                "redis_wait_timeouts",
+                "Calls that timed out waiting for a free Redis connection",
+                self.wait_timeouts.load(Ordering::Relaxed),
+            ),
+        ];
+        for (metric, help, value) in counters.iter() {
+            let _ = writeln!(metrics, "# HELP risk_manager_{} {}", metric, help);
+            let _ = writeln!(metrics, "# TYPE risk_manager_{} counter", metric);
+            let _ = writeln!(
+                metrics,
+                "risk_manager_{}{{pool=\"{}\"}} {}",
+                metric, name, value
+            );
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn unreachable() {
+        let settings = RedisPoolSettings {
+            max_connections: 1,
+            connect_timeout_ms: 100,
+            ..Default::default()
+        };
+        // Nothing listens on port 1
+        let pool = RedisPool::new("test", "redis://127.0.0.1:1", settings).unwrap();
+        assert!(pool.query::<String>(&redis::cmd("PING")).await.is_err());
+        let metrics = pool.stats().render_metrics();
+        assert!(metrics.contains("risk_manager_redis_connection_failures{pool=\"test\"} 1\n"));
+        assert!(
+            metrics.contains("risk_manager_redis_connections{pool=\"test\",state=\"in_use\"} 0\n")
+        );
+    }
+}
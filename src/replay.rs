@@ -0,0 +1,226 @@
+use crate::codec::Codec;
+use crate::input::Input;
+use crate::price_sources::PriceSources;
+use crate::simulation::{
+    DecisionRecord, RecordedEvent, RecordedInput, Simulation, SimulationSummary,
+};
+use crate::{Holdings, LimitsFile, RiskManager, Settings};
+use alpaca::Client;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::{Message, Offset, TopicPartitionList};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for the next message before giving up on the partitions that haven't been
+/// read to the end.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a replay starts or stops: the time messages were produced at, or an offset that applies
+/// to every partition of the input topics. Both ends are inclusive.
+#[derive(Clone, Copy, Debug)]
+pub enum ReplayBound {
+    Time(DateTime<Utc>),
+    Offset(i64),
+}
+
+/// Input messages to replay. Without an end, messages are replayed up to the ones that had been
+/// produced when the replay started.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayRange {
+    pub from: ReplayBound,
+    pub until: Option<ReplayBound>,
+}
+
+impl ReplayRange {
+    /// Whether a message comes after the end of the range.
+    fn is_past(&self, at: DateTime<Utc>, offset: i64) -> bool {
+        match self.until {
+            Some(ReplayBound::Time(until)) => at > until,
+            Some(ReplayBound::Offset(until)) => offset > until,
+            None => false,
+        }
+    }
+}
+
+/// A decision made during a replay, with the message it was made for.
+#[derive(Debug, Serialize)]
+struct ReplayedDecision<'a> {
+    topic: &'a str,
+    partition: i32,
+    offset: i64,
+    #[serde(flatten)]
+    decision: DecisionRecord,
+}
+
+/// Assigns every partition of the input topics from `from`, returning the offset each partition
+/// is read up to.
+fn assign(
+    consumer: &StreamConsumer,
+    topics: &[&str],
+    from: ReplayBound,
+) -> Result<HashMap<(String, i32), i64>> {
+    let mut starts = TopicPartitionList::new();
+    let mut ends = HashMap::new();
+    for topic in topics {
+        let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+        let partitions = metadata
+            .topics()
+            .iter()
+            .find(|t| t.name() == *topic)
+            .map(|t| t.partitions())
+            .ok_or_else(|| anyhow!("Topic {} not found", topic))?;
+        for partition in partitions {
+            // `offsets_for_times` takes the timestamps in place of the offsets
+            let start = match from {
+                ReplayBound::Time(from) => from.timestamp_millis(),
+                ReplayBound::Offset(from) => from,
+            };
+            starts.add_partition_offset(topic, partition.id(), Offset::Offset(start))?;
+            let (_, high) = consumer.fetch_watermarks(topic, partition.id(), METADATA_TIMEOUT)?;
+            ends.insert((topic.to_string(), partition.id()), high);
+        }
+    }
+    let assignment = match from {
+        ReplayBound::Time(_) => consumer.offsets_for_times(starts, METADATA_TIMEOUT)?,
+        ReplayBound::Offset(_) => starts,
+    };
+    for element in assignment.elements() {
+        // Partitions without messages from `from` on have nothing to replay
+        let key = (element.topic().to_string(), element.partition());
+        let has_messages = match (element.offset(), ends.get(&key)) {
+            (Offset::Offset(start), Some(end)) => start < *end,
+            _ => false,
+        };
+        if !has_messages {
+            ends.remove(&key);
+        }
+    }
+    consumer.assign(&assignment)?;
+    Ok(ends)
+}
+
+async fn risk_manager(settings: &Settings) -> Result<RiskManager> {
+    let datastore_url = settings
+        .datastore
+        .as_ref()
+        .map(|datastore| datastore.base_url.clone())
+        .unwrap_or_default();
+    let mut risk_manager = RiskManager::new(datastore_url, settings.risk.clone());
+    risk_manager.bind_price_sources(Arc::new(PriceSources::from_settings(settings)?));
+    if let Some(limits) = settings.limits.clone() {
+        risk_manager.bind_limits(LimitsFile::load(limits)?.limits());
+    }
+    match settings.holdings_file.as_deref() {
+        Some(path) => {
+            info!(%path, "Replaying from imported holdings");
+            risk_manager.import_holdings(Holdings::from_csv(&std::fs::read_to_string(path)?)?);
+        }
+        None => {
+            warn!("Replaying from the broker's current account, not the one at the time");
+            let alpaca = &settings.alpaca;
+            let client = Client::new(
+                alpaca.base_url.clone(),
+                alpaca.key_id.clone(),
+                alpaca.secret_key.clone(),
+            )?;
+            risk_manager.bind_alpaca_client(client);
+            risk_manager.initialize().await?;
+        }
+    }
+    Ok(risk_manager)
+}
+
+/// Reads the input topics over `range` outside of the consumer group and runs the intents
+/// through the risk manager as if in shadow mode: nothing is published, and every decision is
+/// written to `output` as a JSON line.
+///
+/// The account starts from the holdings file if there is one, and from the broker otherwise.
+/// Market orders are priced at the last lot of their ticker in the range, or by the configured
+/// price sources if there hasn't been one.
+pub async fn replay(
+    settings: Settings,
+    range: ReplayRange,
+    output: &Path,
+) -> Result<SimulationSummary> {
+    let consumer = kafka_settings::consumer(&settings.kafka)?;
+    consumer.unsubscribe();
+    let mut remaining = assign(&consumer, &settings.topics.inputs(false), range.from)?;
+    let codec = Codec::new(&settings.codec)?;
+    // The clock follows the messages, so without a start time it only matters until the first one
+    let mut last_at = match range.from {
+        ReplayBound::Time(from) => from,
+        ReplayBound::Offset(_) => Utc::now(),
+    };
+    let mut simulation = Simulation::new(risk_manager(&settings).await?, last_at);
+    let mut writer = BufWriter::new(File::create(output)?);
+    let mut summary = SimulationSummary::default();
+    info!(?range, partitions = remaining.len(), "Replaying");
+    while !remaining.is_empty() {
+        let message = match tokio::time::timeout(IDLE_TIMEOUT, consumer.recv()).await {
+            Ok(message) => message?,
+            Err(_) => {
+                warn!(partitions = ?remaining.keys(), "Stopped waiting for messages");
+                break;
+            }
+        };
+        let key = (message.topic().to_string(), message.partition());
+        let end = match remaining.get(&key) {
+            Some(end) => *end,
+            None => continue,
+        };
+        let at = message
+            .timestamp()
+            .to_millis()
+            .map(|millis| Utc.timestamp_millis(millis))
+            .unwrap_or(last_at);
+        last_at = at;
+        if range.is_past(at, message.offset()) {
+            remaining.remove(&key);
+            continue;
+        }
+        if message.offset() + 1 >= end {
+            remaining.remove(&key);
+        }
+        let payload = match message.payload() {
+            Some(payload) => payload,
+            None => continue,
+        };
+        let input = match codec.decode_input(payload).await {
+            Ok(Input::Lot(lot)) => RecordedInput::Lot(lot),
+            Ok(Input::TradeIntent(intent)) => RecordedInput::Intent(intent),
+            Ok(Input::PriceUpdate { ticker, price, .. }) => RecordedInput::Price { ticker, price },
+            Ok(_) => continue,
+            Err(e) => {
+                debug!(
+                    ?e,
+                    offset = message.offset(),
+                    "Skipping undecodable message"
+                );
+                continue;
+            }
+        };
+        if let Some(decision) = simulation.step(RecordedEvent { at, input }) {
+            summary.record(&decision);
+            let replayed = ReplayedDecision {
+                topic: message.topic(),
+                partition: message.partition(),
+                offset: message.offset(),
+                decision,
+            };
+            serde_json::to_writer(&mut writer, &replayed)?;
+            writeln!(writer)?;
+        }
+    }
+    writer.flush()?;
+    info!(?summary, output = %output.display(), "Replay finished");
+    Ok(summary)
+}
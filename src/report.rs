@@ -0,0 +1,83 @@
+use crate::day_trade_calls::DayTradeCall;
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use trading_base::TradeIntent;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PositionSide {
+    Long,
+    Short,
+    /// Every share has been sold or covered
+    Flat,
+}
+
+impl PositionSide {
+    pub fn of(shares: Decimal) -> Self {
+        if shares.is_zero() {
+            PositionSide::Flat
+        } else if shares.is_sign_negative() {
+            PositionSide::Short
+        } else {
+            PositionSide::Long
+        }
+    }
+}
+
+/// A position valued at the last price the risk manager knows of.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Position {
+    pub ticker: String,
+    /// Negative for short positions
+    pub shares: Decimal,
+    /// Average price the shares were bought, or sold short, at
+    pub avg_cost: Decimal,
+    pub market_price: Decimal,
+    pub market_value: Decimal,
+    pub unrealized_pnl: Decimal,
+    /// PnL realized by the fills of the day
+    #[serde(default)]
+    pub realized_pnl: Decimal,
+    pub side: PositionSide,
+}
+
+impl Position {
+    /// A market order closing the position. Only whole shares can be sent in an intent, so there
+    /// is none for positions of less than a share.
+    pub fn closing_intent(&self) -> Option<TradeIntent> {
+        match (-self.shares).trunc().to_isize() {
+            Some(qty) if qty != 0 => Some(TradeIntent::new(&self.ticker, qty)),
+            _ => None,
+        }
+    }
+}
+
+/// Equity, margins, exposures and buying powers of the account, as used by the risk checks.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RiskReport {
+    pub cash: Decimal,
+    pub equity: Decimal,
+    pub last_equity: Decimal,
+    pub last_maintenance_margin: Decimal,
+    pub long_market_exposure: Decimal,
+    pub short_market_exposure: Decimal,
+    pub gross_market_exposure: Decimal,
+    pub net_market_exposure: Decimal,
+    pub initial_margin: Decimal,
+    pub maintenance_margin: Decimal,
+    pub multiplier: Decimal,
+    pub regt_buying_power: Decimal,
+    pub daytrading_buying_power: Decimal,
+    pub buying_power: Decimal,
+    /// Buying power set aside for working orders
+    pub reserved_buying_power: Decimal,
+    /// PnL realized by the fills of the day
+    #[serde(default)]
+    pub realized_pnl: Decimal,
+    /// PnL of the positions held, at their last known prices
+    #[serde(default)]
+    pub unrealized_pnl: Decimal,
+    /// Day-trading call that hasn't been met yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub day_trade_call: Option<DayTradeCall>,
+}
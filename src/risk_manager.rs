@@ -1,36 +1,260 @@
+use crate::assets::{Asset, AssetCache};
+use crate::clock::{Clock, SharedClock};
+use crate::control::{AccountBlocks, AppliedControls, ControlCommand, TradingHalts};
+use crate::day_trade_calls::{DayTradeCall, DayTradeCalls};
+use crate::day_trades::{DayTradeTracker, MAX_DAY_TRADES};
+use crate::dedup::RecentCache;
+use crate::drawdown::DrawdownBreaker;
+use crate::error::{Result, RiskManagerError};
+use crate::eval_cache::{EvaluationCache, EvaluationCacheStats, EvaluationKey};
+use crate::events::{Alert, AlertLevel, ExposureUpdate};
+use crate::handoff::HandoffState;
+use crate::holdings::{Holding, Holdings};
+use crate::input::{Lot, MultiLegIntent, OrderAmendment, RequestOptions};
+use crate::latency::LatencyStats;
+use crate::limits::{RiskLimits, SharedLimits};
+use crate::margin::MarginMethod;
+use crate::options::{OptionContract, OptionRight};
+use crate::orders::WorkingOrders;
+use crate::price_sources::PriceSources;
+use crate::prices::{PriceCache, PriceTick};
+use crate::report::{Position, PositionSide, RiskReport};
+use crate::rules::{
+    Evaluation, PortfolioContext, PriceAge, RiskRule, RiskScore, RuleOutcome, RulePipeline,
+    RuleStats, RuleTrace,
+};
+use crate::settings::{RiskSettings, RuleEvaluationMode};
+use crate::settlement::SettlementLedger;
+use crate::sharding::{Shard, ShardState};
+use crate::tax_lots::TaxLots;
+use crate::units::{Price, Shares};
+#[cfg(feature = "alpaca")]
 use alpaca::{rest::account::GetAccount, rest::positions::GetPositions, Client};
-use anyhow::{anyhow, Context, Result};
-use num_traits::sign::Signed;
-use rdkafka::consumer::StreamConsumer;
+use chrono::{DateTime, NaiveDate, Utc};
 use rust_decimal::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use tracing::{debug, trace};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tracing::{debug, error, info, trace};
 use trading_base::{OrderType, TradeIntent};
-
-#[derive(Copy, Clone)]
-pub struct Shares(pub Decimal);
-
-#[derive(Copy, Clone)]
-pub struct Price(pub Decimal);
+use uuid::Uuid;
 
 #[derive(Default)]
 pub struct RiskManager {
-    pub(super) kafka_consumer: Option<StreamConsumer>,
+    #[cfg(feature = "alpaca")]
     alpaca_client: Option<Client>,
     cash: Decimal,
+    /// Shares and last known price by ticker
     holdings: HashMap<String, (Shares, Price)>,
+    /// Average price of the shares held by ticker
+    avg_costs: HashMap<String, Price>,
+    /// Open lots of the holdings and the PnL realized closing them
+    tax_lots: TaxLots,
+    drawdown: DrawdownBreaker,
+    day_trade_calls: DayTradeCalls,
+    /// Tickers that must be flat overnight, while in the window before the close
+    flattening: HashSet<String>,
     is_pattern_day_trader: bool,
+    /// Buying power multiplier reported by the broker, once the account has been fetched
+    account_multiplier: Option<Decimal>,
+    shorting_disabled: bool,
+    account_blocks: AccountBlocks,
+    /// Day of the session that `last_equity` and `last_maintenance_margin` lead into
+    session_day: Option<NaiveDate>,
     last_equity: Decimal,
     last_maintenance_margin: Decimal,
-    datastore_url: String,
+    /// Where prices that aren't cached are looked up
+    price_sources: Arc<PriceSources>,
+    /// Prices kept up to date by a price feed, used before looking prices up
+    prices: PriceCache,
+    assets: AssetCache,
+    /// Annualized borrow rates from the borrow rates topic, by ticker
+    borrow_rates: HashMap<String, Decimal>,
+    /// Deltas of option contracts from the greeks topic, by ticker
+    deltas: HashMap<String, Decimal>,
+    is_cash_account: bool,
+    settlement_ledger: SettlementLedger,
+    day_trades: DayTradeTracker,
+    recent_decisions: RecentCache<Uuid, RiskCheckResponse>,
+    working_orders: WorkingOrders,
+    rules: RulePipeline,
+    limits: SharedLimits,
+    evaluation_cache: EvaluationCache,
+    halts: TradingHalts,
+    applied_controls: AppliedControls,
+    shard: Shard,
+    latency: Arc<LatencyStats>,
+    clock: SharedClock,
+    /// Incremented whenever state that risk checks depend on changes, other than marks
+    state_version: u64,
+    settings: RiskSettings,
+}
+
+/// Point-in-time view of everything the risk manager knows about the account.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct PortfolioSnapshot {
+    #[serde(flatten)]
+    pub report: RiskReport,
+    pub is_pattern_day_trader: bool,
+    pub positions: Vec<Position>,
+}
+
+/// The decision an intent would get and the portfolio as it would look if the intent were filled
+/// in full.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WhatIfResponse {
+    pub decision: RiskCheckResponse,
+    pub projected: PortfolioSnapshot,
+}
+
+/// Decisions of the legs of a multi-leg intent, in order. Either every leg is granted or every leg
+/// is denied.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct MultiLegResponse {
+    pub id: Uuid,
+    pub legs: Vec<RiskCheckResponse>,
+}
+
+impl MultiLegResponse {
+    pub fn is_granted(&self) -> bool {
+        self.legs
+            .iter()
+            .all(|leg| matches!(leg, RiskCheckResponse::Granted { .. }))
+    }
+
+    /// Denies every leg if any leg was denied. A single decision is left as it is.
+    pub fn all_or_nothing(legs: Vec<RiskCheckResponse>) -> Vec<RiskCheckResponse> {
+        let denied = legs
+            .iter()
+            .any(|leg| matches!(leg, RiskCheckResponse::Denied { .. }));
+        if !denied {
+            return legs;
+        }
+        legs.into_iter()
+            .map(|leg| match leg {
+                RiskCheckResponse::Denied { .. } => leg,
+                leg => leg.into_denied(vec![DenyReason::OtherLegDenied]),
+            })
+            .collect()
+    }
+}
+
+/// A decision along with what every rule in the pipeline made of the intent, including the rules
+/// that didn't get to decide it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Explanation {
+    pub decision: RiskCheckResponse,
+    pub rules: Vec<RuleTrace>,
+}
+
+/// The account before and after a granted intent, as if the intent were filled in full.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TradeMetrics {
+    pub buying_power_before: Decimal,
+    pub buying_power_after: Decimal,
+    /// Shares held in the ticker after the intent
+    pub projected_position: Decimal,
+    pub projected_gross_exposure: Decimal,
+    pub projected_net_exposure: Decimal,
+    /// Maintenance margin as a fraction of equity after the intent, if equity is positive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub margin_utilization: Option<Decimal>,
+}
+
+/// When the input behind a decision was received and when the decision was made.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct DecisionTiming {
+    pub received_at: DateTime<Utc>,
+    pub decided_at: DateTime<Utc>,
+    pub latency_ms: i64,
+}
+
+impl DecisionTiming {
+    /// Timing of a decision made now.
+    pub fn since(received_at: DateTime<Utc>) -> Self {
+        let decided_at = Utc::now();
+        Self {
+            received_at,
+            decided_at,
+            latency_ms: (decided_at - received_at).num_milliseconds(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum DenyReason {
-    InsufficientBuyingPower { buying_power: Decimal },
+    InsufficientBuyingPower {
+        buying_power: Decimal,
+    },
     ChangeInPositionSide,
+    GoodFaithViolation,
+    PatternDayTradeProtection,
+    Blocklisted,
+    NotionalLimitExceeded {
+        max_notional: Decimal,
+    },
+    GrossExposureLimitExceeded {
+        max_gross_exposure: Decimal,
+    },
+    NetExposureLimitExceeded {
+        max_net_exposure: Decimal,
+    },
+    ConcentrationLimitExceeded {
+        max_concentration: Decimal,
+    },
+    ComplianceRejected {
+        reason: Option<String>,
+    },
+    ComplianceUnavailable,
+    TradingPaused,
+    KillSwitchEngaged,
+    /// The last price of a market order was traded at `age` seconds ago
+    StalePrice {
+        age: Decimal,
+    },
+    /// The last price of a market order came from a source that doesn't say when it was traded
+    /// at, so it may be stale
+    PriceAgeUnknown,
+    /// Equity fell more than `max_drawdown` from its peak of the day
+    MaxDrawdownExceeded {
+        max_drawdown: Decimal,
+    },
+    /// The ticker must be flat overnight and the market is about to close
+    FlattenWindow,
+    /// The broker doesn't allow the account to open short positions, or the asset is crypto
+    ShortingDisabled,
+    /// The broker has blocked trading in the account
+    AccountRestricted,
+    /// The asset is inactive or can't be traded at the broker
+    AssetNotTradable,
+    /// The quantity is fractional but the asset only trades in whole shares
+    FractionalNotAllowed,
+    /// Another leg of a multi-leg intent was denied
+    OtherLegDenied,
+    /// The intent couldn't be evaluated, e.g. because its price couldn't be looked up
+    EvaluationFailed,
+    /// Borrowing the shares of a new short costs `rate` a year, more than `max_rate`
+    BorrowFeeTooHigh {
+        rate: Decimal,
+        max_rate: Decimal,
+    },
+    /// An option held or traded has no known delta, so delta-adjusted exposure can't be measured
+    DeltaUnknown,
+}
+
+impl DenyReason {
+    /// Whether the intent could pass with a smaller quantity.
+    pub fn is_size_limit(&self) -> bool {
+        matches!(
+            self,
+            DenyReason::InsufficientBuyingPower { .. }
+                | DenyReason::NotionalLimitExceeded { .. }
+                | DenyReason::GrossExposureLimitExceeded { .. }
+                | DenyReason::NetExposureLimitExceeded { .. }
+                | DenyReason::ConcentrationLimitExceeded { .. }
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
@@ -38,72 +262,730 @@ pub enum DenyReason {
 pub enum RiskCheckResponse {
     Granted {
         intent: TradeIntent,
+        /// Slippage-adjusted price used to size market orders
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        buffered_price: Option<Decimal>,
+        /// Where the last price `buffered_price` was derived from came from
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        price_source: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        score: Option<RiskScore>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metrics: Option<TradeMetrics>,
+        #[serde(flatten, default, skip_serializing_if = "Option::is_none")]
+        timing: Option<DecisionTiming>,
     },
     Denied {
         intent: TradeIntent,
-        reason: DenyReason,
+        reasons: Vec<DenyReason>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        buffered_price: Option<Decimal>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        price_source: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        score: Option<RiskScore>,
+        #[serde(flatten, default, skip_serializing_if = "Option::is_none")]
+        timing: Option<DecisionTiming>,
+    },
+    /// The intent was too large, but a smaller quantity was granted.
+    Amended {
+        original: TradeIntent,
+        approved_qty: isize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        buffered_price: Option<Decimal>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        price_source: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        score: Option<RiskScore>,
+        #[serde(flatten, default, skip_serializing_if = "Option::is_none")]
+        timing: Option<DecisionTiming>,
     },
 }
 
+impl RiskCheckResponse {
+    pub fn intent(&self) -> &TradeIntent {
+        match self {
+            RiskCheckResponse::Granted { intent, .. } => intent,
+            RiskCheckResponse::Denied { intent, .. } => intent,
+            RiskCheckResponse::Amended { original, .. } => original,
+        }
+    }
+
+    /// The intent as it was granted, if any part of it was.
+    pub fn granted_intent(&self) -> Option<TradeIntent> {
+        match self {
+            RiskCheckResponse::Granted { intent, .. } => Some(intent.clone()),
+            RiskCheckResponse::Denied { .. } => None,
+            RiskCheckResponse::Amended {
+                original,
+                approved_qty,
+                ..
+            } => {
+                let mut intent = original.clone();
+                intent.qty = *approved_qty;
+                Some(intent)
+            }
+        }
+    }
+
+    fn into_parts(
+        self,
+    ) -> (
+        TradeIntent,
+        Option<Decimal>,
+        Option<String>,
+        Option<RiskScore>,
+        Option<DecisionTiming>,
+    ) {
+        match self {
+            RiskCheckResponse::Granted {
+                intent,
+                buffered_price,
+                price_source,
+                score,
+                timing,
+                ..
+            }
+            | RiskCheckResponse::Denied {
+                intent,
+                buffered_price,
+                price_source,
+                score,
+                timing,
+                ..
+            }
+            | RiskCheckResponse::Amended {
+                original: intent,
+                buffered_price,
+                price_source,
+                score,
+                timing,
+                ..
+            } => (intent, buffered_price, price_source, score, timing),
+        }
+    }
+
+    /// Turns the decision into a denial of the original intent.
+    pub fn into_denied(self, reasons: Vec<DenyReason>) -> RiskCheckResponse {
+        let (intent, buffered_price, price_source, score, timing) = self.into_parts();
+        RiskCheckResponse::Denied {
+            intent,
+            reasons,
+            buffered_price,
+            price_source,
+            score,
+            timing,
+        }
+    }
+
+    /// The same decision for a different intent. The new decision hasn't been timed yet.
+    pub fn with_intent(self, intent: TradeIntent) -> RiskCheckResponse {
+        match self {
+            RiskCheckResponse::Granted {
+                buffered_price,
+                price_source,
+                score,
+                metrics,
+                ..
+            } => RiskCheckResponse::Granted {
+                intent,
+                buffered_price,
+                price_source,
+                score,
+                metrics,
+                timing: None,
+            },
+            RiskCheckResponse::Denied {
+                reasons,
+                buffered_price,
+                price_source,
+                score,
+                ..
+            } => RiskCheckResponse::Denied {
+                intent,
+                reasons,
+                buffered_price,
+                price_source,
+                score,
+                timing: None,
+            },
+            RiskCheckResponse::Amended {
+                approved_qty,
+                buffered_price,
+                price_source,
+                score,
+                ..
+            } => RiskCheckResponse::Amended {
+                original: intent,
+                approved_qty,
+                buffered_price,
+                price_source,
+                score,
+                timing: None,
+            },
+        }
+    }
+
+    /// Stamps the decision as made now, for an input received at `received_at`.
+    pub fn timed(mut self, received_at: DateTime<Utc>) -> RiskCheckResponse {
+        match &mut self {
+            RiskCheckResponse::Granted { timing, .. }
+            | RiskCheckResponse::Denied { timing, .. }
+            | RiskCheckResponse::Amended { timing, .. } => {
+                *timing = Some(DecisionTiming::since(received_at))
+            }
+        }
+        self
+    }
+
+    pub fn timing(&self) -> Option<&DecisionTiming> {
+        match self {
+            RiskCheckResponse::Granted { timing, .. }
+            | RiskCheckResponse::Denied { timing, .. }
+            | RiskCheckResponse::Amended { timing, .. } => timing.as_ref(),
+        }
+    }
+
+    /// Turns the decision into a grant of the original intent. Metrics are only kept if the
+    /// decision already was a grant.
+    pub fn into_granted(self) -> RiskCheckResponse {
+        if let RiskCheckResponse::Granted { .. } = self {
+            return self;
+        }
+        let (intent, buffered_price, price_source, score, timing) = self.into_parts();
+        RiskCheckResponse::Granted {
+            intent,
+            buffered_price,
+            price_source,
+            score,
+            metrics: None,
+            timing,
+        }
+    }
+
+    pub fn metrics(&self) -> Option<&TradeMetrics> {
+        match self {
+            RiskCheckResponse::Granted { metrics, .. } => metrics.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn score(&self) -> Option<&RiskScore> {
+        match self {
+            RiskCheckResponse::Granted { score, .. }
+            | RiskCheckResponse::Denied { score, .. }
+            | RiskCheckResponse::Amended { score, .. } => score.as_ref(),
+        }
+    }
+
+    pub fn buffered_price(&self) -> Option<Decimal> {
+        match self {
+            RiskCheckResponse::Granted { buffered_price, .. }
+            | RiskCheckResponse::Denied { buffered_price, .. }
+            | RiskCheckResponse::Amended { buffered_price, .. } => *buffered_price,
+        }
+    }
+
+    pub fn price_source(&self) -> Option<&str> {
+        match self {
+            RiskCheckResponse::Granted { price_source, .. }
+            | RiskCheckResponse::Denied { price_source, .. }
+            | RiskCheckResponse::Amended { price_source, .. } => price_source.as_deref(),
+        }
+    }
+}
+
 impl RiskManager {
-    pub fn new(datastore_url: String) -> Self {
+    /// Looks prices up in the datastore until other price sources are bound. If its client can't
+    /// be built, lookups fail until then.
+    pub fn new(datastore_url: String, settings: RiskSettings) -> Self {
+        #[cfg(feature = "reqwest")]
+        let price_sources = PriceSources::datastore(datastore_url).unwrap_or_else(|e| {
+            error!(?e, "Failed to build the datastore price source");
+            PriceSources::default()
+        });
+        #[cfg(not(feature = "reqwest"))]
+        let price_sources = {
+            let _ = datastore_url;
+            PriceSources::default()
+        };
         Self {
-            kafka_consumer: None,
+            #[cfg(feature = "alpaca")]
             alpaca_client: None,
             cash: Decimal::ZERO,
             holdings: HashMap::new(),
+            avg_costs: HashMap::new(),
+            tax_lots: TaxLots::default(),
+            drawdown: DrawdownBreaker::default(),
+            day_trade_calls: DayTradeCalls::default(),
+            flattening: HashSet::new(),
             is_pattern_day_trader: false,
+            account_multiplier: None,
+            shorting_disabled: false,
+            account_blocks: AccountBlocks::default(),
+            session_day: None,
             last_equity: Decimal::ZERO,
             last_maintenance_margin: Decimal::ZERO,
-            datastore_url,
+            price_sources: Arc::new(price_sources),
+            prices: PriceCache::default(),
+            assets: AssetCache::default(),
+            borrow_rates: HashMap::new(),
+            deltas: HashMap::new(),
+            is_cash_account: false,
+            settlement_ledger: SettlementLedger::default(),
+            day_trades: DayTradeTracker::default(),
+            recent_decisions: RecentCache::new(settings.duplicate_intent_cache_size),
+            working_orders: WorkingOrders::default(),
+            rules: RulePipeline::from_kinds(&settings.rules),
+            limits: SharedLimits::default(),
+            evaluation_cache: EvaluationCache::new(&settings.evaluation_cache),
+            halts: TradingHalts::default(),
+            applied_controls: AppliedControls::default(),
+            shard: Shard::default(),
+            latency: Default::default(),
+            clock: SharedClock::default(),
+            state_version: 0,
+            settings,
         }
     }
 
+    #[cfg(feature = "alpaca")]
     pub async fn initialize(&mut self) -> Result<()> {
         if let Some(client) = self.alpaca_client.as_ref() {
-            let account = client.send(GetAccount).await?;
+            let broker = |e| RiskManagerError::Broker(Box::new(e));
+            let account = client.send(GetAccount).await.map_err(broker)?;
             let holdings = client
                 .send(GetPositions)
-                .await?
+                .await
+                .map_err(broker)?
                 .into_iter()
+                .filter(|pos| pos.qty != 0)
                 .map(|pos| {
                     let shares = Decimal::from_i32(pos.qty).unwrap();
                     (pos.symbol, (Shares(shares), Price(pos.avg_entry_price)))
                 })
                 .collect();
             self.cash = account.cash;
+            self.avg_costs = Self::prices(&holdings);
             self.holdings = holdings;
+            self.seed_tax_lots();
             self.is_pattern_day_trader = account.pattern_day_trader;
+            self.account_multiplier = Some(account.multiplier);
+            self.shorting_disabled = !account.shorting_enabled;
+            self.account_blocks = AccountBlocks {
+                trading_blocked: account.trading_blocked,
+                account_blocked: account.account_blocked,
+                trade_suspended_by_user: account.trade_suspended_by_user,
+            };
             self.last_equity = account.last_equity;
             self.last_maintenance_margin = account.last_maintenance_margin;
+            self.session_day = Some(self.clock.today());
+            self.is_cash_account = account.multiplier == Decimal::ONE;
+            self.settlement_ledger = SettlementLedger::new(account.cash);
+            self.day_trades = DayTradeTracker::new(account.daytrade_count as usize);
+            self.watch_holdings();
+            self.touch();
             Ok(())
         } else {
-            Err(anyhow!("Alpaca client not initialized"))
+            Err(RiskManagerError::Configuration(
+                "Alpaca client not initialized".into(),
+            ))
+        }
+    }
+
+    #[cfg(not(feature = "alpaca"))]
+    pub async fn initialize(&mut self) -> Result<()> {
+        Err(RiskManagerError::Configuration(
+            "risk-manager was built without the `alpaca` feature".into(),
+        ))
+    }
+
+    /// Starts a new session the first time the market opens on a new day. The previous close's
+    /// equity and maintenance margin are fetched from the broker again, or rolled over from the
+    /// current figures without one, and the counters that only last a day are reset.
+    pub async fn roll_session(&mut self) -> Result<()> {
+        let today = self.clock.today();
+        if self.session_day == Some(today) {
+            return Ok(());
+        }
+        if !self.refresh_account().await? {
+            self.last_equity = self.equity();
+            self.last_maintenance_margin = self.maintenance_margin();
+        }
+        self.session_day = Some(today);
+        self.settlement_ledger.settle(today);
+        self.flattening.clear();
+        let expired = self.working_orders.expire_day_orders();
+        if expired > 0 {
+            info!(expired, "Day orders expired");
+        }
+        info!(
+            %today,
+            last_equity = %self.last_equity,
+            last_maintenance_margin = %self.last_maintenance_margin,
+            "New session"
+        );
+        self.touch();
+        Ok(())
+    }
+
+    /// Fetches the figures of the account that change from one session to the next. Returns
+    /// `false` without a broker to fetch them from.
+    #[cfg(feature = "alpaca")]
+    async fn refresh_account(&mut self) -> Result<bool> {
+        let client = match self.alpaca_client.as_ref() {
+            Some(client) => client,
+            None => return Ok(false),
+        };
+        let account = client
+            .send(GetAccount)
+            .await
+            .map_err(|e| RiskManagerError::Broker(Box::new(e)))?;
+        self.is_pattern_day_trader = account.pattern_day_trader;
+        self.account_multiplier = Some(account.multiplier);
+        self.shorting_disabled = !account.shorting_enabled;
+        self.account_blocks = AccountBlocks {
+            trading_blocked: account.trading_blocked,
+            account_blocked: account.account_blocked,
+            trade_suspended_by_user: account.trade_suspended_by_user,
+        };
+        self.last_equity = account.last_equity;
+        self.last_maintenance_margin = account.last_maintenance_margin;
+        self.day_trades = DayTradeTracker::new(account.daytrade_count as usize);
+        // Fills account for every other change in cash, so any more cash the broker holds was
+        // deposited since the last refresh
+        let deposited = account.cash - self.cash;
+        if deposited > Decimal::ZERO {
+            self.record_deposit(deposited);
+        }
+        Ok(true)
+    }
+
+    #[cfg(not(feature = "alpaca"))]
+    async fn refresh_account(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// The state to hand over to the instance replacing this one.
+    pub fn handoff_state(&self) -> HandoffState {
+        HandoffState {
+            saved_at: self.clock.now(),
+            cash: self.cash,
+            holdings: self.holdings.clone(),
+            avg_costs: self.avg_costs.clone(),
+            tax_lots: Some(self.tax_lots.clone()),
+            drawdown: self.drawdown.clone(),
+            day_trade_calls: self.day_trade_calls.clone(),
+            is_pattern_day_trader: self.is_pattern_day_trader,
+            account_multiplier: self.account_multiplier,
+            shorting_disabled: self.shorting_disabled,
+            account_blocks: self.account_blocks.clone(),
+            session_day: self.session_day,
+            last_equity: self.last_equity,
+            last_maintenance_margin: self.last_maintenance_margin,
+            is_cash_account: self.is_cash_account,
+            settlement_ledger: self.settlement_ledger.clone(),
+            day_trades: self.day_trades.clone(),
+            working_orders: self.working_orders.clone(),
+            recent_decisions: self
+                .recent_decisions
+                .entries()
+                .map(|(id, response)| (*id, response.clone()))
+                .collect(),
+            halts: self.halts.clone(),
+            applied_controls: self.applied_controls.clone(),
+            borrow_rates: self.borrow_rates.clone(),
+        }
+    }
+
+    /// Takes over the state of the previous instance instead of initializing from the broker.
+    pub fn restore(&mut self, state: HandoffState) {
+        info!(saved_at = %state.saved_at, "Restoring handed off state");
+        self.cash = state.cash;
+        self.holdings = state.holdings;
+        // States saved before closed positions were removed can still have them
+        self.holdings.retain(|_, (shares, _)| !shares.is_zero());
+        // States saved before average costs were tracked only have prices
+        self.avg_costs = if state.avg_costs.is_empty() {
+            Self::prices(&self.holdings)
+        } else {
+            state.avg_costs
+        };
+        self.drawdown = state.drawdown;
+        self.day_trade_calls = state.day_trade_calls;
+        match state.tax_lots {
+            Some(tax_lots) => self.tax_lots = tax_lots,
+            None => self.seed_tax_lots(),
         }
+        self.is_pattern_day_trader = state.is_pattern_day_trader;
+        self.account_multiplier = state.account_multiplier;
+        self.shorting_disabled = state.shorting_disabled;
+        self.account_blocks = state.account_blocks;
+        self.session_day = state.session_day;
+        self.last_equity = state.last_equity;
+        self.last_maintenance_margin = state.last_maintenance_margin;
+        self.is_cash_account = state.is_cash_account;
+        self.settlement_ledger = state.settlement_ledger;
+        self.day_trades = state.day_trades;
+        self.working_orders = state.working_orders;
+        for (id, response) in state.recent_decisions {
+            self.recent_decisions.insert(id, response);
+        }
+        self.halts = state.halts;
+        self.applied_controls = state.applied_controls;
+        self.borrow_rates = state.borrow_rates;
+        self.watch_holdings();
+        self.touch();
+    }
+
+    pub fn bind_latency(&mut self, latency: Arc<LatencyStats>) {
+        self.latency = latency;
+    }
+
+    /// Replaces the system clock, e.g. with a `ManualClock` in tests.
+    pub fn bind_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = SharedClock::new(clock);
+        self.touch();
+    }
+
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
     }
 
+    #[cfg(feature = "alpaca")]
     pub fn bind_alpaca_client(&mut self, client: Client) {
         self.alpaca_client = Some(client)
     }
 
-    pub fn bind_consumer(&mut self, consumer: StreamConsumer) {
-        self.kafka_consumer = Some(consumer)
+    /// Shares a price cache with a price feed, which keeps the prices of the tickers held or
+    /// traded here up to date.
+    pub fn bind_prices(&mut self, prices: PriceCache) {
+        self.prices = prices;
+        self.watch_holdings();
+    }
+
+    /// Replaces where prices are looked up, e.g. with the sources configured.
+    pub fn bind_price_sources(&mut self, price_sources: Arc<PriceSources>) {
+        self.price_sources = price_sources;
+    }
+
+    /// Looks up whether assets can be traded before checking intents for them.
+    pub fn bind_assets(&mut self, assets: AssetCache) {
+        self.assets = assets;
+        self.touch();
+    }
+
+    /// What the broker allows for `ticker`, if assets are looked up and its record has been
+    /// fetched.
+    pub fn asset(&self, ticker: &str) -> Option<Asset> {
+        self.assets.get(ticker)
+    }
+
+    /// Whether `ticker` is traded as crypto.
+    pub fn is_crypto(&self, ticker: &str) -> bool {
+        self.settings
+            .crypto
+            .as_ref()
+            .map(|crypto| crypto.symbols.contains(ticker))
+            .unwrap_or(false)
+    }
+
+    /// The option contract `ticker` stands for, if options are traded and it is an OCC symbol.
+    pub fn option_contract(&self, ticker: &str) -> Option<OptionContract> {
+        self.settings.options.as_ref()?;
+        OptionContract::parse(ticker)
+    }
+
+    /// Shares of the underlying per unit of `ticker`: the contract multiplier of options, and 1
+    /// otherwise.
+    pub fn contract_multiplier(&self, ticker: &str) -> Decimal {
+        match self.settings.options.as_ref() {
+            Some(options) if OptionContract::parse(ticker).is_some() => options.multiplier,
+            _ => Decimal::ONE,
+        }
+    }
+
+    /// Whether positions in `ticker` can be bought on margin. Assets other than crypto and
+    /// options are marginable unless their cached record says otherwise. Margin is computed over
+    /// every holding, so assets are never looked up here.
+    pub fn is_marginable(&self, ticker: &str) -> bool {
+        !self.is_crypto(ticker)
+            && self.option_contract(ticker).is_none()
+            && self
+                .asset(ticker)
+                .map(|asset| asset.marginable)
+                .unwrap_or(true)
+    }
+
+    /// Lets the price cache know which tickers are held.
+    fn watch_holdings(&self) {
+        self.prices.set_held(self.holdings.keys());
+    }
+
+    pub fn bind_limits(&mut self, limits: SharedLimits) {
+        self.limits = limits;
+        self.touch();
+    }
+
+    fn touch(&mut self) {
+        self.state_version += 1
+    }
+
+    pub fn apply_control(&mut self, command: &ControlCommand) {
+        self.halts.apply(command);
+        self.touch();
+    }
+
+    /// Replaces the trading halts, e.g. with those of a saved state.
+    pub fn bind_halts(&mut self, halts: TradingHalts) {
+        self.halts = halts;
+        self.touch();
+    }
+
+    pub fn halts(&self) -> &TradingHalts {
+        &self.halts
+    }
+
+    pub fn rule_stats(&self) -> Arc<RuleStats> {
+        self.rules.stats()
+    }
+
+    pub fn evaluation_cache_stats(&self) -> Arc<EvaluationCacheStats> {
+        self.evaluation_cache.stats()
     }
 
     #[tracing::instrument(skip(self, cash))]
     pub fn update_cash(&mut self, cash: Decimal) {
         trace!(%cash, "Updating cash");
-        self.cash = cash
+        self.cash = cash;
+        self.touch();
     }
 
     #[tracing::instrument(skip(self, ticker, price))]
     pub fn update_price<T: ToString + std::fmt::Display>(&mut self, ticker: T, price: Price) {
-        trace!(%ticker, price = %price.0, "Updating price");
+        trace!(%ticker, %price, "Updating price");
+        // Marks move constantly, so they are left to the expiry of cached decisions rather than
+        // invalidating them
         self.holdings
             .entry(ticker.to_string())
             .and_modify(|(_, p)| *p = price);
     }
 
+    /// Marks a position at a price from the prices topic, and keeps the price to value market
+    /// orders with. Ticks older than the cached one are ignored, so that positions aren't marked
+    /// back to a stale price.
+    pub fn apply_price_update(&mut self, ticker: &str, tick: PriceTick) {
+        if self.prices.update(ticker, tick, self.clock.now()) {
+            self.update_price(ticker, Price(tick.price));
+        }
+    }
+
+    /// Records the annualized borrow rate of `ticker` from the borrow rates topic.
+    pub fn update_borrow_rate(&mut self, ticker: &str, rate: Decimal) {
+        trace!(%ticker, %rate, "Updating borrow rate");
+        self.borrow_rates.insert(ticker.to_string(), rate);
+        self.touch();
+    }
+
+    /// Annualized borrow rate of `ticker`, from the topic or else the settings, if known.
+    pub fn borrow_rate(&self, ticker: &str) -> Option<Decimal> {
+        self.borrow_rates
+            .get(ticker)
+            .or_else(|| self.settings.borrow_fees.rates.get(ticker))
+            .copied()
+    }
+
+    /// Records the delta of an option contract from the greeks topic.
+    pub fn update_delta(&mut self, ticker: &str, delta: Decimal) {
+        trace!(%ticker, %delta, "Updating delta");
+        self.deltas.insert(ticker.to_string(), delta);
+        self.touch();
+    }
+
+    /// Delta of an option contract, from the topic or else the settings.
+    fn delta(&self, ticker: &str) -> Option<Decimal> {
+        self.deltas
+            .get(ticker)
+            .or_else(|| {
+                self.settings
+                    .options
+                    .as_ref()
+                    .and_then(|options| options.deltas.get(ticker))
+            })
+            .copied()
+    }
+
+    /// Whether exposure limits apply to delta-adjusted exposure, which they do once options are
+    /// traded.
+    pub fn is_delta_adjusted(&self) -> bool {
+        self.settings.options.is_some()
+    }
+
+    /// Underlier and delta-adjusted notional of `qty` of `ticker` at `price`. Options count as
+    /// the shares they control times their delta, at the underlier's price. There is no
+    /// delta-adjusted notional for an option whose delta isn't known, as any delta assumed for it
+    /// could understate the exposure it adds.
+    pub fn delta_notional(
+        &self,
+        ticker: &str,
+        qty: Decimal,
+        price: Decimal,
+    ) -> Option<(String, Decimal)> {
+        match self.option_contract(ticker) {
+            Some(contract) => {
+                let delta = match self.delta(ticker) {
+                    Some(delta) => delta,
+                    None => {
+                        debug!(%ticker, "Delta unknown");
+                        return None;
+                    }
+                };
+                let shares = qty * self.contract_multiplier(ticker) * delta;
+                let notional = shares * self.underlying_price(&contract);
+                Some((contract.underlying, notional))
+            }
+            None => Some((ticker.to_string(), qty * price)),
+        }
+    }
+
+    fn delta_exposures_of<'a>(
+        &self,
+        holdings: impl Iterator<Item = (&'a String, &'a (Shares, Price))>,
+    ) -> Option<HashMap<String, Decimal>> {
+        let mut exposures: HashMap<String, Decimal> = HashMap::new();
+        for (ticker, (shares, price)) in holdings {
+            let (underlier, notional) = self.delta_notional(ticker, shares.0, price.0)?;
+            *exposures.entry(underlier).or_default() += notional;
+        }
+        Some(exposures)
+    }
+
+    /// Delta-adjusted notional of the positions held, netted by underlier, unless an option held
+    /// has no known delta.
+    pub fn delta_exposures(&self) -> Option<HashMap<String, Decimal>> {
+        self.delta_exposures_of(self.valued_holdings())
+    }
+
+    /// Gross and net market exposure once `notional` is added to `underlier`, with the positions
+    /// of every instance taken at their delta-adjusted notional and netted by underlier. Unknown
+    /// if an option held here or by another instance has no known delta.
+    pub fn delta_adjusted_exposure(
+        &self,
+        underlier: &str,
+        notional: Decimal,
+    ) -> Option<(Decimal, Decimal)> {
+        let mut exposures = self.delta_exposures()?;
+        for peer in self.shard.peers() {
+            for (peer_underlier, exposure) in peer.delta_exposures.as_ref()?.iter() {
+                *exposures.entry(peer_underlier.clone()).or_default() += *exposure;
+            }
+        }
+        *exposures.entry(underlier.to_string()).or_default() += notional;
+        let gross = exposures.values().map(|exposure| exposure.abs()).sum();
+        let net = exposures.values().sum();
+        Some((gross, net))
+    }
+
     #[tracing::instrument(skip(self, ticker, shares, price))]
     pub fn update_holdings<T: ToString + std::fmt::Display>(
         &mut self,
@@ -111,389 +993,2731 @@ impl RiskManager {
         shares: Shares,
         price: Price,
     ) {
-        trace!(%ticker, shares = %shares.0, price = %price.0, "Updating holdings");
-        self.holdings
-            .entry(ticker.to_string())
+        trace!(%ticker, %shares, %price, "Updating holdings");
+        let ticker = ticker.to_string();
+        self.update_avg_cost(ticker.clone(), shares, price);
+        let (held, _) = self
+            .holdings
+            .entry(ticker.clone())
             .and_modify(|(s, p)| {
-                *s = Shares(s.0 + shares.0);
+                *s += shares;
                 *p = price
             })
             .or_insert((shares, price));
-        self.cash -= shares.0 * price.0;
+        // Closed positions are forgotten, so that they don't count as held
+        if held.is_zero() {
+            self.holdings.remove(&ticker);
+        }
+        self.cash -= self.market_value(&ticker, &shares, &price);
+        self.watch_holdings();
+        self.touch();
     }
 
-    pub fn long_market_exposure(&self) -> Decimal {
-        self.holdings
-            .values()
-            .filter(|(s, _)| s.0.is_sign_positive())
-            .fold(Decimal::ZERO, |state, (shares, price)| {
-                state + shares.0 * price.0
-            })
+    /// Averages in the price of shares added to a position. Reducing a position leaves its
+    /// average cost as it is, and flipping it starts over at `price`.
+    fn update_avg_cost(&mut self, ticker: String, shares: Shares, price: Price) {
+        let held = self
+            .holdings
+            .get(&ticker)
+            .map(|(shares, _)| *shares)
+            .unwrap_or_default();
+        let total = held + shares;
+        if total.is_zero() {
+            self.avg_costs.remove(&ticker);
+            return;
+        }
+        let avg_cost = match self.avg_costs.get(&ticker) {
+            Some(avg_cost) if !held.is_zero() && !held.is_opposite(total) => {
+                if total.abs() <= held.abs() {
+                    *avg_cost
+                } else {
+                    let cost = *avg_cost * held + price * shares;
+                    Price(self.settings.rounding.price(cost / total.0))
+                }
+            }
+            _ => price,
+        };
+        self.avg_costs.insert(ticker, avg_cost);
     }
 
-    pub fn short_market_exposure(&self) -> Decimal {
-        self.holdings
-            .values()
-            .filter(|(s, _)| s.0.is_sign_negative())
-            .fold(Decimal::ZERO, |state, (shares, price)| {
-                state + -shares.0 * price.0
-            })
+    /// Starts the lots over at one per holding, at its average cost, for when the fills behind the
+    /// holdings aren't known.
+    fn seed_tax_lots(&mut self) {
+        let avg_costs = &self.avg_costs;
+        let positions = self.holdings.iter().map(|(ticker, (shares, price))| {
+            let avg_cost = avg_costs.get(ticker).unwrap_or(price);
+            (ticker, shares.0, avg_cost.0)
+        });
+        self.tax_lots = TaxLots::seeded(positions, self.clock.now());
     }
 
-    pub fn gross_market_exposure(&self) -> Decimal {
-        self.holdings
-            .values()
-            .fold(Decimal::ZERO, |state, (shares, price)| {
-                state + shares.0.abs() * price.0
-            })
+    /// Prices of some holdings, for when they are all that is known of their average cost.
+    fn prices(holdings: &HashMap<String, (Shares, Price)>) -> HashMap<String, Price> {
+        holdings
+            .iter()
+            .map(|(ticker, (_, price))| (ticker.clone(), *price))
+            .collect()
     }
 
-    pub fn net_market_exposure(&self) -> Decimal {
-        self.holdings
-            .values()
-            .fold(Decimal::ZERO, |state, (shares, price)| {
-                state + shares.0 * price.0
-            })
+    #[tracing::instrument(skip(self, lot), fields(id = %lot.id))]
+    pub fn process_lot(&mut self, lot: Lot) {
+        let date = lot.fill_time.date().naive_utc();
+        let shares = self.settings.rounding.quantity(lot.shares);
+        let notional = self
+            .settings
+            .rounding
+            .money(shares.abs() * lot.price * self.contract_multiplier(&lot.ticker));
+        // Crypto settles right away and isn't subject to PDT rules
+        let is_crypto = self.is_crypto(&lot.ticker);
+        if shares.is_sign_positive() {
+            self.settlement_ledger
+                .record_purchase(&lot.ticker, shares, notional, date);
+        } else if is_crypto {
+            self.settlement_ledger.record_settled_sale(notional, date);
+        } else {
+            self.settlement_ledger
+                .record_sale(&lot.ticker, shares.abs(), notional, date);
+        }
+        if !is_crypto {
+            if self.is_closing(&lot.ticker, shares) {
+                self.day_trades.record_close(&lot.ticker, date);
+            } else {
+                self.day_trades.record_open(&lot.ticker, date);
+            }
+        }
+        self.working_orders.fill(&lot.order_id, shares);
+        let fill = Lot {
+            shares,
+            ..lot.clone()
+        };
+        let multiplier = self.contract_multiplier(&lot.ticker);
+        for close in self.tax_lots.record(&fill, multiplier, self.trading_day()) {
+            debug!(
+                ticker = %close.ticker,
+                shares = %close.shares,
+                realized_pnl = %close.realized_pnl,
+                "Closed lot"
+            );
+        }
+        self.update_holdings(lot.ticker, Shares(shares), Price(lot.price));
     }
 
-    pub fn equity(&self) -> Decimal {
-        self.net_market_exposure() + self.cash
+    /// Updates state that depends on decisions rather than fills, such as positions opened by
+    /// intents that haven't been filled yet.
+    pub fn record_decision(&mut self, response: &RiskCheckResponse) {
+        if let Some(intent) = response.granted_intent() {
+            let qty = Decimal::from_isize(intent.qty).unwrap_or_default();
+            if !self.is_closing(&intent.ticker, qty) {
+                self.day_trades
+                    .record_open(&intent.ticker, self.clock.today());
+            }
+            let price = Self::granted_price(&intent, response.buffered_price());
+            self.working_orders.insert(intent.id, intent, price);
+        }
+        self.recent_decisions
+            .insert(response.intent().id, response.clone());
+        self.touch();
     }
 
-    pub fn initial_margin(&self) -> Decimal {
-        self.holdings
-            .values()
-            .fold(Decimal::ZERO, |state, (shares, price)| {
-                state + shares.0.abs() * price.0 * Decimal::new(5, 1)
-            })
+    /// Releases what is left of the reservation of a canceled or expired order.
+    pub fn cancel_order(&mut self, id: &Uuid) {
+        if self.working_orders.remove(id).is_some() {
+            debug!(%id, "Working order canceled");
+            self.touch();
+        }
     }
 
-    pub fn maintenance_margin(&self) -> Decimal {
-        self.holdings
-            .values()
-            .fold(Decimal::ZERO, |state, (shares, price)| {
-                let factor = if shares.0.is_sign_positive() {
-                    if price.0 >= Decimal::new(25, 1) {
-                        Decimal::new(3, 1)
-                    } else {
-                        Decimal::ONE
-                    }
-                } else if price.0 >= Decimal::new(5, 0) {
-                    Decimal::new(3, 1)
-                } else {
-                    Decimal::ONE
-                };
-                state + shares.0.abs() * price.0 * factor
-            })
+    /// Like `record_decision`, but a granted amendment replaces the reservation of the working
+    /// order it amends instead of creating a new one.
+    pub fn record_amendment(&mut self, amendment: &OrderAmendment, response: &RiskCheckResponse) {
+        if let Some(intent) = response.granted_intent() {
+            let price = Self::granted_price(&intent, response.buffered_price());
+            self.working_orders.insert(amendment.amends, intent, price);
+        }
+        self.recent_decisions
+            .insert(response.intent().id, response.clone());
+        self.touch();
     }
 
-    pub fn multiplier(&self) -> Decimal {
-        let equity = self.equity();
-        if self.is_pattern_day_trader {
-            if equity < Decimal::new(2000, 0) {
-                Decimal::ONE
-            } else if equity < Decimal::new(25000, 0) {
-                Decimal::new(2, 0)
-            } else {
-                Decimal::new(4, 0)
-            }
-        } else if equity > Decimal::new(25000, 0) {
-            Decimal::new(2, 0)
-        } else {
-            Decimal::new(1, 0)
+    fn granted_price(intent: &TradeIntent, buffered_price: Option<Decimal>) -> Option<Decimal> {
+        match intent.order_type {
+            OrderType::Limit { limit_price } => Some(limit_price),
+            _ => buffered_price,
         }
     }
 
-    pub fn regt_buying_power(&self) -> Decimal {
-        ((self.equity() - self.initial_margin()) * Decimal::new(2, 0)).max(Decimal::ZERO)
+    /// Returns the decision previously made for the intent with the given id, if it is still
+    /// remembered. Kafka delivers at least once, so the same intent can be received twice.
+    pub fn previous_decision(&self, id: &Uuid) -> Option<&RiskCheckResponse> {
+        self.recent_decisions.get(id)
     }
 
-    pub fn daytrading_buying_power(&self) -> Decimal {
-        ((self.last_equity - self.last_maintenance_margin) * self.multiplier()
-            - self.gross_market_exposure())
-        .max(Decimal::ZERO)
+    /// Up to `n` decisions, most recent first.
+    pub fn recent_decisions(&self, n: usize) -> Vec<RiskCheckResponse> {
+        self.recent_decisions.latest().take(n).cloned().collect()
     }
 
-    pub fn buying_power(&self) -> Decimal {
-        self.regt_buying_power().max(self.daytrading_buying_power())
+    /// Buying power set aside for granted intents that haven't been completely filled.
+    pub fn reserved_buying_power(&self) -> Decimal {
+        self.own_reserved_buying_power() + self.shard.peer_total(|s| s.reserved_buying_power)
     }
 
-    #[tracing::instrument(skip(self, trade_intent), fields(id = %trade_intent.id))]
-    pub fn risk_check(&self, trade_intent: &TradeIntent) -> Result<RiskCheckResponse> {
-        debug!("Running risk_check");
-        let owned_shares = self.holdings.get(&trade_intent.ticker);
-        if let Some((shares, _)) = owned_shares {
-            let qty = Decimal::from_isize(trade_intent.qty)
-                .context("Failed to convert isize to Decimal")?;
-            if (qty.signum() * shares.0.signum()) == Decimal::new(-1, 0) {
-                // This is a closing trade
-                if qty.abs() > shares.0.abs() {
-                    trace!("Change in position, risk check denied");
-                    return Ok(RiskCheckResponse::Denied {
-                        intent: trade_intent.clone(),
-                        reason: DenyReason::ChangeInPositionSide,
-                    });
-                } else {
-                    trace!("Closing trade, risk check granted");
-                    return Ok(RiskCheckResponse::Granted {
-                        intent: trade_intent.clone(),
-                    });
-                }
-            }
+    /// Buying power set aside for the working orders of this instance.
+    fn own_reserved_buying_power(&self) -> Decimal {
+        self.working_orders
+            .total_reserved(|ticker| self.contract_multiplier(ticker))
+    }
+
+    /// Shares the account with other instances, each owning the tickers of its partitions.
+    /// Cash flows are counted from now, so instances should start from the same account state.
+    pub fn enable_sharding(&mut self, instance_id: String) {
+        info!(%instance_id, "Sharding by ticker");
+        self.shard = Shard::new(instance_id, self.cash);
+        self.touch();
+    }
+
+    /// Claims a ticker for this instance, returning whether it wasn't owned before.
+    pub fn claim_ticker(&mut self, ticker: &str) -> bool {
+        let claimed = self.shard.claim(ticker);
+        if claimed {
+            self.touch();
         }
-        let required_buying_power = match trade_intent.order_type {
-            OrderType::Limit { limit_price } => {
-                limit_price
-                    * Decimal::from_isize(trade_intent.qty.abs())
-                        .context("Failed to convert isize to Decimal")?
-            }
-            OrderType::Market => {
-                let url = format!("{}/last/{}", self.datastore_url, trade_intent.ticker);
-                let price: Decimal = reqwest::blocking::get(url)?.json()?;
-                price
-                    * Decimal::new(103, 2)
-                    * Decimal::from_isize(trade_intent.qty.abs())
-                        .context("Failed to convert isize to Decimal")?
+        claimed
+    }
+
+    /// Records the state of another instance. Only its fills and reservations invalidate cached
+    /// decisions, since its exposure and margin also move with its marks.
+    pub fn update_peer(&mut self, state: ShardState) {
+        let changed = self
+            .shard
+            .peer(&state.instance_id)
+            .map_or(true, |previous| {
+                previous.tickers != state.tickers
+                    || previous.cash_flow != state.cash_flow
+                    || previous.reserved_buying_power != state.reserved_buying_power
+            });
+        self.shard.update_peer(state);
+        if changed {
+            self.touch();
+        }
+    }
+
+    /// The state of the tickers this instance owns, to share with the others.
+    pub fn shard_state(&self) -> Option<ShardState> {
+        let instance_id = self.shard.instance_id()?.to_string();
+        let owned = self
+            .holdings
+            .iter()
+            .filter(|(ticker, _)| self.shard.owns(ticker));
+        let (long, short) = self.exposures(owned.clone());
+        Some(ShardState {
+            instance_id,
+            tickers: self.shard.owned().clone(),
+            cash_flow: self.cash - self.shard.starting_cash(),
+            long_market_exposure: long,
+            short_market_exposure: short,
+            initial_margin: self.initial_margin_of(owned.clone()),
+            maintenance_margin: self.maintenance_margin_of(owned),
+            reserved_buying_power: self.own_reserved_buying_power(),
+            delta_exposures: self.delta_exposures_of(owned.clone()),
+            updated_at: self.clock.now(),
+        })
+    }
+
+    /// Positions valued by this instance, which are all of them unless another instance owns
+    /// them.
+    fn valued_holdings(&self) -> impl Iterator<Item = (&String, &(Shares, Price))> + Clone {
+        self.holdings
+            .iter()
+            .filter(move |(ticker, _)| !self.shard.is_peer_owned(ticker))
+    }
+
+    pub(crate) fn is_closing(&self, ticker: &str, qty: Decimal) -> bool {
+        self.holdings
+            .get(ticker)
+            .map(|(shares, _)| shares.is_opposite(Shares(qty)))
+            .unwrap_or(false)
+    }
+
+    pub fn export_holdings(&self) -> Holdings {
+        let mut positions: Vec<Holding> = self
+            .holdings
+            .iter()
+            .map(|(ticker, (shares, price))| Holding {
+                ticker: ticker.clone(),
+                shares: shares.0,
+                price: price.0,
+            })
+            .collect();
+        positions.sort_by(|a, b| a.ticker.cmp(&b.ticker));
+        Holdings {
+            cash: self.cash,
+            positions,
+        }
+    }
+
+    /// Replaces cash and positions. Imported cash is treated as settled, and the reservations of
+    /// working orders and day trades made against the previous positions are dropped.
+    pub fn import_holdings(&mut self, holdings: Holdings) {
+        self.holdings = holdings
+            .positions
+            .into_iter()
+            .filter(|holding| !holding.shares.is_zero())
+            .map(|holding| {
+                (
+                    holding.ticker,
+                    (Shares(holding.shares), Price(holding.price)),
+                )
+            })
+            .collect();
+        self.avg_costs = Self::prices(&self.holdings);
+        self.seed_tax_lots();
+        self.cash = holdings.cash;
+        self.settlement_ledger = SettlementLedger::new(holdings.cash);
+        self.day_trades = DayTradeTracker::default();
+        self.working_orders = WorkingOrders::default();
+        self.watch_holdings();
+        self.touch();
+    }
+
+    /// Signed market value of a position, rounded like the broker's
+    fn market_value(&self, ticker: &str, shares: &Shares, price: &Price) -> Decimal {
+        self.settings
+            .rounding
+            .money(*shares * *price * self.contract_multiplier(ticker))
+    }
+
+    /// Long and short exposure of some positions.
+    fn exposures<'a>(
+        &self,
+        positions: impl Iterator<Item = (&'a String, &'a (Shares, Price))>,
+    ) -> (Decimal, Decimal) {
+        positions.fold(
+            (Decimal::ZERO, Decimal::ZERO),
+            |(long, short), (ticker, (shares, price))| {
+                let market_value = self.market_value(ticker, shares, price);
+                if shares.is_long() {
+                    (long + market_value, short)
+                } else {
+                    (long, short - market_value)
+                }
+            },
+        )
+    }
+
+    pub fn long_market_exposure(&self) -> Decimal {
+        self.exposures(self.valued_holdings()).0 + self.shard.peer_total(|s| s.long_market_exposure)
+    }
+
+    pub fn short_market_exposure(&self) -> Decimal {
+        self.exposures(self.valued_holdings()).1
+            + self.shard.peer_total(|s| s.short_market_exposure)
+    }
+
+    pub fn gross_market_exposure(&self) -> Decimal {
+        let (long, short) = self.exposures(self.valued_holdings());
+        long + short
+            + self
+                .shard
+                .peer_total(|s| s.long_market_exposure + s.short_market_exposure)
+    }
+
+    pub fn net_market_exposure(&self) -> Decimal {
+        let (long, short) = self.exposures(self.valued_holdings());
+        long - short
+            + self
+                .shard
+                .peer_total(|s| s.long_market_exposure - s.short_market_exposure)
+    }
+
+    /// Cash of the whole account, including the fills processed by other instances
+    pub fn cash(&self) -> Decimal {
+        self.cash + self.shard.peer_total(|s| s.cash_flow)
+    }
+
+    pub fn equity(&self) -> Decimal {
+        self.settings
+            .rounding
+            .money(self.net_market_exposure() + self.cash())
+    }
+
+    pub fn initial_margin(&self) -> Decimal {
+        self.initial_margin_of(self.valued_holdings()) + self.shard.peer_total(|s| s.initial_margin)
+    }
+
+    fn initial_margin_of<'a>(
+        &self,
+        positions: impl Iterator<Item = (&'a String, &'a (Shares, Price))>,
+    ) -> Decimal {
+        let (options, positions) = self.split_options(positions);
+        let options_margin = self.options_margin_of(&options);
+        let schedule = &self.settings.margin;
+        if schedule.method == MarginMethod::Portfolio {
+            return self.portfolio_margin_of(positions.into_iter()) + options_margin;
+        }
+        let margin =
+            positions
+                .into_iter()
+                .fold(Decimal::ZERO, |state, (ticker, (shares, price))| {
+                    let factor = if self.is_marginable(ticker) {
+                        schedule.initial(ticker)
+                    } else {
+                        Decimal::ONE
+                    };
+                    state + shares.abs() * *price * factor
+                });
+        self.settings.rounding.money(margin) + options_margin
+    }
+
+    pub fn maintenance_margin(&self) -> Decimal {
+        self.maintenance_margin_of(self.valued_holdings())
+            + self.shard.peer_total(|s| s.maintenance_margin)
+    }
+
+    fn maintenance_margin_of<'a>(
+        &self,
+        positions: impl Iterator<Item = (&'a String, &'a (Shares, Price))>,
+    ) -> Decimal {
+        let (options, positions) = self.split_options(positions);
+        let options_margin = self.options_margin_of(&options);
+        let schedule = &self.settings.margin;
+        if schedule.method == MarginMethod::Portfolio {
+            return self.portfolio_margin_of(positions.into_iter()) + options_margin;
+        }
+        let concentration = schedule
+            .concentration
+            .as_ref()
+            .map(|concentration| (concentration, self.gross_market_exposure(), self.equity()));
+        let margin =
+            positions
+                .into_iter()
+                .fold(Decimal::ZERO, |state, (ticker, (shares, price))| {
+                    let market_value = shares.abs() * *price;
+                    if !self.is_marginable(ticker) {
+                        return state + market_value;
+                    }
+                    let mut factor = schedule.maintenance(ticker, *shares, *price);
+                    if let Some((concentration, gross_exposure, equity)) = concentration {
+                        factor += concentration.add_on(market_value, gross_exposure, equity);
+                    }
+                    state + market_value * factor.min(Decimal::ONE)
+                });
+        self.settings.rounding.money(margin) + options_margin
+    }
+
+    /// Splits option positions from the others, which the margin schedule applies to.
+    #[allow(clippy::type_complexity)]
+    fn split_options<'a>(
+        &self,
+        positions: impl Iterator<Item = (&'a String, &'a (Shares, Price))>,
+    ) -> (
+        Vec<(&'a String, &'a (Shares, Price))>,
+        Vec<(&'a String, &'a (Shares, Price))>,
+    ) {
+        positions.partition(|(ticker, _)| self.option_contract(ticker).is_some())
+    }
+
+    /// Requirement of option positions under the option margin rules. Short calls are covered by
+    /// the shares of their underlying held, in order of ticker.
+    fn options_margin_of(&self, options: &[(&String, &(Shares, Price))]) -> Decimal {
+        let settings = match self.settings.options.as_ref() {
+            Some(settings) => settings,
+            None => return Decimal::ZERO,
+        };
+        let mut options: Vec<_> = options
+            .iter()
+            .filter_map(|(ticker, position)| {
+                Some((*ticker, self.option_contract(ticker)?, *position))
+            })
+            .collect();
+        options.sort_by(|a, b| a.0.cmp(b.0));
+        // Contracts' worth of shares of each underlying not yet covering a call
+        let mut cover: HashMap<String, Decimal> = HashMap::new();
+        let margin =
+            options
+                .into_iter()
+                .fold(Decimal::ZERO, |state, (_, contract, (shares, price))| {
+                    let underlying_price = self.underlying_price(&contract);
+                    let cover = cover.entry(contract.underlying.clone()).or_insert_with(|| {
+                        self.shares(&contract.underlying)
+                            .map(|shares| (shares / settings.multiplier).floor())
+                            .unwrap_or_default()
+                            .max(Decimal::ZERO)
+                    });
+                    let covered = if contract.right == OptionRight::Call && shares.is_short() {
+                        let covered = (*cover).min(shares.0.abs());
+                        *cover -= covered;
+                        covered
+                    } else {
+                        Decimal::ZERO
+                    };
+                    state
+                        + settings.requirement(
+                            &contract,
+                            shares.0,
+                            price.0,
+                            underlying_price,
+                            covered,
+                        )
+                });
+        self.settings.rounding.money(margin)
+    }
+
+    /// Last known price of the underlying of an option, or its strike if there is none.
+    fn underlying_price(&self, contract: &OptionContract) -> Decimal {
+        self.holdings
+            .get(&contract.underlying)
+            .map(|(_, price)| price.0)
+            .or_else(|| {
+                self.prices
+                    .get(&contract.underlying, self.clock.now())
+                    .map(|tick| tick.price)
+            })
+            .unwrap_or(contract.strike)
+    }
+
+    /// Under portfolio margin, the initial and maintenance requirements are both the stress loss.
+    /// Non-marginable positions are left out of the stress test and fully margined.
+    fn portfolio_margin_of<'a>(
+        &self,
+        positions: impl Iterator<Item = (&'a String, &'a (Shares, Price))>,
+    ) -> Decimal {
+        let (marginable, non_marginable): (Vec<_>, Vec<_>) =
+            positions.partition(|(ticker, _)| self.is_marginable(ticker));
+        let fully_margined: Decimal = non_marginable
+            .into_iter()
+            .map(|(_, (shares, price))| shares.abs() * *price)
+            .sum();
+        let margin = self
+            .settings
+            .margin
+            .portfolio_requirement(marginable.into_iter().map(|(ticker, (shares, price))| {
+                (ticker.as_str(), self.market_value(ticker, shares, price))
+            }));
+        self.settings.rounding.money(margin + fully_margined)
+    }
+
+    /// The broker's multiplier once the account has been fetched, or an estimate from equity
+    /// until then.
+    pub fn multiplier(&self) -> Decimal {
+        if let Some(multiplier) = self.account_multiplier {
+            return multiplier;
+        }
+        let settings = &self.settings.multiplier;
+        let equity = self.equity();
+        if self.is_pattern_day_trader {
+            if equity < settings.min_margin_equity {
+                Decimal::ONE
+            } else if equity < settings.pdt_equity {
+                Decimal::new(2, 0)
+            } else {
+                Decimal::new(4, 0)
+            }
+        } else if equity > settings.pdt_equity {
+            Decimal::new(2, 0)
+        } else {
+            Decimal::new(1, 0)
+        }
+    }
+
+    /// Whether the broker allows the account to open short positions.
+    pub fn is_shorting_enabled(&self) -> bool {
+        !self.shorting_disabled
+    }
+
+    /// Equity in excess of the initial margin of the positions held.
+    pub fn excess_equity(&self) -> Decimal {
+        let excess_equity = self.equity() - self.initial_margin();
+        self.settings
+            .rounding
+            .money(excess_equity.max(Decimal::ZERO))
+    }
+
+    /// How much buying `qty` more of `ticker` at `price` adds to the initial margin.
+    pub fn initial_margin_change(&self, ticker: &str, qty: Decimal, price: Decimal) -> Decimal {
+        self.projection(ticker, qty, price).initial_margin() - self.initial_margin()
+    }
+
+    /// Whether requirements are the stress loss of the portfolio, rather than Reg-T margin.
+    pub fn is_portfolio_margin(&self) -> bool {
+        self.settings.margin.method == MarginMethod::Portfolio
+    }
+
+    /// Buying power an order for `ticker` uses per unit of notional. Buying power is stated at
+    /// the schedule's default initial margin, so symbols with a higher one use more of it, e.g.
+    /// 2 at 100% initial margin, where the default is 50%.
+    pub fn buying_power_factor(&self, ticker: &str) -> Decimal {
+        let schedule = &self.settings.margin;
+        if !self.is_marginable(ticker) || schedule.initial.is_zero() || self.is_portfolio_margin() {
+            return Decimal::ONE;
+        }
+        schedule.initial(ticker) / schedule.initial
+    }
+
+    /// Twice the excess equity under Reg-T. Under portfolio margin, where the requirement
+    /// already is the stress loss, it is the excess equity itself.
+    pub fn regt_buying_power(&self) -> Decimal {
+        if self.is_portfolio_margin() {
+            return self.excess_equity();
+        }
+        let buying_power = (self.equity() - self.initial_margin()) * Decimal::new(2, 0);
+        self.settings
+            .rounding
+            .money(buying_power.max(Decimal::ZERO))
+    }
+
+    /// Multiplier of the excess equity allowed for day trading, restricted while a day-trading
+    /// call is outstanding.
+    fn daytrading_multiplier(&self) -> Decimal {
+        self.day_trade_calls
+            .restrict(self.multiplier(), self.clock.today())
+    }
+
+    /// Gross exposure allowed before day-trading buying power runs out.
+    fn daytrading_limit(&self) -> Decimal {
+        (self.last_equity - self.last_maintenance_margin) * self.daytrading_multiplier()
+    }
+
+    /// Under portfolio margin, day trades need the same stress requirement as other trades, so
+    /// there is no separate day-trading buying power beyond the excess equity.
+    pub fn daytrading_buying_power(&self) -> Decimal {
+        if self.is_portfolio_margin() {
+            return self.excess_equity();
+        }
+        let buying_power = self.daytrading_limit() - self.gross_market_exposure();
+        self.settings
+            .rounding
+            .money(buying_power.max(Decimal::ZERO))
+    }
+
+    pub fn buying_power(&self) -> Decimal {
+        self.regt_buying_power().max(self.daytrading_buying_power())
+    }
+
+    /// Buying power for assets that can't be bought on margin: settled and unsettled cash, up to
+    /// the buying power of the account.
+    pub fn cash_buying_power(&self) -> Decimal {
+        self.cash().max(Decimal::ZERO).min(self.buying_power())
+    }
+
+    fn position_of(&self, ticker: &str, shares: &Shares, price: &Price) -> Position {
+        let avg_cost = *self.avg_costs.get(ticker).unwrap_or(price);
+        let market_value = self.market_value(ticker, shares, price);
+        Position {
+            ticker: ticker.to_string(),
+            shares: shares.0,
+            avg_cost: avg_cost.into(),
+            market_price: price.0,
+            market_value,
+            unrealized_pnl: market_value - self.market_value(ticker, shares, &avg_cost),
+            realized_pnl: self.tax_lots.realized_pnl_of(ticker, self.trading_day()),
+            side: PositionSide::of(shares.0),
+        }
+    }
+
+    /// Positions held by this instance, leaving out those owned by other instances.
+    pub fn positions(&self) -> impl Iterator<Item = Position> + '_ {
+        self.holdings
+            .iter()
+            .filter(move |(ticker, _)| !self.shard.is_peer_owned(ticker))
+            .map(move |(ticker, (shares, price))| self.position_of(ticker, shares, price))
+    }
+
+    /// Positions of this instance closed out during the session, which only carry the PnL they
+    /// realized.
+    pub fn closed_positions(&self) -> impl Iterator<Item = Position> + '_ {
+        let day = self.trading_day();
+        self.tax_lots
+            .realized_tickers(day)
+            .filter(move |ticker| {
+                !self.holdings.contains_key(*ticker) && !self.shard.is_peer_owned(ticker)
+            })
+            .map(move |ticker| Position {
+                ticker: ticker.clone(),
+                shares: Decimal::ZERO,
+                avg_cost: Decimal::ZERO,
+                market_price: Decimal::ZERO,
+                market_value: Decimal::ZERO,
+                unrealized_pnl: Decimal::ZERO,
+                realized_pnl: self.tax_lots.realized_pnl_of(ticker, day),
+                side: PositionSide::Flat,
+            })
+    }
+
+    pub fn position(&self, ticker: &str) -> Option<Position> {
+        self.holdings
+            .get(ticker)
+            .map(|(shares, price)| self.position_of(ticker, shares, price))
+    }
+
+    pub fn risk_report(&self) -> RiskReport {
+        RiskReport {
+            cash: self.cash(),
+            equity: self.equity(),
+            last_equity: self.last_equity,
+            last_maintenance_margin: self.last_maintenance_margin,
+            long_market_exposure: self.long_market_exposure(),
+            short_market_exposure: self.short_market_exposure(),
+            gross_market_exposure: self.gross_market_exposure(),
+            net_market_exposure: self.net_market_exposure(),
+            initial_margin: self.initial_margin(),
+            maintenance_margin: self.maintenance_margin(),
+            multiplier: self.multiplier(),
+            regt_buying_power: self.regt_buying_power(),
+            daytrading_buying_power: self.daytrading_buying_power(),
+            buying_power: self.buying_power(),
+            reserved_buying_power: self.reserved_buying_power(),
+            realized_pnl: self.realized_pnl(),
+            unrealized_pnl: self.unrealized_pnl(),
+            day_trade_call: self.day_trade_calls.outstanding().cloned(),
+        }
+    }
+
+    /// Open lots and realized PnL of the holdings.
+    pub fn tax_lots(&self) -> &TaxLots {
+        &self.tax_lots
+    }
+
+    /// Follows equity for the drawdown breaker. Returns an alert if the breaker trips.
+    pub fn update_drawdown(&mut self) -> Option<Alert> {
+        let max_drawdown = self.settings.max_drawdown?;
+        let equity = self.equity();
+        let drawdown = self
+            .drawdown
+            .observe(equity, self.clock.today(), max_drawdown)?;
+        self.touch();
+        Some(Alert {
+            level: AlertLevel::Critical,
+            message: format!(
+                "Equity of {} is {}% below its peak of {}, denying opening trades",
+                equity.round_dp(2),
+                (drawdown * Decimal::new(100, 0)).round_dp(2),
+                self.drawdown.peak().round_dp(2)
+            ),
+        })
+    }
+
+    /// Compares the exposure of the day's trades to the day-trading limit of pattern day traders.
+    /// Returns an alert if this issues a day-trading call.
+    pub fn update_day_trade_call(&mut self) -> Option<Alert> {
+        if !self.is_pattern_day_trader {
+            return None;
+        }
+        let outstanding = self.day_trade_calls.outstanding().is_some();
+        let call = self.day_trade_calls.observe(
+            self.clock.today(),
+            self.daytrade_exposure(),
+            self.daytrading_limit(),
+            self.daytrading_multiplier(),
+        );
+        if outstanding != self.day_trade_calls.outstanding().is_some() {
+            self.touch();
+        }
+        let call = call?;
+        Some(Alert {
+            level: AlertLevel::Critical,
+            message: format!(
+                "Day-trading call of {} issued, due on {}; day-trading buying power is restricted \
+                 until it is met",
+                call.amount.round_dp(2),
+                call.due_on
+            ),
+        })
+    }
+
+    /// Exposure of the positions opened during the day, the ones that can still be day traded.
+    fn daytrade_exposure(&self) -> Decimal {
+        let today = self.clock.today();
+        self.positions()
+            .filter(|position| self.day_trades.is_day_trade(&position.ticker, today))
+            .map(|position| position.market_value.abs())
+            .sum()
+    }
+
+    /// Records cash deposited into the account, which goes towards meeting a day-trading call.
+    pub fn record_deposit(&mut self, amount: Decimal) {
+        info!(%amount, "Cash deposited");
+        self.cash += amount;
+        self.day_trade_calls.deposit(amount);
+        self.touch();
+    }
+
+    /// The day-trading call that hasn't been met yet, if any.
+    pub fn day_trade_call(&self) -> Option<&DayTradeCall> {
+        self.day_trade_calls.outstanding()
+    }
+
+    /// Whether equity fell far enough from its peak today that opening trades are denied.
+    pub fn drawdown_tripped(&self) -> bool {
+        self.drawdown.is_tripped(self.clock.today())
+    }
+
+    /// Denies opening trades in `tickers` until the window before the close ends. An empty set
+    /// ends the window.
+    pub fn set_flatten_window(&mut self, tickers: HashSet<String>) {
+        if tickers != self.flattening {
+            self.flattening = tickers;
+            self.touch();
+        }
+    }
+
+    /// Whether `ticker` must be flat overnight and the market is about to close.
+    pub fn is_flattening(&self, ticker: &str) -> bool {
+        self.flattening.contains(ticker)
+    }
+
+    /// The trading session fills count towards, which lasts until the market opens on another
+    /// day.
+    fn trading_day(&self) -> NaiveDate {
+        self.session_day.unwrap_or_else(|| self.clock.today())
+    }
+
+    /// PnL realized by the session's fills.
+    pub fn realized_pnl(&self) -> Decimal {
+        self.tax_lots.realized_pnl(self.trading_day())
+    }
+
+    /// PnL of the positions held by this instance, at their last known prices.
+    pub fn unrealized_pnl(&self) -> Decimal {
+        self.positions()
+            .map(|position| position.unrealized_pnl)
+            .sum()
+    }
+
+    pub fn portfolio_snapshot(&self) -> PortfolioSnapshot {
+        PortfolioSnapshot {
+            report: self.risk_report(),
+            is_pattern_day_trader: self.is_pattern_day_trader,
+            positions: self.positions().chain(self.closed_positions()).collect(),
+        }
+    }
+
+    pub fn exposure_update(&self) -> ExposureUpdate {
+        let report = self.risk_report();
+        ExposureUpdate {
+            cash: report.cash,
+            equity: report.equity,
+            long_market_exposure: report.long_market_exposure,
+            short_market_exposure: report.short_market_exposure,
+            gross_market_exposure: report.gross_market_exposure,
+            net_market_exposure: report.net_market_exposure,
+            buying_power: report.buying_power,
+            positions: self.positions().collect(),
+        }
+    }
+
+    /// Fraction added to the last price of market orders to account for slippage.
+    pub fn slippage(&self, ticker: &str) -> Decimal {
+        let global = match self.settings.crypto.as_ref() {
+            Some(crypto) if crypto.symbols.contains(ticker) => crypto.market_order_slippage,
+            _ => self.settings.market_order_slippage,
+        };
+        self.limits().slippage(ticker, global)
+    }
+
+    pub fn limits(&self) -> Arc<RiskLimits> {
+        self.limits.current()
+    }
+
+    pub(crate) fn settings(&self) -> &RiskSettings {
+        &self.settings
+    }
+
+    pub(crate) fn shares(&self, ticker: &str) -> Option<Decimal> {
+        self.holdings.get(ticker).map(|(shares, _)| shares.0)
+    }
+
+    pub(crate) fn is_good_faith_violation(&self, ticker: &str, today: NaiveDate) -> bool {
+        self.is_cash_account
+            && !self.is_crypto(ticker)
+            && self
+                .settlement_ledger
+                .is_good_faith_violation(ticker, today)
+    }
+
+    pub(crate) fn is_pattern_day_trade_violation(&self, ticker: &str, today: NaiveDate) -> bool {
+        // Like the broker, we use the previous close's equity to decide whether the account is
+        // subject to PDT rules
+        self.last_equity < Decimal::new(25000, 0)
+            && !self.is_crypto(ticker)
+            && self.day_trades.is_day_trade(ticker, today)
+            && self.day_trades.count(today) >= MAX_DAY_TRADES
+    }
+
+    /// Appends a custom rule to the end of the pipeline.
+    pub fn add_rule(&mut self, rule: Box<dyn RiskRule>) {
+        self.rules.push(rule);
+        self.touch();
+    }
+
+    /// Returns the price used to value an opening intent, and the last trade it was derived from
+    /// if it wasn't given by the intent. The last price is looked up unless it was fetched ahead
+    /// of time.
+    fn price_intent(
+        &self,
+        trade_intent: &TradeIntent,
+        last_price: Option<PriceTick>,
+    ) -> Result<(Decimal, Option<PriceTick>)> {
+        match trade_intent.order_type {
+            OrderType::Limit { limit_price } => Ok((limit_price, None)),
+            OrderType::Market => {
+                let tick = match last_price {
+                    Some(tick) => tick,
+                    None => self.last_tick(&trade_intent.ticker)?,
+                };
+                let price = self
+                    .settings
+                    .rounding
+                    .price(tick.price * (Decimal::ONE + self.slippage(&trade_intent.ticker)));
+                Ok((price, Some(tick)))
+            }
+            _ => Err(RiskManagerError::RuleEvaluation(
+                "Risk manager can only deal with Market and Limit orders currently".into(),
+            )),
+        }
+    }
+
+    /// The last trade price of a ticker, from the price cache if it has a fresh one, or looked up
+    /// in the first price source that answers otherwise.
+    pub fn last_price(&self, ticker: &str) -> Result<Decimal> {
+        Ok(self.last_tick(ticker)?.price)
+    }
+
+    /// The last trade price of a ticker along with when it was traded at.
+    pub fn last_tick(&self, ticker: &str) -> Result<PriceTick> {
+        let now = self.clock.now();
+        if let Some(tick) = self.prices.get(ticker, now) {
+            debug!(%ticker, source = "cache", "Priced");
+            return Ok(tick);
+        }
+        let (tick, source) = self
+            .latency
+            .price_lookup
+            .time(|| self.price_sources.last_price(ticker))
+            .map_err(|e| RiskManagerError::price_source(ticker, e))?;
+        debug!(%ticker, source, "Priced");
+        self.prices.record_lookup(ticker, tick, now);
+        Ok(tick)
+    }
+
+    pub fn risk_check(&self, trade_intent: &TradeIntent) -> Result<RiskCheckResponse> {
+        self.risk_check_with(trade_intent, RequestOptions::default())
+    }
+
+    pub fn risk_check_with(
+        &self,
+        trade_intent: &TradeIntent,
+        options: RequestOptions,
+    ) -> Result<RiskCheckResponse> {
+        self.risk_check_priced(trade_intent, options, None)
+    }
+
+    /// Checks an intent whose last trade price may already have been fetched, e.g. by its
+    /// ticker's actor.
+    #[tracing::instrument(skip(self, trade_intent), fields(id = %trade_intent.id))]
+    pub fn risk_check_priced(
+        &self,
+        trade_intent: &TradeIntent,
+        options: RequestOptions,
+        last_price: Option<PriceTick>,
+    ) -> Result<RiskCheckResponse> {
+        debug!("Running risk_check");
+        self.prices.traded(&trade_intent.ticker);
+        if !self.evaluation_cache.is_enabled() {
+            return self.check(
+                trade_intent,
+                Decimal::ZERO,
+                options,
+                last_price,
+                Evaluation::Decision,
+            );
+        }
+        let key = EvaluationKey {
+            ticker: trade_intent.ticker.clone(),
+            qty: trade_intent.qty,
+            order_type: format!("{:?}", trade_intent.order_type),
+            allow_partial: self.allow_partial(options),
+            today: self.clock.today(),
+            state: (self.state_version, self.limits.generation()),
+        };
+        if let Some(response) = self.evaluation_cache.get(&key, self.clock.now()) {
+            debug!("Reusing cached decision");
+            return Ok(response.with_intent(trade_intent.clone()));
+        }
+        let response = self.check(
+            trade_intent,
+            Decimal::ZERO,
+            options,
+            last_price,
+            Evaluation::Decision,
+        )?;
+        self.evaluation_cache
+            .insert(key, response.clone(), self.clock.now());
+        Ok(response)
+    }
+
+    fn allow_partial(&self, options: RequestOptions) -> bool {
+        options
+            .allow_partial
+            .unwrap_or(self.settings.partial_approvals)
+    }
+
+    /// Runs the full rule pipeline and projects the portfolio after the intent, without reserving
+    /// anything or recording a decision or rule statistics.
+    #[tracing::instrument(skip(self, trade_intent), fields(id = %trade_intent.id))]
+    pub fn what_if(&self, trade_intent: &TradeIntent) -> Result<WhatIfResponse> {
+        debug!("Running what_if");
+        let decision = self.check(
+            trade_intent,
+            Decimal::ZERO,
+            RequestOptions::default(),
+            None,
+            Evaluation::Probe,
+        )?;
+        let price = Self::granted_price(trade_intent, decision.buffered_price())
+            .or_else(|| self.holdings.get(&trade_intent.ticker).map(|(_, p)| p.0))
+            .ok_or_else(|| {
+                RiskManagerError::price_source(&trade_intent.ticker, "No limit, fill or last price")
+            })?;
+        let qty = Self::qty(trade_intent)?;
+        let mut projected = self.projection(&trade_intent.ticker, qty, price);
+        // Filled in full, an intent resubmitting a working order no longer reserves anything
+        projected.working_orders.remove(&trade_intent.id);
+        Ok(WhatIfResponse {
+            decision,
+            projected: projected.portfolio_snapshot(),
+        })
+    }
+
+    /// Decides an intent like `risk_check_priced`, without the evaluation cache or rule
+    /// statistics, and runs every rule on it to show how each of them saw it.
+    pub fn explain(
+        &self,
+        trade_intent: &TradeIntent,
+        last_price: Option<PriceTick>,
+    ) -> Result<Explanation> {
+        let decision = self.check(
+            trade_intent,
+            Decimal::ZERO,
+            RequestOptions::default(),
+            last_price,
+            Evaluation::Probe,
+        )?;
+        let (ctx, _, _) = self.context(trade_intent, Decimal::ZERO, last_price)?;
+        Ok(Explanation {
+            decision,
+            rules: self.rules.trace(&ctx, trade_intent),
+        })
+    }
+
+    /// A copy of the account with `qty` more shares of `ticker` bought at `price`.
+    fn projection(&self, ticker: &str, qty: Decimal, price: Decimal) -> RiskManager {
+        self.projection_of(&[(ticker, qty, price)])
+    }
+
+    /// Maintenance margin of the account once the fills, given by ticker, quantity and price, are
+    /// made.
+    pub(crate) fn maintenance_margin_after(&self, fills: &[(&str, Decimal, Decimal)]) -> Decimal {
+        self.projection_of(fills).maintenance_margin()
+    }
+
+    /// A copy of the account with fills given by ticker, quantity and price. Everything the rules
+    /// read is copied; the broker client, the rule pipeline and the caches of decisions are not.
+    fn projection_of(&self, fills: &[(&str, Decimal, Decimal)]) -> RiskManager {
+        let mut projection = RiskManager {
+            #[cfg(feature = "alpaca")]
+            alpaca_client: None,
+            cash: self.cash,
+            holdings: self.holdings.clone(),
+            avg_costs: self.avg_costs.clone(),
+            tax_lots: self.tax_lots.clone(),
+            drawdown: self.drawdown.clone(),
+            day_trade_calls: self.day_trade_calls.clone(),
+            flattening: self.flattening.clone(),
+            is_pattern_day_trader: self.is_pattern_day_trader,
+            account_multiplier: self.account_multiplier,
+            shorting_disabled: self.shorting_disabled,
+            account_blocks: self.account_blocks.clone(),
+            session_day: self.session_day,
+            last_equity: self.last_equity,
+            last_maintenance_margin: self.last_maintenance_margin,
+            price_sources: self.price_sources.clone(),
+            prices: Default::default(),
+            assets: self.assets.clone(),
+            borrow_rates: self.borrow_rates.clone(),
+            deltas: self.deltas.clone(),
+            is_cash_account: self.is_cash_account,
+            settlement_ledger: self.settlement_ledger.clone(),
+            day_trades: self.day_trades.clone(),
+            recent_decisions: Default::default(),
+            working_orders: self.working_orders.clone(),
+            rules: Default::default(),
+            limits: self.limits.clone(),
+            evaluation_cache: Default::default(),
+            halts: self.halts.clone(),
+            shard: self.shard.clone(),
+            latency: self.latency.clone(),
+            clock: self.clock.clone(),
+            state_version: self.state_version,
+            settings: self.settings.clone(),
+        };
+        for (ticker, qty, price) in fills {
+            projection.update_holdings(*ticker, Shares(*qty), Price(*price));
+        }
+        // Only bound once filled, so that the projected holdings aren't marked as held in the cache
+        projection.prices = self.prices.clone();
+        projection
+    }
+
+    /// Decides the legs of a multi-leg intent as a unit. The account is projected once with every
+    /// leg filled, and each leg runs through the rules against that projection with its own fill
+    /// backed out, so that the rules see the combined portfolio. A leg is only granted if every
+    /// leg passes.
+    #[tracing::instrument(skip(self, intent), fields(id = %intent.id))]
+    pub fn risk_check_legs(&self, intent: &MultiLegIntent) -> Result<MultiLegResponse> {
+        debug!("Running multi-leg risk_check");
+        let mut fills = Vec::with_capacity(intent.legs.len());
+        let mut ticks = Vec::with_capacity(intent.legs.len());
+        for leg in intent.legs.iter() {
+            self.prices.traded(&leg.ticker);
+            let qty = Self::qty(leg)?;
+            let (price, tick) = match self.holdings.get(&leg.ticker) {
+                Some((_, price)) if self.is_closing(&leg.ticker, qty) => {
+                    (Self::granted_price(leg, None).unwrap_or(price.0), None)
+                }
+                _ => self.price_intent(leg, None)?,
+            };
+            fills.push((leg.ticker.as_str(), qty, price));
+            ticks.push(tick);
+        }
+        let combined = self.projection_of(&fills);
+        let mut legs = Vec::with_capacity(intent.legs.len());
+        for ((leg, tick), (ticker, qty, price)) in intent.legs.iter().zip(ticks).zip(fills.iter()) {
+            let projection = combined.projection_of(&[(*ticker, -*qty, *price)]);
+            legs.push(self.check_leg(leg, &projection, tick)?);
+        }
+        let response = MultiLegResponse {
+            id: intent.id,
+            legs: MultiLegResponse::all_or_nothing(legs),
+        };
+        if !response.is_granted() {
+            debug!("Multi-leg risk_check denied");
+        }
+        Ok(response)
+    }
+
+    /// Runs the rules on one leg of a multi-leg intent against the account with the other legs
+    /// filled.
+    fn check_leg(
+        &self,
+        leg: &TradeIntent,
+        projection: &RiskManager,
+        tick: Option<PriceTick>,
+    ) -> Result<RiskCheckResponse> {
+        let halt = self
+            .halts
+            .denial(&leg.ticker)
+            .or_else(|| self.account_blocks.denial());
+        if let Some(reason) = halt {
+            return Ok(RiskCheckResponse::Denied {
+                intent: leg.clone(),
+                reasons: vec![reason],
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                timing: None,
+            });
+        }
+        // A leg resubmitting a working order only needs buying power for the increase
+        let reserved = self
+            .working_orders
+            .get(&leg.id)
+            .map(|order| order.reserved(self.contract_multiplier(&leg.ticker)))
+            .unwrap_or_default();
+        let (ctx, buffered_price, price_source) = projection.context(leg, reserved, tick)?;
+        let reasons = self.evaluate(&ctx, leg, Evaluation::Decision);
+        let score = if self.settings.scoring {
+            Some(self.rules.score(&ctx, leg))
+        } else {
+            None
+        };
+        if reasons.is_empty() {
+            Ok(RiskCheckResponse::Granted {
+                intent: leg.clone(),
+                buffered_price,
+                price_source,
+                score,
+                metrics: projection.trade_metrics(leg, ctx.price),
+                timing: None,
+            })
+        } else {
+            Ok(RiskCheckResponse::Denied {
+                intent: leg.clone(),
+                reasons,
+                buffered_price,
+                price_source,
+                score,
+                timing: None,
+            })
+        }
+    }
+
+    /// Projects the account after a granted intent. Closing intents are valued at their limit
+    /// price, or the last known price of the position.
+    fn trade_metrics(
+        &self,
+        trade_intent: &TradeIntent,
+        price: Option<Decimal>,
+    ) -> Option<TradeMetrics> {
+        let price = price
+            .or_else(|| Self::granted_price(trade_intent, None))
+            .or_else(|| self.holdings.get(&trade_intent.ticker).map(|(_, p)| p.0))?;
+        let qty = Decimal::from_isize(trade_intent.qty)?;
+        let projection = self.projection(&trade_intent.ticker, qty, price);
+        let equity = projection.equity();
+        let margin_utilization = if equity > Decimal::ZERO {
+            Some(projection.maintenance_margin() / equity)
+        } else {
+            None
+        };
+        Some(TradeMetrics {
+            buying_power_before: self.buying_power(),
+            buying_power_after: projection.buying_power(),
+            projected_position: projection.shares(&trade_intent.ticker).unwrap_or_default(),
+            projected_gross_exposure: projection.gross_market_exposure(),
+            projected_net_exposure: projection.net_market_exposure(),
+            margin_utilization,
+        })
+    }
+
+    /// Checks an amendment of a working order. The amended order is checked like a new intent,
+    /// except that it only needs buying power for the increase over the original reservation.
+    #[tracing::instrument(skip(self, amendment), fields(id = %amendment.id, amends = %amendment.amends))]
+    pub fn amendment_check(
+        &self,
+        amendment: &OrderAmendment,
+        options: RequestOptions,
+    ) -> Result<RiskCheckResponse> {
+        debug!("Running amendment_check");
+        let order = self.working_orders.get(&amendment.amends).ok_or_else(|| {
+            RiskManagerError::RuleEvaluation(format!("Unknown working order {}", amendment.amends))
+        })?;
+        if amendment.qty.signum() != order.intent.qty.signum() {
+            return Err(RiskManagerError::RuleEvaluation(
+                "Amendments can't change the side of an order".into(),
+            ));
+        }
+        let mut intent = order.intent.clone();
+        intent.id = amendment.id;
+        intent.qty = amendment.qty;
+        if let Some(limit_price) = amendment.limit_price {
+            match intent.order_type {
+                OrderType::Limit { .. } => intent.order_type = OrderType::Limit { limit_price },
+                _ => {
+                    return Err(RiskManagerError::RuleEvaluation(
+                        "Only limit orders can be repriced".into(),
+                    ))
+                }
+            }
+        }
+        let reserved = order.reserved(self.contract_multiplier(&order.intent.ticker));
+        self.check(&intent, reserved, options, None, Evaluation::Decision)
+    }
+
+    fn qty(trade_intent: &TradeIntent) -> Result<Decimal> {
+        Decimal::from_isize(trade_intent.qty).ok_or_else(|| {
+            RiskManagerError::RuleEvaluation("Failed to convert isize to Decimal".into())
+        })
+    }
+
+    fn evaluate(
+        &self,
+        ctx: &PortfolioContext,
+        trade_intent: &TradeIntent,
+        evaluation: Evaluation,
+    ) -> Vec<DenyReason> {
+        match self.settings.rule_evaluation {
+            RuleEvaluationMode::FirstFailure => {
+                match self.rules.evaluate(ctx, trade_intent, evaluation) {
+                    RuleOutcome::Pass | RuleOutcome::Approve => Vec::new(),
+                    RuleOutcome::Deny(reason) => vec![reason],
+                }
+            }
+            RuleEvaluationMode::All => self.rules.evaluate_all(ctx, trade_intent, evaluation),
+        }
+    }
+
+    /// Finds the largest quantity of the intent that passes every rule. Size limits only get
+    /// stricter as the quantity grows, so the passing quantities form a range we can bisect.
+    /// Only the intent itself counts towards rule statistics, not the quantities tried.
+    fn largest_approved_qty(&self, ctx: &PortfolioContext, trade_intent: &TradeIntent) -> isize {
+        let sign = trade_intent.qty.signum();
+        let mut candidate = trade_intent.clone();
+        let (mut passing, mut failing) = (0, trade_intent.qty.abs());
+        while failing - passing > 1 {
+            let qty = passing + (failing - passing) / 2;
+            candidate.qty = qty * sign;
+            if self.evaluate(ctx, &candidate, Evaluation::Probe).is_empty() {
+                passing = qty
+            } else {
+                failing = qty
+            }
+        }
+        passing * sign
+    }
+
+    /// Prices the intent and builds the context its rules run in, returning the slippage-adjusted
+    /// price and where its last price came from separately for market orders.
+    fn context(
+        &self,
+        trade_intent: &TradeIntent,
+        reserved: Decimal,
+        last_price: Option<PriceTick>,
+    ) -> Result<(PortfolioContext<'_>, Option<Decimal>, Option<String>)> {
+        let qty = Self::qty(trade_intent)?;
+        let mut buffered_price = None;
+        let mut price_source = None;
+        let (price, price_age) = if self.is_closing(&trade_intent.ticker, qty) {
+            (None, None)
+        } else {
+            let (price, tick) = self.price_intent(trade_intent, last_price)?;
+            if let Some(tick) = tick {
+                buffered_price = Some(price);
+                price_source = tick.source.map(String::from);
+            }
+            let price_age = tick.map(|tick| match tick.timestamp {
+                Some(timestamp) => PriceAge::Known(self.clock.now() - timestamp),
+                None => PriceAge::Unknown,
+            });
+            (Some(price), price_age)
+        };
+        let ctx = PortfolioContext {
+            manager: self,
+            today: self.clock.today(),
+            price,
+            price_age,
+            reserved,
+        };
+        Ok((ctx, buffered_price, price_source))
+    }
+
+    fn check(
+        &self,
+        trade_intent: &TradeIntent,
+        reserved: Decimal,
+        options: RequestOptions,
+        last_price: Option<PriceTick>,
+        evaluation: Evaluation,
+    ) -> Result<RiskCheckResponse> {
+        let halt = self
+            .halts
+            .denial(&trade_intent.ticker)
+            .or_else(|| self.account_blocks.denial());
+        if let Some(reason) = halt {
+            debug!(?reason, "Risk-check denied by trading halt");
+            return Ok(RiskCheckResponse::Denied {
+                intent: trade_intent.clone(),
+                reasons: vec![reason],
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                timing: None,
+            });
+        }
+        let (ctx, buffered_price, price_source) =
+            self.context(trade_intent, reserved, last_price)?;
+        let price = ctx.price;
+        let reasons = self
+            .latency
+            .rule_evaluation
+            .time(|| self.evaluate(&ctx, trade_intent, evaluation));
+        let score = if self.settings.scoring {
+            Some(self.rules.score(&ctx, trade_intent))
+        } else {
+            None
+        };
+        if self.allow_partial(options)
+            && !reasons.is_empty()
+            && reasons.iter().all(DenyReason::is_size_limit)
+        {
+            let approved_qty = self.largest_approved_qty(&ctx, trade_intent);
+            if approved_qty != 0 {
+                debug!(approved_qty, "Risk-check granted reduced quantity");
+                return Ok(RiskCheckResponse::Amended {
+                    original: trade_intent.clone(),
+                    approved_qty,
+                    buffered_price,
+                    price_source: price_source.clone(),
+                    score,
+                    timing: None,
+                });
+            }
+        }
+        if reasons.is_empty() {
+            debug!("Risk-check granted");
+            Ok(RiskCheckResponse::Granted {
+                intent: trade_intent.clone(),
+                buffered_price,
+                price_source,
+                score,
+                metrics: self.trade_metrics(trade_intent, price),
+                timing: None,
+            })
+        } else {
+            debug!(?reasons, "Risk-check denied");
+            Ok(RiskCheckResponse::Denied {
+                intent: trade_intent.clone(),
+                reasons,
+                buffered_price,
+                price_source,
+                score,
+                timing: None,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::limits::TickerLimits;
+    use crate::settings::{CryptoSettings, GoodFaithViolationPolicy};
+    use chrono::TimeZone;
+
+    #[test]
+    fn realistic_equity_calculations() {
+        let mut manager = RiskManager {
+            is_pattern_day_trader: true,
+            last_equity: Decimal::new(99791448, 2),
+            ..Default::default()
+        };
+
+        manager.update_holdings(
+            "TRP",
+            Shares(Decimal::new(5118, 0)),
+            Price(Decimal::new(4869, 2)),
+        );
+        manager.update_holdings(
+            "MPLX",
+            Shares(Decimal::new(8825, 0)),
+            Price(Decimal::new(2812, 2)),
+        );
+        manager.update_holdings(
+            "E",
+            Shares(Decimal::new(19539, 0)),
+            Price(Decimal::new(2556, 2)),
+        );
+        manager.update_holdings(
+            "DVN",
+            Shares(Decimal::new(-8146, 0)),
+            Price(Decimal::new(3058, 2)),
+        );
+        manager.update_holdings(
+            "CVE",
+            Shares(Decimal::new(-27716, 0)),
+            Price(Decimal::new(910, 2)),
+        );
+        manager.update_holdings(
+            "COP",
+            Shares(Decimal::new(-4005, 0)),
+            Price(Decimal::new(6243, 2)),
+        );
+        manager.update_holdings(
+            "BKR",
+            Shares(Decimal::new(10527, 0)),
+            Price(Decimal::new(2368, 2)),
+        );
+        manager.update_holdings(
+            "APA",
+            Shares(Decimal::new(-24754, 0)),
+            Price(Decimal::new(2033, 2)),
+        );
+        manager.update_cash(Decimal::new(99283298, 2));
+        assert_eq!(manager.long_market_exposure(), Decimal::new(124605062, 2));
+        assert_eq!(manager.short_market_exposure(), Decimal::new(125460125, 2));
+        assert_eq!(manager.gross_market_exposure(), Decimal::new(250065187, 2));
+        assert_eq!(manager.net_market_exposure(), Decimal::new(-855063, 2));
+        assert_eq!(manager.equity(), Decimal::new(98428235, 2));
+        assert_eq!(manager.initial_margin(), Decimal::new(125032594, 2));
+        assert_eq!(manager.maintenance_margin(), Decimal::new(75019556, 2));
+        assert_eq!(manager.regt_buying_power(), Decimal::ZERO);
+        assert_eq!(
+            manager.daytrading_buying_power(),
+            Decimal::new(149100605, 2)
+        );
+        assert_eq!(manager.buying_power(), Decimal::new(149100605, 2));
+    }
+
+    #[test]
+    fn equity_calculations() {
+        let mut manager = RiskManager {
+            is_pattern_day_trader: true,
+            ..Default::default()
+        };
+
+        manager.update_holdings("AAPL", Shares(Decimal::ONE), Price(Decimal::new(100, 0)));
+        manager.update_cash(Decimal::new(300, 0));
+        assert_eq!(manager.long_market_exposure(), Decimal::new(100, 0));
+        assert_eq!(manager.short_market_exposure(), Decimal::ZERO);
+        assert_eq!(manager.gross_market_exposure(), Decimal::new(100, 0));
+        assert_eq!(manager.net_market_exposure(), Decimal::new(100, 0));
+        assert_eq!(manager.equity(), Decimal::new(400, 0));
+        assert_eq!(manager.initial_margin(), Decimal::new(50, 0));
+        assert_eq!(manager.maintenance_margin(), Decimal::new(30, 0));
+        assert_eq!(manager.regt_buying_power(), Decimal::new(700, 0));
+        assert_eq!(manager.daytrading_buying_power(), Decimal::ZERO);
+        assert_eq!(manager.buying_power(), Decimal::new(700, 0));
+
+        manager.update_holdings(
+            "TSLA",
+            Shares(Decimal::new(-2, 0)),
+            Price(Decimal::new(80, 0)),
+        );
+        assert_eq!(manager.long_market_exposure(), Decimal::new(100, 0));
+        assert_eq!(manager.short_market_exposure(), Decimal::new(160, 0));
+        assert_eq!(manager.gross_market_exposure(), Decimal::new(260, 0));
+        assert_eq!(manager.net_market_exposure(), Decimal::new(-60, 0));
+        assert_eq!(manager.equity(), Decimal::new(400, 0));
+        assert_eq!(manager.initial_margin(), Decimal::new(130, 0));
+        assert_eq!(manager.maintenance_margin(), Decimal::new(78, 0));
+        assert_eq!(manager.regt_buying_power(), Decimal::new(540, 0));
+        assert_eq!(manager.daytrading_buying_power(), Decimal::ZERO);
+        assert_eq!(manager.buying_power(), Decimal::new(540, 0));
+
+        manager.update_holdings(
+            "TSLA",
+            Shares(Decimal::new(-1, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        assert_eq!(manager.long_market_exposure(), Decimal::new(100, 0));
+        assert_eq!(manager.short_market_exposure(), Decimal::new(300, 0));
+        assert_eq!(manager.gross_market_exposure(), Decimal::new(400, 0));
+        assert_eq!(manager.net_market_exposure(), Decimal::new(-200, 0));
+        assert_eq!(manager.equity(), Decimal::new(360, 0));
+        assert_eq!(manager.initial_margin(), Decimal::new(200, 0));
+        assert_eq!(manager.maintenance_margin(), Decimal::new(120, 0));
+        assert_eq!(manager.regt_buying_power(), Decimal::new(320, 0));
+        assert_eq!(manager.daytrading_buying_power(), Decimal::ZERO);
+        assert_eq!(manager.buying_power(), Decimal::new(320, 0));
+
+        manager.update_holdings(
+            "TSLA",
+            Shares(Decimal::new(3, 0)),
+            Price(Decimal::new(90, 0)),
+        );
+        assert_eq!(manager.long_market_exposure(), Decimal::new(100, 0));
+        assert_eq!(manager.short_market_exposure(), Decimal::ZERO);
+        assert_eq!(manager.gross_market_exposure(), Decimal::new(100, 0));
+        assert_eq!(manager.net_market_exposure(), Decimal::new(100, 0));
+        assert_eq!(manager.equity(), Decimal::new(390, 0));
+        assert_eq!(manager.initial_margin(), Decimal::new(50, 0));
+        assert_eq!(manager.maintenance_margin(), Decimal::new(30, 0));
+        assert_eq!(manager.regt_buying_power(), Decimal::new(680, 0));
+        assert_eq!(manager.daytrading_buying_power(), Decimal::ZERO);
+        assert_eq!(manager.buying_power(), Decimal::new(680, 0));
+    }
+
+    #[test]
+    fn risk_check() {
+        let mut manager = RiskManager {
+            is_pattern_day_trader: true,
+            ..Default::default()
+        };
+
+        manager.update_holdings("AAPL", Shares(Decimal::ONE), Price(Decimal::new(100, 0)));
+        manager.update_holdings(
+            "TSLA",
+            Shares(Decimal::new(-2, 0)),
+            Price(Decimal::new(80, 0)),
+        );
+        manager.update_cash(Decimal::new(300, 0));
+
+        let trade_intent = TradeIntent::new("AAPL", 1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        });
+        let response = manager.risk_check(&trade_intent).unwrap();
+        assert_eq!(
+            response,
+            RiskCheckResponse::Granted {
+                intent: trade_intent,
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                metrics: Some(TradeMetrics {
+                    buying_power_before: Decimal::new(220, 0),
+                    buying_power_after: Decimal::new(120, 0),
+                    projected_position: Decimal::new(2, 0),
+                    projected_gross_exposure: Decimal::new(360, 0),
+                    projected_net_exposure: Decimal::new(40, 0),
+                    margin_utilization: Some(Decimal::new(45, 2)),
+                }),
+                timing: None,
+            }
+        );
+
+        let trade_intent = TradeIntent::new("AAPL", 1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(240, 0),
+        });
+        let response = manager.risk_check(&trade_intent).unwrap();
+        assert_eq!(
+            response,
+            RiskCheckResponse::Denied {
+                intent: trade_intent,
+                reasons: vec![DenyReason::InsufficientBuyingPower {
+                    buying_power: Decimal::new(220, 0)
+                }],
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                timing: None,
+            }
+        );
+
+        let trade_intent = TradeIntent::new("AAPL", -2).order_type(OrderType::Limit {
+            limit_price: Decimal::new(120, 0),
+        });
+        let response = manager.risk_check(&trade_intent).unwrap();
+        assert_eq!(
+            response,
+            RiskCheckResponse::Denied {
+                intent: trade_intent,
+                reasons: vec![DenyReason::ChangeInPositionSide],
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                timing: None,
             }
-            _ => {
-                return Err(anyhow!(
-                    "Risk manager can only deal with Market and Limit orders currently"
-                ))
+        );
+
+        let trade_intent = TradeIntent::new("AAPL", -1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(120, 0),
+        });
+        let response = manager.risk_check(&trade_intent).unwrap();
+        assert_eq!(
+            response,
+            RiskCheckResponse::Granted {
+                intent: trade_intent,
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                metrics: Some(TradeMetrics {
+                    buying_power_before: Decimal::new(220, 0),
+                    buying_power_after: Decimal::new(360, 0),
+                    projected_position: Decimal::ZERO,
+                    projected_gross_exposure: Decimal::new(160, 0),
+                    projected_net_exposure: Decimal::new(-160, 0),
+                    margin_utilization: Some(Decimal::new(48, 0) / Decimal::new(260, 0)),
+                }),
+                timing: None,
             }
+        )
+    }
+
+    #[test]
+    fn good_faith_violation() {
+        let mut manager = RiskManager {
+            cash: Decimal::new(1000, 0),
+            is_cash_account: true,
+            settlement_ledger: SettlementLedger::new(Decimal::new(1000, 0)),
+            settings: RiskSettings {
+                good_faith_violation_policy: GoodFaithViolationPolicy::Deny,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let lot = |ticker: &str, shares, price| Lot {
+            id: uuid::Uuid::new_v4(),
+            order_id: uuid::Uuid::new_v4(),
+            ticker: ticker.into(),
+            fill_time: Utc::now(),
+            price: Decimal::new(price, 0),
+            shares: Decimal::new(shares, 0),
         };
-        let buying_power = self.buying_power();
-        trace!(?buying_power, ?required_buying_power);
+        manager.process_lot(lot("AAPL", 10, 100));
+        manager.process_lot(lot("AAPL", -10, 100));
+        manager.process_lot(lot("TSLA", 1, 1000));
 
-        if buying_power > required_buying_power {
-            debug!("Risk-check granted");
-            Ok(RiskCheckResponse::Granted {
-                intent: trade_intent.clone(),
-            })
-        } else {
-            debug!("Insufficient buying power, risk check denied");
-            Ok(RiskCheckResponse::Denied {
-                intent: trade_intent.clone(),
-                reason: DenyReason::InsufficientBuyingPower { buying_power },
-            })
-        }
+        let trade_intent = TradeIntent::new("TSLA", -1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(1000, 0),
+        });
+        let response = manager.risk_check(&trade_intent).unwrap();
+        assert_eq!(
+            response,
+            RiskCheckResponse::Denied {
+                intent: trade_intent,
+                reasons: vec![DenyReason::GoodFaithViolation],
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                timing: None,
+            }
+        );
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn realized_pnl() {
+        let mut manager = RiskManager {
+            cash: Decimal::new(10000, 0),
+            ..Default::default()
+        };
+        let lot = |shares, price| Lot {
+            id: uuid::Uuid::new_v4(),
+            order_id: uuid::Uuid::new_v4(),
+            ticker: "AAPL".into(),
+            fill_time: Utc::now(),
+            price: Decimal::new(price, 0),
+            shares: Decimal::new(shares, 0),
+        };
+        manager.process_lot(lot(10, 100));
+        manager.process_lot(lot(10, 110));
+        manager.process_lot(lot(-15, 120));
+        let report = manager.risk_report();
+        assert_eq!(report.realized_pnl, Decimal::new(250, 0));
+        assert_eq!(report.unrealized_pnl, Decimal::new(75, 0));
+        let position = manager.position("AAPL").unwrap();
+        assert_eq!(position.realized_pnl, Decimal::new(250, 0));
+        assert_eq!(position.unrealized_pnl, Decimal::new(75, 0));
+        let open: Vec<_> = manager.tax_lots().open_lots("AAPL").collect();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].price, Decimal::new(110, 0));
+
+        // Imported holdings start over with a lot at the price they were imported at
+        let intent = TradeIntent::new("AAPL", 10);
+        manager
+            .working_orders
+            .insert(intent.id, intent, Some(Decimal::new(120, 0)));
+        manager
+            .day_trades
+            .record_open("AAPL", manager.clock.today());
+        manager.import_holdings(manager.export_holdings());
+        let open: Vec<_> = manager.tax_lots().open_lots("AAPL").collect();
+        assert_eq!(open[0].lot_id, uuid::Uuid::nil());
+        assert_eq!(open[0].shares, Decimal::new(5, 0));
+        // Nor do they keep the working orders and day trades of the previous positions
+        assert_eq!(manager.reserved_buying_power(), Decimal::ZERO);
+        assert!(!manager
+            .day_trades
+            .is_day_trade("AAPL", manager.clock.today()));
+    }
 
     #[test]
-    fn realistic_equity_calculations() {
+    fn closed_position_pnl() {
+        // Fills after midnight UTC still count towards the session that started the day before
+        let session_day = Utc::now().date().naive_utc().pred();
         let mut manager = RiskManager {
-            kafka_consumer: None,
-            alpaca_client: None,
-            cash: Decimal::ZERO,
-            holdings: HashMap::new(),
-            is_pattern_day_trader: true,
-            last_equity: Decimal::new(99791448, 2),
-            last_maintenance_margin: Decimal::ZERO,
-            datastore_url: String::new(),
+            cash: Decimal::new(10000, 0),
+            session_day: Some(session_day),
+            ..Default::default()
+        };
+        let lot = |shares, price| Lot {
+            id: uuid::Uuid::new_v4(),
+            order_id: uuid::Uuid::new_v4(),
+            ticker: "AAPL".into(),
+            fill_time: Utc::now(),
+            price: Decimal::new(price, 0),
+            shares: Decimal::new(shares, 0),
+        };
+        manager.process_lot(lot(10, 100));
+        manager.process_lot(lot(-10, 120));
+        assert!(manager.position("AAPL").is_none());
+        assert_eq!(manager.risk_report().realized_pnl, Decimal::new(200, 0));
+        let snapshot = manager.portfolio_snapshot();
+        assert_eq!(snapshot.positions.len(), 1);
+        assert_eq!(snapshot.positions[0].ticker, "AAPL");
+        assert_eq!(snapshot.positions[0].side, PositionSide::Flat);
+        assert_eq!(snapshot.positions[0].realized_pnl, Decimal::new(200, 0));
+
+        // The next session starts over
+        manager.session_day = Some(session_day.succ());
+        assert_eq!(manager.risk_report().realized_pnl, Decimal::ZERO);
+        assert!(manager.portfolio_snapshot().positions.is_empty());
+    }
+
+    #[test]
+    fn pattern_day_trade_protection() {
+        let mut manager = RiskManager {
+            cash: Decimal::new(10000, 0),
+            last_equity: Decimal::new(10000, 0),
+            day_trades: DayTradeTracker::new(3),
+            ..Default::default()
+        };
+        let lot = |ticker: &str, shares| Lot {
+            id: uuid::Uuid::new_v4(),
+            order_id: uuid::Uuid::new_v4(),
+            ticker: ticker.into(),
+            fill_time: Utc::now(),
+            price: Decimal::new(100, 0),
+            shares: Decimal::new(shares, 0),
         };
+        manager.process_lot(lot("AAPL", 10));
+
+        let trade_intent = TradeIntent::new("AAPL", -10).order_type(OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        });
+        let response = manager.risk_check(&trade_intent).unwrap();
+        assert_eq!(
+            response,
+            RiskCheckResponse::Denied {
+                intent: trade_intent.clone(),
+                reasons: vec![DenyReason::PatternDayTradeProtection],
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                timing: None,
+            }
+        );
+
+        manager.last_equity = Decimal::new(25000, 0);
+        let response = manager.risk_check(&trade_intent).unwrap();
+        assert_eq!(
+            response,
+            RiskCheckResponse::Granted {
+                intent: trade_intent,
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                metrics: Some(TradeMetrics {
+                    buying_power_before: Decimal::new(24000, 0),
+                    buying_power_after: Decimal::new(25000, 0),
+                    projected_position: Decimal::ZERO,
+                    projected_gross_exposure: Decimal::ZERO,
+                    projected_net_exposure: Decimal::ZERO,
+                    margin_utilization: Some(Decimal::ZERO),
+                }),
+                timing: None,
+            }
+        );
+    }
+
+    #[test]
+    fn duplicate_intents() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
+        let trade_intent = TradeIntent::new("AAPL", 1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        });
+        assert!(manager.previous_decision(&trade_intent.id).is_none());
+        let response = manager.risk_check(&trade_intent).unwrap();
+        manager.record_decision(&response);
+        assert_eq!(manager.previous_decision(&trade_intent.id), Some(&response));
+    }
 
+    #[test]
+    fn handoff() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
         manager.update_holdings(
-            "TRP",
-            Shares(Decimal::new(5118, 0)),
-            Price(Decimal::new(4869, 2)),
+            "AAPL",
+            Shares(Decimal::new(5, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        let trade_intent = TradeIntent::new("AAPL", 2).order_type(OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        });
+        let response = manager.risk_check(&trade_intent).unwrap();
+        manager.record_decision(&response);
+        manager.apply_control(&ControlCommand::PauseTicker("TSLA".into()));
+        manager.update_borrow_rate("GME", Decimal::new(50, 2));
+
+        // The state survives being serialized on its way to the next instance
+        let state = serde_json::to_string(&manager.handoff_state()).unwrap();
+        let mut restored = RiskManager::default();
+        restored.restore(serde_json::from_str(&state).unwrap());
+        assert_eq!(restored.portfolio_snapshot(), manager.portfolio_snapshot());
+        assert_eq!(restored.reserved_buying_power(), Decimal::new(200, 0));
+        assert_eq!(
+            restored.previous_decision(&trade_intent.id),
+            Some(&response)
+        );
+        assert_eq!(restored.halts(), manager.halts());
+        assert_eq!(restored.borrow_rate("GME"), Some(Decimal::new(50, 2)));
+    }
+
+    #[test]
+    fn drawdown_breaker() {
+        let mut manager = RiskManager::new(
+            String::new(),
+            RiskSettings {
+                max_drawdown: Some(Decimal::new(5, 2)),
+                ..Default::default()
+            },
         );
+        manager.update_cash(Decimal::new(10000, 0));
         manager.update_holdings(
-            "MPLX",
-            Shares(Decimal::new(8825, 0)),
-            Price(Decimal::new(2812, 2)),
+            "AAPL",
+            Shares(Decimal::new(10, 0)),
+            Price(Decimal::new(100, 0)),
         );
+        assert_eq!(manager.update_drawdown(), None);
+        manager.update_price("AAPL", Price(Decimal::new(40, 0)));
+        let alert = manager.update_drawdown().unwrap();
+        assert_eq!(alert.level, AlertLevel::Critical);
+        assert!(manager.drawdown_tripped());
+
+        let opening = TradeIntent::new("TSLA", 1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(10, 0),
+        });
+        assert!(matches!(
+            manager.risk_check(&opening).unwrap(),
+            RiskCheckResponse::Denied { reasons, .. }
+                if reasons == vec![DenyReason::MaxDrawdownExceeded {
+                    max_drawdown: Decimal::new(5, 2)
+                }]
+        ));
+        let closing = TradeIntent::new("AAPL", -10).order_type(OrderType::Limit {
+            limit_price: Decimal::new(40, 0),
+        });
+        assert!(matches!(
+            manager.risk_check(&closing).unwrap(),
+            RiskCheckResponse::Granted { .. }
+        ));
+    }
+
+    #[test]
+    fn closed_positions() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
         manager.update_holdings(
-            "E",
-            Shares(Decimal::new(19539, 0)),
-            Price(Decimal::new(2556, 2)),
+            "AAPL",
+            Shares(Decimal::new(5, 0)),
+            Price(Decimal::new(100, 0)),
         );
         manager.update_holdings(
-            "DVN",
-            Shares(Decimal::new(-8146, 0)),
-            Price(Decimal::new(3058, 2)),
+            "AAPL",
+            Shares(Decimal::new(-5, 0)),
+            Price(Decimal::new(110, 0)),
+        );
+        assert!(manager.position("AAPL").is_none());
+        assert_eq!(manager.positions().count(), 0);
+        assert!(!manager.is_closing("AAPL", Decimal::new(-1, 0)));
+        // A price for a closed position doesn't bring it back
+        manager.update_price("AAPL", Price(Decimal::new(120, 0)));
+        assert!(manager.position("AAPL").is_none());
+    }
+
+    #[test]
+    fn portfolio_reads() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(5, 0)),
+            Price(Decimal::new(100, 0)),
         );
+        // Buying the shares was paid for in cash
+        assert_eq!(manager.cash(), Decimal::new(500, 0));
+        let positions: Vec<_> = manager.positions().collect();
+        assert_eq!(positions.len(), 1);
+        assert_eq!(manager.position("AAPL").as_ref(), positions.first());
+        assert_eq!(positions[0].market_value, Decimal::new(500, 0));
+        assert!(manager.position("TSLA").is_none());
+        assert_eq!(manager.portfolio_snapshot().positions, positions);
+    }
+
+    #[test]
+    fn position_pnl() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(10000, 0));
         manager.update_holdings(
-            "CVE",
-            Shares(Decimal::new(-27716, 0)),
-            Price(Decimal::new(910, 2)),
+            "AAPL",
+            Shares(Decimal::new(10, 0)),
+            Price(Decimal::new(100, 0)),
         );
         manager.update_holdings(
-            "COP",
-            Shares(Decimal::new(-4005, 0)),
-            Price(Decimal::new(6243, 2)),
+            "AAPL",
+            Shares(Decimal::new(10, 0)),
+            Price(Decimal::new(110, 0)),
         );
+        let position = manager.position("AAPL").unwrap();
+        assert_eq!(position.avg_cost, Decimal::new(105, 0));
+        assert_eq!(position.market_price, Decimal::new(110, 0));
+        assert_eq!(position.unrealized_pnl, Decimal::new(100, 0));
+        assert_eq!(position.side, PositionSide::Long);
+
+        // Selling keeps the average cost, and flipping short starts over
         manager.update_holdings(
-            "BKR",
-            Shares(Decimal::new(10527, 0)),
-            Price(Decimal::new(2368, 2)),
+            "AAPL",
+            Shares(Decimal::new(-5, 0)),
+            Price(Decimal::new(120, 0)),
+        );
+        assert_eq!(
+            manager.position("AAPL").unwrap().avg_cost,
+            Decimal::new(105, 0)
+        );
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(-20, 0)),
+            Price(Decimal::new(120, 0)),
+        );
+        manager.update_price("AAPL", Price(Decimal::new(100, 0)));
+        let position = manager.position("AAPL").unwrap();
+        assert_eq!(position.avg_cost, Decimal::new(120, 0));
+        assert_eq!(position.unrealized_pnl, Decimal::new(100, 0));
+        assert_eq!(position.side, PositionSide::Short);
+
+        let snapshot = manager.portfolio_snapshot();
+        assert_eq!(snapshot.report, manager.risk_report());
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["equity"], serde_json::json!(snapshot.report.equity));
+        assert_eq!(
+            serde_json::from_value::<PortfolioSnapshot>(json).unwrap(),
+            snapshot
+        );
+    }
+
+    #[test]
+    fn amendments() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
+        let trade_intent = TradeIntent::new("AAPL", 15).order_type(OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        });
+        let response = manager.risk_check(&trade_intent).unwrap();
+        manager.record_decision(&response);
+        let amend = |qty| OrderAmendment {
+            id: Uuid::new_v4(),
+            amends: trade_intent.id,
+            qty,
+            limit_price: None,
+        };
+
+        // Buying power is 2000, so only the increase over the 1500 reserved has to fit
+        let response = manager
+            .amendment_check(&amend(40), RequestOptions::default())
+            .unwrap();
+        assert!(matches!(response, RiskCheckResponse::Denied { .. }));
+        let amendment = amend(25);
+        let response = manager
+            .amendment_check(&amendment, RequestOptions::default())
+            .unwrap();
+        assert!(matches!(response, RiskCheckResponse::Granted { .. }));
+        assert_eq!(response.intent().qty, 25);
+        assert_eq!(response.intent().id, amendment.id);
+        manager.record_amendment(&amendment, &response);
+        let response = manager
+            .amendment_check(&amend(40), RequestOptions::default())
+            .unwrap();
+        assert!(matches!(response, RiskCheckResponse::Granted { .. }));
+
+        assert!(manager
+            .amendment_check(&amend(-5), RequestOptions::default())
+            .is_err());
+        let unknown = OrderAmendment {
+            amends: Uuid::new_v4(),
+            ..amend(5)
+        };
+        assert!(manager
+            .amendment_check(&unknown, RequestOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    fn partial_approvals() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
+        let trade_intent = TradeIntent::new("AAPL", -30).order_type(OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        });
+        let allow_partial = RequestOptions {
+            allow_partial: Some(true),
+        };
+        let response = manager.risk_check(&trade_intent).unwrap();
+        assert!(matches!(response, RiskCheckResponse::Denied { .. }));
+        let response = manager
+            .risk_check_with(&trade_intent, allow_partial)
+            .unwrap();
+        assert_eq!(
+            response,
+            RiskCheckResponse::Amended {
+                original: trade_intent.clone(),
+                approved_qty: -19,
+                buffered_price: None,
+                price_source: None,
+                score: None,
+                timing: None,
+            }
         );
-        manager.update_holdings(
-            "APA",
-            Shares(Decimal::new(-24754, 0)),
-            Price(Decimal::new(2033, 2)),
+        // The quantities tried on the way to the approved one aren't counted as evaluations
+        let stats = manager.rule_stats();
+        assert_eq!(stats.get("buying_power").evaluations(), 2);
+        assert_eq!(stats.get("buying_power").denials, 2);
+
+        manager.bind_limits(SharedLimits::new(RiskLimits {
+            blocklist: vec!["AAPL".to_string()].into_iter().collect(),
+            ..Default::default()
+        }));
+        let response = manager
+            .risk_check_with(&trade_intent, allow_partial)
+            .unwrap();
+        assert!(matches!(response, RiskCheckResponse::Denied { .. }));
+    }
+
+    #[test]
+    fn response_conversions() {
+        let intent = TradeIntent::new("AAPL", 10);
+        let amended = RiskCheckResponse::Amended {
+            original: intent.clone(),
+            approved_qty: 5,
+            buffered_price: Some(Decimal::ONE),
+            price_source: None,
+            score: None,
+            timing: None,
+        };
+        assert_eq!(
+            amended.clone().into_granted(),
+            RiskCheckResponse::Granted {
+                intent: intent.clone(),
+                buffered_price: Some(Decimal::ONE),
+                price_source: None,
+                score: None,
+                metrics: None,
+                timing: None,
+            }
         );
-        manager.update_cash(Decimal::new(99283298, 2));
-        assert_eq!(manager.long_market_exposure(), Decimal::new(124605062, 2));
-        assert_eq!(manager.short_market_exposure(), Decimal::new(125460125, 2));
-        assert_eq!(manager.gross_market_exposure(), Decimal::new(250065187, 2));
-        assert_eq!(manager.net_market_exposure(), Decimal::new(-855063, 2));
-        assert_eq!(manager.equity(), Decimal::new(98428235, 2));
-        assert_eq!(manager.initial_margin(), Decimal::new(1250325935, 3));
-        assert_eq!(manager.maintenance_margin(), Decimal::new(750195561, 3));
-        assert_eq!(manager.regt_buying_power(), Decimal::ZERO);
         assert_eq!(
-            manager.daytrading_buying_power(),
-            Decimal::new(149100605, 2)
+            amended.into_denied(vec![DenyReason::Blocklisted]),
+            RiskCheckResponse::Denied {
+                intent,
+                reasons: vec![DenyReason::Blocklisted],
+                buffered_price: Some(Decimal::ONE),
+                price_source: None,
+                score: None,
+                timing: None,
+            }
         );
-        assert_eq!(manager.buying_power(), Decimal::new(149100605, 2));
     }
 
     #[test]
-    fn equity_calculations() {
-        let mut manager = RiskManager {
-            kafka_consumer: None,
-            alpaca_client: None,
-            cash: Decimal::ZERO,
-            holdings: HashMap::new(),
-            is_pattern_day_trader: true,
-            last_equity: Decimal::ZERO,
-            last_maintenance_margin: Decimal::ZERO,
-            datastore_url: String::new(),
-        };
+    fn decision_timing() {
+        let received_at = Utc::now() - chrono::Duration::milliseconds(250);
+        let response = RiskCheckResponse::Denied {
+            intent: TradeIntent::new("AAPL", 10),
+            reasons: vec![DenyReason::Blocklisted],
+            buffered_price: None,
+            price_source: None,
+            score: None,
+            timing: None,
+        }
+        .timed(received_at);
+        let timing = *response.timing().unwrap();
+        assert_eq!(timing.received_at, received_at);
+        assert!(timing.latency_ms >= 250);
 
-        manager.update_holdings("AAPL", Shares(Decimal::ONE), Price(Decimal::new(100, 0)));
-        manager.update_cash(Decimal::new(300, 0));
-        assert_eq!(manager.long_market_exposure(), Decimal::new(100, 0));
-        assert_eq!(manager.short_market_exposure(), Decimal::ZERO);
-        assert_eq!(manager.gross_market_exposure(), Decimal::new(100, 0));
-        assert_eq!(manager.net_market_exposure(), Decimal::new(100, 0));
-        assert_eq!(manager.equity(), Decimal::new(400, 0));
-        assert_eq!(manager.initial_margin(), Decimal::new(50, 0));
-        assert_eq!(manager.maintenance_margin(), Decimal::new(30, 0));
-        assert_eq!(manager.regt_buying_power(), Decimal::new(700, 0));
-        assert_eq!(manager.daytrading_buying_power(), Decimal::ZERO);
-        assert_eq!(manager.buying_power(), Decimal::new(700, 0));
+        let json = serde_json::to_value(&response).unwrap();
+        assert!(json.get("received_at").is_some());
+        assert!(json.get("latency_ms").is_some());
+        let deserialized: RiskCheckResponse = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized, response);
 
-        manager.update_holdings(
-            "TSLA",
-            Shares(Decimal::new(-2, 0)),
-            Price(Decimal::new(80, 0)),
+        // Untimed decisions, like those from before timing was added, still deserialize
+        let untimed = response.clone().with_intent(TradeIntent::new("AAPL", 10));
+        let json = serde_json::to_value(&untimed).unwrap();
+        assert!(json.get("received_at").is_none());
+        let untimed: RiskCheckResponse = serde_json::from_value(json).unwrap();
+        assert!(untimed.timing().is_none());
+        // Reused decisions get timed again
+        assert!(response
+            .with_intent(TradeIntent::new("AAPL", 10))
+            .timing()
+            .is_none());
+    }
+
+    #[test]
+    #[cfg(feature = "reqwest")]
+    fn evaluation_cache() {
+        let _m = mockito::mock("GET", "/last/MSFT")
+            .with_body("100")
+            .expect(2)
+            .create();
+        let mut manager = RiskManager::new(
+            mockito::server_url(),
+            RiskSettings {
+                evaluation_cache: crate::settings::EvaluationCacheSettings {
+                    enabled: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
         );
-        assert_eq!(manager.long_market_exposure(), Decimal::new(100, 0));
-        assert_eq!(manager.short_market_exposure(), Decimal::new(160, 0));
-        assert_eq!(manager.gross_market_exposure(), Decimal::new(260, 0));
-        assert_eq!(manager.net_market_exposure(), Decimal::new(-60, 0));
-        assert_eq!(manager.equity(), Decimal::new(400, 0));
-        assert_eq!(manager.initial_margin(), Decimal::new(130, 0));
-        assert_eq!(manager.maintenance_margin(), Decimal::new(78, 0));
-        assert_eq!(manager.regt_buying_power(), Decimal::new(540, 0));
-        assert_eq!(manager.daytrading_buying_power(), Decimal::ZERO);
-        assert_eq!(manager.buying_power(), Decimal::new(540, 0));
+        manager.update_cash(Decimal::new(1000, 0));
+        let first = TradeIntent::new("MSFT", 1);
+        let retry = TradeIntent::new("MSFT", 1);
+        let response = manager.risk_check(&first).unwrap();
+        // Marks are left to the expiry of cached decisions
+        manager.update_price("MSFT", Price(Decimal::new(101, 0)));
+        let cached = manager.risk_check(&retry).unwrap();
+        assert_eq!(cached, response.with_intent(retry.clone()));
+        let stats = manager.evaluation_cache_stats();
+        assert_eq!((stats.hits(), stats.misses()), (1, 1));
+
+        // Any change in state invalidates cached decisions
+        manager.update_cash(Decimal::ZERO);
+        let response = manager.risk_check(&retry).unwrap();
+        assert!(matches!(response, RiskCheckResponse::Denied { .. }));
+        assert_eq!((stats.hits(), stats.misses()), (1, 2));
+        _m.assert();
+    }
 
+    #[test]
+    fn concentration_add_on() {
+        let mut manager = RiskManager::new(
+            String::new(),
+            RiskSettings {
+                margin: crate::margin::MarginSchedule {
+                    concentration: Some(crate::margin::ConcentrationAddOn {
+                        threshold: Decimal::new(5, 1),
+                        basis: Default::default(),
+                        add_on: Decimal::new(2, 1),
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        manager.update_cash(Decimal::new(10000, 0));
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(30, 0)),
+            Price(Decimal::new(100, 0)),
+        );
         manager.update_holdings(
             "TSLA",
-            Shares(Decimal::new(-1, 0)),
+            Shares(Decimal::new(10, 0)),
             Price(Decimal::new(100, 0)),
         );
-        assert_eq!(manager.long_market_exposure(), Decimal::new(100, 0));
-        assert_eq!(manager.short_market_exposure(), Decimal::new(300, 0));
-        assert_eq!(manager.gross_market_exposure(), Decimal::new(400, 0));
-        assert_eq!(manager.net_market_exposure(), Decimal::new(-200, 0));
-        assert_eq!(manager.equity(), Decimal::new(360, 0));
-        assert_eq!(manager.initial_margin(), Decimal::new(200, 0));
-        assert_eq!(manager.maintenance_margin(), Decimal::new(120, 0));
-        assert_eq!(manager.regt_buying_power(), Decimal::new(320, 0));
-        assert_eq!(manager.daytrading_buying_power(), Decimal::ZERO);
-        assert_eq!(manager.buying_power(), Decimal::new(320, 0));
-
+        // AAPL is 75% of gross exposure, so it is margined at 50% rather than 30%
+        assert_eq!(manager.maintenance_margin(), Decimal::new(1800, 0));
         manager.update_holdings(
             "TSLA",
-            Shares(Decimal::new(3, 0)),
-            Price(Decimal::new(90, 0)),
+            Shares(Decimal::new(20, 0)),
+            Price(Decimal::new(100, 0)),
         );
-        assert_eq!(manager.long_market_exposure(), Decimal::new(100, 0));
-        assert_eq!(manager.short_market_exposure(), Decimal::ZERO);
-        assert_eq!(manager.gross_market_exposure(), Decimal::new(100, 0));
-        assert_eq!(manager.net_market_exposure(), Decimal::new(100, 0));
-        assert_eq!(manager.equity(), Decimal::new(390, 0));
-        assert_eq!(manager.initial_margin(), Decimal::new(50, 0));
-        assert_eq!(manager.maintenance_margin(), Decimal::new(30, 0));
-        assert_eq!(manager.regt_buying_power(), Decimal::new(680, 0));
-        assert_eq!(manager.daytrading_buying_power(), Decimal::ZERO);
-        assert_eq!(manager.buying_power(), Decimal::new(680, 0));
+        assert_eq!(manager.maintenance_margin(), Decimal::new(1800, 0));
     }
 
     #[test]
-    fn risk_check() {
-        let mut manager = RiskManager {
-            kafka_consumer: None,
-            alpaca_client: None,
-            cash: Decimal::ZERO,
-            holdings: HashMap::new(),
-            is_pattern_day_trader: true,
-            last_equity: Decimal::ZERO,
-            last_maintenance_margin: Decimal::ZERO,
-            datastore_url: String::new(),
+    fn broker_account() {
+        let mut manager = RiskManager::new(
+            String::new(),
+            RiskSettings {
+                multiplier: crate::settings::MultiplierSettings {
+                    min_margin_equity: Decimal::new(2000, 0),
+                    pdt_equity: Decimal::new(10000, 0),
+                },
+                ..Default::default()
+            },
+        );
+        manager.is_pattern_day_trader = true;
+        manager.update_cash(Decimal::new(20000, 0));
+        assert_eq!(manager.multiplier(), Decimal::new(4, 0));
+
+        // The broker's multiplier wins over the estimate
+        manager.account_multiplier = Some(Decimal::new(2, 0));
+        assert_eq!(manager.multiplier(), Decimal::new(2, 0));
+
+        manager.shorting_disabled = true;
+        let limit = || OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
         };
+        let short = TradeIntent::new("AAPL", -1).order_type(limit());
+        assert!(matches!(
+            manager.risk_check(&short).unwrap(),
+            RiskCheckResponse::Denied { reasons, .. } if reasons == vec![DenyReason::ShortingDisabled]
+        ));
+        let long = TradeIntent::new("AAPL", 1).order_type(limit());
+        assert!(matches!(
+            manager.risk_check(&long).unwrap(),
+            RiskCheckResponse::Granted { .. }
+        ));
 
-        manager.update_holdings("AAPL", Shares(Decimal::ONE), Price(Decimal::new(100, 0)));
+        manager.account_blocks.trade_suspended_by_user = true;
+        assert!(matches!(
+            manager.risk_check(&long).unwrap(),
+            RiskCheckResponse::Denied { reasons, .. } if reasons == vec![DenyReason::AccountRestricted]
+        ));
+    }
+
+    #[tokio::test]
+    async fn roll_session() {
+        let clock = ManualClock::new(Utc.ymd(2021, 6, 4).and_hms(13, 30, 0));
+        let mut manager = RiskManager::default();
+        manager.bind_clock(clock.clone());
+        manager.update_cash(Decimal::new(10000, 0));
         manager.update_holdings(
-            "TSLA",
-            Shares(Decimal::new(-2, 0)),
-            Price(Decimal::new(80, 0)),
+            "AAPL",
+            Shares(Decimal::new(10, 0)),
+            Price(Decimal::new(100, 0)),
         );
-        manager.update_cash(Decimal::new(300, 0));
+        manager.roll_session().await.unwrap();
+        assert_eq!(manager.last_equity, Decimal::new(10000, 0));
+        assert_eq!(manager.last_maintenance_margin, Decimal::new(300, 0));
+
+        // Later in the same session nothing rolls
+        manager.update_price("AAPL", Price(Decimal::new(110, 0)));
+        manager.roll_session().await.unwrap();
+        assert_eq!(manager.last_equity, Decimal::new(10000, 0));
+
+        clock.advance(chrono::Duration::days(3));
+        manager.roll_session().await.unwrap();
+        assert_eq!(manager.last_equity, Decimal::new(10100, 0));
+        assert_eq!(manager.last_maintenance_margin, Decimal::new(330, 0));
+    }
 
+    #[test]
+    fn clock() {
+        let clock = ManualClock::new(Utc.ymd(2021, 6, 4).and_hms(15, 0, 0));
+        let mut manager = RiskManager::new(
+            String::new(),
+            RiskSettings {
+                evaluation_cache: crate::settings::EvaluationCacheSettings {
+                    enabled: true,
+                    ttl_ms: 1000,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        manager.bind_clock(clock.clone());
+        manager.update_cash(Decimal::new(1000, 0));
         let trade_intent = TradeIntent::new("AAPL", 1).order_type(OrderType::Limit {
             limit_price: Decimal::new(100, 0),
         });
+        manager.risk_check(&trade_intent).unwrap();
+        manager.risk_check(&trade_intent).unwrap();
+        let stats = manager.evaluation_cache_stats();
+        assert_eq!((stats.hits(), stats.misses()), (1, 1));
+        clock.advance(chrono::Duration::seconds(1));
+        manager.risk_check(&trade_intent).unwrap();
+        assert_eq!((stats.hits(), stats.misses()), (1, 2));
+
+        // Positions are opened on the clock's date
         let response = manager.risk_check(&trade_intent).unwrap();
+        manager.record_decision(&response);
+        assert!(manager
+            .day_trades
+            .is_day_trade("AAPL", NaiveDate::from_ymd(2021, 6, 4)));
+    }
+
+    #[test]
+    fn what_if() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
+        let trade_intent = TradeIntent::new("AAPL", 5).order_type(OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        });
+        let response = manager.what_if(&trade_intent).unwrap();
+        assert!(matches!(
+            response.decision,
+            RiskCheckResponse::Granted { .. }
+        ));
+        assert_eq!(response.projected.report.cash, Decimal::new(500, 0));
+        assert_eq!(response.projected.report.equity, Decimal::new(1000, 0));
         assert_eq!(
-            response,
-            RiskCheckResponse::Granted {
-                intent: trade_intent
-            }
+            response.projected.report.gross_market_exposure,
+            Decimal::new(500, 0)
+        );
+        assert_eq!(
+            response.projected.report.regt_buying_power,
+            Decimal::new(1500, 0)
         );
+        // Nothing about the intent is remembered
+        assert_eq!(manager.buying_power(), Decimal::new(2000, 0));
+        assert!(manager.previous_decision(&trade_intent.id).is_none());
+        assert_eq!(manager.rule_stats().get("buying_power").evaluations(), 0);
 
-        let trade_intent = TradeIntent::new("AAPL", 1).order_type(OrderType::Limit {
-            limit_price: Decimal::new(240, 0),
+        // Other working orders keep their reservations in the projection, as they do in checks
+        let working = TradeIntent::new("TSLA", 1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(200, 0),
         });
-        let response = manager.risk_check(&trade_intent).unwrap();
+        let granted = manager.risk_check(&working).unwrap();
+        manager.record_decision(&granted);
+        let response = manager.what_if(&trade_intent).unwrap();
         assert_eq!(
+            response.projected.report.reserved_buying_power,
+            Decimal::new(200, 0)
+        );
+        let response = manager.what_if(&working).unwrap();
+        assert_eq!(
+            response.projected.report.reserved_buying_power,
+            Decimal::ZERO
+        );
+    }
+
+    fn tick(price: Decimal) -> PriceTick {
+        PriceTick {
+            price,
+            timestamp: Some(Utc::now()),
+            source: None,
+        }
+    }
+
+    #[test]
+    fn stale_price() {
+        let mut manager = RiskManager::new(
+            String::new(),
+            RiskSettings {
+                max_price_age_secs: Some(60),
+                ..Default::default()
+            },
+        );
+        let now = Utc.ymd(2021, 6, 1).and_hms(14, 30, 0);
+        manager.bind_clock(ManualClock::new(now));
+        manager.update_cash(Decimal::new(1000, 0));
+        let trade_intent = TradeIntent::new("AAPL", 1);
+        let stale = PriceTick {
+            price: Decimal::new(100, 0),
+            timestamp: Some(now - chrono::Duration::minutes(20)),
+            source: None,
+        };
+        let response = manager
+            .risk_check_priced(&trade_intent, RequestOptions::default(), Some(stale))
+            .unwrap();
+        assert!(matches!(
             response,
-            RiskCheckResponse::Denied {
-                intent: trade_intent,
-                reason: DenyReason::InsufficientBuyingPower {
-                    buying_power: Decimal::new(220, 0)
-                }
-            }
+            RiskCheckResponse::Denied { reasons, .. }
+                if reasons == vec![DenyReason::StalePrice { age: Decimal::new(1200, 0) }]
+        ));
+    }
+
+    #[test]
+    fn explain() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
+        manager.bind_limits(SharedLimits::new(RiskLimits {
+            max_notional: Some(Decimal::new(400, 0)),
+            ..Default::default()
+        }));
+        let trade_intent = TradeIntent::new("AAPL", 5);
+        let explanation = manager
+            .explain(&trade_intent, Some(tick(Decimal::new(100, 0))))
+            .unwrap();
+        assert!(matches!(
+            explanation.decision,
+            RiskCheckResponse::Denied { .. }
+        ));
+        // Rules after the denial are still traced
+        let outcome = |rule: &str| {
+            explanation
+                .rules
+                .iter()
+                .find(|trace| trace.rule == rule)
+                .map(|trace| trace.outcome.clone())
+        };
+        assert_eq!(
+            outcome("notional_limit"),
+            Some(RuleOutcome::Deny(DenyReason::NotionalLimitExceeded {
+                max_notional: Decimal::new(400, 0)
+            }))
         );
+        assert_eq!(outcome("buying_power"), Some(RuleOutcome::Pass));
+        assert_eq!(explanation.rules.len(), manager.rules.names().len());
+    }
 
-        let trade_intent = TradeIntent::new("AAPL", -2).order_type(OrderType::Limit {
-            limit_price: Decimal::new(120, 0),
-        });
+    #[test]
+    #[cfg(feature = "reqwest")]
+    fn market_order_slippage() {
+        let _m = mockito::mock("GET", "/last/AAPL").with_body("100").create();
+        let mut manager = RiskManager {
+            price_sources: Arc::new(PriceSources::datastore(mockito::server_url()).unwrap()),
+            ..Default::default()
+        };
+        manager.bind_limits(SharedLimits::new(RiskLimits {
+            tickers: vec![(
+                "AAPL".to_string(),
+                TickerLimits {
+                    slippage: Some(Decimal::new(5, 2)),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        }));
+        manager.update_cash(Decimal::new(1000, 0));
+        assert_eq!(manager.slippage("TSLA"), Decimal::new(3, 2));
+        assert_eq!(manager.slippage("AAPL"), Decimal::new(5, 2));
+
+        let trade_intent = TradeIntent::new("AAPL", 1).order_type(OrderType::Market);
         let response = manager.risk_check(&trade_intent).unwrap();
         assert_eq!(
             response,
-            RiskCheckResponse::Denied {
+            RiskCheckResponse::Granted {
                 intent: trade_intent,
-                reason: DenyReason::ChangeInPositionSide
+                buffered_price: Some(Decimal::new(105, 0)),
+                price_source: Some("datastore".into()),
+                score: None,
+                metrics: Some(TradeMetrics {
+                    buying_power_before: Decimal::new(2000, 0),
+                    buying_power_after: Decimal::new(1895, 0),
+                    projected_position: Decimal::ONE,
+                    projected_gross_exposure: Decimal::new(105, 0),
+                    projected_net_exposure: Decimal::new(105, 0),
+                    margin_utilization: Some(Decimal::new(315, 4)),
+                }),
+                timing: None,
             }
         );
+    }
 
-        let trade_intent = TradeIntent::new("AAPL", -1).order_type(OrderType::Limit {
-            limit_price: Decimal::new(120, 0),
+    #[test]
+    fn crypto() {
+        let mut crypto = CryptoSettings::default();
+        crypto.symbols.insert("BTCUSD".into());
+        crypto.symbols.insert("ETHUSD".into());
+        let mut manager = RiskManager {
+            settings: RiskSettings {
+                crypto: Some(crypto),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(manager.slippage("BTCUSD"), Decimal::new(1, 2));
+        assert_eq!(manager.slippage("AAPL"), Decimal::new(3, 2));
+
+        // Crypto is bought with cash and adds nothing to buying power
+        manager.update_cash(Decimal::new(1000, 0));
+        manager.update_holdings(
+            "BTCUSD",
+            Shares(Decimal::new(1, 2)),
+            Price(Decimal::new(40000, 0)),
+        );
+        assert_eq!(manager.initial_margin(), Decimal::new(400, 0));
+        assert_eq!(manager.cash_buying_power(), Decimal::new(600, 0));
+
+        // Nor can it be shorted
+        let trade_intent = TradeIntent::new("ETHUSD", -1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(3000, 0),
         });
-        let response = manager.risk_check(&trade_intent).unwrap();
-        assert_eq!(
-            response,
-            RiskCheckResponse::Granted {
-                intent: trade_intent,
+        match manager.risk_check(&trade_intent).unwrap() {
+            RiskCheckResponse::Denied { reasons, .. } => {
+                assert_eq!(reasons, vec![DenyReason::ShortingDisabled])
             }
-        )
+            response => panic!("Expected a denial, got {:?}", response),
+        }
+
+        // Round trips aren't day trades, and their proceeds settle right away
+        let lot = |shares| Lot {
+            id: uuid::Uuid::new_v4(),
+            order_id: uuid::Uuid::new_v4(),
+            ticker: "ETHUSD".into(),
+            fill_time: Utc::now(),
+            price: Decimal::new(3000, 0),
+            shares: Decimal::new(shares, 2),
+        };
+        manager.process_lot(lot(10));
+        manager.process_lot(lot(-10));
+        let today = Utc::now().date().naive_utc();
+        assert!(!manager.day_trades.is_day_trade("ETHUSD", today));
+        assert_eq!(manager.day_trades.count(today), 0);
+        assert_eq!(manager.settlement_ledger.unsettled_cash(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn options() {
+        let call = "AAPL240119C00150000";
+        assert!(RiskManager::default().option_contract(call).is_none());
+        let mut manager = RiskManager {
+            settings: RiskSettings {
+                options: Some(Default::default()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(manager.contract_multiplier(call), Decimal::new(100, 0));
+        assert_eq!(manager.contract_multiplier("AAPL"), Decimal::ONE);
+
+        manager.update_cash(Decimal::new(100000, 0));
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(100, 0)),
+            Price(Decimal::new(145, 0)),
+        );
+        // The call is covered by the shares
+        manager.update_holdings(call, Shares(-Decimal::ONE), Price(Decimal::new(2, 0)));
+        assert_eq!(manager.cash(), Decimal::new(85700, 0));
+        assert_eq!(
+            manager.position(call).unwrap().market_value,
+            Decimal::new(-200, 0)
+        );
+        assert_eq!(manager.initial_margin(), Decimal::new(7250, 0));
+        assert_eq!(manager.maintenance_margin(), Decimal::new(4350, 0));
+
+        // This one isn't: 1 + 20% of 145 less 10 out of the money
+        manager.update_holdings(
+            "AAPL240119C00155000",
+            Shares(-Decimal::ONE),
+            Price(Decimal::ONE),
+        );
+        assert_eq!(manager.maintenance_margin(), Decimal::new(6350, 0));
+        assert_eq!(manager.equity(), Decimal::new(100000, 0));
+    }
+
+    #[test]
+    fn option_round_trip() {
+        let call = "AAPL240119C00150000";
+        let mut manager = RiskManager {
+            settings: RiskSettings {
+                options: Some(Default::default()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        manager.update_cash(Decimal::new(10000, 0));
+        let lot = |shares, price| Lot {
+            id: uuid::Uuid::new_v4(),
+            order_id: uuid::Uuid::new_v4(),
+            ticker: call.into(),
+            fill_time: Utc::now(),
+            price: Decimal::new(price, 0),
+            shares: Decimal::new(shares, 0),
+        };
+        manager.process_lot(lot(2, 3));
+        manager.process_lot(lot(-1, 5));
+        // Realized and unrealized PnL are both per contract of 100 shares
+        let position = manager.position(call).unwrap();
+        assert_eq!(position.realized_pnl, Decimal::new(200, 0));
+        assert_eq!(position.unrealized_pnl, Decimal::new(200, 0));
+        assert_eq!(manager.cash(), Decimal::new(9900, 0));
+
+        manager.process_lot(lot(-1, 2));
+        let report = manager.risk_report();
+        assert_eq!(report.realized_pnl, Decimal::new(100, 0));
+        assert_eq!(report.unrealized_pnl, Decimal::ZERO);
+    }
+
+    #[test]
+    fn multi_leg() {
+        let limit = |ticker, qty| {
+            TradeIntent::new(ticker, qty).order_type(OrderType::Limit {
+                limit_price: Decimal::new(100, 0),
+            })
+        };
+        let mut manager = RiskManager::default();
+        manager.update_holdings(
+            "MSFT",
+            Shares(Decimal::new(10, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        manager.update_cash(Decimal::ZERO);
+        assert!(manager
+            .risk_check(&limit("AAPL", 15))
+            .unwrap()
+            .granted_intent()
+            .is_none());
+
+        // Selling MSFT frees the buying power to buy AAPL
+        let intent = MultiLegIntent {
+            id: Uuid::new_v4(),
+            legs: vec![limit("MSFT", -10), limit("AAPL", 15)],
+        };
+        assert!(manager.risk_check_legs(&intent).unwrap().is_granted());
+
+        // A leg is never granted on its own
+        manager.shorting_disabled = true;
+        let intent = MultiLegIntent {
+            id: Uuid::new_v4(),
+            legs: vec![limit("AAPL", 5), limit("GOOG", -1)],
+        };
+        let response = manager.risk_check_legs(&intent).unwrap();
+        assert!(!response.is_granted());
+        let reasons: Vec<_> = response
+            .legs
+            .iter()
+            .map(|leg| match leg {
+                RiskCheckResponse::Denied { reasons, .. } => reasons.clone(),
+                _ => panic!("Expected every leg to be denied"),
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                vec![DenyReason::OtherLegDenied],
+                vec![DenyReason::ShortingDisabled]
+            ]
+        );
+    }
+
+    #[test]
+    fn price_updates() {
+        let mut manager = RiskManager::default();
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(10, 0)),
+            Price(Decimal::new(140, 0)),
+        );
+        let now = Utc::now();
+        let tick = |price, timestamp| PriceTick {
+            price: Decimal::new(price, 0),
+            timestamp: Some(timestamp),
+            source: None,
+        };
+        manager.apply_price_update("AAPL", tick(150, now));
+        assert_eq!(manager.holdings["AAPL"].1, Price(Decimal::new(150, 0)));
+        // A tick arriving late doesn't mark the position back
+        manager.apply_price_update("AAPL", tick(100, now - chrono::Duration::seconds(5)));
+        assert_eq!(manager.holdings["AAPL"].1, Price(Decimal::new(150, 0)));
+    }
+
+    #[test]
+    fn covered_calls() {
+        let call = "AAPL240119C00150000";
+        let mut manager = RiskManager {
+            settings: RiskSettings {
+                options: Some(Default::default()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(100, 0)),
+            Price(Decimal::new(145, 0)),
+        );
+        manager.update_cash(Decimal::new(10000, 0));
+        // Writing a covered call isn't a short sale, even where shorting isn't allowed
+        manager.shorting_disabled = true;
+        let intent = TradeIntent::new(call, -1).order_type(OrderType::Limit {
+            limit_price: Decimal::new(2, 0),
+        });
+        let response = manager.risk_check(&intent).unwrap();
+        assert!(response.granted_intent().is_some());
+    }
+
+    #[test]
+    fn multi_leg_deltas() {
+        let call = "AAPL240119C00150000";
+        let mut manager = RiskManager {
+            settings: RiskSettings {
+                options: Some(Default::default()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        manager.bind_limits(SharedLimits::new(RiskLimits {
+            max_gross_exposure: Some(Decimal::new(10000, 0)),
+            ..Default::default()
+        }));
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(50, 0)),
+            Price(Decimal::new(145, 0)),
+        );
+        manager.update_cash(Decimal::new(100000, 0));
+        let limit = |ticker, limit_price| {
+            TradeIntent::new(ticker, 1).order_type(OrderType::Limit { limit_price })
+        };
+        let intent = MultiLegIntent {
+            id: Uuid::new_v4(),
+            legs: vec![
+                limit(call, Decimal::new(3, 0)),
+                limit("AAPL", Decimal::new(145, 0)),
+            ],
+        };
+        // Without a delta the call counts as 100 shares
+        assert!(!manager.risk_check_legs(&intent).unwrap().is_granted());
+        // The legs are checked with the deltas of the account: 51 shares and 10 through the call
+        manager.update_delta(call, Decimal::new(1, 1));
+        assert!(manager.risk_check_legs(&intent).unwrap().is_granted());
     }
 }
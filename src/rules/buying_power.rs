@@ -0,0 +1,288 @@
+use super::{PortfolioContext, RiskRule, RuleOutcome};
+use crate::DenyReason;
+use rust_decimal::Decimal;
+use tracing::trace;
+use trading_base::TradeIntent;
+
+/// Denies intents whose notional value exceeds the account's buying power. Amendments only need
+/// buying power for the increase over their original reservation, and non-marginable assets are
+/// bought with cash only. Symbols with a higher initial margin than the schedule's default use
+/// more buying power per dollar. Option contracts need the option requirement they add, e.g. the
+/// strike of a cash-secured put, out of excess equity, and so do all intents under portfolio
+/// margin, where the requirement they add is the change in stress loss.
+pub struct BuyingPowerRule;
+
+impl BuyingPowerRule {
+    /// Whether the intent is charged the requirement it adds rather than its notional value.
+    fn by_requirement(ctx: &PortfolioContext, intent: &TradeIntent) -> bool {
+        ctx.manager.option_contract(&intent.ticker).is_some()
+            || (ctx.manager.is_portfolio_margin() && ctx.manager.is_marginable(&intent.ticker))
+    }
+
+    fn buying_power(ctx: &PortfolioContext, intent: &TradeIntent) -> Decimal {
+        if Self::by_requirement(ctx, intent) {
+            ctx.manager.excess_equity()
+        } else if PortfolioContext::qty(intent).is_sign_positive()
+            && !ctx.manager.is_marginable(&intent.ticker)
+        {
+            ctx.manager.cash_buying_power()
+        } else {
+            ctx.manager.buying_power()
+        }
+    }
+
+    /// Buying power the intent needs on top of what is already reserved for it.
+    fn required(ctx: &PortfolioContext, intent: &TradeIntent, price: Decimal) -> Decimal {
+        if Self::by_requirement(ctx, intent) {
+            let qty = PortfolioContext::qty(intent);
+            return ctx
+                .manager
+                .initial_margin_change(&intent.ticker, qty, price)
+                - ctx.reserved;
+        }
+        let factor = ctx.manager.buying_power_factor(&intent.ticker);
+        (ctx.notional(intent, price).abs() - ctx.reserved) * factor
+    }
+}
+
+impl RiskRule for BuyingPowerRule {
+    fn name(&self) -> &'static str {
+        "buying_power"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        let price = match ctx.price {
+            Some(price) => price,
+            None => return RuleOutcome::Pass,
+        };
+        let required_buying_power = Self::required(ctx, intent, price);
+        let buying_power = Self::buying_power(ctx, intent);
+        trace!(?buying_power, ?required_buying_power);
+        if required_buying_power <= Decimal::ZERO || buying_power > required_buying_power {
+            RuleOutcome::Pass
+        } else {
+            RuleOutcome::Deny(DenyReason::InsufficientBuyingPower { buying_power })
+        }
+    }
+
+    /// Fraction of the buying power the intent uses.
+    fn score(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> Option<Decimal> {
+        let required_buying_power = Self::required(ctx, intent, ctx.price?);
+        let buying_power = Self::buying_power(ctx, intent);
+        if required_buying_power <= Decimal::ZERO {
+            Some(Decimal::ZERO)
+        } else if buying_power.is_zero() {
+            Some(Decimal::ONE)
+        } else {
+            Some(required_buying_power / buying_power)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Asset, AssetCache, AssetSource, AssetStatus, Price, RiskManager, Shares};
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn buying_power() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(100, 0));
+        let mut ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(50, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        let intent = TradeIntent::new("AAPL", 3);
+        assert_eq!(BuyingPowerRule.evaluate(&ctx, &intent), RuleOutcome::Pass);
+        ctx.price = Some(Decimal::new(100, 0));
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &intent),
+            RuleOutcome::Deny(DenyReason::InsufficientBuyingPower {
+                buying_power: Decimal::new(200, 0)
+            })
+        );
+        ctx.reserved = Decimal::new(150, 0);
+        assert_eq!(BuyingPowerRule.evaluate(&ctx, &intent), RuleOutcome::Pass);
+
+        // Shrinking an amended order needs no buying power at all
+        let broke = RiskManager::default();
+        let ctx = PortfolioContext {
+            manager: &broke,
+            reserved: Decimal::new(300, 0),
+            ..ctx
+        };
+        assert_eq!(BuyingPowerRule.evaluate(&ctx, &intent), RuleOutcome::Pass);
+    }
+
+    struct Assets;
+
+    impl AssetSource for Assets {
+        fn asset(&self, ticker: &str) -> anyhow::Result<Asset> {
+            Ok(Asset {
+                symbol: ticker.into(),
+                status: AssetStatus::Active,
+                tradable: true,
+                marginable: ticker != "OTC",
+                shortable: false,
+                easy_to_borrow: false,
+                fractionable: false,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn non_marginable() {
+        let mut manager = RiskManager::default();
+        let assets = AssetCache::new(Assets, chrono::Duration::hours(1));
+        manager.bind_assets(assets.clone());
+        // Nothing is looked up while margin is computed, and unknown assets are marginable
+        assert!(manager.is_marginable("OTC"));
+        for ticker in ["AAPL", "OTC"].iter() {
+            assets.refresh(ticker, Utc::now()).await;
+        }
+        assert!(!manager.is_marginable("OTC"));
+        manager.update_cash(Decimal::new(1000, 0));
+        assert_eq!(manager.buying_power(), Decimal::new(2000, 0));
+        assert_eq!(manager.cash_buying_power(), Decimal::new(1000, 0));
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(100, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new("AAPL", 15)),
+            RuleOutcome::Pass
+        );
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new("OTC", 15)),
+            RuleOutcome::Deny(DenyReason::InsufficientBuyingPower {
+                buying_power: Decimal::new(1000, 0)
+            })
+        );
+
+        // Non-marginable positions are fully margined, so they add no buying power
+        manager.update_holdings(
+            "OTC",
+            Shares(Decimal::new(5, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        assert_eq!(manager.initial_margin(), Decimal::new(500, 0));
+        assert_eq!(manager.maintenance_margin(), Decimal::new(500, 0));
+        // Only the cash left over counts
+        assert_eq!(manager.regt_buying_power(), Decimal::new(1000, 0));
+        assert_eq!(manager.cash_buying_power(), Decimal::new(500, 0));
+    }
+
+    #[test]
+    fn symbol_initial_margin() {
+        let mut settings = crate::RiskSettings::default();
+        let gme = crate::SymbolMargin {
+            initial: Some(Decimal::ONE),
+            ..Default::default()
+        };
+        settings.margin.symbols.insert("GME".into(), gme);
+        let mut manager = RiskManager::new(String::new(), settings);
+        manager.update_cash(Decimal::new(1000, 0));
+        assert_eq!(manager.buying_power(), Decimal::new(2000, 0));
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(100, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new("AAPL", 15)),
+            RuleOutcome::Pass
+        );
+        // At 100% initial margin, GME can only be bought with the equity itself
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new("GME", 15)),
+            RuleOutcome::Deny(DenyReason::InsufficientBuyingPower {
+                buying_power: Decimal::new(2000, 0)
+            })
+        );
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new("GME", 9)),
+            RuleOutcome::Pass
+        );
+    }
+
+    #[test]
+    fn portfolio_margin() {
+        let mut settings = crate::RiskSettings::default();
+        settings.margin.method = crate::MarginMethod::Portfolio;
+        let mut manager = RiskManager::new(String::new(), settings);
+        manager.update_cash(Decimal::new(10000, 0));
+        // Excess equity isn't doubled, for day trades either
+        assert_eq!(manager.regt_buying_power(), Decimal::new(10000, 0));
+        assert_eq!(manager.daytrading_buying_power(), Decimal::new(10000, 0));
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(100, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        // 50,000 of AAPL adds a stress loss of 7,500
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new("AAPL", 500)),
+            RuleOutcome::Pass
+        );
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new("AAPL", 700)),
+            RuleOutcome::Deny(DenyReason::InsufficientBuyingPower {
+                buying_power: Decimal::new(10000, 0)
+            })
+        );
+    }
+
+    #[test]
+    fn options() {
+        let put = "AAPL240119P00140000";
+        let settings = crate::RiskSettings {
+            options: Some(Default::default()),
+            ..Default::default()
+        };
+        let mut manager = RiskManager::new(String::new(), settings);
+        manager.update_cash(Decimal::new(10000, 0));
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(2, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        // A long option is paid for in full
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new(put, 10)),
+            RuleOutcome::Pass
+        );
+        // Selling a put to open sets its 14,000 strike aside, not the 200 premium
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new(put, -1)),
+            RuleOutcome::Deny(DenyReason::InsufficientBuyingPower {
+                buying_power: Decimal::new(10000, 0)
+            })
+        );
+        manager.update_cash(Decimal::new(20000, 0));
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(2, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        assert_eq!(
+            BuyingPowerRule.evaluate(&ctx, &TradeIntent::new(put, -1)),
+            RuleOutcome::Pass
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use super::{PortfolioContext, RiskRule, RuleOutcome};
+use crate::settings::GoodFaithViolationPolicy;
+use crate::DenyReason;
+use tracing::warn;
+use trading_base::TradeIntent;
+
+/// Denies closing trades that would complete a fourth day trade in five business days while the
+/// account is under the PDT equity threshold.
+pub struct PatternDayTradeRule;
+
+impl RiskRule for PatternDayTradeRule {
+    fn name(&self) -> &'static str {
+        "pattern_day_trade"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        if ctx.is_closing(intent)
+            && ctx
+                .manager
+                .is_pattern_day_trade_violation(&intent.ticker, ctx.today)
+        {
+            RuleOutcome::Deny(DenyReason::PatternDayTradeProtection)
+        } else {
+            RuleOutcome::Pass
+        }
+    }
+}
+
+/// Flags closing trades that would sell a position bought with unsettled funds in a cash account.
+pub struct GoodFaithViolationRule;
+
+impl RiskRule for GoodFaithViolationRule {
+    fn name(&self) -> &'static str {
+        "good_faith_violation"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        if !ctx.is_closing(intent)
+            || !ctx
+                .manager
+                .is_good_faith_violation(&intent.ticker, ctx.today)
+        {
+            return RuleOutcome::Pass;
+        }
+        match ctx.manager.settings().good_faith_violation_policy {
+            GoodFaithViolationPolicy::Deny => RuleOutcome::Deny(DenyReason::GoodFaithViolation),
+            GoodFaithViolationPolicy::Warn => {
+                warn!(id = %intent.id, "Closing trade would cause a good-faith violation");
+                RuleOutcome::Pass
+            }
+            GoodFaithViolationPolicy::Ignore => RuleOutcome::Pass,
+        }
+    }
+}
+
+/// Denies intents for assets the broker reports as inactive or not tradable.
+pub struct TradableRule;
+
+impl RiskRule for TradableRule {
+    fn name(&self) -> &'static str {
+        "tradable"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        match ctx.manager.asset(&intent.ticker) {
+            Some(asset) if !asset.is_tradable() => RuleOutcome::Deny(DenyReason::AssetNotTradable),
+            _ => RuleOutcome::Pass,
+        }
+    }
+}
+
+/// Denies fractional quantities of assets the broker only trades in whole shares. Intents only
+/// carry whole quantities for now, so it isn't part of the default pipeline until they can be
+/// fractional.
+pub struct FractionalRule;
+
+impl RiskRule for FractionalRule {
+    fn name(&self) -> &'static str {
+        "fractional"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        match ctx.manager.asset(&intent.ticker) {
+            Some(asset) if !asset.allows_qty(PortfolioContext::qty(intent)) => {
+                RuleOutcome::Deny(DenyReason::FractionalNotAllowed)
+            }
+            _ => RuleOutcome::Pass,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::RuleKind;
+    use crate::{Asset, AssetCache, AssetSource, AssetStatus, RiskManager};
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+
+    struct Assets;
+
+    impl AssetSource for Assets {
+        fn asset(&self, ticker: &str) -> anyhow::Result<Asset> {
+            Ok(Asset {
+                symbol: ticker.into(),
+                status: AssetStatus::Active,
+                tradable: true,
+                marginable: true,
+                shortable: true,
+                easy_to_borrow: true,
+                fractionable: false,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn whole_share_intents() {
+        let mut manager = RiskManager::default();
+        let assets = AssetCache::new(Assets, chrono::Duration::hours(1));
+        manager.bind_assets(assets.clone());
+        assets.refresh("AAPL", Utc::now()).await;
+        assert!(!manager.asset("AAPL").unwrap().fractionable);
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(100, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        // Whole quantities of whole-share assets are always allowed
+        for qty in [1, -3, 10].iter() {
+            let intent = TradeIntent::new("AAPL", *qty);
+            assert_eq!(FractionalRule.evaluate(&ctx, &intent), RuleOutcome::Pass);
+        }
+        assert!(!RuleKind::default_pipeline().contains(&RuleKind::Fractional));
+    }
+}
@@ -0,0 +1,444 @@
+use super::{PortfolioContext, RiskRule, RuleOutcome};
+use crate::DenyReason;
+use rust_decimal::Decimal;
+use tracing::trace;
+use trading_base::TradeIntent;
+
+/// Denies intents on blocklisted tickers.
+pub struct BlocklistRule;
+
+impl RiskRule for BlocklistRule {
+    fn name(&self) -> &'static str {
+        "blocklist"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        if ctx.manager.limits().blocklist.contains(&intent.ticker) {
+            RuleOutcome::Deny(DenyReason::Blocklisted)
+        } else {
+            RuleOutcome::Pass
+        }
+    }
+}
+
+/// Denies opening trades once equity has fallen more than `max_drawdown` from its peak of the day.
+/// Closing trades are still allowed, to bring risk down.
+pub struct DrawdownRule;
+
+impl RiskRule for DrawdownRule {
+    fn name(&self) -> &'static str {
+        "drawdown"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        let max_drawdown = match ctx.manager.settings().max_drawdown {
+            Some(max_drawdown) => max_drawdown,
+            None => return RuleOutcome::Pass,
+        };
+        if ctx.manager.drawdown_tripped() && !ctx.is_closing(intent) {
+            RuleOutcome::Deny(DenyReason::MaxDrawdownExceeded { max_drawdown })
+        } else {
+            RuleOutcome::Pass
+        }
+    }
+}
+
+/// Denies intents whose notional value exceeds the cap for their ticker, or the global cap.
+pub struct NotionalLimitRule;
+
+impl RiskRule for NotionalLimitRule {
+    fn name(&self) -> &'static str {
+        "notional_limit"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        let max_notional = ctx.manager.limits().max_notional(&intent.ticker);
+        let (price, max_notional) = match (ctx.price, max_notional) {
+            (Some(price), Some(max_notional)) => (price, max_notional),
+            _ => return RuleOutcome::Pass,
+        };
+        let notional = ctx.notional(intent, price).abs();
+        trace!(?notional, ?max_notional);
+        if notional > max_notional {
+            RuleOutcome::Deny(DenyReason::NotionalLimitExceeded { max_notional })
+        } else {
+            RuleOutcome::Pass
+        }
+    }
+
+    fn score(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> Option<Decimal> {
+        let max_notional = ctx.manager.limits().max_notional(&intent.ticker)?;
+        let notional = ctx.notional(intent, ctx.price?).abs();
+        utilization(notional, max_notional)
+    }
+}
+
+/// Denies intents that would take the account's gross or net market exposure past the configured
+/// limits.
+pub struct ExposureLimitRule;
+
+impl ExposureLimitRule {
+    /// Gross and net exposure after the intent, delta-adjusted once options are traded. Unknown
+    /// if delta-adjusted exposure is needed but an option has no known delta.
+    fn exposures(
+        ctx: &PortfolioContext,
+        intent: &TradeIntent,
+        price: Decimal,
+    ) -> Option<(Decimal, Decimal)> {
+        let manager = ctx.manager;
+        if manager.is_delta_adjusted() {
+            let (underlier, notional) =
+                manager.delta_notional(&intent.ticker, PortfolioContext::qty(intent), price)?;
+            return manager.delta_adjusted_exposure(&underlier, notional);
+        }
+        let notional = ctx.notional(intent, price);
+        Some((
+            manager.gross_market_exposure() + notional.abs(),
+            manager.net_market_exposure() + notional,
+        ))
+    }
+}
+
+impl RiskRule for ExposureLimitRule {
+    fn name(&self) -> &'static str {
+        "exposure_limit"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        let price = match ctx.price {
+            Some(price) => price,
+            None => return RuleOutcome::Pass,
+        };
+        let limits = ctx.manager.limits();
+        if limits.max_gross_exposure.is_none() && limits.max_net_exposure.is_none() {
+            return RuleOutcome::Pass;
+        }
+        let (gross_exposure, net_exposure) = match Self::exposures(ctx, intent, price) {
+            Some(exposures) => exposures,
+            None => return RuleOutcome::Deny(DenyReason::DeltaUnknown),
+        };
+        if let Some(max_gross_exposure) = limits.max_gross_exposure {
+            trace!(?gross_exposure, ?max_gross_exposure);
+            if gross_exposure > max_gross_exposure {
+                return RuleOutcome::Deny(DenyReason::GrossExposureLimitExceeded {
+                    max_gross_exposure,
+                });
+            }
+        }
+        if let Some(max_net_exposure) = limits.max_net_exposure {
+            trace!(?net_exposure, ?max_net_exposure);
+            if net_exposure.abs() > max_net_exposure {
+                return RuleOutcome::Deny(DenyReason::NetExposureLimitExceeded {
+                    max_net_exposure,
+                });
+            }
+        }
+        RuleOutcome::Pass
+    }
+
+    /// Utilization of whichever exposure limit is closer after the intent.
+    fn score(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> Option<Decimal> {
+        let limits = ctx.manager.limits();
+        let (gross_exposure, net_exposure) = Self::exposures(ctx, intent, ctx.price?)?;
+        let gross = limits
+            .max_gross_exposure
+            .and_then(|max_gross_exposure| utilization(gross_exposure, max_gross_exposure));
+        let net = limits
+            .max_net_exposure
+            .and_then(|max_net_exposure| utilization(net_exposure.abs(), max_net_exposure));
+        gross.max(net)
+    }
+}
+
+/// Denies intents that would make a single position too large a fraction of equity. Once options
+/// are traded, positions are the delta-adjusted exposure to each underlier.
+pub struct ConcentrationRule;
+
+impl ConcentrationRule {
+    fn concentration(ctx: &PortfolioContext, intent: &TradeIntent) -> Option<Decimal> {
+        let equity = ctx.manager.equity();
+        if equity <= Decimal::ZERO {
+            return None;
+        }
+        if ctx.manager.is_delta_adjusted() {
+            let (underlier, notional) = ctx.manager.delta_notional(
+                &intent.ticker,
+                PortfolioContext::qty(intent),
+                ctx.price?,
+            )?;
+            let held = ctx
+                .manager
+                .delta_exposures()?
+                .get(&underlier)
+                .copied()
+                .unwrap_or_default();
+            return Some((held + notional).abs() / equity);
+        }
+        let shares = ctx.shares(&intent.ticker).unwrap_or_default() + PortfolioContext::qty(intent);
+        let multiplier = ctx.manager.contract_multiplier(&intent.ticker);
+        Some((shares * ctx.price? * multiplier).abs() / equity)
+    }
+}
+
+impl RiskRule for ConcentrationRule {
+    fn name(&self) -> &'static str {
+        "concentration"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        let max_concentration = match ctx.manager.limits().max_concentration(&intent.ticker) {
+            Some(max_concentration) => max_concentration,
+            None => return RuleOutcome::Pass,
+        };
+        match Self::concentration(ctx, intent) {
+            Some(concentration) if concentration > max_concentration => {
+                RuleOutcome::Deny(DenyReason::ConcentrationLimitExceeded { max_concentration })
+            }
+            _ => RuleOutcome::Pass,
+        }
+    }
+
+    /// Concentration relative to the limit, or the raw fraction of equity if there is none.
+    fn score(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> Option<Decimal> {
+        let concentration = Self::concentration(ctx, intent)?;
+        match ctx.manager.limits().max_concentration(&intent.ticker) {
+            Some(max_concentration) => utilization(concentration, max_concentration),
+            None => Some(concentration),
+        }
+    }
+}
+
+fn utilization(value: Decimal, limit: Decimal) -> Option<Decimal> {
+    if limit <= Decimal::ZERO {
+        None
+    } else {
+        Some(value / limit)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::limits::{RiskLimits, SharedLimits, TickerLimits};
+    use crate::testing::given_portfolio;
+    use crate::{OptionsSettings, Price, RiskManager, RiskSettings, Shares};
+    use chrono::Utc;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn ticker_overrides() {
+        let limits = RiskLimits {
+            max_notional: Some(Decimal::new(1000, 0)),
+            tickers: vec![(
+                "AMC".to_string(),
+                TickerLimits {
+                    max_notional: Some(Decimal::new(100, 0)),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        let cases = vec![
+            ("AAPL", 5, RuleOutcome::Pass),
+            ("AMC", 1, RuleOutcome::Pass),
+            (
+                "AMC",
+                5,
+                RuleOutcome::Deny(DenyReason::NotionalLimitExceeded {
+                    max_notional: Decimal::new(100, 0),
+                }),
+            ),
+            (
+                "AAPL",
+                20,
+                RuleOutcome::Deny(DenyReason::NotionalLimitExceeded {
+                    max_notional: Decimal::new(1000, 0),
+                }),
+            ),
+        ];
+        for (ticker, qty, expected) in cases {
+            given_portfolio()
+                .with_limits(limits.clone())
+                .with_rule(NotionalLimitRule)
+                .at_price(100)
+                .when_intent(TradeIntent::new(ticker, qty))
+                .expect(expected);
+        }
+    }
+
+    #[test]
+    fn concentration_and_scores() {
+        let limits = RiskLimits {
+            max_notional: Some(Decimal::new(2000, 0)),
+            max_concentration: Some(Decimal::new(5, 1)),
+            ..Default::default()
+        };
+        let scenario = given_portfolio()
+            .with_position("AAPL", 10, 100)
+            .with_cash(1000)
+            .with_limits(limits)
+            .at_price(100)
+            .when_intent(TradeIntent::new("AAPL", 5));
+        // Concentration is only scored by default
+        scenario.expect_passed();
+        let score = scenario.score();
+        assert_eq!(score.score, Decimal::new(15, 1));
+        let contributions: Vec<(&str, Decimal)> = score
+            .contributions
+            .iter()
+            .map(|c| (c.rule.as_str(), c.score))
+            .collect();
+        assert_eq!(
+            contributions,
+            vec![
+                ("notional_limit", Decimal::new(25, 2)),
+                ("buying_power", Decimal::new(1667, 4)),
+                ("concentration", Decimal::new(15, 1)),
+            ]
+        );
+
+        // And enforced once configured
+        given_portfolio()
+            .with_position("AAPL", 10, 100)
+            .with_cash(1000)
+            .with_limits(RiskLimits {
+                max_concentration: Some(Decimal::new(5, 1)),
+                ..Default::default()
+            })
+            .with_rule(ConcentrationRule)
+            .at_price(100)
+            .when_intent(TradeIntent::new("AAPL", 5))
+            .expect_denied(DenyReason::ConcentrationLimitExceeded {
+                max_concentration: Decimal::new(5, 1),
+            });
+    }
+
+    #[test]
+    fn limits() {
+        let limits = SharedLimits::default();
+        let mut manager = RiskManager::default();
+        manager.bind_limits(limits.clone());
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(10, 0)),
+            Price(Decimal::new(100, 0)),
+        );
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(100, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        let long = TradeIntent::new("AAPL", 10);
+        let short = TradeIntent::new("TSLA", -30);
+        for rule in &[
+            &BlocklistRule as &dyn RiskRule,
+            &NotionalLimitRule,
+            &ExposureLimitRule,
+        ] {
+            assert_eq!(rule.evaluate(&ctx, &long), RuleOutcome::Pass);
+        }
+
+        limits.replace(RiskLimits {
+            max_notional: Some(Decimal::new(1500, 0)),
+            max_gross_exposure: Some(Decimal::new(3000, 0)),
+            max_net_exposure: Some(Decimal::new(1500, 0)),
+            blocklist: vec!["TSLA".to_string()].into_iter().collect(),
+            ..Default::default()
+        });
+        assert_eq!(BlocklistRule.evaluate(&ctx, &long), RuleOutcome::Pass);
+        assert_eq!(
+            BlocklistRule.evaluate(&ctx, &short),
+            RuleOutcome::Deny(DenyReason::Blocklisted)
+        );
+        assert_eq!(NotionalLimitRule.evaluate(&ctx, &long), RuleOutcome::Pass);
+        assert_eq!(
+            NotionalLimitRule.evaluate(&ctx, &short),
+            RuleOutcome::Deny(DenyReason::NotionalLimitExceeded {
+                max_notional: Decimal::new(1500, 0)
+            })
+        );
+        assert_eq!(
+            ExposureLimitRule.evaluate(&ctx, &long),
+            RuleOutcome::Deny(DenyReason::NetExposureLimitExceeded {
+                max_net_exposure: Decimal::new(1500, 0)
+            })
+        );
+        assert_eq!(
+            ExposureLimitRule.evaluate(&ctx, &short),
+            RuleOutcome::Deny(DenyReason::GrossExposureLimitExceeded {
+                max_gross_exposure: Decimal::new(3000, 0)
+            })
+        );
+    }
+
+    #[test]
+    fn delta_adjusted() {
+        let (put, call) = ("AAPL240119P00150000", "AAPL240119C00150000");
+        let mut manager = RiskManager::new(
+            String::new(),
+            RiskSettings {
+                options: Some(OptionsSettings {
+                    deltas: vec![(call.to_string(), Decimal::new(4, 1))]
+                        .into_iter()
+                        .collect(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        manager.bind_limits(SharedLimits::new(RiskLimits {
+            max_gross_exposure: Some(Decimal::new(10000, 0)),
+            max_concentration: Some(Decimal::new(1, 1)),
+            ..Default::default()
+        }));
+        manager.update_holdings(
+            "AAPL",
+            Shares(Decimal::new(100, 0)),
+            Price(Decimal::new(145, 0)),
+        );
+        manager.update_holdings(put, Shares(Decimal::ONE), Price(Decimal::new(5, 0)));
+        manager.update_cash(Decimal::new(100000, 0));
+        manager.update_delta(put, Decimal::new(-5, 1));
+        // The put offsets half of the shares
+        assert_eq!(
+            manager.delta_exposures().unwrap().get("AAPL").copied(),
+            Some(Decimal::new(7250, 0))
+        );
+
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(5, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        // Another put hedges the shares entirely, although raw notional is past the limit
+        let hedge = TradeIntent::new(put, 1);
+        assert_eq!(ExposureLimitRule.evaluate(&ctx, &hedge), RuleOutcome::Pass);
+        assert_eq!(ConcentrationRule.evaluate(&ctx, &hedge), RuleOutcome::Pass);
+        // A call adds 40 shares worth of exposure
+        let call = TradeIntent::new(call, 1);
+        assert_eq!(
+            ExposureLimitRule.evaluate(&ctx, &call),
+            RuleOutcome::Deny(DenyReason::GrossExposureLimitExceeded {
+                max_gross_exposure: Decimal::new(10000, 0),
+            })
+        );
+        assert_eq!(
+            ConcentrationRule.evaluate(&ctx, &call),
+            RuleOutcome::Deny(DenyReason::ConcentrationLimitExceeded {
+                max_concentration: Decimal::new(1, 1),
+            })
+        );
+        // No delta is assumed for a contract without one
+        let unknown = TradeIntent::new("AAPL240119P00140000", 1);
+        assert_eq!(
+            ExposureLimitRule.evaluate(&ctx, &unknown),
+            RuleOutcome::Deny(DenyReason::DeltaUnknown)
+        );
+    }
+}
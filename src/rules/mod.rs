@@ -0,0 +1,422 @@
+use crate::{DenyReason, RiskManager};
+use chrono::NaiveDate;
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::trace;
+use trading_base::TradeIntent;
+
+mod buying_power;
+mod compliance;
+mod limits;
+mod position;
+mod price;
+mod stats;
+
+pub use buying_power::BuyingPowerRule;
+pub use compliance::{FractionalRule, GoodFaithViolationRule, PatternDayTradeRule, TradableRule};
+pub use limits::{
+    BlocklistRule, ConcentrationRule, DrawdownRule, ExposureLimitRule, NotionalLimitRule,
+};
+pub use position::{
+    BorrowFeeRule, ChangeInPositionSideRule, ClosingTradeRule, FlattenRule, ShortingRule,
+};
+pub use price::StalePriceRule;
+pub use stats::{RuleCounters, RuleStats};
+
+/// Age of the last price a market order is valued at.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PriceAge {
+    Known(chrono::Duration),
+    /// The price source doesn't say when the price was traded at
+    Unknown,
+}
+
+/// Read-only view of the portfolio handed to each rule.
+pub struct PortfolioContext<'a> {
+    pub manager: &'a RiskManager,
+    pub today: NaiveDate,
+    /// Price used to value the intent: the limit price, or the slippage-adjusted last price for
+    /// market orders. Closing trades don't consume buying power and aren't priced.
+    pub price: Option<Decimal>,
+    /// How long ago the last price of a market order was traded at
+    pub price_age: Option<PriceAge>,
+    /// Buying power already reserved for the intent, when it amends a working order.
+    pub reserved: Decimal,
+}
+
+impl<'a> PortfolioContext<'a> {
+    pub fn qty(intent: &TradeIntent) -> Decimal {
+        Decimal::from_isize(intent.qty).unwrap_or_default()
+    }
+
+    /// Signed value of the intent at `price`, counting the contract multiplier of options.
+    pub fn notional(&self, intent: &TradeIntent, price: Decimal) -> Decimal {
+        price * Self::qty(intent) * self.manager.contract_multiplier(&intent.ticker)
+    }
+
+    pub fn shares(&self, ticker: &str) -> Option<Decimal> {
+        self.manager.shares(ticker)
+    }
+
+    pub fn is_closing(&self, intent: &TradeIntent) -> bool {
+        self.manager.is_closing(&intent.ticker, Self::qty(intent))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", content = "reason", rename_all = "snake_case")]
+pub enum RuleOutcome {
+    /// The rule has no objection; continue with the next rule.
+    Pass,
+    /// Grant the intent without evaluating the remaining rules.
+    Approve,
+    /// Deny the intent.
+    Deny(DenyReason),
+}
+
+pub trait RiskRule: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome;
+
+    /// How close the intent comes to the rule's limit, where 1 is at the limit. Rules without a
+    /// meaningful measure don't contribute to the risk score.
+    fn score(&self, _ctx: &PortfolioContext, _intent: &TradeIntent) -> Option<Decimal> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RuleScore {
+    pub rule: String,
+    pub score: Decimal,
+}
+
+/// Graded view of a decision. The overall score is that of the closest limit.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct RiskScore {
+    pub score: Decimal,
+    pub contributions: Vec<RuleScore>,
+}
+
+/// What a single rule made of an intent, whether or not it got to decide it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RuleTrace {
+    pub rule: String,
+    #[serde(flatten)]
+    pub outcome: RuleOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<Decimal>,
+}
+
+/// Whether rules run to decide an intent, which counts towards rule statistics, or only to see
+/// what they would decide, as for what-ifs and the quantities tried for partial approvals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Evaluation {
+    Decision,
+    Probe,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleKind {
+    Tradable,
+    Fractional,
+    ChangeInPositionSide,
+    PatternDayTrade,
+    GoodFaithViolation,
+    ClosingTrade,
+    StalePrice,
+    Drawdown,
+    Flatten,
+    Shorting,
+    BorrowFee,
+    Blocklist,
+    NotionalLimit,
+    ExposureLimit,
+    Concentration,
+    BuyingPower,
+}
+
+impl std::str::FromStr for RuleKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.trim().to_string()))
+            .map_err(|_| anyhow::anyhow!("Unknown rule: {}", s))
+    }
+}
+
+impl RuleKind {
+    pub fn default_pipeline() -> Vec<RuleKind> {
+        vec![
+            RuleKind::Tradable,
+            RuleKind::ChangeInPositionSide,
+            RuleKind::PatternDayTrade,
+            RuleKind::GoodFaithViolation,
+            RuleKind::ClosingTrade,
+            RuleKind::StalePrice,
+            RuleKind::Drawdown,
+            RuleKind::Flatten,
+            RuleKind::Shorting,
+            RuleKind::BorrowFee,
+            RuleKind::Blocklist,
+            RuleKind::NotionalLimit,
+            RuleKind::ExposureLimit,
+            RuleKind::BuyingPower,
+        ]
+    }
+
+    /// Rules that only contribute to risk scores unless the pipeline is configured with them.
+    pub fn scoring_only() -> Vec<RuleKind> {
+        vec![RuleKind::Concentration]
+    }
+
+    pub fn build(self) -> Box<dyn RiskRule> {
+        match self {
+            RuleKind::Tradable => Box::new(TradableRule),
+            RuleKind::Fractional => Box::new(FractionalRule),
+            RuleKind::ChangeInPositionSide => Box::new(ChangeInPositionSideRule),
+            RuleKind::PatternDayTrade => Box::new(PatternDayTradeRule),
+            RuleKind::GoodFaithViolation => Box::new(GoodFaithViolationRule),
+            RuleKind::ClosingTrade => Box::new(ClosingTradeRule),
+            RuleKind::StalePrice => Box::new(StalePriceRule),
+            RuleKind::Drawdown => Box::new(DrawdownRule),
+            RuleKind::Flatten => Box::new(FlattenRule),
+            RuleKind::Shorting => Box::new(ShortingRule),
+            RuleKind::BorrowFee => Box::new(BorrowFeeRule),
+            RuleKind::Blocklist => Box::new(BlocklistRule),
+            RuleKind::NotionalLimit => Box::new(NotionalLimitRule),
+            RuleKind::ExposureLimit => Box::new(ExposureLimitRule),
+            RuleKind::Concentration => Box::new(ConcentrationRule),
+            RuleKind::BuyingPower => Box::new(BuyingPowerRule),
+        }
+    }
+}
+
+/// Accepts either a list of rules or a comma-separated string, since lists can't be expressed
+/// directly in environment variables.
+pub fn deserialize_rule_kinds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<RuleKind>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Kinds {
+        List(Vec<RuleKind>),
+        String(String),
+    }
+    match Kinds::deserialize(deserializer)? {
+        Kinds::List(kinds) => Ok(kinds),
+        Kinds::String(s) => s
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|s| s.parse().map_err(serde::de::Error::custom))
+            .collect(),
+    }
+}
+
+/// Ordered list of rules. The first rule that approves or denies an intent decides it; an intent
+/// that passes every rule is granted.
+pub struct RulePipeline {
+    rules: Vec<Box<dyn RiskRule>>,
+    /// Rules that score intents without deciding them
+    scorers: Vec<Box<dyn RiskRule>>,
+    stats: Arc<RuleStats>,
+}
+
+impl RulePipeline {
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            scorers: Vec::new(),
+            stats: Default::default(),
+        }
+    }
+
+    pub fn from_kinds(kinds: &[RuleKind]) -> Self {
+        let mut pipeline = Self::new();
+        for kind in kinds {
+            pipeline.push(kind.build())
+        }
+        pipeline.scorers = RuleKind::scoring_only()
+            .into_iter()
+            .filter(|kind| !kinds.contains(kind))
+            .map(RuleKind::build)
+            .collect();
+        pipeline
+    }
+
+    pub fn push(&mut self, rule: Box<dyn RiskRule>) {
+        self.stats.register(rule.name());
+        self.rules.push(rule)
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.rules.iter().map(|rule| rule.name()).collect()
+    }
+
+    pub fn stats(&self) -> Arc<RuleStats> {
+        self.stats.clone()
+    }
+
+    fn run(
+        &self,
+        rule: &dyn RiskRule,
+        ctx: &PortfolioContext,
+        intent: &TradeIntent,
+        evaluation: Evaluation,
+    ) -> RuleOutcome {
+        let start = Instant::now();
+        let outcome = rule.evaluate(ctx, intent);
+        if evaluation == Evaluation::Decision {
+            self.stats.record(rule.name(), &outcome, start.elapsed());
+        }
+        trace!(rule = rule.name(), ?outcome);
+        outcome
+    }
+
+    pub fn evaluate(
+        &self,
+        ctx: &PortfolioContext,
+        intent: &TradeIntent,
+        evaluation: Evaluation,
+    ) -> RuleOutcome {
+        for rule in &self.rules {
+            let outcome = self.run(rule.as_ref(), ctx, intent, evaluation);
+            if outcome != RuleOutcome::Pass {
+                return outcome;
+            }
+        }
+        RuleOutcome::Pass
+    }
+
+    /// Scores the intent against every rule, regardless of their outcomes, along with the rules
+    /// that only score intents.
+    pub fn score(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RiskScore {
+        let contributions: Vec<RuleScore> = self
+            .rules
+            .iter()
+            .chain(self.scorers.iter())
+            .filter_map(|rule| {
+                rule.score(ctx, intent).map(|score| RuleScore {
+                    rule: rule.name().to_string(),
+                    score: score.round_dp(4),
+                })
+            })
+            .collect();
+        let score = contributions
+            .iter()
+            .map(|contribution| contribution.score)
+            .max()
+            .unwrap_or_default();
+        RiskScore {
+            score,
+            contributions,
+        }
+    }
+
+    /// Runs every rule on the intent, for explaining a decision. Rule statistics are left alone.
+    pub fn trace(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> Vec<RuleTrace> {
+        self.rules
+            .iter()
+            .map(|rule| RuleTrace {
+                rule: rule.name().to_string(),
+                outcome: rule.evaluate(ctx, intent),
+                score: rule.score(ctx, intent).map(|score| score.round_dp(4)),
+            })
+            .collect()
+    }
+
+    /// Runs rules until one approves the intent, collecting the reasons given by every rule that
+    /// denies it. An empty result means the intent is granted.
+    pub fn evaluate_all(
+        &self,
+        ctx: &PortfolioContext,
+        intent: &TradeIntent,
+        evaluation: Evaluation,
+    ) -> Vec<DenyReason> {
+        let mut reasons = Vec::new();
+        for rule in &self.rules {
+            match self.run(rule.as_ref(), ctx, intent, evaluation) {
+                RuleOutcome::Pass => (),
+                RuleOutcome::Approve => break,
+                RuleOutcome::Deny(reason) => reasons.push(reason),
+            }
+        }
+        reasons
+    }
+}
+
+impl Default for RulePipeline {
+    fn default() -> Self {
+        Self::from_kinds(&RuleKind::default_pipeline())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::limits::{RiskLimits, SharedLimits};
+
+    #[test]
+    fn parse_rule_kinds() {
+        let kinds: Vec<RuleKind> =
+            deserialize_rule_kinds(serde_json::json!("closing_trade, buying_power")).unwrap();
+        assert_eq!(kinds, vec![RuleKind::ClosingTrade, RuleKind::BuyingPower]);
+        let kinds: Vec<RuleKind> =
+            deserialize_rule_kinds(serde_json::json!(["pattern_day_trade"])).unwrap();
+        assert_eq!(kinds, vec![RuleKind::PatternDayTrade]);
+        assert!(deserialize_rule_kinds(serde_json::json!("margin")).is_err());
+    }
+
+    #[test]
+    fn evaluate_all() {
+        let mut manager = RiskManager::default();
+        manager.bind_limits(SharedLimits::new(RiskLimits {
+            max_notional: Some(Decimal::new(500, 0)),
+            blocklist: vec!["AAPL".to_string()].into_iter().collect(),
+            ..Default::default()
+        }));
+        let ctx = PortfolioContext {
+            manager: &manager,
+            today: chrono::Utc::today().naive_utc(),
+            price: Some(Decimal::new(100, 0)),
+            price_age: None,
+            reserved: Decimal::ZERO,
+        };
+        let intent = TradeIntent::new("AAPL", 10);
+        let pipeline = RulePipeline::default();
+        assert_eq!(
+            pipeline.evaluate(&ctx, &intent, Evaluation::Decision),
+            RuleOutcome::Deny(DenyReason::Blocklisted)
+        );
+        let stats = pipeline.stats();
+        assert_eq!(stats.get("closing_trade").passes, 1);
+        assert_eq!(stats.get("blocklist").denials, 1);
+        // Rules that weren't reached are still reported
+        assert_eq!(stats.get("buying_power").evaluations(), 0);
+        assert!(stats.render_metrics().contains(
+            "risk_manager_rule_evaluations_total{rule=\"buying_power\",outcome=\"deny\"} 0"
+        ));
+
+        assert_eq!(
+            pipeline.evaluate_all(&ctx, &intent, Evaluation::Decision),
+            vec![
+                DenyReason::Blocklisted,
+                DenyReason::NotionalLimitExceeded {
+                    max_notional: Decimal::new(500, 0)
+                },
+                DenyReason::InsufficientBuyingPower {
+                    buying_power: Decimal::ZERO
+                },
+            ]
+        );
+        assert_eq!(stats.get("blocklist").denials, 2);
+        assert_eq!(stats.get("buying_power").denials, 1);
+
+        // Probes aren't counted
+        pipeline.evaluate_all(&ctx, &intent, Evaluation::Probe);
+        assert_eq!(stats.get("blocklist").denials, 2);
+    }
+}
@@ -0,0 +1,183 @@
+use super::{PortfolioContext, RiskRule, RuleOutcome};
+use crate::DenyReason;
+use trading_base::TradeIntent;
+
+/// Denies closing trades that are larger than the position, which would flip it to the other
+/// side.
+pub struct ChangeInPositionSideRule;
+
+impl RiskRule for ChangeInPositionSideRule {
+    fn name(&self) -> &'static str {
+        "change_in_position_side"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        if !ctx.is_closing(intent) {
+            return RuleOutcome::Pass;
+        }
+        let shares = ctx.shares(&intent.ticker).unwrap_or_default();
+        if PortfolioContext::qty(intent).abs() > shares.abs() {
+            RuleOutcome::Deny(DenyReason::ChangeInPositionSide)
+        } else {
+            RuleOutcome::Pass
+        }
+    }
+}
+
+/// Grants closing trades without further checks, since they only ever free up buying power.
+pub struct ClosingTradeRule;
+
+impl RiskRule for ClosingTradeRule {
+    fn name(&self) -> &'static str {
+        "closing_trade"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        if ctx.is_closing(intent) {
+            RuleOutcome::Approve
+        } else {
+            RuleOutcome::Pass
+        }
+    }
+}
+
+/// Denies opening trades in tickers that must be flat overnight, once the market is about to close.
+pub struct FlattenRule;
+
+impl RiskRule for FlattenRule {
+    fn name(&self) -> &'static str {
+        "flatten"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        if ctx.manager.is_flattening(&intent.ticker) && !ctx.is_closing(intent) {
+            RuleOutcome::Deny(DenyReason::FlattenWindow)
+        } else {
+            RuleOutcome::Pass
+        }
+    }
+}
+
+/// Denies opening short trades when the broker doesn't allow the account to short, and short
+/// trades of crypto, which can't be shorted. Writing options borrows no shares, so option
+/// contracts are left to the option requirements.
+pub struct ShortingRule;
+
+impl RiskRule for ShortingRule {
+    fn name(&self) -> &'static str {
+        "shorting"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        if intent.qty < 0
+            && !ctx.is_closing(intent)
+            && ctx.manager.option_contract(&intent.ticker).is_none()
+            && (!ctx.manager.is_shorting_enabled() || ctx.manager.is_crypto(&intent.ticker))
+        {
+            RuleOutcome::Deny(DenyReason::ShortingDisabled)
+        } else {
+            RuleOutcome::Pass
+        }
+    }
+}
+
+/// Denies opening short trades whose borrow rate is above the configured maximum. Tickers
+/// without a known rate pass, and so do option contracts, which aren't borrowed.
+pub struct BorrowFeeRule;
+
+impl RiskRule for BorrowFeeRule {
+    fn name(&self) -> &'static str {
+        "borrow_fee"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        if intent.qty >= 0
+            || ctx.is_closing(intent)
+            || ctx.manager.option_contract(&intent.ticker).is_some()
+        {
+            return RuleOutcome::Pass;
+        }
+        let max_rate = ctx
+            .manager
+            .limits()
+            .max_borrow_rate(&intent.ticker, ctx.manager.settings().borrow_fees.max_rate);
+        let max_rate = match max_rate {
+            Some(max_rate) => max_rate,
+            None => return RuleOutcome::Pass,
+        };
+        match ctx.manager.borrow_rate(&intent.ticker) {
+            Some(rate) if rate > max_rate => {
+                RuleOutcome::Deny(DenyReason::BorrowFeeTooHigh { rate, max_rate })
+            }
+            _ => RuleOutcome::Pass,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rules::RuleOutcome::*;
+    use crate::testing::given_portfolio;
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn closing_trades() {
+        let cases: Vec<(isize, RuleOutcome, RuleOutcome)> = vec![
+            (-1, Pass, Approve),
+            (-2, Pass, Approve),
+            (-3, Deny(DenyReason::ChangeInPositionSide), Approve),
+            (1, Pass, Pass),
+        ];
+        for (qty, position_side, closing_trade) in cases {
+            let portfolio = || given_portfolio().with_position("AAPL", 2, 1);
+            portfolio()
+                .with_rule(ChangeInPositionSideRule)
+                .when_intent(TradeIntent::new("AAPL", qty))
+                .expect(position_side);
+            portfolio()
+                .with_rule(ClosingTradeRule)
+                .when_intent(TradeIntent::new("AAPL", qty))
+                .expect(closing_trade);
+        }
+    }
+
+    #[test]
+    fn borrow_fee() {
+        let mut settings = crate::RiskSettings::default();
+        settings.borrow_fees.max_rate = Some(Decimal::new(2, 1));
+        settings.options = Some(Default::default());
+        settings
+            .borrow_fees
+            .rates
+            .insert("GME".into(), Decimal::new(1, 1));
+        let mut manager = crate::RiskManager::new(String::new(), settings);
+        let evaluate = |manager: &crate::RiskManager, ticker, qty| {
+            let ctx = PortfolioContext {
+                manager,
+                today: chrono::Utc::today().naive_utc(),
+                price: None,
+                price_age: None,
+                reserved: Decimal::ZERO,
+            };
+            BorrowFeeRule.evaluate(&ctx, &TradeIntent::new(ticker, qty))
+        };
+        assert_eq!(evaluate(&manager, "GME", -10), Pass);
+
+        // Rates from the topic replace the configured ones
+        manager.update_borrow_rate("GME", Decimal::new(35, 2));
+        assert_eq!(
+            evaluate(&manager, "GME", -10),
+            Deny(DenyReason::BorrowFeeTooHigh {
+                rate: Decimal::new(35, 2),
+                max_rate: Decimal::new(2, 1)
+            })
+        );
+        assert_eq!(evaluate(&manager, "GME", 10), Pass);
+
+        // Writing options borrows no shares
+        let call = "GME240119C00020000";
+        manager.update_borrow_rate(call, Decimal::new(35, 2));
+        assert_eq!(evaluate(&manager, call, -1), Pass);
+    }
+}
@@ -0,0 +1,89 @@
+use super::{PortfolioContext, PriceAge, RiskRule, RuleOutcome};
+use crate::settings::StalePricePolicy;
+use crate::DenyReason;
+use rust_decimal::Decimal;
+use tracing::warn;
+use trading_base::TradeIntent;
+
+/// Flags market orders priced at a last trade price older than `max_price_age_secs`, since the
+/// market may have moved well away from it, e.g. across a trading halt. Prices from sources that
+/// don't say when they were traded at may be of any age, so they are flagged too.
+pub struct StalePriceRule;
+
+impl RiskRule for StalePriceRule {
+    fn name(&self) -> &'static str {
+        "stale_price"
+    }
+
+    fn evaluate(&self, ctx: &PortfolioContext, intent: &TradeIntent) -> RuleOutcome {
+        let settings = ctx.manager.settings();
+        let max_age = ctx
+            .manager
+            .limits()
+            .max_price_age_secs(&intent.ticker, settings.max_price_age_secs);
+        let (max_age, age) = match (max_age, ctx.price_age) {
+            (Some(max_age), Some(age)) => (max_age, age),
+            _ => return RuleOutcome::Pass,
+        };
+        let reason = match age {
+            PriceAge::Known(age) if age <= chrono::Duration::seconds(max_age as i64) => {
+                return RuleOutcome::Pass
+            }
+            PriceAge::Known(age) => DenyReason::StalePrice {
+                age: Decimal::new(age.num_milliseconds(), 3),
+            },
+            PriceAge::Unknown => DenyReason::PriceAgeUnknown,
+        };
+        match settings.stale_price_policy {
+            StalePricePolicy::Deny => RuleOutcome::Deny(reason),
+            StalePricePolicy::Warn => {
+                warn!(id = %intent.id, ?reason, "Market order priced at a stale price");
+                RuleOutcome::Pass
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::settings::RiskSettings;
+    use crate::RiskManager;
+    use chrono::{Duration, Utc};
+
+    #[test]
+    fn stale_price() {
+        let manager = RiskManager::new(
+            String::new(),
+            RiskSettings {
+                max_price_age_secs: Some(60),
+                ..Default::default()
+            },
+        );
+        let mut ctx = PortfolioContext {
+            manager: &manager,
+            today: Utc::today().naive_utc(),
+            price: Some(Decimal::new(100, 0)),
+            price_age: Some(PriceAge::Known(Duration::seconds(60))),
+            reserved: Decimal::ZERO,
+        };
+        let intent = TradeIntent::new("AAPL", 1);
+        assert_eq!(StalePriceRule.evaluate(&ctx, &intent), RuleOutcome::Pass);
+        ctx.price_age = Some(PriceAge::Known(Duration::milliseconds(1_200_500)));
+        assert_eq!(
+            StalePriceRule.evaluate(&ctx, &intent),
+            RuleOutcome::Deny(DenyReason::StalePrice {
+                age: Decimal::new(1_200_500, 3)
+            })
+        );
+        // A price of unknown age may be older still
+        ctx.price_age = Some(PriceAge::Unknown);
+        assert_eq!(
+            StalePriceRule.evaluate(&ctx, &intent),
+            RuleOutcome::Deny(DenyReason::PriceAgeUnknown)
+        );
+        // Limit orders aren't priced at the last price
+        ctx.price_age = None;
+        assert_eq!(StalePriceRule.evaluate(&ctx, &intent), RuleOutcome::Pass);
+    }
+}
@@ -0,0 +1,102 @@
+use super::RuleOutcome;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often a rule was evaluated, what it decided and how long it took.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RuleCounters {
+    pub passes: u64,
+    pub approvals: u64,
+    pub denials: u64,
+    pub duration: Duration,
+}
+
+impl RuleCounters {
+    pub fn evaluations(&self) -> u64 {
+        self.passes + self.approvals + self.denials
+    }
+}
+
+/// Per-rule counters, so slow rules and rules that never fire can be found.
+#[derive(Debug, Default)]
+pub struct RuleStats {
+    rules: Mutex<BTreeMap<&'static str, RuleCounters>>,
+}
+
+impl RuleStats {
+    /// Adds a rule with empty counters, so that rules that are never reached still show up.
+    pub fn register(&self, rule: &'static str) {
+        self.rules
+            .lock()
+            .expect("Rule stats lock poisoned")
+            .entry(rule)
+            .or_default();
+    }
+
+    pub fn record(&self, rule: &'static str, outcome: &RuleOutcome, duration: Duration) {
+        let mut rules = self.rules.lock().expect("Rule stats lock poisoned");
+        let counters = rules.entry(rule).or_default();
+        match outcome {
+            RuleOutcome::Pass => counters.passes += 1,
+            RuleOutcome::Approve => counters.approvals += 1,
+            RuleOutcome::Deny(_) => counters.denials += 1,
+        }
+        counters.duration += duration;
+    }
+
+    pub fn get(&self, rule: &str) -> RuleCounters {
+        self.rules
+            .lock()
+            .expect("Rule stats lock poisoned")
+            .get(rule)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Renders the counters in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let rules = self.rules.lock().expect("Rule stats lock poisoned");
+        let mut metrics = String::new();
+        let _ = writeln!(
+            metrics,
+            "# HELP risk_manager_rule_evaluations_total Rule evaluations by outcome"
+        );
+        let _ = writeln!(
+            metrics,
+            "# TYPE risk_manager_rule_evaluations_total counter"
+        );
+        for (rule, counters) in rules.iter() {
+            let outcomes = [
+                ("pass", counters.passes),
+                ("approve", counters.approvals),
+                ("deny", counters.denials),
+            ];
+            for (outcome, value) in outcomes.iter() {
+                let _ = writeln!(
+                    metrics,
+                    "risk_manager_rule_evaluations_total{{rule=\"{}\",outcome=\"{}\"}} {}",
+                    rule, outcome, value
+                );
+            }
+        }
+        let _ = writeln!(
+            metrics,
+            "# HELP risk_manager_rule_evaluation_seconds_total Time spent evaluating each rule"
+        );
+        let _ = writeln!(
+            metrics,
+            "# TYPE risk_manager_rule_evaluation_seconds_total counter"
+        );
+        for (rule, counters) in rules.iter() {
+            let _ = writeln!(
+                metrics,
+                "risk_manager_rule_evaluation_seconds_total{{rule=\"{}\"}} {}",
+                rule,
+                counters.duration.as_secs_f64()
+            );
+        }
+        metrics
+    }
+}
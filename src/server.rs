@@ -0,0 +1,615 @@
+use crate::admin::{AdminCommand, AdminSender};
+use crate::codec::ProducerStats;
+use crate::control::ControlCommand;
+use crate::eval_cache::EvaluationCacheStats;
+use crate::events::{EventBus, Topic};
+use crate::health::Health;
+use crate::holdings::Holdings;
+use crate::input::RequestOptions;
+use crate::latency::LatencyStats;
+use crate::limits::LimitsFile;
+use crate::offsets::{render_metrics, PartitionOffsets};
+use crate::pipeline::QueueStats;
+use crate::price_sources::PriceSourceStats;
+use crate::quarantine::QuarantineStats;
+use crate::redis_pool::RedisPoolStats;
+use crate::report::PositionSide;
+use crate::risk_manager::PortfolioSnapshot;
+use crate::rules::RuleStats;
+use crate::settings::WebServerSettings;
+use crate::signature::constant_time_eq;
+use crate::symbols::SymbolMetadata;
+use crate::transport::MessageSource;
+use futures::{SinkExt, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tracing::{debug, error, info, warn};
+use trading_base::TradeIntent;
+use warp::http::StatusCode;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Filter, Rejection, Reply};
+
+const DEFAULT_DECISIONS: usize = 50;
+
+/// Counters kept by the risk manager, exposed at `/metrics`.
+#[derive(Clone)]
+pub struct Stats {
+    pub evaluation_cache: Arc<EvaluationCacheStats>,
+    pub rules: Arc<RuleStats>,
+    /// Groups positions into the sector and asset class exposure gauges
+    pub symbols: Arc<SymbolMetadata>,
+    pub latency: Arc<LatencyStats>,
+    pub producer: Arc<ProducerStats>,
+    pub quarantine: Arc<QuarantineStats>,
+    pub queues: Arc<QueueStats>,
+    pub redis_pools: Vec<Arc<RedisPoolStats>>,
+    pub price_sources: PriceSourceStats,
+}
+
+/// The admin bearer token is missing.
+#[derive(Debug)]
+struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// The admin bearer token is wrong, or no token is configured so admin endpoints are disabled.
+#[derive(Debug)]
+struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+fn check_token(token: Option<&String>, presented: Option<&str>) -> Result<(), Rejection> {
+    match (token, presented) {
+        (None, _) => Err(warp::reject::custom(Forbidden)),
+        (Some(_), None) => Err(warp::reject::custom(Unauthorized)),
+        (Some(token), Some(presented)) => {
+            if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+                Ok(())
+            } else {
+                Err(warp::reject::custom(Forbidden))
+            }
+        }
+    }
+}
+
+fn bearer(header: &str) -> &str {
+    header.strip_prefix("Bearer ").unwrap_or_default()
+}
+
+/// Rejects requests without the admin bearer token. Without a configured token, admin requests
+/// are always rejected rather than left open.
+fn authorized(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let result = check_token(token.as_ref(), header.as_deref().map(bearer));
+            async move { result }
+        })
+        .untuple_one()
+}
+
+/// Like `authorized`, but also takes the token from the `token` query parameter, since browsers
+/// can't set headers on WebSocket requests.
+fn authorized_stream(
+    token: Option<String>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(
+            move |header: Option<String>, query: HashMap<String, String>| {
+                let presented = header
+                    .as_deref()
+                    .map(bearer)
+                    .or_else(|| query.get("token").map(String::as_str));
+                let result = check_token(token.as_ref(), presented);
+                async move { result }
+            },
+        )
+        .untuple_one()
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (message, status) = if rejection.find::<Unauthorized>().is_some() {
+        ("Unauthorized", StatusCode::UNAUTHORIZED)
+    } else if rejection.find::<Forbidden>().is_some() {
+        ("Forbidden", StatusCode::FORBIDDEN)
+    } else if rejection.is_not_found() {
+        ("Not found", StatusCode::NOT_FOUND)
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        ("Method not allowed", StatusCode::METHOD_NOT_ALLOWED)
+    } else if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        ("Payload too large", StatusCode::PAYLOAD_TOO_LARGE)
+    } else {
+        debug!(?rejection, "Rejected request");
+        ("Bad request", StatusCode::BAD_REQUEST)
+    };
+    Ok(text_reply(format!("{}\n", message), status))
+}
+
+fn parse_topics(query: &HashMap<String, String>) -> HashSet<Topic> {
+    match query.get("topics") {
+        Some(topics) => topics
+            .split(',')
+            .filter_map(|topic| match topic.trim().parse() {
+                Ok(topic) => Some(topic),
+                Err(e) => {
+                    warn!(?e, "Ignoring topic filter");
+                    None
+                }
+            })
+            .collect(),
+        None => vec![Topic::Decisions, Topic::Exposures, Topic::Alerts]
+            .into_iter()
+            .collect(),
+    }
+}
+
+async fn stream_events(socket: WebSocket, events: EventBus, topics: HashSet<Topic>) {
+    debug!(?topics, "WebSocket client connected");
+    let (mut tx, mut rx) = socket.split();
+    let mut subscription = events.subscribe();
+    loop {
+        tokio::select! {
+            event = subscription.recv() => match event {
+                Ok(event) => {
+                    if !topics.contains(&event.topic()) {
+                        continue;
+                    }
+                    let payload = match serde_json::to_string(&event) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            warn!(?e, "Failed to serialize event");
+                            continue;
+                        }
+                    };
+                    if tx.send(Message::text(payload)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => warn!(skipped, "WebSocket client lagging"),
+                Err(RecvError::Closed) => break,
+            },
+            // Clients don't send anything meaningful, but we need to notice when they leave
+            msg = rx.next() => match msg {
+                Some(Ok(msg)) if !msg.is_close() => (),
+                _ => break,
+            },
+        }
+    }
+    debug!("WebSocket client disconnected");
+}
+
+fn reload_limits(limits_file: &Option<LimitsFile>) -> warp::reply::WithStatus<warp::reply::Json> {
+    let (body, status) = match limits_file.as_ref().map(LimitsFile::reload) {
+        Some(Ok(limits)) => (serde_json::json!(*limits), StatusCode::OK),
+        Some(Err(e)) => (
+            serde_json::json!({ "error": e.to_string() }),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        None => (
+            serde_json::json!({ "error": "No limits file configured" }),
+            StatusCode::NOT_FOUND,
+        ),
+    };
+    warp::reply::with_status(warp::reply::json(&body), status)
+}
+
+fn liveness(health: &Health) -> warp::reply::WithStatus<String> {
+    if health.is_alive() {
+        text_reply("OK\n".into(), StatusCode::OK)
+    } else {
+        text_reply(
+            "Main loop stalled\n".into(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+    }
+}
+
+fn readiness(health: &Health, source: &dyn MessageSource) -> warp::reply::WithStatus<String> {
+    match health.readiness(source) {
+        Ok(()) => text_reply("OK\n".into(), StatusCode::OK),
+        Err(reason) => text_reply(format!("{}\n", reason), StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
+
+async fn offsets(source: Arc<dyn MessageSource>) -> anyhow::Result<Vec<PartitionOffsets>> {
+    tokio::task::spawn_blocking(move || source.partition_offsets()).await?
+}
+
+async fn offsets_reply(
+    source: Arc<dyn MessageSource>,
+) -> Result<warp::reply::WithStatus<warp::reply::Json>, warp::Rejection> {
+    let reply = match offsets(source).await {
+        Ok(offsets) => warp::reply::with_status(warp::reply::json(&offsets), StatusCode::OK),
+        Err(e) => {
+            error!(?e, "Failed to fetch consumer offsets");
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "error": e.to_string() })),
+                StatusCode::SERVICE_UNAVAILABLE,
+            )
+        }
+    };
+    Ok(reply)
+}
+
+async fn metrics_reply(
+    source: Arc<dyn MessageSource>,
+    stats: Stats,
+    admin: AdminSender,
+) -> Result<String, warp::Rejection> {
+    let mut metrics = stats.evaluation_cache.render_metrics();
+    metrics.push_str(&stats.rules.render_metrics());
+    metrics.push_str(&stats.latency.render_metrics());
+    metrics.push_str(&stats.producer.render_metrics());
+    metrics.push_str(&stats.quarantine.render_metrics());
+    metrics.push_str(&stats.queues.render_metrics());
+    for pool in stats.redis_pools.iter() {
+        metrics.push_str(&pool.render_metrics());
+    }
+    metrics.push_str(&stats.price_sources.render_metrics());
+    let (tx, rx) = oneshot::channel();
+    let portfolio = match admin.send(AdminCommand::Portfolio(tx)).await {
+        Ok(()) => rx.await.ok(),
+        Err(_) => None,
+    };
+    match portfolio {
+        Some(portfolio) => {
+            metrics.push_str(&render_portfolio_metrics(&portfolio));
+            metrics.push_str(&stats.symbols.render_metrics(&portfolio.positions));
+        }
+        None => warn!("Risk manager is not running, skipping exposure metrics"),
+    }
+    match offsets(source).await {
+        Ok(offsets) => metrics.push_str(&render_metrics(&offsets)),
+        Err(e) => error!(?e, "Failed to fetch consumer offsets"),
+    }
+    Ok(metrics)
+}
+
+/// Renders how many positions are held on each side, and the PnL of the day in total and by
+/// position.
+fn render_portfolio_metrics(portfolio: &PortfolioSnapshot) -> String {
+    let mut metrics = String::new();
+    let _ = writeln!(
+        metrics,
+        "# HELP risk_manager_positions Number of positions held"
+    );
+    let _ = writeln!(metrics, "# TYPE risk_manager_positions gauge");
+    for (label, side) in [("long", PositionSide::Long), ("short", PositionSide::Short)].iter() {
+        let count = portfolio
+            .positions
+            .iter()
+            .filter(|p| p.side == *side)
+            .count();
+        let _ = writeln!(
+            metrics,
+            "risk_manager_positions{{side=\"{}\"}} {}",
+            label, count
+        );
+    }
+    let _ = writeln!(metrics, "# HELP risk_manager_pnl PnL of the day");
+    let _ = writeln!(metrics, "# TYPE risk_manager_pnl gauge");
+    let report = &portfolio.report;
+    let _ = writeln!(
+        metrics,
+        "risk_manager_pnl{{kind=\"realized\"}} {}",
+        report.realized_pnl
+    );
+    let _ = writeln!(
+        metrics,
+        "risk_manager_pnl{{kind=\"unrealized\"}} {}",
+        report.unrealized_pnl
+    );
+    let _ = writeln!(
+        metrics,
+        "# HELP risk_manager_position_pnl PnL of the day by position"
+    );
+    let _ = writeln!(metrics, "# TYPE risk_manager_position_pnl gauge");
+    for position in portfolio.positions.iter() {
+        let pnl = [
+            ("realized", position.realized_pnl),
+            ("unrealized", position.unrealized_pnl),
+        ];
+        for (kind, value) in pnl.iter() {
+            let _ = writeln!(
+                metrics,
+                "risk_manager_position_pnl{{ticker=\"{}\",kind=\"{}\"}} {}",
+                position.ticker, kind, value
+            );
+        }
+    }
+    metrics
+}
+
+fn text_reply(body: String, status: StatusCode) -> warp::reply::WithStatus<String> {
+    warp::reply::with_status(body, status)
+}
+
+async fn export_holdings(admin: AdminSender) -> Result<impl warp::Reply, warp::Rejection> {
+    let (tx, rx) = oneshot::channel();
+    let holdings = match admin.send(AdminCommand::ExportHoldings(tx)).await {
+        Ok(()) => rx.await.ok(),
+        Err(_) => None,
+    };
+    Ok(match holdings {
+        Some(holdings) => text_reply(holdings.to_csv(), StatusCode::OK),
+        None => text_reply(
+            "Risk manager is not running\n".into(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    })
+}
+
+async fn import_holdings(
+    admin: AdminSender,
+    body: warp::hyper::body::Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let holdings = match std::str::from_utf8(&body)
+        .map_err(anyhow::Error::from)
+        .and_then(Holdings::from_csv)
+    {
+        Ok(holdings) => holdings,
+        Err(e) => return Ok(text_reply(format!("{}\n", e), StatusCode::BAD_REQUEST)),
+    };
+    let (tx, rx) = oneshot::channel();
+    let result = match admin.send(AdminCommand::ImportHoldings(holdings, tx)).await {
+        Ok(()) => rx.await.ok(),
+        Err(_) => None,
+    };
+    Ok(match result {
+        Some(()) => text_reply("Imported\n".into(), StatusCode::OK),
+        None => text_reply(
+            "Risk manager is not running\n".into(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    })
+}
+
+async fn inspect_state(
+    admin: AdminSender,
+    query: HashMap<String, String>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let decisions = query
+        .get("decisions")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_DECISIONS);
+    let (tx, rx) = oneshot::channel();
+    let state = match admin.send(AdminCommand::InspectState(decisions, tx)).await {
+        Ok(()) => rx.await.ok(),
+        Err(_) => None,
+    };
+    Ok(match state {
+        Some(state) => warp::reply::with_status(warp::reply::json(&state), StatusCode::OK),
+        None => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": "Risk manager is not running" })),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    })
+}
+
+async fn control(
+    admin: AdminSender,
+    command: ControlCommand,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let (tx, rx) = oneshot::channel();
+    let halts = match admin.send(AdminCommand::Control(command, tx)).await {
+        Ok(()) => rx.await.ok(),
+        Err(_) => None,
+    };
+    Ok(match halts {
+        Some(halts) => warp::reply::with_status(warp::reply::json(&halts), StatusCode::OK),
+        None => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": "Risk manager is not running" })),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    })
+}
+
+async fn risk_check(
+    admin: AdminSender,
+    allow_partial: Option<String>,
+    intent: TradeIntent,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let options = RequestOptions {
+        allow_partial: allow_partial
+            .and_then(|value| RequestOptions::parse_allow_partial(value.as_bytes())),
+    };
+    let (tx, rx) = oneshot::channel();
+    let response = match admin
+        .send(AdminCommand::RiskCheck(intent, options, tx))
+        .await
+    {
+        // The reply is dropped if the check fails, e.g. because the price couldn't be fetched
+        Ok(()) => rx
+            .await
+            .map_err(|_| "Risk check failed, see risk manager logs"),
+        Err(_) => Err("Risk manager is not running"),
+    };
+    Ok(match response {
+        Ok(response) => warp::reply::with_status(warp::reply::json(&response), StatusCode::OK),
+        Err(error) => warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "error": error })),
+            StatusCode::SERVICE_UNAVAILABLE,
+        ),
+    })
+}
+
+pub async fn serve(
+    settings: WebServerSettings,
+    events: EventBus,
+    limits_file: Option<LimitsFile>,
+    source: Arc<dyn MessageSource>,
+    admin: AdminSender,
+    stats: Stats,
+    health: Health,
+) {
+    // The stream carries every decision and the whole account, so it needs the admin token too
+    let ws = warp::path("ws")
+        .and(authorized_stream(settings.admin_token.clone()))
+        .and(warp::ws())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |ws: Ws, query: HashMap<String, String>| {
+            let events = events.clone();
+            let topics = parse_topics(&query);
+            ws.on_upgrade(move |socket| stream_events(socket, events, topics))
+        });
+    if settings.admin_token.is_none() {
+        warn!("No admin token configured, admin endpoints and the event stream are disabled");
+    }
+    let admin_auth = authorized(settings.admin_token);
+    let reload = warp::path!("admin" / "reload")
+        .and(warp::post())
+        .and(admin_auth.clone())
+        .map(move || reload_limits(&limits_file));
+    let healthz = {
+        let health = health.clone();
+        warp::path!("healthz")
+            .and(warp::get())
+            .map(move || liveness(&health))
+    };
+    let readyz = {
+        let source = source.clone();
+        warp::path!("readyz")
+            .and(warp::get())
+            .map(move || readiness(&health, &*source))
+    };
+    let with_source = warp::any().map(move || -> Arc<dyn MessageSource> { source.clone() });
+    let offsets = warp::path!("admin" / "offsets")
+        .and(warp::get())
+        .and(admin_auth.clone())
+        .and(with_source.clone())
+        .and_then(offsets_reply);
+    let with_admin = warp::any().map(move || admin.clone());
+    let metrics = warp::path!("metrics")
+        .and(warp::get())
+        .and(with_source)
+        .and(warp::any().map(move || stats.clone()))
+        .and(with_admin.clone())
+        .and_then(metrics_reply);
+    let export = warp::path!("admin" / "holdings")
+        .and(warp::get())
+        .and(admin_auth.clone())
+        .and(with_admin.clone())
+        .and_then(export_holdings);
+    let import = warp::path!("admin" / "holdings")
+        .and(warp::post())
+        .and(admin_auth.clone())
+        .and(with_admin.clone())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::bytes())
+        .and_then(import_holdings);
+    let state = warp::path!("admin" / "state")
+        .and(warp::get())
+        .and(admin_auth.clone())
+        .and(with_admin.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(inspect_state);
+    // Decisions made here reserve buying power like any other, so require the admin token
+    let check = warp::path!("check")
+        .and(warp::post())
+        .and(admin_auth.clone())
+        .and(with_admin.clone())
+        .and(warp::header::optional::<String>("allow-partial"))
+        .and(warp::body::content_length_limit(64 * 1024))
+        .and(warp::body::json())
+        .and_then(risk_check);
+    let with_control = warp::post().and(admin_auth).and(with_admin);
+    let pause_all = warp::path!("admin" / "pause")
+        .and(with_control.clone())
+        .and_then(|admin| control(admin, ControlCommand::PauseAll));
+    let pause_ticker = warp::path!("admin" / "pause" / String)
+        .and(with_control.clone())
+        .and_then(|ticker, admin| control(admin, ControlCommand::PauseTicker(ticker)));
+    let resume_all = warp::path!("admin" / "resume")
+        .and(with_control.clone())
+        .and_then(|admin| control(admin, ControlCommand::Resume));
+    let resume_ticker = warp::path!("admin" / "resume" / String)
+        .and(with_control)
+        .and_then(|ticker, admin| control(admin, ControlCommand::ResumeTicker(ticker)));
+    let routes = ws
+        .or(healthz)
+        .or(readyz)
+        .or(reload)
+        .or(offsets)
+        .or(metrics)
+        .or(export)
+        .or(import)
+        .or(state)
+        .or(pause_all)
+        .or(pause_ticker)
+        .or(resume_all)
+        .or(resume_ticker)
+        .or(check)
+        .recover(handle_rejection);
+    info!(port = settings.port, "Starting web server");
+    warp::serve(routes).run(([0, 0, 0, 0], settings.port)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn status(token: Option<&str>, header: Option<&str>) -> StatusCode {
+        let route = warp::path!("admin")
+            .and(authorized(token.map(String::from)))
+            .map(|| "OK")
+            .recover(handle_rejection);
+        let mut request = warp::test::request().path("/admin");
+        if let Some(header) = header {
+            request = request.header("authorization", header);
+        }
+        request.reply(&route).await.status()
+    }
+
+    #[tokio::test]
+    async fn admin_auth() {
+        assert_eq!(status(None, None).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(None, Some("Bearer ")).await, StatusCode::FORBIDDEN);
+        assert_eq!(status(Some("secret"), None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(Some("secret"), Some("Bearer wrong")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(Some("secret"), Some("secret")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(Some("secret"), Some("Bearer secret")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn stream_auth() {
+        let route = warp::path!("ws")
+            .and(authorized_stream(Some("secret".into())))
+            .map(|| "OK")
+            .recover(handle_rejection);
+        let status = |path: &str, header: Option<&str>| {
+            let mut request = warp::test::request().path(path);
+            if let Some(header) = header {
+                request = request.header("authorization", header);
+            }
+            request.reply(&route)
+        };
+        assert_eq!(status("/ws", None).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status("/ws?token=wrong", None).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status("/ws?topics=alerts&token=secret", None)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("/ws", Some("Bearer secret")).await.status(),
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,959 @@
+use crate::actors::{PendingIntent, TickerActors};
+use crate::admin::AdminCommand;
+use crate::codec::{Codec, Producer};
+use crate::fixtures::{DecisionFixture, FixtureRecorder};
+use crate::flatten::Flattener;
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::handoff::Handoff;
+use crate::health::Health;
+use crate::latency::LatencyStats;
+use crate::lifecycle::Lifecycle;
+use crate::liquidation::LiquidationMonitor;
+use crate::margin_call::{MarginAlertLevel, MarginCallMonitor};
+use crate::monitor::DenialMonitor;
+use crate::notifier::Notifier;
+use crate::pipeline::{Outbox, QueueStats, Received};
+use crate::preflight::preflight;
+use crate::price_sources::PriceSources;
+use crate::prices::{PriceCache, PriceTick};
+use crate::quarantine::Quarantine;
+use crate::settings::TopicSettings;
+use crate::snapshot::Snapshots;
+use crate::stop_loss::StopLossMonitor;
+use crate::supervisor::{Backoff, ErrorClass};
+#[cfg(feature = "aws")]
+use crate::transport::SqsSnsTransport;
+use crate::transport::{KafkaTransport, MessageSource, Transport};
+use crate::{admin, health, input, offsets, pipeline, price_feed, server, sharding, supervisor};
+use crate::{
+    Alert, AlertLevel, AlpacaAssetSource, AssetCache, ComplianceHook, ControlCommand,
+    DecisionSampler, DenyReason, Event, EventBus, FixDropCopy, Holdings, LifecycleStage,
+    LimitsFile, MultiLegResponse, RiskCheckResponse, RiskManager, Settings, ShutdownPolicy,
+    SnapshotTrigger, SymbolMetadata, WebhookSink, WhatIfResponse,
+};
+use alpaca::Client;
+use anyhow::Result;
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tracing::{error, info, info_span, trace, warn};
+use trading_base::OrderType;
+
+/// How long the run loop has to last before an error no longer counts as a failed restart.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Shares this instance's part of the account with the other instances, when sharding.
+async fn publish_shard_state(
+    outbox: &Outbox,
+    topic: &str,
+    risk_manager: &RiskManager,
+) -> Result<()> {
+    match risk_manager.shard_state() {
+        Some(state) => outbox.send(topic, &state.instance_id, &state, None).await,
+        None => Ok(()),
+    }
+}
+
+/// Publishes an alert on the alerts topic, to webhooks and to dashboards.
+async fn raise_alert(
+    producer: &Producer,
+    topic: &str,
+    webhooks: &WebhookSink,
+    events: &EventBus,
+    alert: Alert,
+) {
+    if let Err(e) = producer.send(topic, "risk-manager", &alert, None).await {
+        error!(?e, "Failed to publish alert");
+    }
+    webhooks.notify_alert(&alert);
+    events.publish(Event::Alert(alert));
+}
+
+/// Everything that watches the positions as they are marked, by fills and prices.
+struct Watchers {
+    stop_loss: Option<StopLossMonitor>,
+    liquidation: Option<LiquidationMonitor>,
+    margin_call: Option<MarginCallMonitor>,
+    symbols: Arc<SymbolMetadata>,
+}
+
+impl Watchers {
+    async fn marked(
+        &mut self,
+        risk_manager: &mut RiskManager,
+        producer: &Producer,
+        topics: &TopicSettings,
+        webhooks: &WebhookSink,
+        events: &EventBus,
+    ) {
+        events.publish(Event::Exposure(risk_manager.exposure_update()));
+        if let Some(alert) = risk_manager.update_drawdown() {
+            warn!(message = %alert.message, "Drawdown breaker tripped");
+            raise_alert(producer, &topics.alerts, webhooks, events, alert).await;
+        }
+        if let Some(alert) = risk_manager.update_day_trade_call() {
+            raise_alert(producer, &topics.alerts, webhooks, events, alert).await;
+        }
+        let margin_alert = self
+            .margin_call
+            .as_mut()
+            .and_then(|monitor| monitor.check(risk_manager));
+        let margin_called = margin_alert
+            .as_ref()
+            .map_or(false, |alert| alert.level == MarginAlertLevel::Breach);
+        if let Some(alert) = margin_alert {
+            // The alerts topic and webhooks carry alerts of one shape, while dashboards and the
+            // notifier get the figures behind it
+            let summary = Alert::from(&alert);
+            let topic = &topics.alerts;
+            if let Err(e) = producer.send(topic, "risk-manager", &summary, None).await {
+                error!(?e, "Failed to publish margin alert");
+            }
+            webhooks.notify_alert(&summary);
+            events.publish(Event::MarginAlert(alert));
+        }
+        if let Some(monitor) = self.stop_loss.as_mut() {
+            for intent in monitor.check(risk_manager.positions()) {
+                let sent = producer
+                    .send(&topics.requests, &intent.ticker, &intent, None)
+                    .await;
+                if let Err(e) = sent {
+                    error!(?e, ticker = %intent.ticker, "Failed to send stop loss intent");
+                }
+            }
+        }
+        let plan = self
+            .liquidation
+            .as_mut()
+            .and_then(|monitor| monitor.check(risk_manager, &self.symbols, margin_called));
+        if let Some(plan) = plan {
+            let topic = &topics.liquidations;
+            if let Err(e) = producer.send(topic, "risk-manager", &plan, None).await {
+                error!(?e, "Failed to publish liquidation suggestions");
+            }
+        }
+    }
+}
+
+async fn send_response(
+    outbox: &Outbox,
+    topic: &str,
+    response: &RiskCheckResponse,
+    correlation_id: Option<&str>,
+) -> Result<()> {
+    outbox
+        .send_decision(topic, &response.intent().ticker, response, correlation_id)
+        .await
+}
+
+/// Tells the source an input has been handled. Failing to only means it may be delivered again,
+/// and a repeated intent gets its original decision.
+async fn acknowledge(source: &dyn MessageSource, handled: Option<(String, i32, i64)>) {
+    if let Some((topic, partition, offset)) = handled {
+        if let Err(e) = source.ack(&topic, partition, offset).await {
+            warn!(?e, %topic, partition, offset, "Failed to acknowledge input");
+        }
+    }
+}
+
+pub async fn run(settings: Settings) -> Result<()> {
+    #[cfg(feature = "aws")]
+    if let Some(aws) = settings.aws.clone() {
+        let transport = SqsSnsTransport::new(aws)?;
+        return run_with(settings, &transport).await;
+    }
+    let transport = KafkaTransport::new(settings.kafka.clone());
+    run_with(settings, &transport).await
+}
+
+/// Runs the service on the given transport instead of the Kafka cluster in the settings, e.g.
+/// an [`InMemoryBroker`](crate::transport::InMemoryBroker) in tests. Sharding still goes
+/// through Kafka.
+pub async fn run_with(settings: Settings, transport: &dyn Transport) -> Result<()> {
+    info!("Running RiskManager");
+    preflight(&settings).await?;
+    let price_sources = Arc::new(PriceSources::from_settings(&settings)?);
+    let topics = settings.topics;
+    let consumer = transport.source(&topics.inputs(settings.control.is_some()))?;
+    let codec = Codec::new(&settings.codec)?;
+    let latency = Arc::new(LatencyStats::default());
+    let producer = Producer::new(
+        transport.sink()?,
+        codec.clone(),
+        latency.clone(),
+        settings.producer_retry,
+    );
+    let mut lifecycle = Lifecycle::new(&producer, &topics.lifecycle);
+    lifecycle
+        .publish(LifecycleStage::Starting, None, None)
+        .await;
+    let assets = match settings.assets {
+        Some(assets) => {
+            let source = AlpacaAssetSource::new(
+                settings.alpaca.base_url.clone(),
+                settings.alpaca.key_id.clone(),
+                settings.alpaca.secret_key.clone(),
+                Duration::from_millis(assets.timeout_ms),
+            )?;
+            Some(AssetCache::new(
+                source,
+                chrono::Duration::seconds(assets.refresh_secs as i64),
+            ))
+        }
+        None => None,
+    };
+    let client = Client::new(
+        settings.alpaca.base_url,
+        settings.alpaca.key_id,
+        settings.alpaca.secret_key,
+    );
+    let events = EventBus::new();
+    let (admin, mut admin_commands) = admin::channel();
+    let health = Health::new(std::time::Duration::from_secs(
+        settings.webserver.liveness_timeout_secs,
+    ));
+    let limits_file = settings.limits.map(LimitsFile::load).transpose()?;
+    let webhooks = WebhookSink::new(settings.webhooks);
+    let drop_copy = settings.fix.map(FixDropCopy::spawn);
+    let sampler = settings.validation.map(DecisionSampler::new);
+    let compliance_hook = settings.compliance_hook.map(ComplianceHook::new);
+    let mut denial_monitor = settings.denial_monitor.map(DenialMonitor::new);
+    let mut flattener = settings.flatten.map(Flattener::new);
+    if let Some(notifications) = settings.notifications {
+        tokio::spawn(Notifier::new(notifications).run(events.subscribe()));
+    }
+    if let Some(lag_monitor) = settings.lag_monitor {
+        tokio::spawn(offsets::monitor_lag(
+            consumer.clone(),
+            lag_monitor,
+            events.clone(),
+        ));
+    }
+    let shadow = settings.shadow;
+    let holdings_file = settings.holdings_file;
+    let control = settings.control;
+    let sharding = settings.sharding;
+    let redis_pool = settings.redis_pool;
+    let handoff = settings
+        .handoff
+        .map(|handoff| Handoff::new(handoff, redis_pool.clone()))
+        .transpose()?;
+    let supervisor = settings.supervisor;
+    let symbols = Arc::new(match settings.symbols_file.as_deref() {
+        Some(path) => SymbolMetadata::load(path)?,
+        None => SymbolMetadata::default(),
+    });
+    let mut watchers = Watchers {
+        stop_loss: settings.stop_loss.map(StopLossMonitor::new),
+        liquidation: settings.liquidation.map(LiquidationMonitor::new),
+        margin_call: settings.margin_call.map(MarginCallMonitor::new),
+        symbols: symbols.clone(),
+    };
+    let mut snapshots = settings.snapshots.map(|snapshots| {
+        let period = std::time::Duration::from_secs(snapshots.interval_secs.max(1));
+        (
+            Snapshots::new(&producer, &topics.snapshots),
+            tokio::time::interval(period),
+        )
+    });
+    let quarantine = Quarantine::new(
+        producer.clone(),
+        topics.dead_letters.clone(),
+        consumer.clone(),
+        settings.quarantine,
+    );
+    let queues = Arc::new(QueueStats::new(&settings.pipeline));
+    let (errors, mut stage_errors) = mpsc::unbounded_channel();
+    let (outbox, producer_task) = Outbox::new(
+        producer.clone(),
+        codec.clone(),
+        queues.clone(),
+        errors.clone(),
+    );
+    if let Some(shadow) = shadow.as_ref() {
+        warn!(topic = %shadow.topic, "Running in shadow mode, all intents will be granted");
+    }
+    let fixtures = shadow
+        .as_ref()
+        .and_then(|shadow| shadow.fixtures.as_deref())
+        .map(FixtureRecorder::open)
+        .transpose()?;
+    let prices = match settings.price_cache.as_ref() {
+        Some(cache) => PriceCache::with_ttl(chrono::Duration::milliseconds(cache.ttl_ms as i64)),
+        None => PriceCache::new(),
+    };
+    let prefetch = settings
+        .price_cache
+        .and_then(|cache| cache.prefetch_interval_ms);
+    if let Some(prefetch) = prefetch {
+        tokio::spawn(price_feed::prefetch(
+            price_sources.clone(),
+            prices.clone(),
+            Duration::from_millis(prefetch),
+            latency.clone(),
+        ));
+    }
+    if let Some(price_feed) = settings.price_feed {
+        tokio::spawn(price_feed::follow(price_feed, redis_pool, prices.clone()));
+    }
+    let polygon = settings.price_sources.polygon.clone();
+    if let Some(polygon) = polygon.filter(|polygon| polygon.websocket_url.is_some()) {
+        tokio::spawn(price_feed::follow_polygon(polygon, prices.clone()));
+    }
+    let mut actors = if settings.ticker_actors {
+        Some(TickerActors::new(
+            price_sources.clone(),
+            prices.clone(),
+            latency.clone(),
+        ))
+    } else {
+        None
+    };
+    let datastore_url = settings.datastore.map(|datastore| datastore.base_url);
+    let mut risk = settings.risk;
+    risk.margin = risk.margin.with_leverage(&symbols);
+    risk.crypto = risk.crypto.map(|crypto| crypto.with_symbols(&symbols));
+    // Crypto trades around the clock, so by default the service keeps running after the close
+    let shutdown = ShutdownPolicy::resolve(settings.shutdown, risk.crypto.is_some());
+    let mut shutdown_timer = shutdown
+        .remaining(Utc::now())
+        .map(|remaining| Box::pin(tokio::time::sleep(remaining)));
+    let mut risk_manager = RiskManager::new(datastore_url.unwrap_or_default(), risk);
+    risk_manager.bind_prices(prices);
+    risk_manager.bind_price_sources(price_sources.clone());
+    // Kept to look assets up ahead of the checks that read them
+    if let Some(assets) = assets.clone() {
+        risk_manager.bind_assets(assets);
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = settings.grpc {
+        let admin_token = settings.webserver.admin_token.clone();
+        tokio::spawn(grpc::serve(grpc, admin_token, admin.clone()));
+    }
+    tokio::spawn(server::serve(
+        settings.webserver,
+        events.clone(),
+        limits_file.clone(),
+        consumer.clone(),
+        admin.clone(),
+        server::Stats {
+            evaluation_cache: risk_manager.evaluation_cache_stats(),
+            rules: risk_manager.rule_stats(),
+            symbols,
+            latency: latency.clone(),
+            producer: producer.stats(),
+            quarantine: quarantine.stats(),
+            queues: queues.clone(),
+            redis_pools: handoff.iter().map(Handoff::pool_stats).collect(),
+            price_sources: price_sources.stats(),
+        },
+        health.clone(),
+    ));
+    risk_manager.bind_latency(latency.clone());
+    if let Some(limits_file) = limits_file {
+        risk_manager.bind_limits(limits_file.limits());
+        tokio::spawn(limits_file.watch());
+    }
+    if let Ok(client) = client {
+        risk_manager.bind_alpaca_client(client);
+    }
+    let initialized: Result<Option<String>> = async {
+        let handed_off = match handoff.as_ref() {
+            Some(handoff) => handoff.take().await.unwrap_or_else(|e| {
+                warn!(?e, "Failed to load handoff state");
+                None
+            }),
+            None => None,
+        };
+        if let Some(state) = handed_off {
+            let reason = format!("State handed off at {}", state.saved_at);
+            risk_manager.restore(state);
+            return Ok(Some(reason));
+        }
+        risk_manager.initialize().await?;
+        match holdings_file {
+            Some(path) => {
+                let holdings = Holdings::from_csv(&std::fs::read_to_string(&path)?)?;
+                info!(%path, "Importing starting holdings");
+                risk_manager.import_holdings(holdings);
+                Ok(Some(format!("Holdings imported from {}", path)))
+            }
+            None => Ok(None),
+        }
+    }
+    .await;
+    match initialized {
+        Ok(reason) => {
+            health.mark_initialized();
+            lifecycle
+                .publish(LifecycleStage::Initialized, reason, Some(&risk_manager))
+                .await
+        }
+        Err(e) => {
+            let reason = format!("Initialization failed: {}", e);
+            lifecycle
+                .publish(LifecycleStage::Stopped, Some(reason), None)
+                .await;
+            return Err(e);
+        }
+    }
+    if let Some(sharding) = sharding {
+        risk_manager.enable_sharding(sharding.instance_id);
+        let states = sharding::state_consumer(&settings.kafka, &topics.account_state)?;
+        tokio::spawn(sharding::follow(states, codec.clone(), admin));
+    }
+    let source = consumer.clone();
+    let mut inputs = pipeline::consume(
+        consumer,
+        codec,
+        latency,
+        quarantine.clone(),
+        &supervisor,
+        queues,
+        errors,
+    );
+    let mut heartbeat = tokio::time::interval(health::HEARTBEAT_INTERVAL);
+    let mut terminate = signal(SignalKind::terminate())?;
+    // Once terminating, the queued inputs are processed before stopping, as their offsets may
+    // already have been committed
+    let mut terminating = false;
+    let mut drained = false;
+    let mut backoff = Backoff::new(&supervisor);
+    // Where the last input was read from. It is acknowledged once handled, so that a crash
+    // while handling it leaves it to be delivered again.
+    let mut handled: Option<(String, i32, i64)> = None;
+    let result = loop {
+        let started = Instant::now();
+        let result: Result<()> = async {
+            loop {
+                acknowledge(&*source, handled.take()).await;
+                health.heartbeat();
+                let idle = match actors.as_ref() {
+                    Some(actors) => actors.is_idle(),
+                    None => true,
+                };
+                if drained && idle {
+                    info!("Queued inputs processed, shutting down");
+                    let reason = Some("Terminated".to_string());
+                    lifecycle
+                        .publish(LifecycleStage::ShuttingDown, reason, Some(&risk_manager))
+                        .await;
+                    return Ok(());
+                }
+                // Decisions for intents from the admin channel are sent to `reply` instead of
+                // Kafka. `priced` is set once an intent's ticker actor has looked up its price.
+                let (received, mut reply, priced) = tokio::select! {
+                    _ = heartbeat.tick() => continue,
+                    Some(snapshots) = async {
+                        let (snapshots, interval) = snapshots.as_mut()?;
+                        interval.tick().await;
+                        Some(snapshots)
+                    } => {
+                        snapshots.publish(SnapshotTrigger::Interval, &risk_manager).await;
+                        continue;
+                    }
+                    Some(()) = async {
+                        shutdown_timer.as_mut()?.await;
+                        Some(())
+                    } => {
+                        info!("Shutdown time reached, shutting down");
+                        let reason = Some("Scheduled shutdown".to_string());
+                        lifecycle
+                            .publish(LifecycleStage::ShuttingDown, reason, Some(&risk_manager))
+                            .await;
+                        return Ok(());
+                    }
+                    _ = terminate.recv(), if !terminating => {
+                        info!("Terminating, processing queued inputs");
+                        terminating = true;
+                        inputs.close();
+                        continue;
+                    }
+                    received = inputs.recv(), if !drained => match received {
+                        Ok(received) => (received, None, None),
+                        Err(_) if terminating => {
+                            drained = true;
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    Some(e) = stage_errors.recv() => return Err(e),
+                    Some(pending) = async { actors.as_mut()?.next().await } => {
+                        let PendingIntent {
+                            intent,
+                            options,
+                            correlation_id,
+                            origin,
+                            reply,
+                            received_at,
+                            last_price,
+                        } = pending;
+                        let received = Received {
+                            input: input::Input::TradeIntent(intent),
+                            options,
+                            correlation_id,
+                            origin,
+                            received_at,
+                        };
+                        (received, reply, Some(last_price))
+                    }
+                    Some(command) = admin_commands.recv() => match command {
+                        AdminCommand::RiskCheck(intent, options, reply) => {
+                            let received = Received {
+                                input: input::Input::TradeIntent(intent),
+                                options,
+                                correlation_id: None,
+                                origin: None,
+                                received_at: Utc::now(),
+                            };
+                            (received, Some(reply), None)
+                        }
+                        command => {
+                            if let Some(reason) = risk_manager.handle_admin_command(command) {
+                                lifecycle
+                                    .publish(
+                                    LifecycleStage::Reconciled,
+                                    Some(reason),
+                                    Some(&risk_manager),
+                                )
+                                    .await;
+                            }
+                            continue;
+                        }
+                    }
+                };
+                let Received {
+                    input: message,
+                    options,
+                    correlation_id,
+                    origin,
+                    received_at,
+                } = received;
+                handled = origin
+                    .as_ref()
+                    .map(|origin| (origin.topic.clone(), origin.partition, origin.offset));
+                let correlation_id = correlation_id.as_deref();
+                let span = info_span!("request", correlation_id);
+                // Every leg of a multi-leg intent gets a decision, made as a unit
+                let (id, amendment, responses) = match message {
+                    input::Input::Lot(lot) => {
+                        trace!("Lot received");
+                        if origin.is_some() {
+                            risk_manager.claim_ticker(&lot.ticker);
+                        }
+                        // Margin is computed from the cached records of the held assets
+                        if let Some(assets) = assets.as_ref() {
+                            assets.refresh(&lot.ticker, Utc::now()).await;
+                        }
+                        risk_manager.process_lot(lot);
+                        watchers
+                            .marked(&mut risk_manager, &producer, &topics, &webhooks, &events)
+                            .await;
+                        publish_shard_state(&outbox, &topics.account_state, &risk_manager).await?;
+                        continue;
+                    }
+                    input::Input::PriceUpdate {
+                        ticker,
+                        price,
+                        timestamp,
+                    } => {
+                        trace!("PriceUpdate received");
+                        let tick = PriceTick {
+                            price,
+                            timestamp: Some(timestamp),
+                            source: Some("price_updates"),
+                        };
+                        risk_manager.apply_price_update(&ticker, tick);
+                        watchers
+                            .marked(&mut risk_manager, &producer, &topics, &webhooks, &events)
+                            .await;
+                        continue;
+                    }
+                    input::Input::BorrowRate {
+                        ticker,
+                        borrow_rate,
+                    } => {
+                        trace!("BorrowRate received");
+                        risk_manager.update_borrow_rate(&ticker, borrow_rate);
+                        continue;
+                    }
+                    input::Input::Greeks { ticker, delta } => {
+                        trace!("Greeks received");
+                        risk_manager.update_delta(&ticker, delta);
+                        continue;
+                    }
+                    input::Input::MultiLeg(intent) => {
+                        trace!("MultiLegIntent received");
+                        if origin.is_some() {
+                            for leg in intent.legs.iter() {
+                                risk_manager.claim_ticker(&leg.ticker);
+                            }
+                        }
+                        if let Some(assets) = assets.as_ref() {
+                            for leg in intent.legs.iter() {
+                                assets.refresh(&leg.ticker, Utc::now()).await;
+                            }
+                        }
+                        let response = quarantine.retry(|| {
+                            span.in_scope(|| risk_manager.risk_check_legs(&intent))
+                        });
+                        let legs = match response {
+                            Ok(Ok(response)) => response.legs,
+                            // The caller waits for a decision on every leg, so deny them all
+                            Ok(Err(e)) => {
+                                error!(?e, id = %intent.id, "Multi-leg evaluation failed");
+                                intent
+                                    .legs
+                                    .iter()
+                                    .map(|leg| RiskCheckResponse::Denied {
+                                        intent: leg.clone(),
+                                        reasons: vec![DenyReason::EvaluationFailed],
+                                        buffered_price: None,
+                                        price_source: None,
+                                        score: None,
+                                        timing: None,
+                                    })
+                                    .collect()
+                            }
+                            Err(error) => {
+                                let attempts = quarantine.max_attempts();
+                                quarantine.dead_letter(origin, attempts, &error).await?;
+                                continue;
+                            }
+                        };
+                        (intent.id, None, Ok(legs))
+                    }
+                    input::Input::TradeIntent(trade_intent) => {
+                        trace!("TradeIntent received");
+                        // Inputs are keyed by ticker, so a ticker read from Kafka is ours to own
+                        if origin.is_some() {
+                            risk_manager.claim_ticker(&trade_intent.ticker);
+                        }
+                        let last_price = match (priced, actors.as_mut()) {
+                            (Some(last_price), _) => last_price,
+                            (None, Some(actors)) => {
+                                // Handled once the actor hands the intent back
+                                handled = None;
+                                actors.dispatch(PendingIntent {
+                                    intent: trade_intent,
+                                    options,
+                                    correlation_id: correlation_id.map(String::from),
+                                    origin,
+                                    reply,
+                                    received_at,
+                                    last_price: None,
+                                });
+                                continue;
+                            }
+                            (None, None) => None,
+                        };
+                        if let Some(previous) = risk_manager.previous_decision(&trade_intent.id) {
+                            let id = trade_intent.id;
+                            warn!(%id, "Duplicate TradeIntent, replaying original decision");
+                            match reply {
+                                Some(reply) => {
+                                    let _ = reply.send(previous.clone());
+                                }
+                                None => {
+                                    let topic = &topics.responses;
+                                    send_response(&outbox, topic, previous, correlation_id).await?
+                                }
+                            }
+                            continue;
+                        }
+                        if let Some(assets) = assets.as_ref() {
+                            assets.refresh(&trade_intent.ticker, Utc::now()).await;
+                        }
+                        // Fixtures need the price the decision was made at, so look it up once
+                        let last_price = match (last_price, fixtures.as_ref()) {
+                            (None, Some(_))
+                                if matches!(trade_intent.order_type, OrderType::Market) =>
+                            {
+                                risk_manager.last_tick(&trade_intent.ticker).ok()
+                            }
+                            (last_price, _) => last_price,
+                        };
+                        let response = quarantine.retry(|| {
+                            let intent = &trade_intent;
+                            span.in_scope(|| {
+                                risk_manager.risk_check_priced(intent, options, last_price)
+                            })
+                        });
+                        if let (Ok(Ok(response)), Some(fixtures)) =
+                            (response.as_ref(), fixtures.as_ref())
+                        {
+                            let fixture = DecisionFixture::capture(
+                                &risk_manager,
+                                &trade_intent,
+                                last_price,
+                                response,
+                            );
+                            if let Err(e) = fixtures.record(&fixture) {
+                                error!(?e, "Failed to record decision fixture");
+                            }
+                        }
+                        match response {
+                            Ok(response) => (trade_intent.id, None, response.map(|r| vec![r])),
+                            Err(error) => {
+                                let attempts = quarantine.max_attempts();
+                                quarantine.dead_letter(origin, attempts, &error).await?;
+                                continue;
+                            }
+                        }
+                    }
+                    input::Input::Cancellation(cancellation) => {
+                        trace!("OrderCancellation received");
+                        risk_manager.cancel_order(&cancellation.cancels);
+                        continue;
+                    }
+                    input::Input::Amendment(amendment) => {
+                        trace!("OrderAmendment received");
+                        if let Some(previous) = risk_manager.previous_decision(&amendment.id) {
+                            let id = amendment.id;
+                            warn!(%id, "Duplicate OrderAmendment, replaying original decision");
+                            let topic = &topics.responses;
+                            send_response(&outbox, topic, previous, correlation_id).await?;
+                            continue;
+                        }
+                        let response = quarantine.retry(|| {
+                            span.in_scope(|| risk_manager.amendment_check(&amendment, options))
+                        });
+                        match response {
+                            Ok(response) => {
+                                (amendment.id, Some(amendment), response.map(|r| vec![r]))
+                            }
+                            Err(error) => {
+                                let attempts = quarantine.max_attempts();
+                                quarantine.dead_letter(origin, attempts, &error).await?;
+                                continue;
+                            }
+                        }
+                    }
+                    input::Input::Query(request) => {
+                        trace!("Query received");
+                        let response = risk_manager.answer(&request);
+                        let key = request.id.to_string();
+                        outbox
+                            .send(&topics.query_responses, &key, &response, correlation_id)
+                            .await?;
+                        continue;
+                    }
+                    input::Input::WhatIf(trade_intent) => {
+                        trace!("WhatIf received");
+                        let response = quarantine
+                            .retry(|| span.in_scope(|| risk_manager.what_if(&trade_intent)));
+                        let response = match response {
+                            Ok(Ok(response)) => response,
+                            // The caller waits for an answer, so deny it and project nothing
+                            Ok(Err(e)) => {
+                                error!(?e, id = %trade_intent.id, "What-if evaluation failed");
+                                WhatIfResponse {
+                                    decision: RiskCheckResponse::Denied {
+                                        intent: trade_intent.clone(),
+                                        reasons: vec![DenyReason::EvaluationFailed],
+                                        buffered_price: None,
+                                        price_source: None,
+                                        score: None,
+                                        timing: None,
+                                    },
+                                    projected: risk_manager.portfolio_snapshot(),
+                                }
+                            }
+                            Err(error) => {
+                                let attempts = quarantine.max_attempts();
+                                quarantine.dead_letter(origin, attempts, &error).await?;
+                                continue;
+                            }
+                        };
+                        let ticker = &trade_intent.ticker;
+                        outbox
+                            .send(&topics.what_if_responses, ticker, &response, correlation_id)
+                            .await?;
+                        continue;
+                    }
+                    input::Input::Control(request) => {
+                        trace!("ControlRequest received");
+                        let ack = risk_manager.handle_control(&request, control.as_ref());
+                        if ack.accepted && request.command == ControlCommand::KillSwitch {
+                            events.publish(Event::Alert(Alert {
+                                level: AlertLevel::Critical,
+                                message: "Kill switch engaged, denying all intents".into(),
+                            }));
+                        }
+                        let key = request.id.to_string();
+                        outbox
+                            .send(&topics.admin_responses, &key, &ack, correlation_id)
+                            .await?;
+                        continue;
+                    }
+                    input::Input::Time(input::State::Open { next_close }) => {
+                        if let Err(e) = risk_manager.roll_session().await {
+                            error!(?e, "Failed to start the new session");
+                        }
+                        if let Some((snapshots, _)) = snapshots.as_mut() {
+                            snapshots.market_state(true, &risk_manager).await;
+                        }
+                        if let Some(flattener) = flattener.as_mut() {
+                            let intents = flattener.market_open(next_close, &mut risk_manager);
+                            for intent in intents {
+                                let sent = producer
+                                    .send(&topics.requests, &intent.ticker, &intent, None)
+                                    .await;
+                                if let Err(e) = sent {
+                                    error!(?e, ticker = %intent.ticker, "Failed to send flatten intent");
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                    input::Input::Time(input::State::Closed { next_open }) => {
+                        if let Some((snapshots, _)) = snapshots.as_mut() {
+                            snapshots.market_state(false, &risk_manager).await;
+                        }
+                        if let Some(flattener) = flattener.as_ref() {
+                            flattener.market_closed(&mut risk_manager);
+                        }
+                        if shutdown.at_close(risk_manager.clock().now(), next_open) {
+                            info!("Market closed, shutting down");
+                            let reason = Some("Market closed".to_string());
+                            lifecycle
+                                .publish(LifecycleStage::ShuttingDown, reason, Some(&risk_manager))
+                                .await;
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                };
+                let responses = match (responses, compliance_hook.as_ref()) {
+                    (Ok(responses), Some(hook)) => {
+                        let portfolio = risk_manager.portfolio_snapshot();
+                        let mut reviewed = Vec::with_capacity(responses.len());
+                        for response in responses {
+                            reviewed.push(hook.review(response, &portfolio).await);
+                        }
+                        Ok(reviewed)
+                    }
+                    (responses, _) => responses,
+                };
+                // The legs of a multi-leg intent still stand or fall together once reviewed
+                let responses = responses.map(|responses| {
+                    MultiLegResponse::all_or_nothing(responses)
+                        .into_iter()
+                        .map(|response| response.timed(received_at))
+                        .collect::<Vec<_>>()
+                });
+                match responses {
+                    Ok(decisions) => {
+                        lifecycle.healthy(&risk_manager).await;
+                        for decision in decisions {
+                            // Monitor the real decisions, also in shadow mode
+                            let alert = denial_monitor.as_mut().and_then(|m| m.record(&decision));
+                            if let Some(alert) = alert {
+                                warn!(message = %alert.message, "Denial rate alert");
+                                let topic = &topics.alerts;
+                                raise_alert(&producer, topic, &webhooks, &events, alert).await;
+                            }
+                            let response = match shadow.as_ref() {
+                                Some(shadow) => {
+                                    let ticker = &decision.intent().ticker;
+                                    let topic = &shadow.topic;
+                                    outbox
+                                        .send_decision(topic, ticker, &decision, correlation_id)
+                                        .await?;
+                                    decision.into_granted()
+                                }
+                                None => decision,
+                            };
+                            if let Some(sampler) = sampler.as_ref() {
+                                let portfolio = risk_manager.portfolio_snapshot();
+                                let sample = sampler.sample(&response, portfolio);
+                                if let Some(sample) = sample {
+                                    let ticker = &response.intent().ticker;
+                                    outbox
+                                        .send(sampler.topic(), ticker, &sample, correlation_id)
+                                        .await?;
+                                }
+                            }
+                            match amendment.as_ref() {
+                                Some(amendment) => {
+                                    risk_manager.record_amendment(amendment, &response)
+                                }
+                                None => risk_manager.record_decision(&response),
+                            }
+                            let topic = &topics.account_state;
+                            publish_shard_state(&outbox, topic, &risk_manager).await?;
+                            webhooks.notify(&response);
+                            events.publish(Event::Decision(response.clone()));
+                            if let Some(drop_copy) = drop_copy.as_ref() {
+                                drop_copy.notify(&response);
+                            }
+                            match reply.take() {
+                                Some(reply) => {
+                                    let _ = reply.send(response);
+                                }
+                                None => {
+                                    let topic = &topics.responses;
+                                    send_response(&outbox, topic, &response, correlation_id)
+                                        .await?
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!(?e);
+                        let message = format!("Risk check failed for {}: {}", id, e);
+                        lifecycle.degraded(message.clone(), &risk_manager).await;
+                        events.publish(Event::Alert(Alert {
+                            level: AlertLevel::Warning,
+                            message,
+                        }));
+                    }
+                }
+            }
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                acknowledge(&*source, handled.take()).await;
+                break Ok(());
+            }
+            Err(e) if supervisor::classify(&e) == ErrorClass::Fatal => break Err(e),
+            Err(e) => {
+                // The input that failed is left unacknowledged
+                handled = None;
+                // Only keep backing off if the loop keeps failing straight away
+                if started.elapsed() > STABLE_RUN {
+                    backoff.reset()
+                }
+                let delay = backoff.next_delay();
+                warn!(?e, ?delay, "Transient error in the run loop, restarting");
+                let reason = format!("Run loop restarting after error: {}", e);
+                lifecycle.degraded(reason, &risk_manager).await;
+                tokio::time::sleep(delay).await;
+            }
+        }
+    };
+    // Let the producer task send what is still queued
+    drop(outbox);
+    if let Err(e) = producer_task.await {
+        error!(?e, "Producer task failed");
+    }
+    if let (true, Some(handoff)) = (terminating, handoff.as_ref()) {
+        if let Err(e) = handoff.save(&risk_manager.handoff_state()).await {
+            error!(?e, "Failed to save state for handoff");
+        }
+    }
+    if let Err(e) = result.as_ref() {
+        let reason = Some(e.to_string());
+        lifecycle
+            .publish(LifecycleStage::ShuttingDown, reason, Some(&risk_manager))
+            .await;
+    }
+    lifecycle
+        .publish(LifecycleStage::Stopped, None, Some(&risk_manager))
+        .await;
+    result
+}
@@ -1,7 +1,27 @@
-use config::{Config, ConfigError, Environment};
+use crate::margin::MarginSchedule;
+use crate::money::RoundingPolicy;
+use crate::options::OptionsSettings;
+use crate::rules::{deserialize_rule_kinds, RuleKind};
+use crate::shutdown::ShutdownPolicy;
+use crate::symbols::SymbolMetadata;
+#[cfg(feature = "service")]
+use config::{Config, ConfigError, Environment, File};
+#[cfg(feature = "service")]
 use kafka_settings::KafkaSettings;
+use rust_decimal::Decimal;
+#[cfg(feature = "service")]
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 
+/// Environment variable naming the configuration file to read for the environment, e.g. `prod`
+/// for `config/prod.toml`.
+#[cfg(feature = "service")]
+const RUN_ENV: &str = "RUN_ENV";
+#[cfg(feature = "service")]
+const CONFIG_DIR: &str = "config";
+
+#[cfg(feature = "service")]
 #[derive(Debug, Deserialize)]
 pub struct AlpacaSettings {
     pub base_url: String,
@@ -9,22 +29,1153 @@ pub struct AlpacaSettings {
     pub secret_key: String,
 }
 
+/// Needed by the `datastore` price source.
+#[cfg(feature = "service")]
 #[derive(Debug, Deserialize)]
 pub struct DatastoreSettings {
     pub base_url: String,
 }
 
+fn default_port() -> u16 {
+    8080
+}
+
+fn default_liveness_timeout_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebServerSettings {
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Bearer token required by the `/admin` endpoints, `/check` and the gRPC service. They are
+    /// disabled if not set.
+    pub admin_token: Option<String>,
+    /// `/healthz` fails once the main loop hasn't made progress for this long
+    #[serde(default = "default_liveness_timeout_secs")]
+    pub liveness_timeout_secs: u64,
+}
+
+impl Default for WebServerSettings {
+    fn default() -> Self {
+        Self {
+            port: default_port(),
+            admin_token: None,
+            liveness_timeout_secs: default_liveness_timeout_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GoodFaithViolationPolicy {
+    Ignore,
+    Warn,
+    Deny,
+}
+
+impl Default for GoodFaithViolationPolicy {
+    fn default() -> Self {
+        Self::Warn
+    }
+}
+
+/// What to do with market orders priced at a stale price.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StalePricePolicy {
+    Warn,
+    Deny,
+}
+
+impl Default for StalePricePolicy {
+    fn default() -> Self {
+        Self::Deny
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleEvaluationMode {
+    /// Stop at the first rule that denies the intent
+    FirstFailure,
+    /// Run every rule and report all the reasons an intent is denied
+    All,
+}
+
+impl Default for RuleEvaluationMode {
+    fn default() -> Self {
+        Self::FirstFailure
+    }
+}
+
+fn default_evaluation_cache_ttl_ms() -> u64 {
+    1000
+}
+
+fn default_evaluation_cache_capacity() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EvaluationCacheSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How long a decision can be reused. Bounds how stale market-order prices can get.
+    #[serde(default = "default_evaluation_cache_ttl_ms")]
+    pub ttl_ms: u64,
+    #[serde(default = "default_evaluation_cache_capacity")]
+    pub capacity: usize,
+}
+
+impl Default for EvaluationCacheSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_ms: default_evaluation_cache_ttl_ms(),
+            capacity: default_evaluation_cache_capacity(),
+        }
+    }
+}
+
+fn default_market_order_slippage() -> Decimal {
+    Decimal::new(3, 2)
+}
+
+fn default_min_margin_equity() -> Decimal {
+    Decimal::new(2000, 0)
+}
+
+fn default_pdt_equity() -> Decimal {
+    Decimal::new(25000, 0)
+}
+
+/// How the buying power multiplier is estimated until the broker's is known, e.g. when starting
+/// from a holdings file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MultiplierSettings {
+    /// Equity under which pattern day traders get no leverage
+    #[serde(default = "default_min_margin_equity")]
+    pub min_margin_equity: Decimal,
+    /// Equity from which pattern day traders get 4x, and other accounts 2x
+    #[serde(default = "default_pdt_equity")]
+    pub pdt_equity: Decimal,
+}
+
+impl Default for MultiplierSettings {
+    fn default() -> Self {
+        Self {
+            min_margin_equity: default_min_margin_equity(),
+            pdt_equity: default_pdt_equity(),
+        }
+    }
+}
+
+/// Annualized borrow rates of shorts, as fractions. Rates from the borrow rates topic replace the
+/// configured ones.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BorrowFeeSettings {
+    /// Highest borrow rate new shorts may pay. Unlimited if unset.
+    pub max_rate: Option<Decimal>,
+    #[serde(default)]
+    pub rates: HashMap<String, Decimal>,
+}
+
+fn default_crypto_slippage() -> Decimal {
+    Decimal::new(1, 2)
+}
+
+/// Crypto trading. Crypto can't be shorted or bought on margin, and trades around the clock.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CryptoSettings {
+    /// Crypto tickers, along with those of the `crypto` asset class in the symbol metadata
+    #[serde(default)]
+    pub symbols: HashSet<String>,
+    /// Fraction added to the last price when sizing crypto market orders
+    #[serde(default = "default_crypto_slippage")]
+    pub market_order_slippage: Decimal,
+}
+
+impl Default for CryptoSettings {
+    fn default() -> Self {
+        Self {
+            symbols: HashSet::new(),
+            market_order_slippage: default_crypto_slippage(),
+        }
+    }
+}
+
+impl CryptoSettings {
+    /// Adds the tickers of the `crypto` asset class in the symbol metadata.
+    pub fn with_symbols(mut self, symbols: &SymbolMetadata) -> Self {
+        self.symbols
+            .extend(symbols.of_asset_class("crypto").map(str::to_string));
+        self
+    }
+}
+
+fn default_duplicate_intent_cache_size() -> usize {
+    10_000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RiskSettings {
+    #[serde(default)]
+    pub good_faith_violation_policy: GoodFaithViolationPolicy,
+    /// Number of recent decisions remembered to answer redelivered intents.
+    #[serde(default = "default_duplicate_intent_cache_size")]
+    pub duplicate_intent_cache_size: usize,
+    /// Fraction added to the last price when sizing market orders.
+    #[serde(default = "default_market_order_slippage")]
+    pub market_order_slippage: Decimal,
+    /// Oldest last trade price market orders may be priced at. Unlimited if unset.
+    pub max_price_age_secs: Option<u64>,
+    #[serde(default)]
+    pub stale_price_policy: StalePricePolicy,
+    /// Largest fraction of the day's peak equity that may be given back before opening trades
+    /// are denied for the rest of the day. Unlimited if unset.
+    pub max_drawdown: Option<Decimal>,
+    /// Rules to run, in order.
+    #[serde(
+        default = "RuleKind::default_pipeline",
+        deserialize_with = "deserialize_rule_kinds"
+    )]
+    pub rules: Vec<RuleKind>,
+    #[serde(default)]
+    pub rule_evaluation: RuleEvaluationMode,
+    /// Grant the largest quantity that passes when an intent is only too large, rather than
+    /// denying it outright.
+    #[serde(default)]
+    pub partial_approvals: bool,
+    /// Attach a risk score with per-rule contributions to every response.
+    #[serde(default)]
+    pub scoring: bool,
+    #[serde(default)]
+    pub evaluation_cache: EvaluationCacheSettings,
+    #[serde(default)]
+    pub rounding: RoundingPolicy,
+    #[serde(default)]
+    pub multiplier: MultiplierSettings,
+    /// Margin requirements used for margins, buying power and projections
+    #[serde(default)]
+    pub margin: MarginSchedule,
+    #[serde(default)]
+    pub borrow_fees: BorrowFeeSettings,
+    /// Crypto trading. Off if unset.
+    pub crypto: Option<CryptoSettings>,
+    /// Options trading. Off if unset, in which case option symbols are traded like shares.
+    pub options: Option<OptionsSettings>,
+}
+
+impl Default for RiskSettings {
+    fn default() -> Self {
+        Self {
+            good_faith_violation_policy: Default::default(),
+            duplicate_intent_cache_size: default_duplicate_intent_cache_size(),
+            market_order_slippage: default_market_order_slippage(),
+            max_price_age_secs: None,
+            stale_price_policy: Default::default(),
+            max_drawdown: None,
+            rules: RuleKind::default_pipeline(),
+            rule_evaluation: Default::default(),
+            partial_approvals: false,
+            scoring: false,
+            evaluation_cache: Default::default(),
+            rounding: Default::default(),
+            multiplier: Default::default(),
+            margin: Default::default(),
+            borrow_fees: Default::default(),
+            crypto: None,
+            options: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFilter {
+    /// Every decision
+    All,
+    Denied,
+    /// Alerts instead of decisions
+    Alerts,
+}
+
+impl Default for WebhookFilter {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+fn default_max_retries() -> usize {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    500
+}
+
+fn default_timeout_ms() -> u64 {
+    5000
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookSettings {
+    pub url: String,
+    /// Shared secret used to HMAC-sign payloads. Requests are unsigned if not set.
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub filter: WebhookFilter,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: usize,
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FixMode {
+    Initiator,
+    Acceptor,
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FixSettings {
+    pub mode: FixMode,
+    /// Address to connect to as an initiator, or to listen on as an acceptor.
+    pub address: String,
+    pub sender_comp_id: String,
+    pub target_comp_id: String,
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+}
+
+fn default_reload_interval_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitsSettings {
+    /// TOML, YAML or JSON file holding the risk limits
+    pub path: String,
+    #[serde(default = "default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ComplianceFailurePolicy {
+    /// Keep the rule pipeline's decision when the service can't be reached
+    Open,
+    /// Deny intents when the service can't be reached
+    Closed,
+}
+
+impl Default for ComplianceFailurePolicy {
+    fn default() -> Self {
+        Self::Closed
+    }
+}
+
+fn default_compliance_timeout_ms() -> u64 {
+    500
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ComplianceHookSettings {
+    pub url: String,
+    #[serde(default = "default_compliance_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub failure_policy: ComplianceFailurePolicy,
+}
+
+fn default_shadow_topic() -> String {
+    "risk-check-shadow".into()
+}
+
+/// In shadow mode every intent is granted, and the decision that would have been made is
+/// published to `topic` instead. Used to trial new rules in production.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShadowSettings {
+    #[serde(default = "default_shadow_topic")]
+    pub topic: String,
+    /// File to append decisions to, with the state they were made in, for golden tests
+    pub fixtures: Option<String>,
+}
+
+fn default_control_max_age_secs() -> u64 {
+    60
+}
+
+/// Enables control commands from the admin topic.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ControlSettings {
+    /// Shared secret used to verify command signatures
+    pub secret: String,
+    /// How long after being issued a command is still applied
+    #[serde(default = "default_control_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+fn default_snapshot_interval_secs() -> u64 {
+    60
+}
+
+/// Enables publishing risk snapshots of the portfolio every `interval_secs`, and whenever the
+/// market opens or closes.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SnapshotSettings {
+    #[serde(default = "default_snapshot_interval_secs")]
+    pub interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationFormat {
+    /// The alert as JSON
+    Json,
+    /// A Slack incoming webhook message
+    Slack,
+}
+
+impl Default for NotificationFormat {
+    fn default() -> Self {
+        Self::Json
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotificationEndpoint {
+    pub url: String,
+    #[serde(default)]
+    pub format: NotificationFormat,
+}
+
+fn default_max_notifications_per_minute() -> usize {
+    10
+}
+
+/// Endpoints notified of critical alerts, such as the kill switch being engaged.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotificationSettings {
+    #[serde(default)]
+    pub endpoints: HashMap<String, NotificationEndpoint>,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: usize,
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Alerts beyond this many in a minute are dropped
+    #[serde(default = "default_max_notifications_per_minute")]
+    pub max_per_minute: usize,
+}
+
+fn default_initial_restart_backoff_ms() -> u64 {
+    500
+}
+
+fn default_max_restart_backoff_ms() -> u64 {
+    30_000
+}
+
+/// Backoff between restarts of the run loop after transient errors.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SupervisorSettings {
+    #[serde(default = "default_initial_restart_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_restart_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+impl Default for SupervisorSettings {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: default_initial_restart_backoff_ms(),
+            max_backoff_ms: default_max_restart_backoff_ms(),
+        }
+    }
+}
+
+fn default_max_produce_retries() -> usize {
+    3
+}
+
+fn default_initial_produce_backoff_ms() -> u64 {
+    100
+}
+
+fn default_max_produce_backoff_ms() -> u64 {
+    5_000
+}
+
+/// Retries of failed sends to Kafka. The error only reaches the supervisor once they run out.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProducerRetrySettings {
+    #[serde(default = "default_max_produce_retries")]
+    pub max_retries: usize,
+    #[serde(default = "default_initial_produce_backoff_ms")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_produce_backoff_ms")]
+    pub max_backoff_ms: u64,
+}
+
+impl Default for ProducerRetrySettings {
+    fn default() -> Self {
+        Self {
+            max_retries: default_max_produce_retries(),
+            initial_backoff_ms: default_initial_produce_backoff_ms(),
+            max_backoff_ms: default_max_produce_backoff_ms(),
+        }
+    }
+}
+
+fn default_queue_capacity() -> usize {
+    1_000
+}
+
+/// Capacity of the queues between consuming, evaluating and producing. A full queue makes the
+/// stage before it wait.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PipelineSettings {
+    #[serde(default = "default_queue_capacity")]
+    pub input_capacity: usize,
+    #[serde(default = "default_queue_capacity")]
+    pub output_capacity: usize,
+}
+
+impl Default for PipelineSettings {
+    fn default() -> Self {
+        Self {
+            input_capacity: default_queue_capacity(),
+            output_capacity: default_queue_capacity(),
+        }
+    }
+}
+
+fn default_max_attempts() -> usize {
+    3
+}
+
+/// How often a message may fail processing before it is moved to the dead-letter topic.
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuarantineSettings {
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+}
+
+impl Default for QuarantineSettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+        }
+    }
+}
+
+fn default_denial_window() -> usize {
+    100
+}
+
+fn default_denial_threshold() -> f64 {
+    0.5
+}
+
+fn default_min_decisions() -> usize {
+    20
+}
+
+/// Alerts when more than `threshold` of the last `window` decisions were denials. Alerts are
+/// published to the alerts topic and to webhooks with the `alerts` filter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DenialMonitorSettings {
+    #[serde(default = "default_denial_window")]
+    pub window: usize,
+    #[serde(default = "default_denial_threshold")]
+    pub threshold: f64,
+    /// Decisions needed in the window before alerting, so a few early denials don't alert
+    #[serde(default = "default_min_decisions")]
+    pub min_decisions: usize,
+}
+
+/// Closes positions whose mark moves against their average cost by more than their stop loss,
+/// by sending closing market orders to the requests topic.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StopLossSettings {
+    /// Stop loss of positions without their own, as a fraction of their average cost
+    pub default_stop: Option<Decimal>,
+    /// Stop losses by ticker, as fractions of average cost
+    #[serde(default)]
+    pub stops: HashMap<String, Decimal>,
+}
+
+fn default_minutes_before_close() -> u64 {
+    15
+}
+
+/// Closes the positions of strategies that must be flat overnight shortly before the close, and
+/// denies them new positions until the market closes. Positions are kept by ticker, so strategies
+/// are flattened through the tickers they trade.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FlattenSettings {
+    #[serde(default = "default_minutes_before_close")]
+    pub minutes_before_close: u64,
+    /// Tickers traded by each strategy that must be flat overnight
+    #[serde(default)]
+    pub strategies: HashMap<String, Vec<String>>,
+}
+
+fn default_liquidation_buffer() -> Decimal {
+    Decimal::new(1, 1)
+}
+
+/// Suggests positions to close once maintenance margin exceeds equity, publishing them to the
+/// liquidations topic.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LiquidationSettings {
+    /// Fraction of equity that should be left over maintenance margin after the liquidations
+    #[serde(default = "default_liquidation_buffer")]
+    pub buffer: Decimal,
+}
+
+fn default_warning_cushion() -> Decimal {
+    Decimal::new(25, 2)
+}
+
+fn default_breach_cushion() -> Decimal {
+    Decimal::new(1, 1)
+}
+
+/// Alerts when the equity in excess of maintenance margin, as a fraction of equity, drops below
+/// the thresholds.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MarginCallSettings {
+    #[serde(default = "default_warning_cushion")]
+    pub warning_cushion: Decimal,
+    #[serde(default = "default_breach_cushion")]
+    pub breach_cushion: Decimal,
+}
+
+fn default_lag_interval_secs() -> u64 {
+    30
+}
+
+fn default_lag_threshold() -> i64 {
+    1_000
+}
+
+/// Periodically checks how far the consumer is behind, alerting when any input partition lags
+/// by more than `threshold` messages.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LagMonitorSettings {
+    #[serde(default = "default_lag_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default = "default_lag_threshold")]
+    pub threshold: i64,
+}
+
+fn default_handoff_key() -> String {
+    "risk-manager:handoff".into()
+}
+
+fn default_handoff_ttl_secs() -> u64 {
+    300
+}
+
+/// Hands the in-memory state over to the next instance through Redis on SIGTERM, so that a
+/// deploy doesn't need a full re-sync from the broker.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HandoffSettings {
+    pub redis_url: String,
+    #[serde(default = "default_handoff_key")]
+    pub key: String,
+    /// How long a saved state stays usable. Older states are discarded and the account is
+    /// re-synced from the broker instead.
+    #[serde(default = "default_handoff_ttl_secs")]
+    pub ttl_secs: u64,
+    /// How long to wait at startup for the previous instance to save its state, when both run
+    /// side by side during a deploy
+    #[serde(default)]
+    pub wait_secs: u64,
+}
+
+/// Keeps the prices of held and recently traded tickers in memory, following their updates in
+/// Redis, so that market orders are priced without a lookup. At least one of `channel` and
+/// `key_prefix` has to be set.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PriceFeedSettings {
+    pub redis_url: String,
+    /// Pattern of the pub/sub channels price ticks are published on, e.g. `prices.*`. Ticks are
+    /// JSON, like `{"ticker": "AAPL", "price": "150.10", "timestamp": "2021-06-01T14:30:00Z"}`.
+    pub channel: Option<String>,
+    /// Prefix of the keys holding the last price of each ticker, e.g. `price:` for `price:AAPL`.
+    /// Changes are followed through keyspace notifications, which have to be enabled on the
+    /// server.
+    pub key_prefix: Option<String>,
+}
+
+fn default_price_ttl_ms() -> u64 {
+    1_000
+}
+
+fn default_asset_refresh_secs() -> u64 {
+    3600
+}
+
+fn default_asset_timeout_ms() -> u64 {
+    1_000
+}
+
+/// Looks up whether assets can be traded on the broker's assets endpoint, denying intents for
+/// those that can't.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AssetSettings {
+    /// How long an asset record is used for before it is looked up again
+    #[serde(default = "default_asset_refresh_secs")]
+    pub refresh_secs: u64,
+    #[serde(default = "default_asset_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+/// Caches the last prices looked up for market orders.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PriceCacheSettings {
+    /// How long a price is used for after it was looked up or received
+    #[serde(default = "default_price_ttl_ms")]
+    pub ttl_ms: u64,
+    /// Refreshes the prices of all held tickers this often, so that orders for them rarely wait
+    /// on a lookup. Should be shorter than the TTL.
+    pub prefetch_interval_ms: Option<u64>,
+}
+
+/// A place market order prices can be looked up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSourceKind {
+    /// Keys holding the last price of each ticker
+    Redis,
+    /// The datastore service
+    Datastore,
+    /// Polygon.io's last trade endpoint
+    Polygon,
+    /// Alpaca's market data API, with the credentials of the trading account
+    Alpaca,
+}
+
+fn default_price_source_order() -> Vec<PriceSourceKind> {
+    vec![PriceSourceKind::Datastore, PriceSourceKind::Alpaca]
+}
+
+fn default_price_source_timeout_ms() -> u64 {
+    1_000
+}
+
+fn default_breaker_failures() -> u32 {
+    5
+}
+
+fn default_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_price_key_prefix() -> String {
+    "price:".into()
+}
+
+fn default_alpaca_data_url() -> String {
+    "https://data.alpaca.markets".into()
+}
+
+fn default_polygon_url() -> String {
+    "https://api.polygon.io".into()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PolygonSettings {
+    pub api_key: String,
+    #[serde(default = "default_polygon_url")]
+    pub base_url: String,
+    /// Polygon's trade stream, e.g. `wss://socket.polygon.io/stocks`. When set, the trades of
+    /// held and recently traded tickers are followed into the price cache, like the price feed
+    /// does from Redis.
+    pub websocket_url: Option<String>,
+}
+
+/// Where market order prices are looked up when they aren't cached. Sources are tried in order,
+/// and a source that fails `breaker_failures` times in a row is skipped for
+/// `breaker_cooldown_secs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PriceSourcesSettings {
+    /// The datastore, then Alpaca, unless set
+    #[serde(default = "default_price_source_order")]
+    pub order: Vec<PriceSourceKind>,
+    /// How long each source is given to answer
+    #[serde(default = "default_price_source_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_breaker_failures")]
+    pub breaker_failures: u32,
+    #[serde(default = "default_breaker_cooldown_secs")]
+    pub breaker_cooldown_secs: u64,
+    /// Needed by the `redis` source
+    pub redis_url: Option<String>,
+    /// Prefix of the keys the `redis` source reads, e.g. `price:` for `price:AAPL`
+    #[serde(default = "default_price_key_prefix")]
+    pub redis_key_prefix: String,
+    /// Needed by the `polygon` source
+    pub polygon: Option<PolygonSettings>,
+    /// Used by the `alpaca` source
+    #[serde(default = "default_alpaca_data_url")]
+    pub alpaca_data_url: String,
+}
+
+impl Default for PriceSourcesSettings {
+    fn default() -> Self {
+        Self {
+            order: default_price_source_order(),
+            timeout_ms: default_price_source_timeout_ms(),
+            breaker_failures: default_breaker_failures(),
+            breaker_cooldown_secs: default_breaker_cooldown_secs(),
+            redis_url: None,
+            redis_key_prefix: default_price_key_prefix(),
+            polygon: None,
+            alpaca_data_url: default_alpaca_data_url(),
+        }
+    }
+}
+
+fn default_max_redis_connections() -> usize {
+    8
+}
+
+fn default_redis_timeout_ms() -> u64 {
+    1_000
+}
+
+/// Async Redis connections, kept open and shared between calls.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedisPoolSettings {
+    #[serde(default = "default_max_redis_connections")]
+    pub max_connections: usize,
+    /// How long to wait for a connection when all of them are in use
+    #[serde(default = "default_redis_timeout_ms")]
+    pub wait_timeout_ms: u64,
+    #[serde(default = "default_redis_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_redis_timeout_ms")]
+    pub command_timeout_ms: u64,
+}
+
+impl Default for RedisPoolSettings {
+    fn default() -> Self {
+        Self {
+            max_connections: default_max_redis_connections(),
+            wait_timeout_ms: default_redis_timeout_ms(),
+            connect_timeout_ms: default_redis_timeout_ms(),
+            command_timeout_ms: default_redis_timeout_ms(),
+        }
+    }
+}
+
+/// Runs one of several instances, each owning the tickers of the partitions assigned to it. The
+/// request and lot topics must be keyed by ticker.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ShardingSettings {
+    /// Unique among the instances, and stable across restarts
+    pub instance_id: String,
+}
+
+/// Names of the topics the risk manager reads from and writes to. The consumer is subscribed to
+/// the input topics, replacing any from the Kafka settings.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TopicSettings {
+    /// Trade intents, amendments, queries and what-if requests
+    pub requests: String,
+    pub responses: String,
+    pub lots: String,
+    /// Market open and close notifications
+    pub time: String,
+    /// Control commands. Only consumed if control commands are enabled.
+    pub admin: String,
+    pub admin_responses: String,
+    pub query_responses: String,
+    pub what_if_responses: String,
+    pub lifecycle: String,
+    pub snapshots: String,
+    pub alerts: String,
+    /// Messages that repeatedly failed processing
+    pub dead_letters: String,
+    /// Compacted topic where sharded instances share their part of the account
+    pub account_state: String,
+    /// Suggested liquidations, published when maintenance margin exceeds equity
+    pub liquidations: String,
+    /// Market prices to mark positions at. Only consumed if set.
+    pub prices: Option<String>,
+    /// Annualized borrow rates of shortable tickers. Only consumed if set.
+    pub borrow_rates: Option<String>,
+    /// Deltas of option contracts. Only consumed if set.
+    pub greeks: Option<String>,
+}
+
+impl Default for TopicSettings {
+    fn default() -> Self {
+        Self {
+            requests: "risk-check-request".into(),
+            responses: "risk-check-response".into(),
+            lots: "lots".into(),
+            time: "time".into(),
+            admin: "risk-admin".into(),
+            admin_responses: "risk-admin-response".into(),
+            query_responses: "risk-query-response".into(),
+            what_if_responses: "risk-what-if-response".into(),
+            lifecycle: "risk-lifecycle".into(),
+            snapshots: "risk-snapshots".into(),
+            alerts: "risk-alerts".into(),
+            dead_letters: "risk-dead-letters".into(),
+            account_state: "risk-account-state".into(),
+            liquidations: "risk-liquidations".into(),
+            prices: None,
+            borrow_rates: None,
+            greeks: None,
+        }
+    }
+}
+
+impl TopicSettings {
+    pub fn inputs(&self, control: bool) -> Vec<&str> {
+        let mut topics = vec![
+            self.requests.as_str(),
+            self.lots.as_str(),
+            self.time.as_str(),
+        ];
+        if control {
+            topics.push(self.admin.as_str())
+        }
+        if let Some(prices) = self.prices.as_deref() {
+            topics.push(prices)
+        }
+        if let Some(borrow_rates) = self.borrow_rates.as_deref() {
+            topics.push(borrow_rates)
+        }
+        if let Some(greeks) = self.greeks.as_deref() {
+            topics.push(greeks)
+        }
+        topics
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct GrpcSettings {
+    pub port: u16,
+}
+
+#[cfg(feature = "aws")]
+fn default_wait_time_secs() -> i64 {
+    20
+}
+
+/// Reads inputs from SQS queues and publishes outputs to SNS topics instead of Kafka. Queues and
+/// topics are named after the topics in the topic settings, and the SNS subscriptions feeding
+/// the queues need raw message delivery. Messages are deleted once handled, so the queues'
+/// visibility timeout should be longer than inputs wait to be evaluated. The Kafka settings are
+/// still used for sharding and replays.
+#[cfg(feature = "aws")]
+#[derive(Debug, Clone, Deserialize)]
+pub struct AwsSettings {
+    /// e.g. `us-east-1`. Credentials are read from the environment or the instance profile.
+    pub region: String,
+    /// Prepended to the input topics to get their queue URLs, e.g.
+    /// `https://sqs.us-east-1.amazonaws.com/123456789012/`
+    pub queue_url_prefix: String,
+    /// Prepended to the output topics to get their SNS topic ARNs, e.g.
+    /// `arn:aws:sns:us-east-1:123456789012:`
+    pub topic_arn_prefix: String,
+    /// How long each receive waits for messages to arrive, up to 20 seconds
+    #[serde(default = "default_wait_time_secs")]
+    pub wait_time_secs: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecFormat {
+    Json,
+    /// Requires the `avro` feature
+    Avro,
+    /// Requires the `protobuf` feature. Inputs are `RiskInput` envelopes and decisions are
+    /// `RiskCheckResponse` messages, as defined in `proto/risk.proto`. Other outputs stay JSON.
+    Protobuf,
+}
+
+impl Default for CodecFormat {
+    fn default() -> Self {
+        CodecFormat::Json
+    }
+}
+
+/// Wire format of Kafka payloads, for both inputs and outputs.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CodecSettings {
+    #[serde(default)]
+    pub format: CodecFormat,
+    /// Confluent schema registry, required for Avro
+    pub schema_registry_url: Option<String>,
+}
+
+fn default_validation_topic() -> String {
+    "risk-validation".into()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ValidationSettings {
+    #[serde(default = "default_validation_topic")]
+    pub topic: String,
+    /// Fraction of granted intents, between 0 and 1, published for model validation.
+    pub sample_rate: f64,
+}
+
+#[cfg(feature = "service")]
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub alpaca: AlpacaSettings,
     pub kafka: KafkaSettings,
-    pub datastore: DatastoreSettings,
+    pub datastore: Option<DatastoreSettings>,
+    #[serde(default)]
+    pub risk: RiskSettings,
+    #[serde(default)]
+    pub webhooks: HashMap<String, WebhookSettings>,
+    pub fix: Option<FixSettings>,
+    #[serde(default)]
+    pub webserver: WebServerSettings,
+    pub validation: Option<ValidationSettings>,
+    pub limits: Option<LimitsSettings>,
+    /// CSV of holdings that replaces the broker's as the starting state, e.g. in paper
+    /// environments.
+    pub holdings_file: Option<String>,
+    /// TOML, YAML or JSON file mapping tickers to their sector and asset class
+    pub symbols_file: Option<String>,
+    pub compliance_hook: Option<ComplianceHookSettings>,
+    pub shadow: Option<ShadowSettings>,
+    pub control: Option<ControlSettings>,
+    pub snapshots: Option<SnapshotSettings>,
+    pub denial_monitor: Option<DenialMonitorSettings>,
+    pub stop_loss: Option<StopLossSettings>,
+    pub flatten: Option<FlattenSettings>,
+    pub liquidation: Option<LiquidationSettings>,
+    pub margin_call: Option<MarginCallSettings>,
+    pub lag_monitor: Option<LagMonitorSettings>,
+    /// Looks up the prices of intents in a task per ticker, so that tickers don't wait on each
+    /// other's lookups
+    #[serde(default)]
+    pub ticker_actors: bool,
+    pub sharding: Option<ShardingSettings>,
+    pub handoff: Option<HandoffSettings>,
+    #[serde(default)]
+    pub redis_pool: RedisPoolSettings,
+    pub price_feed: Option<PriceFeedSettings>,
+    pub price_cache: Option<PriceCacheSettings>,
+    pub assets: Option<AssetSettings>,
+    /// When to stop on its own. After the post-market close if unset, unless crypto is traded.
+    pub shutdown: Option<ShutdownPolicy>,
+    #[serde(default)]
+    pub price_sources: PriceSourcesSettings,
+    pub notifications: Option<NotificationSettings>,
+    #[serde(default)]
+    pub supervisor: SupervisorSettings,
+    #[serde(default)]
+    pub producer_retry: ProducerRetrySettings,
+    #[serde(default)]
+    pub quarantine: QuarantineSettings,
+    #[serde(default)]
+    pub pipeline: PipelineSettings,
+    #[serde(default)]
+    pub topics: TopicSettings,
+    #[serde(default)]
+    pub codec: CodecSettings,
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<GrpcSettings>,
+    #[cfg(feature = "aws")]
+    pub aws: Option<AwsSettings>,
 }
 
+#[cfg(feature = "service")]
 impl Settings {
+    /// Reads `config/default` and the file of the environment selected by `RUN_ENV`, in TOML,
+    /// YAML or JSON, then environment variables. Later sources override earlier ones, and only
+    /// the file of a selected environment is required.
     pub fn new() -> Result<Self, ConfigError> {
-        let mut s = Config::new();
-        s.merge(Environment::new().separator("__"))?;
-        s.try_into()
+        layered(CONFIG_DIR, std::env::var(RUN_ENV).ok().as_deref())
+    }
+}
+
+#[cfg(feature = "service")]
+impl RiskSettings {
+    /// Reads only the `risk` section, from the same sources as `Settings::new`, for tools that
+    /// run the engine without the rest of the service's configuration.
+    pub fn load() -> Result<Self, ConfigError> {
+        #[derive(Deserialize)]
+        struct Risk {
+            #[serde(default)]
+            risk: RiskSettings,
+        }
+        let risk: Risk = layered(CONFIG_DIR, std::env::var(RUN_ENV).ok().as_deref())?;
+        Ok(risk.risk)
+    }
+}
+
+#[cfg(feature = "service")]
+fn layered<T: DeserializeOwned>(dir: &str, run_env: Option<&str>) -> Result<T, ConfigError> {
+    let mut s = Config::new();
+    s.merge(File::with_name(&format!("{}/default", dir)).required(false))?;
+    if let Some(run_env) = run_env {
+        s.merge(File::with_name(&format!("{}/{}", dir, run_env)))?;
+    }
+    s.merge(Environment::new().separator("__"))?;
+    s.try_into()
+}
+
+#[cfg(all(test, feature = "service"))]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Layers {
+        from_default: String,
+        from_env_file: String,
+        layered_settings_test_override: String,
+    }
+
+    #[test]
+    fn layering() {
+        let dir = std::env::temp_dir().join(format!("config-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(
+            dir.join("default.toml"),
+            "from_default = \"default\"\nfrom_env_file = \"default\"\n\
+             layered_settings_test_override = \"default\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("staging.yaml"),
+            "from_env_file: staging\nlayered_settings_test_override: staging\n",
+        )
+        .unwrap();
+        std::env::set_var("LAYERED_SETTINGS_TEST_OVERRIDE", "env");
+        let dir = dir.to_str().unwrap();
+
+        let layers: Layers = layered(dir, Some("staging")).unwrap();
+        assert_eq!(layers.from_default, "default");
+        assert_eq!(layers.from_env_file, "staging");
+        assert_eq!(layers.layered_settings_test_override, "env");
+        let layers: Layers = layered(dir, None).unwrap();
+        assert_eq!(layers.from_env_file, "default");
+        // A selected environment must have a file
+        assert!(layered::<Layers>(dir, Some("prod")).is_err());
     }
 }
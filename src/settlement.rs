@@ -0,0 +1,215 @@
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+use rust_decimal::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tracing::trace;
+
+/// Number of business days it takes for a trade to settle.
+const SETTLEMENT_DAYS: usize = 2;
+
+pub fn settlement_date(trade_date: NaiveDate) -> NaiveDate {
+    let mut date = trade_date;
+    let mut remaining = SETTLEMENT_DAYS;
+    while remaining > 0 {
+        date += Duration::days(1);
+        if !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) {
+            remaining -= 1;
+        }
+    }
+    date
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct PendingSettlement {
+    amount: Decimal,
+    settles_on: NaiveDate,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct UnsettledPurchase {
+    shares: Decimal,
+    funds_settle_on: NaiveDate,
+}
+
+/// Tracks settled and unsettled cash along with purchases that were funded by unsettled sale
+/// proceeds. Selling one of those purchases before its funding has settled is a good-faith
+/// violation in a cash account.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SettlementLedger {
+    settled_cash: Decimal,
+    pending: Vec<PendingSettlement>,
+    unsettled_purchases: HashMap<String, Vec<UnsettledPurchase>>,
+    violations: usize,
+}
+
+impl SettlementLedger {
+    pub fn new(settled_cash: Decimal) -> Self {
+        Self {
+            settled_cash,
+            ..Default::default()
+        }
+    }
+
+    pub fn settled_cash(&self) -> Decimal {
+        self.settled_cash
+    }
+
+    pub fn unsettled_cash(&self) -> Decimal {
+        self.pending
+            .iter()
+            .fold(Decimal::ZERO, |state, p| state + p.amount)
+    }
+
+    pub fn violations(&self) -> usize {
+        self.violations
+    }
+
+    pub fn settle(&mut self, today: NaiveDate) {
+        let (settled, pending): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|p| p.settles_on <= today);
+        for p in settled {
+            self.settled_cash += p.amount
+        }
+        self.pending = pending;
+        for purchases in self.unsettled_purchases.values_mut() {
+            purchases.retain(|p| p.funds_settle_on > today)
+        }
+        self.unsettled_purchases.retain(|_, v| !v.is_empty());
+    }
+
+    pub fn record_purchase(
+        &mut self,
+        ticker: &str,
+        shares: Decimal,
+        cost: Decimal,
+        date: NaiveDate,
+    ) {
+        self.settle(date);
+        if cost <= self.settled_cash {
+            self.settled_cash -= cost;
+            return;
+        }
+        let mut remaining = cost - self.settled_cash.max(Decimal::ZERO);
+        self.settled_cash = self.settled_cash.min(Decimal::ZERO);
+        let mut funds_settle_on = date;
+        self.pending.sort_by_key(|p| p.settles_on);
+        for p in self.pending.iter_mut() {
+            if remaining.is_zero() {
+                break;
+            }
+            let used = remaining.min(p.amount);
+            p.amount -= used;
+            remaining -= used;
+            funds_settle_on = funds_settle_on.max(p.settles_on);
+        }
+        self.pending.retain(|p| !p.amount.is_zero());
+        // Anything left over was bought on margin or with cash we don't know about
+        self.settled_cash -= remaining;
+        if funds_settle_on > date {
+            trace!(%ticker, %shares, %funds_settle_on, "Purchase funded with unsettled cash");
+            self.unsettled_purchases
+                .entry(ticker.to_string())
+                .or_default()
+                .push(UnsettledPurchase {
+                    shares,
+                    funds_settle_on,
+                })
+        }
+    }
+
+    /// Records a sale whose proceeds settle right away, like those of crypto.
+    pub fn record_settled_sale(&mut self, proceeds: Decimal, date: NaiveDate) {
+        self.settle(date);
+        self.settled_cash += proceeds;
+    }
+
+    pub fn record_sale(
+        &mut self,
+        ticker: &str,
+        shares: Decimal,
+        proceeds: Decimal,
+        date: NaiveDate,
+    ) {
+        self.settle(date);
+        self.pending.push(PendingSettlement {
+            amount: proceeds,
+            settles_on: settlement_date(date),
+        });
+        if let Some(purchases) = self.unsettled_purchases.get_mut(ticker) {
+            let mut remaining = shares;
+            for p in purchases.iter_mut() {
+                if remaining.is_zero() {
+                    break;
+                }
+                let sold = remaining.min(p.shares);
+                p.shares -= sold;
+                remaining -= sold;
+                if !sold.is_zero() {
+                    self.violations += 1;
+                }
+            }
+            purchases.retain(|p| !p.shares.is_zero());
+        }
+        self.unsettled_purchases.retain(|_, v| !v.is_empty());
+    }
+
+    /// Returns `true` if selling `ticker` on `today` would liquidate a position that was bought
+    /// with funds that haven't settled yet.
+    pub fn is_good_faith_violation(&self, ticker: &str, today: NaiveDate) -> bool {
+        self.unsettled_purchases
+            .get(ticker)
+            .map(|purchases| purchases.iter().any(|p| p.funds_settle_on > today))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd(y, m, d)
+    }
+
+    #[test]
+    fn settlement_skips_weekends() {
+        // Wednesday -> Friday
+        assert_eq!(settlement_date(date(2021, 11, 3)), date(2021, 11, 5));
+        // Thursday -> Monday
+        assert_eq!(settlement_date(date(2021, 11, 4)), date(2021, 11, 8));
+        // Friday -> Tuesday
+        assert_eq!(settlement_date(date(2021, 11, 5)), date(2021, 11, 9));
+    }
+
+    #[test]
+    fn good_faith_violation() {
+        let monday = date(2021, 11, 1);
+        let tuesday = date(2021, 11, 2);
+        let thursday = date(2021, 11, 4);
+        let mut ledger = SettlementLedger::new(Decimal::new(1000, 0));
+
+        // Buying with settled cash is always fine
+        ledger.record_purchase("AAPL", Decimal::new(10, 0), Decimal::new(1000, 0), monday);
+        assert!(!ledger.is_good_faith_violation("AAPL", monday));
+        ledger.record_sale("AAPL", Decimal::new(10, 0), Decimal::new(1000, 0), monday);
+        assert_eq!(ledger.settled_cash(), Decimal::ZERO);
+        assert_eq!(ledger.unsettled_cash(), Decimal::new(1000, 0));
+
+        // Buying with unsettled proceeds and selling before settlement is a violation
+        ledger.record_purchase("TSLA", Decimal::new(1, 0), Decimal::new(1000, 0), tuesday);
+        assert!(ledger.is_good_faith_violation("TSLA", tuesday));
+        assert!(!ledger.is_good_faith_violation("AAPL", tuesday));
+        ledger.record_sale("TSLA", Decimal::new(1, 0), Decimal::new(1000, 0), tuesday);
+        assert_eq!(ledger.violations(), 1);
+
+        // Once the funding sale has settled, the purchase is considered paid for
+        ledger.record_purchase("TSLA", Decimal::new(1, 0), Decimal::new(1000, 0), tuesday);
+        assert!(ledger.is_good_faith_violation("TSLA", tuesday));
+        assert!(!ledger.is_good_faith_violation("TSLA", thursday));
+
+        // Proceeds that settle right away can be spent at once
+        let settled = ledger.settled_cash();
+        ledger.record_settled_sale(Decimal::new(500, 0), thursday);
+        assert_eq!(ledger.settled_cash(), settled + Decimal::new(500, 0));
+    }
+}
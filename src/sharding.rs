@@ -0,0 +1,222 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+#[cfg(feature = "service")]
+pub use topic::{follow, state_consumer};
+use tracing::info;
+
+/// The part of the account held by one instance, published to the compacted account state topic
+/// keyed by instance id. Values only cover the tickers the instance owns.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ShardState {
+    pub instance_id: String,
+    pub tickers: BTreeSet<String>,
+    /// Change in cash from the fills the instance processed since it started
+    pub cash_flow: Decimal,
+    pub long_market_exposure: Decimal,
+    pub short_market_exposure: Decimal,
+    pub initial_margin: Decimal,
+    pub maintenance_margin: Decimal,
+    pub reserved_buying_power: Decimal,
+    /// Delta-adjusted notional by underlier, unless an option held has no known delta
+    #[serde(default)]
+    pub delta_exposures: Option<HashMap<String, Decimal>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Which tickers this instance owns, and what the other instances hold.
+///
+/// An instance owns the tickers of the partitions assigned to it, so it claims a ticker when it
+/// receives an input for it. If a rebalance moves a ticker, the newer claim wins. Positions that
+/// no instance has claimed yet are valued by every instance, but published by none, so they are
+/// counted once.
+#[derive(Clone, Debug, Default)]
+pub struct Shard {
+    instance_id: Option<String>,
+    owned: BTreeSet<String>,
+    starting_cash: Decimal,
+    peers: HashMap<String, ShardState>,
+}
+
+impl Shard {
+    pub fn new(instance_id: String, starting_cash: Decimal) -> Self {
+        Self {
+            instance_id: Some(instance_id),
+            starting_cash,
+            ..Default::default()
+        }
+    }
+
+    pub fn instance_id(&self) -> Option<&str> {
+        self.instance_id.as_deref()
+    }
+
+    /// Claims a ticker, returning whether it wasn't owned before.
+    pub fn claim(&mut self, ticker: &str) -> bool {
+        if self.instance_id.is_none() || self.owned.contains(ticker) {
+            return false;
+        }
+        for peer in self.peers.values_mut() {
+            peer.tickers.remove(ticker);
+        }
+        self.owned.insert(ticker.to_string())
+    }
+
+    pub fn owns(&self, ticker: &str) -> bool {
+        self.owned.contains(ticker)
+    }
+
+    pub fn is_peer_owned(&self, ticker: &str) -> bool {
+        self.peers
+            .values()
+            .any(|peer| peer.tickers.contains(ticker))
+    }
+
+    /// Records the latest state of another instance.
+    pub fn update_peer(&mut self, state: ShardState) {
+        if Some(state.instance_id.as_str()) == self.instance_id() {
+            return;
+        }
+        for ticker in state.tickers.iter() {
+            if self.owned.remove(ticker) {
+                info!(%ticker, peer = %state.instance_id, "Ticker moved to another instance");
+            }
+        }
+        self.peers.insert(state.instance_id.clone(), state);
+    }
+
+    pub fn starting_cash(&self) -> Decimal {
+        self.starting_cash
+    }
+
+    /// The latest state of another instance, if it has published one.
+    pub fn peer(&self, instance_id: &str) -> Option<&ShardState> {
+        self.peers.get(instance_id)
+    }
+
+    /// The latest states of the other instances.
+    pub fn peers(&self) -> impl Iterator<Item = &ShardState> {
+        self.peers.values()
+    }
+
+    /// Sums a value over the other instances.
+    pub fn peer_total(&self, value: impl Fn(&ShardState) -> Decimal) -> Decimal {
+        self.peers.values().map(value).sum()
+    }
+
+    pub fn owned(&self) -> &BTreeSet<String> {
+        &self.owned
+    }
+}
+
+/// Sharing states through the account state topic.
+#[cfg(feature = "service")]
+mod topic {
+    use super::ShardState;
+    use crate::admin::{AdminCommand, AdminSender};
+    use crate::codec::Codec;
+    use anyhow::{anyhow, Result};
+    use kafka_settings::KafkaSettings;
+    use rdkafka::consumer::{Consumer, StreamConsumer};
+    use rdkafka::{Message, Offset, TopicPartitionList};
+    use std::time::Duration;
+    use tracing::{debug, error};
+
+    const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Creates a consumer that reads every partition of the account state topic from the start,
+    /// outside of the consumer group, since each instance needs the state of all the others.
+    pub fn state_consumer(settings: &KafkaSettings, topic: &str) -> Result<StreamConsumer> {
+        let consumer = kafka_settings::consumer(settings)?;
+        consumer.unsubscribe();
+        let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+        let partitions = metadata
+            .topics()
+            .iter()
+            .find(|t| t.name() == topic)
+            .map(|t| t.partitions())
+            .ok_or_else(|| anyhow!("Account state topic {} not found", topic))?;
+        let mut assignment = TopicPartitionList::new();
+        for partition in partitions {
+            assignment.add_partition_offset(topic, partition.id(), Offset::Beginning)?;
+        }
+        consumer.assign(&assignment)?;
+        Ok(consumer)
+    }
+
+    /// Hands the states published by every instance to the main loop.
+    pub async fn follow(consumer: StreamConsumer, codec: Codec, admin: AdminSender) {
+        loop {
+            let message = match consumer.recv().await {
+                Ok(message) => message,
+                Err(e) => {
+                    error!(?e, "Failed to read account state");
+                    continue;
+                }
+            };
+            // Deleted states of instances that are gone have no payload
+            let payload = match message.payload() {
+                Some(payload) => payload,
+                None => continue,
+            };
+            match codec.decode::<ShardState>(payload).await {
+                Ok(state) => {
+                    debug!(instance = %state.instance_id, "Account state received");
+                    if admin.send(AdminCommand::PeerState(state)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => error!(?e, "Failed to decode account state"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn peer(instance_id: &str, tickers: &[&str], long: i64) -> ShardState {
+        ShardState {
+            instance_id: instance_id.into(),
+            tickers: tickers.iter().map(|t| t.to_string()).collect(),
+            cash_flow: Decimal::ZERO,
+            long_market_exposure: Decimal::new(long, 0),
+            short_market_exposure: Decimal::ZERO,
+            initial_margin: Decimal::ZERO,
+            maintenance_margin: Decimal::ZERO,
+            reserved_buying_power: Decimal::ZERO,
+            delta_exposures: None,
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn ownership() {
+        let mut shard = Shard::new("a".into(), Decimal::ZERO);
+        assert!(shard.claim("AAPL"));
+        assert!(!shard.claim("AAPL"));
+        shard.update_peer(peer("b", &["MSFT"], 100));
+        shard.update_peer(peer("c", &["TSLA", "AAPL"], 200));
+        // Our own state coming back from the topic is ignored
+        shard.update_peer(peer("a", &["AAPL"], 1000));
+        assert!(shard.is_peer_owned("MSFT"));
+        assert!(!shard.owns("AAPL"));
+        assert_eq!(
+            shard.peer_total(|s| s.long_market_exposure),
+            Decimal::new(300, 0)
+        );
+        // AAPL moves back to us
+        assert!(shard.claim("AAPL"));
+        assert!(!shard.is_peer_owned("AAPL"));
+        assert!(shard.is_peer_owned("TSLA"));
+    }
+
+    #[test]
+    fn unsharded() {
+        let mut shard = Shard::default();
+        assert!(!shard.claim("AAPL"));
+        assert!(!shard.is_peer_owned("AAPL"));
+    }
+}
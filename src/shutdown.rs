@@ -0,0 +1,99 @@
+use crate::calendar::exchange_date;
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use serde::Deserialize;
+
+/// When the service stops on its own, besides being terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub enum ShutdownPolicy {
+    /// Keep running until terminated
+    Never,
+    /// Shut down when the market closes for the day, with the next open on a later trading day,
+    /// rather than between sessions of the same day
+    AfterClose,
+    /// Shut down at a fixed time of day, in UTC
+    At { time: NaiveTime },
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        Self::AfterClose
+    }
+}
+
+impl ShutdownPolicy {
+    /// The configured policy, or else shutting down after the close unless something trades
+    /// around the clock.
+    pub fn resolve(configured: Option<Self>, around_the_clock: bool) -> Self {
+        match configured {
+            Some(policy) => policy,
+            None if around_the_clock => Self::Never,
+            None => Self::default(),
+        }
+    }
+
+    /// Whether to shut down when the market closes at `now` with the next open `next_open`
+    /// seconds away.
+    pub fn at_close(&self, now: DateTime<Utc>, next_open: usize) -> bool {
+        match self {
+            Self::AfterClose => {
+                let opens_at = now + Duration::seconds(next_open as i64);
+                exchange_date(opens_at) > exchange_date(now)
+            }
+            Self::Never | Self::At { .. } => false,
+        }
+    }
+
+    /// Time left from `now` until a fixed-time shutdown.
+    pub fn remaining(&self, now: DateTime<Utc>) -> Option<std::time::Duration> {
+        let time = match self {
+            Self::At { time } => *time,
+            Self::Never | Self::AfterClose => return None,
+        };
+        let mut at = now.date().and_time(time)?;
+        if at <= now {
+            at = at + Duration::days(1);
+        }
+        (at - now).to_std().ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn shutdown_policy() {
+        let policy = ShutdownPolicy::resolve(None, false);
+        // The close at 4pm in New York, with the next open at 9:30am the next day
+        let close = Utc.ymd(2021, 6, 1).and_hms(20, 0, 0);
+        assert!(policy.at_close(close, 17 * 3600 + 1800));
+        // The close of a half day, with the next open 20 and a half hours away
+        let half_day = Utc.ymd(2021, 11, 26).and_hms(18, 0, 0);
+        assert!(policy.at_close(half_day, 68 * 3600 + 1800));
+        // The pre-market close, with the regular session opening the same day
+        let pre_market = Utc.ymd(2021, 6, 1).and_hms(13, 0, 0);
+        assert!(!policy.at_close(pre_market, 1800));
+        assert!(!ShutdownPolicy::resolve(None, true).at_close(close, 17 * 3600 + 1800));
+
+        let after_close: ShutdownPolicy =
+            serde_json::from_str(r#"{"policy":"after_close"}"#).unwrap();
+        assert_eq!(after_close, ShutdownPolicy::AfterClose);
+        assert!(after_close.remaining(Utc::now()).is_none());
+
+        let at: ShutdownPolicy =
+            serde_json::from_str(r#"{"policy":"at","time":"21:00:00"}"#).unwrap();
+        assert!(!at.at_close(close, 17 * 3600 + 1800));
+        let now = Utc.ymd(2021, 6, 1).and_hms(20, 30, 0);
+        assert_eq!(
+            at.remaining(now),
+            Some(std::time::Duration::from_secs(30 * 60))
+        );
+        let now = Utc.ymd(2021, 6, 1).and_hms(22, 0, 0);
+        assert_eq!(
+            at.remaining(now),
+            Some(std::time::Duration::from_secs(23 * 3600))
+        );
+    }
+}
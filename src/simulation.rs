@@ -0,0 +1,270 @@
+//! Replays a recorded day of lots, prices and intents through the risk manager, to see how a
+//! change of limits or rules would have decided.
+//!
+//! ```ignore
+//! let risk_manager = RiskManager::builder()
+//!     .holdings(starting_holdings)
+//!     .limits(proposed_limits)
+//!     .build()
+//!     .await?;
+//! let events = simulation::read_events(BufReader::new(File::open("2021-06-04.jsonl")?))?;
+//! let report = Simulation::new(risk_manager, start).run(events);
+//! println!("{:?}", report.summary());
+//! ```
+use crate::clock::ManualClock;
+use crate::error::Result;
+use crate::input::{Lot, RequestOptions};
+use crate::json_lines::read_json_lines;
+use crate::prices::PriceTick;
+use crate::report::RiskReport;
+use crate::{Price, RiskCheckResponse, RiskManager};
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::BufRead;
+use trading_base::TradeIntent;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordedInput {
+    Lot(Lot),
+    Price { ticker: String, price: Decimal },
+    Intent(TradeIntent),
+}
+
+/// An input as it was received, at the time it was received.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RecordedEvent {
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub input: RecordedInput,
+}
+
+/// Reads events from JSON lines, skipping blank lines.
+pub fn read_events(reader: impl BufRead) -> Result<Vec<RecordedEvent>> {
+    read_json_lines(reader)
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct DecisionRecord {
+    pub at: DateTime<Utc>,
+    pub intent_id: Uuid,
+    /// Missing if the intent couldn't be evaluated, e.g. for lack of a price
+    pub response: Option<RiskCheckResponse>,
+    pub error: Option<String>,
+}
+
+/// The account after an event.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct PortfolioPoint {
+    pub at: DateTime<Utc>,
+    pub report: RiskReport,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct SimulationSummary {
+    pub granted: usize,
+    pub amended: usize,
+    pub denied: usize,
+    pub errors: usize,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SimulationReport {
+    pub decisions: Vec<DecisionRecord>,
+    pub timeline: Vec<PortfolioPoint>,
+}
+
+impl SimulationSummary {
+    /// Counts a decision, for summaries of decisions that aren't kept.
+    pub fn record(&mut self, decision: &DecisionRecord) {
+        match decision.response {
+            Some(RiskCheckResponse::Granted { .. }) => self.granted += 1,
+            Some(RiskCheckResponse::Amended { .. }) => self.amended += 1,
+            Some(RiskCheckResponse::Denied { .. }) => self.denied += 1,
+            None => self.errors += 1,
+        }
+    }
+}
+
+impl SimulationReport {
+    pub fn summary(&self) -> SimulationSummary {
+        let mut summary = SimulationSummary::default();
+        for decision in self.decisions.iter() {
+            summary.record(decision);
+        }
+        summary
+    }
+}
+
+/// Feeds events to a risk manager on a clock that follows the events, recording its decisions
+/// and the account after every event. Granted intents reserve buying power until their lots
+/// arrive, as they do live.
+pub struct Simulation {
+    risk_manager: RiskManager,
+    clock: ManualClock,
+    /// Last recorded price by ticker, used to price market orders
+    prices: HashMap<String, PriceTick>,
+}
+
+impl Simulation {
+    pub fn new(mut risk_manager: RiskManager, start: DateTime<Utc>) -> Self {
+        let clock = ManualClock::new(start);
+        risk_manager.bind_clock(clock.clone());
+        Self {
+            risk_manager,
+            clock,
+            prices: HashMap::new(),
+        }
+    }
+
+    pub fn run(mut self, events: impl IntoIterator<Item = RecordedEvent>) -> SimulationReport {
+        let mut report = SimulationReport::default();
+        for event in events {
+            let at = event.at;
+            if let Some(decision) = self.step(event) {
+                report.decisions.push(decision);
+            }
+            report.timeline.push(PortfolioPoint {
+                at,
+                report: self.risk_manager.risk_report(),
+            });
+        }
+        report
+    }
+
+    /// Applies a single event, returning the decision if it was an intent.
+    pub fn step(&mut self, event: RecordedEvent) -> Option<DecisionRecord> {
+        self.clock.set(event.at);
+        self.apply(event.at, event.input)
+    }
+
+    /// The risk manager in the state the events left it in.
+    pub fn risk_manager(&self) -> &RiskManager {
+        &self.risk_manager
+    }
+
+    fn apply(&mut self, at: DateTime<Utc>, input: RecordedInput) -> Option<DecisionRecord> {
+        match input {
+            RecordedInput::Lot(lot) => {
+                let tick = PriceTick {
+                    price: lot.price,
+                    timestamp: Some(at),
+                    source: None,
+                };
+                self.prices.insert(lot.ticker.clone(), tick);
+                self.risk_manager.process_lot(lot);
+                None
+            }
+            RecordedInput::Price { ticker, price } => {
+                self.risk_manager.update_price(&ticker, Price(price));
+                self.prices.insert(
+                    ticker,
+                    PriceTick {
+                        price,
+                        timestamp: Some(at),
+                        source: None,
+                    },
+                );
+                None
+            }
+            RecordedInput::Intent(intent) => {
+                let last_price = self.prices.get(&intent.ticker).copied();
+                let result = self.risk_manager.risk_check_priced(
+                    &intent,
+                    RequestOptions::default(),
+                    last_price,
+                );
+                if let Ok(response) = result.as_ref() {
+                    self.risk_manager.record_decision(response);
+                }
+                Some(DecisionRecord {
+                    at,
+                    intent_id: intent.id,
+                    error: result.as_ref().err().map(ToString::to_string),
+                    response: result.ok(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::limits::{RiskLimits, SharedLimits};
+    use chrono::TimeZone;
+    use trading_base::OrderType;
+
+    #[test]
+    fn simulation() {
+        let start = Utc.ymd(2021, 6, 4).and_hms(13, 30, 0);
+        let at = |minutes| start + chrono::Duration::minutes(minutes);
+        let buy = TradeIntent::new("AAPL", 10);
+        let too_big = TradeIntent::new("AAPL", 50).order_type(OrderType::Limit {
+            limit_price: Decimal::new(100, 0),
+        });
+        let lot = Lot {
+            id: Uuid::new_v4(),
+            order_id: buy.id,
+            ticker: "AAPL".into(),
+            fill_time: at(2),
+            price: Decimal::new(100, 0),
+            shares: Decimal::new(10, 0),
+        };
+        let events = [
+            RecordedEvent {
+                at: at(0),
+                input: RecordedInput::Price {
+                    ticker: "AAPL".into(),
+                    price: Decimal::new(100, 0),
+                },
+            },
+            RecordedEvent {
+                at: at(1),
+                input: RecordedInput::Intent(buy.clone()),
+            },
+            RecordedEvent {
+                at: at(2),
+                input: RecordedInput::Lot(lot),
+            },
+            RecordedEvent {
+                at: at(3),
+                input: RecordedInput::Intent(too_big),
+            },
+        ];
+        // Recorded events are read back from JSON lines
+        let lines: Vec<String> = events
+            .iter()
+            .map(|event| serde_json::to_string(event).unwrap())
+            .collect();
+        let events = read_events(lines.join("\n").as_bytes()).unwrap();
+
+        let mut risk_manager = RiskManager::default();
+        risk_manager.update_cash(Decimal::new(5000, 0));
+        risk_manager.bind_limits(SharedLimits::new(RiskLimits {
+            max_notional: Some(Decimal::new(2000, 0)),
+            ..Default::default()
+        }));
+        let report = Simulation::new(risk_manager, start).run(events);
+        assert_eq!(
+            report.summary(),
+            SimulationSummary {
+                granted: 1,
+                denied: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(report.decisions[0].intent_id, buy.id);
+        assert_eq!(report.timeline.len(), 4);
+        let after_fill = &report.timeline[2];
+        assert_eq!(after_fill.at, at(2));
+        assert_eq!(
+            after_fill.report.long_market_exposure,
+            Decimal::new(1000, 0)
+        );
+        assert_eq!(after_fill.report.reserved_buying_power, Decimal::ZERO);
+    }
+}
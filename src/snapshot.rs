@@ -0,0 +1,97 @@
+use crate::codec::Producer;
+use crate::{PortfolioSnapshot, RiskManager};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+/// Why a snapshot was published.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotTrigger {
+    Interval,
+    MarketOpen,
+    MarketClose,
+}
+
+/// Risk figures of the whole portfolio at a point in time, for dashboards.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct RiskSnapshot {
+    pub trigger: SnapshotTrigger,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub portfolio: PortfolioSnapshot,
+}
+
+impl RiskManager {
+    pub fn risk_snapshot(&self, trigger: SnapshotTrigger) -> RiskSnapshot {
+        RiskSnapshot {
+            trigger,
+            timestamp: Utc::now(),
+            portfolio: self.portfolio_snapshot(),
+        }
+    }
+}
+
+pub struct Snapshots<'a> {
+    producer: &'a Producer,
+    topic: &'a str,
+    market_open: Option<bool>,
+}
+
+impl<'a> Snapshots<'a> {
+    pub fn new(producer: &'a Producer, topic: &'a str) -> Self {
+        Self {
+            producer,
+            topic,
+            market_open: None,
+        }
+    }
+
+    /// Publishes a snapshot. Failures are logged rather than returned, since dashboards missing a
+    /// snapshot shouldn't stop risk checks.
+    pub async fn publish(&self, trigger: SnapshotTrigger, risk_manager: &RiskManager) {
+        let snapshot = risk_manager.risk_snapshot(trigger);
+        debug!(?trigger, "Publishing risk snapshot");
+        if let Err(e) = self
+            .producer
+            .send(self.topic, "risk-manager", &snapshot, None)
+            .await
+        {
+            error!(?e, "Failed to publish risk snapshot");
+        }
+    }
+
+    /// Publishes a snapshot if the market opened or closed since the last market state.
+    pub async fn market_state(&mut self, open: bool, risk_manager: &RiskManager) {
+        if self.market_open.replace(open) != Some(open) {
+            let trigger = if open {
+                SnapshotTrigger::MarketOpen
+            } else {
+                SnapshotTrigger::MarketClose
+            };
+            self.publish(trigger, risk_manager).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Price, Shares};
+    use rust_decimal::Decimal;
+
+    #[test]
+    fn risk_snapshot() {
+        let mut manager = RiskManager::default();
+        manager.update_cash(Decimal::new(1000, 0));
+        manager.update_holdings("AAPL", Shares(Decimal::ONE), Price(Decimal::new(100, 0)));
+        let snapshot = manager.risk_snapshot(SnapshotTrigger::MarketOpen);
+        assert_eq!(snapshot.portfolio.report.equity, Decimal::new(1000, 0));
+
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["trigger"], "market_open");
+        assert_eq!(json["positions"][0]["ticker"], "AAPL");
+        let deserialized: RiskSnapshot = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized, snapshot);
+    }
+}
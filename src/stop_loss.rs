@@ -0,0 +1,132 @@
+use crate::report::{Position, PositionSide};
+use crate::settings::StopLossSettings;
+use rust_decimal::Decimal;
+use std::collections::HashSet;
+use tracing::warn;
+use trading_base::TradeIntent;
+
+/// Watches the marks of positions against their stop losses, and closes the positions that
+/// breach them.
+pub struct StopLossMonitor {
+    settings: StopLossSettings,
+    /// Positions that breached their stop at the last check. Each breach is only closed once, so
+    /// the position has to recover or be closed before it can trigger again.
+    breached: HashSet<String>,
+}
+
+impl StopLossMonitor {
+    pub fn new(settings: StopLossSettings) -> Self {
+        Self {
+            settings,
+            breached: HashSet::new(),
+        }
+    }
+
+    fn stop(&self, ticker: &str) -> Option<Decimal> {
+        self.settings
+            .stops
+            .get(ticker)
+            .copied()
+            .or(self.settings.default_stop)
+    }
+
+    /// Loss of a position as a fraction of its cost.
+    fn loss(position: &Position) -> Option<Decimal> {
+        if position.avg_cost.is_zero() {
+            return None;
+        }
+        let loss = match position.side {
+            PositionSide::Long => position.avg_cost - position.market_price,
+            PositionSide::Short => position.market_price - position.avg_cost,
+            PositionSide::Flat => return None,
+        };
+        Some(loss / position.avg_cost)
+    }
+
+    /// Closing market orders for the positions that breached their stop since the last check.
+    pub fn check(&mut self, positions: impl IntoIterator<Item = Position>) -> Vec<TradeIntent> {
+        let mut breached = HashSet::new();
+        let mut intents = Vec::new();
+        for position in positions {
+            let (stop, loss) = match (self.stop(&position.ticker), Self::loss(&position)) {
+                (Some(stop), Some(loss)) => (stop, loss),
+                _ => continue,
+            };
+            if loss < stop {
+                continue;
+            }
+            breached.insert(position.ticker.clone());
+            if self.breached.contains(&position.ticker) {
+                continue;
+            }
+            let intent = match position.closing_intent() {
+                Some(intent) => intent,
+                None => continue,
+            };
+            warn!(
+                ticker = %position.ticker,
+                %loss,
+                %stop,
+                market_price = %position.market_price,
+                "Stop loss breached, closing position"
+            );
+            intents.push(intent);
+        }
+        self.breached = breached;
+        intents
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn position(ticker: &str, shares: i64, avg_cost: i64, market_price: i64) -> Position {
+        let shares = Decimal::new(shares, 0);
+        let market_value = shares * Decimal::new(market_price, 0);
+        Position {
+            ticker: ticker.into(),
+            shares,
+            avg_cost: Decimal::new(avg_cost, 0),
+            market_price: Decimal::new(market_price, 0),
+            market_value,
+            unrealized_pnl: market_value - shares * Decimal::new(avg_cost, 0),
+            realized_pnl: Decimal::ZERO,
+            side: PositionSide::of(shares),
+        }
+    }
+
+    #[test]
+    fn stop_loss() {
+        let mut stops = HashMap::new();
+        stops.insert("TSLA".to_string(), Decimal::new(2, 1));
+        let mut monitor = StopLossMonitor::new(StopLossSettings {
+            default_stop: Some(Decimal::new(1, 1)),
+            stops,
+        });
+        // Long AAPL is 10% down, short TSLA is 15% up against a 20% stop
+        let intents = monitor.check(vec![
+            position("AAPL", 10, 100, 90),
+            position("TSLA", -5, 100, 115),
+        ]);
+        assert_eq!(intents.len(), 1);
+        assert_eq!(intents[0].ticker, "AAPL");
+        assert_eq!(intents[0].qty, -10);
+
+        // Each breach is only closed once
+        let intents = monitor.check(vec![
+            position("AAPL", 10, 100, 85),
+            position("TSLA", -5, 100, 120),
+        ]);
+        assert_eq!(intents.len(), 1);
+        assert_eq!(intents[0].ticker, "TSLA");
+        assert_eq!(intents[0].qty, 5);
+
+        // Recovering re-arms the stop
+        assert!(monitor
+            .check(vec![position("AAPL", 10, 100, 95)])
+            .is_empty());
+        assert_eq!(monitor.check(vec![position("AAPL", 10, 100, 90)]).len(), 1);
+    }
+}
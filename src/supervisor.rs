@@ -0,0 +1,137 @@
+use crate::error::RiskManagerError;
+use crate::settings::SupervisorSettings;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use std::time::Duration;
+
+/// Kafka errors that retrying won't fix, because the configuration or credentials are wrong.
+const FATAL_KAFKA_ERRORS: [RDKafkaErrorCode; 7] = [
+    RDKafkaErrorCode::Fatal,
+    RDKafkaErrorCode::Authentication,
+    RDKafkaErrorCode::SaslAuthenticationFailed,
+    RDKafkaErrorCode::TopicAuthorizationFailed,
+    RDKafkaErrorCode::GroupAuthorizationFailed,
+    RDKafkaErrorCode::ClusterAuthorizationFailed,
+    RDKafkaErrorCode::InvalidArgument,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The run loop can be restarted, e.g. after a broker hiccup or a message we couldn't decode
+    Transient,
+    /// The service has to stop
+    Fatal,
+}
+
+/// Decides whether an error that ended the run loop is worth restarting it for. Anything that
+/// isn't known to be fatal is treated as transient.
+pub fn classify(error: &anyhow::Error) -> ErrorClass {
+    let misconfigured = error.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<RiskManagerError>(),
+            Some(RiskManagerError::Configuration(_))
+        )
+    });
+    if misconfigured {
+        return ErrorClass::Fatal;
+    }
+    let kafka_error = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<KafkaError>());
+    match kafka_error.and_then(KafkaError::rdkafka_error_code) {
+        Some(code) if FATAL_KAFKA_ERRORS.contains(&code) => ErrorClass::Fatal,
+        _ => ErrorClass::Transient,
+    }
+}
+
+/// Exponential backoff between restarts.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(settings: &SupervisorSettings) -> Self {
+        Self::with_bounds(
+            Duration::from_millis(settings.initial_backoff_ms),
+            Duration::from_millis(settings.max_backoff_ms),
+        )
+    }
+
+    pub fn with_bounds(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            next: initial,
+        }
+    }
+
+    /// Returns the delay before the next attempt, doubling the one after.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// Like `next_delay`, but picks a random delay between half and all of it, so that callers
+    /// failing at the same time don't retry in lockstep.
+    pub fn next_jittered_delay(&mut self) -> Duration {
+        let delay = self.next_delay();
+        delay / 2 + (delay / 2).mul_f64(rand::random())
+    }
+
+    pub fn reset(&mut self) {
+        self.next = self.initial
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn classification() {
+        let fatal = Err::<(), _>(KafkaError::MessageConsumption(
+            RDKafkaErrorCode::TopicAuthorizationFailed,
+        ))
+        .context("Failed to receive message")
+        .unwrap_err();
+        assert_eq!(classify(&fatal), ErrorClass::Fatal);
+        let transient = anyhow::Error::new(KafkaError::MessageConsumption(
+            RDKafkaErrorCode::BrokerTransportFailure,
+        ));
+        assert_eq!(classify(&transient), ErrorClass::Transient);
+        let decoding = anyhow::Error::new(serde_json::from_str::<u8>("").unwrap_err());
+        assert_eq!(classify(&decoding), ErrorClass::Transient);
+        let misconfigured = anyhow::Error::new(RiskManagerError::Configuration(
+            "Alpaca client not initialized".into(),
+        ));
+        assert_eq!(classify(&misconfigured), ErrorClass::Fatal);
+    }
+
+    #[test]
+    fn backoff() {
+        let mut backoff = Backoff::new(&SupervisorSettings {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 300,
+        });
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(200));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(300));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(300));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter() {
+        let mut backoff =
+            Backoff::with_bounds(Duration::from_millis(100), Duration::from_millis(400));
+        for expected in [100, 200, 400, 400].iter() {
+            let delay = backoff.next_jittered_delay();
+            assert!(delay >= Duration::from_millis(expected / 2));
+            assert!(delay <= Duration::from_millis(*expected));
+        }
+    }
+}
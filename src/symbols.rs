@@ -0,0 +1,197 @@
+use crate::report::Position;
+use anyhow::Result;
+use config::{Config, File};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use tracing::info;
+
+const UNKNOWN: &str = "unknown";
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SymbolInfo {
+    pub sector: Option<String>,
+    pub asset_class: Option<String>,
+    /// Shares traded on an average day
+    pub avg_daily_volume: Option<Decimal>,
+    /// Daily leverage of leveraged and inverse ETFs, e.g. 3 for TQQQ and -3 for SQQQ
+    pub leverage: Option<Decimal>,
+}
+
+/// Sector, asset class and liquidity of each ticker. Tickers that aren't listed are reported as `unknown`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SymbolMetadata {
+    symbols: HashMap<String, SymbolInfo>,
+}
+
+#[derive(Default)]
+struct Exposure {
+    long: Decimal,
+    short: Decimal,
+    gross: Decimal,
+}
+
+impl SymbolMetadata {
+    pub fn new(symbols: HashMap<String, SymbolInfo>) -> Self {
+        // Config lowercases keys, so tickers are looked up case-insensitively
+        let symbols = symbols
+            .into_iter()
+            .map(|(ticker, info)| (ticker.to_uppercase(), info))
+            .collect();
+        Self { symbols }
+    }
+
+    /// Reads the mapping from a TOML, YAML or JSON file keyed by ticker, depending on its
+    /// extension.
+    pub fn load(path: &str) -> Result<Self> {
+        let mut c = Config::new();
+        c.merge(File::with_name(path))?;
+        let metadata = Self::new(c.try_into()?);
+        info!(
+            path,
+            symbols = metadata.symbols.len(),
+            "Loaded symbol metadata"
+        );
+        Ok(metadata)
+    }
+
+    pub fn sector(&self, ticker: &str) -> &str {
+        self.symbols
+            .get(&ticker.to_uppercase())
+            .and_then(|info| info.sector.as_deref())
+            .unwrap_or(UNKNOWN)
+    }
+
+    pub fn asset_class(&self, ticker: &str) -> &str {
+        self.symbols
+            .get(&ticker.to_uppercase())
+            .and_then(|info| info.asset_class.as_deref())
+            .unwrap_or(UNKNOWN)
+    }
+
+    pub fn avg_daily_volume(&self, ticker: &str) -> Option<Decimal> {
+        self.symbols
+            .get(&ticker.to_uppercase())
+            .and_then(|info| info.avg_daily_volume)
+    }
+
+    /// Tickers of an asset class.
+    pub fn of_asset_class<'a>(&'a self, asset_class: &'a str) -> impl Iterator<Item = &'a str> {
+        self.symbols
+            .iter()
+            .filter(move |(_, info)| info.asset_class.as_deref() == Some(asset_class))
+            .map(|(ticker, _)| ticker.as_str())
+    }
+
+    /// Tickers of leveraged and inverse ETFs with their leverage.
+    pub fn leveraged(&self) -> impl Iterator<Item = (&str, Decimal)> {
+        self.symbols
+            .iter()
+            .filter_map(|(ticker, info)| Some((ticker.as_str(), info.leverage?)))
+    }
+
+    fn exposures<'a>(
+        &'a self,
+        positions: &[Position],
+        group: impl Fn(&'a Self, &str) -> &'a str,
+    ) -> BTreeMap<&'a str, Exposure> {
+        let mut exposures: BTreeMap<&str, Exposure> = BTreeMap::new();
+        for position in positions {
+            let exposure = exposures.entry(group(self, &position.ticker)).or_default();
+            if position.market_value.is_sign_negative() {
+                exposure.short -= position.market_value
+            } else {
+                exposure.long += position.market_value
+            }
+            exposure.gross += position.market_value.abs();
+        }
+        exposures
+    }
+
+    /// Renders long, short and gross exposure per sector and asset class in the Prometheus text
+    /// exposition format.
+    pub fn render_metrics(&self, positions: &[Position]) -> String {
+        let mut metrics = String::new();
+        let groups = [
+            ("sector", self.exposures(positions, Self::sector)),
+            ("asset_class", self.exposures(positions, Self::asset_class)),
+        ];
+        for (label, exposures) in groups.iter() {
+            let _ = writeln!(
+                metrics,
+                "# HELP risk_manager_{}_exposure Market exposure by {}",
+                label,
+                label.replace('_', " ")
+            );
+            let _ = writeln!(metrics, "# TYPE risk_manager_{}_exposure gauge", label);
+            for (group, exposure) in exposures.iter() {
+                let sides = [
+                    ("long", exposure.long),
+                    ("short", exposure.short),
+                    ("gross", exposure.gross),
+                ];
+                for (side, value) in sides.iter() {
+                    let _ = writeln!(
+                        metrics,
+                        "risk_manager_{}_exposure{{{}=\"{}\",side=\"{}\"}} {}",
+                        label, label, group, side, value
+                    );
+                }
+            }
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::report::PositionSide;
+
+    fn position(ticker: &str, market_value: i64) -> Position {
+        let shares = Decimal::new(market_value.signum(), 0);
+        Position {
+            ticker: ticker.into(),
+            shares,
+            avg_cost: Decimal::new(market_value.abs(), 0),
+            market_price: Decimal::new(market_value.abs(), 0),
+            market_value: Decimal::new(market_value, 0),
+            unrealized_pnl: Decimal::ZERO,
+            realized_pnl: Decimal::ZERO,
+            side: PositionSide::of(shares),
+        }
+    }
+
+    #[test]
+    fn render_metrics() {
+        let mut symbols = HashMap::new();
+        let technology = SymbolInfo {
+            sector: Some("technology".into()),
+            asset_class: Some("equity".into()),
+            avg_daily_volume: None,
+        };
+        symbols.insert("aapl".to_string(), technology.clone());
+        symbols.insert("MSFT".to_string(), technology);
+        let metadata = SymbolMetadata::new(symbols);
+        assert_eq!(metadata.sector("AAPL"), "technology");
+        assert_eq!(metadata.asset_class("SPY"), "unknown");
+
+        let positions = vec![
+            position("AAPL", 300),
+            position("MSFT", -100),
+            position("SPY", 50),
+        ];
+        let metrics = metadata.render_metrics(&positions);
+        assert!(metrics
+            .contains("risk_manager_sector_exposure{sector=\"technology\",side=\"long\"} 300"));
+        assert!(metrics
+            .contains("risk_manager_sector_exposure{sector=\"technology\",side=\"short\"} 100"));
+        assert!(metrics
+            .contains("risk_manager_sector_exposure{sector=\"technology\",side=\"gross\"} 400"));
+        assert!(metrics.contains(
+            "risk_manager_asset_class_exposure{asset_class=\"unknown\",side=\"gross\"} 50"
+        ));
+    }
+}
@@ -0,0 +1,230 @@
+use crate::input::Lot;
+use chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use uuid::Uuid;
+
+/// How many of the latest closes are kept for reporting
+const RECENT_CLOSES: usize = 1_000;
+
+/// Shares bought, or sold short, by a single fill that haven't been closed yet.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct TaxLot {
+    /// Id of the fill that opened the lot. Positions held before fills were tracked are a single
+    /// lot with a nil id.
+    pub lot_id: Uuid,
+    pub opened_at: DateTime<Utc>,
+    /// Negative for short lots
+    pub shares: Decimal,
+    pub price: Decimal,
+}
+
+/// Shares of an open lot closed by a later fill, and the profit or loss they realized.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ClosedLot {
+    pub ticker: String,
+    pub opening_lot: Uuid,
+    pub closing_lot: Uuid,
+    pub opened_at: DateTime<Utc>,
+    pub closed_at: DateTime<Utc>,
+    /// Negative when a short lot was covered
+    pub shares: Decimal,
+    pub open_price: Decimal,
+    pub close_price: Decimal,
+    pub realized_pnl: Decimal,
+}
+
+/// The open lots of every position, closed first in, first out.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct TaxLots {
+    open: HashMap<String, VecDeque<TaxLot>>,
+    /// Realized PnL by ticker, for the session of the latest closing fill. Starts over with the
+    /// first close of every session.
+    realized: BTreeMap<NaiveDate, HashMap<String, Decimal>>,
+    /// The latest closes, most recent last
+    closes: VecDeque<ClosedLot>,
+}
+
+impl TaxLots {
+    /// Lots for positions whose fills aren't known, one per position at its average cost.
+    pub fn seeded<'a>(
+        positions: impl IntoIterator<Item = (&'a String, Decimal, Decimal)>,
+        at: DateTime<Utc>,
+    ) -> Self {
+        let open = positions
+            .into_iter()
+            .filter(|(_, shares, _)| !shares.is_zero())
+            .map(|(ticker, shares, price)| {
+                let lot = TaxLot {
+                    lot_id: Uuid::nil(),
+                    opened_at: at,
+                    shares,
+                    price,
+                };
+                (ticker.clone(), vec![lot].into())
+            })
+            .collect();
+        Self {
+            open,
+            ..Default::default()
+        }
+    }
+
+    /// Closes the oldest lots on the other side of a fill, opening a new lot with whatever is left
+    /// of it. The PnL realized, scaled by the contract `multiplier` of options, is booked to the
+    /// trading session `day`. Returns the closes.
+    pub fn record(&mut self, fill: &Lot, multiplier: Decimal, day: NaiveDate) -> Vec<ClosedLot> {
+        let mut closes = Vec::new();
+        let mut remaining = fill.shares;
+        let lots = self.open.entry(fill.ticker.clone()).or_default();
+        while !remaining.is_zero() {
+            let oldest = match lots.front_mut() {
+                Some(lot) if lot.shares.is_sign_positive() != remaining.is_sign_positive() => lot,
+                _ => break,
+            };
+            let shares = if oldest.shares.abs() <= remaining.abs() {
+                oldest.shares
+            } else {
+                -remaining
+            };
+            closes.push(ClosedLot {
+                ticker: fill.ticker.clone(),
+                opening_lot: oldest.lot_id,
+                closing_lot: fill.id,
+                opened_at: oldest.opened_at,
+                closed_at: fill.fill_time,
+                shares,
+                open_price: oldest.price,
+                close_price: fill.price,
+                realized_pnl: shares * (fill.price - oldest.price) * multiplier,
+            });
+            oldest.shares -= shares;
+            remaining += shares;
+            if oldest.shares.is_zero() {
+                lots.pop_front();
+            }
+        }
+        if !remaining.is_zero() {
+            lots.push_back(TaxLot {
+                lot_id: fill.id,
+                opened_at: fill.fill_time,
+                shares: remaining,
+                price: fill.price,
+            })
+        }
+        if lots.is_empty() {
+            self.open.remove(&fill.ticker);
+        }
+        if !closes.is_empty() {
+            self.realized = self.realized.split_off(&day);
+        }
+        for close in closes.iter() {
+            *self
+                .realized
+                .entry(day)
+                .or_default()
+                .entry(fill.ticker.clone())
+                .or_default() += close.realized_pnl;
+            if self.closes.len() == RECENT_CLOSES {
+                self.closes.pop_front();
+            }
+            self.closes.push_back(close.clone());
+        }
+        closes
+    }
+
+    /// Open lots of a ticker, oldest first.
+    pub fn open_lots(&self, ticker: &str) -> impl Iterator<Item = &TaxLot> {
+        self.open.get(ticker).into_iter().flatten()
+    }
+
+    /// Tickers with PnL realized on `day`.
+    pub fn realized_tickers(&self, day: NaiveDate) -> impl Iterator<Item = &String> {
+        self.realized
+            .get(&day)
+            .into_iter()
+            .flat_map(|realized| realized.keys())
+    }
+
+    /// PnL realized by fills on `day`.
+    pub fn realized_pnl(&self, day: NaiveDate) -> Decimal {
+        self.realized
+            .get(&day)
+            .map(|realized| realized.values().sum())
+            .unwrap_or_default()
+    }
+
+    /// PnL realized by fills of `ticker` on `day`.
+    pub fn realized_pnl_of(&self, ticker: &str, day: NaiveDate) -> Decimal {
+        self.realized
+            .get(&day)
+            .and_then(|realized| realized.get(ticker))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The latest closes, most recent last.
+    pub fn recent_closes(&self) -> impl Iterator<Item = &ClosedLot> {
+        self.closes.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn fill(shares: i64, price: i64, hour: u32) -> Lot {
+        Lot {
+            id: Uuid::new_v4(),
+            order_id: Uuid::new_v4(),
+            ticker: "AAPL".into(),
+            fill_time: Utc.ymd(2021, 6, 1).and_hms(hour, 0, 0),
+            price: Decimal::new(price, 0),
+            shares: Decimal::new(shares, 0),
+        }
+    }
+
+    #[test]
+    fn fifo() {
+        let day = Utc.ymd(2021, 6, 1).naive_utc();
+        let mut lots = TaxLots::default();
+        let first = fill(10, 100, 14);
+        let second = fill(10, 110, 15);
+        assert!(lots.record(&first, Decimal::ONE, day).is_empty());
+        assert!(lots.record(&second, Decimal::ONE, day).is_empty());
+
+        // Closes the oldest lot and part of the next one
+        let closes = lots.record(&fill(-15, 120, 16), Decimal::ONE, day);
+        assert_eq!(closes.len(), 2);
+        assert_eq!(closes[0].opening_lot, first.id);
+        assert_eq!(closes[0].realized_pnl, Decimal::new(200, 0));
+        assert_eq!(closes[1].opening_lot, second.id);
+        assert_eq!(closes[1].shares, Decimal::new(5, 0));
+        assert_eq!(closes[1].realized_pnl, Decimal::new(50, 0));
+        let open: Vec<_> = lots.open_lots("AAPL").collect();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].shares, Decimal::new(5, 0));
+
+        // Selling more than is held opens a short lot, which loses when covered higher
+        lots.record(&fill(-10, 100, 17), Decimal::ONE, day);
+        let short: Vec<_> = lots.open_lots("AAPL").collect();
+        assert_eq!(short[0].shares, Decimal::new(-5, 0));
+        let closes = lots.record(&fill(5, 104, 18), Decimal::ONE, day);
+        assert_eq!(closes[0].realized_pnl, Decimal::new(-20, 0));
+        assert_eq!(lots.open_lots("AAPL").count(), 0);
+
+        assert_eq!(lots.realized_pnl(day), Decimal::new(180, 0));
+        assert_eq!(lots.realized_pnl_of("AAPL", day), Decimal::new(180, 0));
+        assert_eq!(lots.realized_pnl_of("TSLA", day), Decimal::ZERO);
+        assert_eq!(lots.realized_tickers(day).collect::<Vec<_>>(), vec!["AAPL"]);
+        assert_eq!(lots.recent_closes().count(), 4);
+
+        // The next session's first close starts the day over
+        lots.record(&fill(-5, 100, 19), Decimal::ONE, day.succ());
+        lots.record(&fill(5, 90, 20), Decimal::ONE, day.succ());
+        assert_eq!(lots.realized_pnl(day), Decimal::ZERO);
+        assert_eq!(lots.realized_pnl(day.succ()), Decimal::new(50, 0));
+    }
+}
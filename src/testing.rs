@@ -0,0 +1,175 @@
+//! Builders for testing risk rules, available with the `test-util` feature.
+//!
+//! ```ignore
+//! given_portfolio()
+//!     .with_position("AAPL", 100, 150)
+//!     .with_rule(ChangeInPositionSideRule)
+//!     .when_intent(TradeIntent::new("AAPL", -200))
+//!     .expect_denied(DenyReason::ChangeInPositionSide);
+//! ```
+use crate::fixtures;
+use crate::limits::{RiskLimits, SharedLimits};
+use crate::rules::{Evaluation, PortfolioContext, RiskRule, RiskScore, RuleOutcome, RulePipeline};
+use crate::settings::RiskSettings;
+use crate::{DenyReason, Price, RiskManager, Shares};
+use chrono::{NaiveDate, Utc};
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use trading_base::TradeIntent;
+
+pub fn given_portfolio() -> Portfolio {
+    Portfolio {
+        cash: None,
+        positions: Vec::new(),
+        limits: RiskLimits::default(),
+        rules: None,
+        today: Utc::today().naive_utc(),
+        price: None,
+    }
+}
+
+/// Portfolio and rules an intent is evaluated against. Without any `with_rule` calls, the default
+/// pipeline is used.
+pub struct Portfolio {
+    cash: Option<Decimal>,
+    positions: Vec<(String, Decimal, Decimal)>,
+    limits: RiskLimits,
+    rules: Option<RulePipeline>,
+    today: NaiveDate,
+    price: Option<Decimal>,
+}
+
+impl Portfolio {
+    /// Sets the cash balance after positions have been bought.
+    pub fn with_cash(mut self, cash: impl Into<Decimal>) -> Self {
+        self.cash = Some(cash.into());
+        self
+    }
+
+    pub fn with_position(
+        mut self,
+        ticker: &str,
+        shares: impl Into<Decimal>,
+        price: impl Into<Decimal>,
+    ) -> Self {
+        self.positions
+            .push((ticker.to_string(), shares.into(), price.into()));
+        self
+    }
+
+    pub fn with_limits(mut self, limits: RiskLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_rule(mut self, rule: impl RiskRule + 'static) -> Self {
+        self.rules
+            .get_or_insert_with(RulePipeline::new)
+            .push(Box::new(rule));
+        self
+    }
+
+    pub fn on(mut self, today: NaiveDate) -> Self {
+        self.today = today;
+        self
+    }
+
+    /// Sets the price the intent is valued at, as the risk manager would for an opening intent.
+    pub fn at_price(mut self, price: impl Into<Decimal>) -> Self {
+        self.price = Some(price.into());
+        self
+    }
+
+    pub fn when_intent(self, intent: TradeIntent) -> Scenario {
+        let mut manager = RiskManager::default();
+        for (ticker, shares, price) in self.positions {
+            manager.update_holdings(ticker, Shares(shares), Price(price));
+        }
+        if let Some(cash) = self.cash {
+            manager.update_cash(cash);
+        }
+        manager.bind_limits(SharedLimits::new(self.limits));
+        Scenario {
+            manager,
+            rules: self.rules.unwrap_or_default(),
+            today: self.today,
+            price: self.price,
+            intent,
+        }
+    }
+}
+
+pub struct Scenario {
+    manager: RiskManager,
+    rules: RulePipeline,
+    today: NaiveDate,
+    price: Option<Decimal>,
+    intent: TradeIntent,
+}
+
+impl Scenario {
+    fn context(&self) -> PortfolioContext<'_> {
+        PortfolioContext {
+            manager: &self.manager,
+            today: self.today,
+            price: self.price,
+            price_age: None,
+            reserved: Decimal::ZERO,
+        }
+    }
+
+    pub fn outcome(&self) -> RuleOutcome {
+        self.rules
+            .evaluate(&self.context(), &self.intent, Evaluation::Decision)
+    }
+
+    pub fn score(&self) -> RiskScore {
+        self.rules.score(&self.context(), &self.intent)
+    }
+
+    #[track_caller]
+    pub fn expect(&self, expected: RuleOutcome) {
+        assert_eq!(
+            self.outcome(),
+            expected,
+            "unexpected outcome for {:?} with rules {:?}",
+            self.intent,
+            self.rules.names()
+        );
+    }
+
+    #[track_caller]
+    pub fn expect_passed(&self) {
+        self.expect(RuleOutcome::Pass)
+    }
+
+    #[track_caller]
+    pub fn expect_approved(&self) {
+        self.expect(RuleOutcome::Approve)
+    }
+
+    #[track_caller]
+    pub fn expect_denied(&self, reason: DenyReason) {
+        self.expect(RuleOutcome::Deny(reason))
+    }
+}
+
+/// Replays the decisions recorded in a fixtures file, panicking with every decision that came
+/// out differently.
+pub fn assert_golden(path: impl AsRef<Path>, settings: &RiskSettings) {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .unwrap_or_else(|e| panic!("Failed to open fixtures {}: {}", path.display(), e));
+    let fixtures = fixtures::read_fixtures(BufReader::new(file))
+        .unwrap_or_else(|e| panic!("Failed to read fixtures {}: {}", path.display(), e));
+    let mismatches = fixtures::mismatches(&fixtures, settings);
+    assert!(
+        mismatches.is_empty(),
+        "{} of {} recorded decisions changed: {:#?}",
+        mismatches.len(),
+        fixtures.len(),
+        mismatches
+    );
+}
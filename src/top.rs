@@ -0,0 +1,270 @@
+use crate::events::{Alert, Event, ExposureUpdate};
+use crate::RiskCheckResponse;
+use anyhow::Result;
+use crossterm::event::{self, Event as TermEvent, KeyCode};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use futures::StreamExt;
+use ratatui::backend::{Backend, CrosstermBackend};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, Borders, Cell, Gauge, List, ListItem, Row, Table};
+use ratatui::{Frame, Terminal};
+use rust_decimal::prelude::*;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::{HeaderValue, AUTHORIZATION};
+use tokio_tungstenite::tungstenite::Message;
+
+const MAX_DECISIONS: usize = 100;
+const MAX_ALERTS: usize = 20;
+const REFRESH_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+struct State {
+    connected: bool,
+    exposure: Option<ExposureUpdate>,
+    decisions: VecDeque<RiskCheckResponse>,
+    alerts: VecDeque<Alert>,
+}
+
+impl State {
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Decision(decision) => {
+                self.decisions.push_front(decision);
+                self.decisions.truncate(MAX_DECISIONS);
+            }
+            Event::Exposure(exposure) => self.exposure = Some(exposure),
+            Event::Alert(alert) => {
+                self.alerts.push_front(alert);
+                self.alerts.truncate(MAX_ALERTS);
+            }
+            Event::MarginAlert(alert) => {
+                self.alerts.push_front(Alert::from(&alert));
+                self.alerts.truncate(MAX_ALERTS);
+            }
+        }
+    }
+}
+
+async fn listen(url: String, token: Option<String>, state: Arc<Mutex<State>>) -> Result<()> {
+    let mut request = url.into_client_request()?;
+    if let Some(token) = token {
+        let bearer = HeaderValue::from_str(&format!("Bearer {}", token))?;
+        request.headers_mut().insert(AUTHORIZATION, bearer);
+    }
+    let (mut stream, _) = connect_async(request).await?;
+    state
+        .lock()
+        .expect("Dashboard state lock poisoned")
+        .connected = true;
+    while let Some(msg) = stream.next().await {
+        if let Message::Text(text) = msg? {
+            if let Ok(event) = serde_json::from_str(&text) {
+                state
+                    .lock()
+                    .expect("Dashboard state lock poisoned")
+                    .apply(event)
+            }
+        }
+    }
+    state
+        .lock()
+        .expect("Dashboard state lock poisoned")
+        .connected = false;
+    Ok(())
+}
+
+fn render_exposures<B: Backend>(f: &mut Frame<B>, area: Rect, state: &State) {
+    let title = if state.connected {
+        "Exposures"
+    } else {
+        "Exposures (disconnected)"
+    };
+    let rows = match state.exposure.as_ref() {
+        Some(e) => vec![
+            ("Cash", e.cash),
+            ("Equity", e.equity),
+            ("Long", e.long_market_exposure),
+            ("Short", e.short_market_exposure),
+            ("Gross", e.gross_market_exposure),
+            ("Net", e.net_market_exposure),
+            ("Buying power", e.buying_power),
+        ],
+        None => vec![],
+    };
+    let rows = rows.into_iter().map(|(name, value)| {
+        Row::new(vec![
+            Cell::from(name),
+            Cell::from(value.round_dp(2).to_string()),
+        ])
+    });
+    let table = Table::new(rows)
+        .block(Block::default().title(title).borders(Borders::ALL))
+        .widths(&[Constraint::Length(14), Constraint::Length(20)]);
+    f.render_widget(table, area)
+}
+
+fn render_utilization<B: Backend>(f: &mut Frame<B>, area: Rect, state: &State) {
+    // Share of total capacity (used plus remaining buying power) already deployed
+    let ratio = state
+        .exposure
+        .as_ref()
+        .and_then(|e| {
+            let capacity = e.gross_market_exposure + e.buying_power;
+            if capacity.is_zero() {
+                None
+            } else {
+                (e.gross_market_exposure / capacity).to_f64()
+            }
+        })
+        .unwrap_or(0.0)
+        .clamp(0.0, 1.0);
+    let color = if ratio > 0.9 {
+        Color::Red
+    } else if ratio > 0.7 {
+        Color::Yellow
+    } else {
+        Color::Green
+    };
+    let gauge = Gauge::default()
+        .block(
+            Block::default()
+                .title("Buying power utilization")
+                .borders(Borders::ALL),
+        )
+        .gauge_style(Style::default().fg(color))
+        .ratio(ratio);
+    f.render_widget(gauge, area)
+}
+
+fn render_positions<B: Backend>(f: &mut Frame<B>, area: Rect, state: &State) {
+    let rows = state
+        .exposure
+        .iter()
+        .flat_map(|e| e.positions.iter())
+        .map(|p| {
+            Row::new(vec![
+                Cell::from(p.ticker.clone()),
+                Cell::from(p.shares.to_string()),
+                Cell::from(p.market_price.round_dp(2).to_string()),
+                Cell::from(p.market_value.round_dp(2).to_string()),
+                Cell::from(p.unrealized_pnl.round_dp(2).to_string()),
+            ])
+        });
+    let table = Table::new(rows)
+        .header(Row::new(vec![
+            "Ticker",
+            "Shares",
+            "Price",
+            "Market value",
+            "Unrealized P&L",
+        ]))
+        .block(Block::default().title("Positions").borders(Borders::ALL))
+        .widths(&[
+            Constraint::Length(8),
+            Constraint::Length(12),
+            Constraint::Length(12),
+            Constraint::Length(16),
+            Constraint::Length(16),
+        ]);
+    f.render_widget(table, area)
+}
+
+fn render_decisions<B: Backend>(f: &mut Frame<B>, area: Rect, state: &State) {
+    let items: Vec<ListItem> = state
+        .decisions
+        .iter()
+        .map(|decision| {
+            let intent = decision.intent();
+            match decision {
+                RiskCheckResponse::Granted { .. } => ListItem::new(format!(
+                    "GRANTED {} {} ({})",
+                    intent.ticker, intent.qty, intent.id
+                ))
+                .style(Style::default().fg(Color::Green)),
+                RiskCheckResponse::Denied { reasons, .. } => ListItem::new(format!(
+                    "DENIED  {} {} ({}): {:?}",
+                    intent.ticker, intent.qty, intent.id, reasons
+                ))
+                .style(Style::default().fg(Color::Red)),
+                RiskCheckResponse::Amended { approved_qty, .. } => ListItem::new(format!(
+                    "AMENDED {} {}/{} ({})",
+                    intent.ticker, approved_qty, intent.qty, intent.id
+                ))
+                .style(Style::default().fg(Color::Yellow)),
+            }
+        })
+        .collect();
+    let list = List::new(items).block(Block::default().title("Decisions").borders(Borders::ALL));
+    f.render_widget(list, area)
+}
+
+fn render_alerts<B: Backend>(f: &mut Frame<B>, area: Rect, state: &State) {
+    let items: Vec<ListItem> = state
+        .alerts
+        .iter()
+        .map(|alert| ListItem::new(format!("{:?}: {}", alert.level, alert.message)))
+        .collect();
+    let list = List::new(items).block(Block::default().title("Alerts").borders(Borders::ALL));
+    f.render_widget(list, area)
+}
+
+fn render<B: Backend>(f: &mut Frame<B>, state: &State) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(11),
+            Constraint::Percentage(50),
+            Constraint::Min(5),
+        ])
+        .split(f.size());
+    let top = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+        .split(rows[0]);
+    let top_right = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(3)])
+        .split(top[1]);
+    render_exposures(f, top[0], state);
+    render_utilization(f, top_right[0], state);
+    render_alerts(f, top_right[1], state);
+    render_positions(f, rows[1], state);
+    render_decisions(f, rows[2], state);
+}
+
+/// Renders a live dashboard of the risk manager listening at `url` until `q` is pressed. The
+/// event stream needs the admin `token`.
+pub async fn run(url: String, token: Option<String>) -> Result<()> {
+    let state = Arc::new(Mutex::new(State::default()));
+    let listener = tokio::spawn(listen(url, token, state.clone()));
+
+    enable_raw_mode()?;
+    let mut stdout = std::io::stdout();
+    execute!(stdout, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+    // Terminal input is blocking, so keep it off the async worker threads
+    let result = tokio::task::block_in_place(|| -> Result<()> {
+        loop {
+            terminal.draw(|f| render(f, &state.lock().expect("Dashboard state lock poisoned")))?;
+            if event::poll(REFRESH_INTERVAL)? {
+                if let TermEvent::Key(key) = event::read()? {
+                    if key.code == KeyCode::Char('q') {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    });
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    listener.abort();
+    result
+}
@@ -0,0 +1,416 @@
+use super::{IncomingMessage, MessageHeaders, MessageSink, MessageSource, Transport};
+use crate::offsets::PartitionOffsets;
+use crate::settings::AwsSettings;
+use anyhow::{anyhow, Context, Result};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use rusoto_core::Region;
+use rusoto_sns::{MessageAttributeValue, PublishInput, Sns, SnsClient};
+use rusoto_sqs::{
+    DeleteMessageRequest, GetQueueAttributesRequest, Message, ReceiveMessageRequest, Sqs, SqsClient,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Message attribute carrying the key, which SNS has no field for
+const KEY_ATTRIBUTE: &str = "message-key";
+/// Most messages SQS returns per receive
+const BATCH_SIZE: i64 = 10;
+/// Messages read by the last `REPLAY_CAPACITY` per queue can be sought back to
+const REPLAY_CAPACITY: usize = 1000;
+
+/// Reads the input topics from SQS queues and publishes outputs to SNS topics of the same name.
+pub struct SqsSnsTransport {
+    settings: AwsSettings,
+    region: Region,
+}
+
+impl SqsSnsTransport {
+    pub fn new(settings: AwsSettings) -> Result<Self> {
+        let region = settings
+            .region
+            .parse()
+            .with_context(|| format!("Invalid AWS region {}", settings.region))?;
+        Ok(Self { settings, region })
+    }
+}
+
+impl Transport for SqsSnsTransport {
+    fn source(&self, topics: &[&str]) -> Result<Arc<dyn MessageSource>> {
+        let client = SqsClient::new(self.region.clone());
+        let (sender, receiver) = mpsc::channel(BATCH_SIZE as usize);
+        let queues: Vec<Queue> = topics
+            .iter()
+            .map(|topic| Queue {
+                topic: topic.to_string(),
+                url: format!("{}{}", self.settings.queue_url_prefix, topic),
+            })
+            .collect();
+        for queue in queues.iter() {
+            tokio::spawn(poll(
+                client.clone(),
+                queue.clone(),
+                self.settings.wait_time_secs,
+                sender.clone(),
+            ));
+        }
+        Ok(Arc::new(SqsSource {
+            client,
+            queues,
+            received: tokio::sync::Mutex::new(receiver),
+            state: Mutex::new(ReadState::default()),
+        }))
+    }
+
+    fn sink(&self) -> Result<Arc<dyn MessageSink>> {
+        Ok(Arc::new(SnsSink {
+            client: SnsClient::new(self.region.clone()),
+            topic_arn_prefix: self.settings.topic_arn_prefix.clone(),
+        }))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Queue {
+    topic: String,
+    url: String,
+}
+
+/// A message received from a queue, with the handle to delete it by once it has been handled.
+struct Polled {
+    message: IncomingMessage,
+    receipt_handle: Option<String>,
+}
+
+/// Long-polls a queue, handing its messages to the source in the order they arrive. Offsets are
+/// counted from when polling started, as SQS has none.
+async fn poll(
+    client: SqsClient,
+    queue: Queue,
+    wait_time_secs: i64,
+    messages: mpsc::Sender<Result<Polled>>,
+) {
+    let mut offset = 0;
+    loop {
+        let request = ReceiveMessageRequest {
+            queue_url: queue.url.clone(),
+            max_number_of_messages: Some(BATCH_SIZE),
+            wait_time_seconds: Some(wait_time_secs),
+            message_attribute_names: Some(vec!["All".into()]),
+            ..Default::default()
+        };
+        let received = match client.receive_message(request).await {
+            Ok(result) => result.messages.unwrap_or_default(),
+            Err(e) => {
+                let e =
+                    anyhow::Error::new(e).context(format!("Failed to receive from {}", queue.url));
+                // Handing the error over leaves backing off to the consumer
+                if messages.send(Err(e)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+        };
+        for mut message in received {
+            // Messages stay in the queue until acknowledged. Those that aren't, because the
+            // service stopped before handling them, are received again once their visibility
+            // timeout expires.
+            let receipt_handle = message.receipt_handle.take();
+            let message = incoming(&queue.topic, offset, message);
+            offset += 1;
+            let polled = Polled {
+                message,
+                receipt_handle,
+            };
+            if messages.send(Ok(polled)).await.is_err() {
+                // The source was dropped
+                return;
+            }
+        }
+    }
+}
+
+fn incoming(topic: &str, offset: i64, message: Message) -> IncomingMessage {
+    let mut key = None;
+    let mut headers = MessageHeaders::new();
+    for (name, value) in message.message_attributes.unwrap_or_default() {
+        let value = match (value.binary_value, value.string_value) {
+            (Some(binary), _) => binary.to_vec(),
+            (None, Some(string)) => string.into_bytes(),
+            (None, None) => continue,
+        };
+        if name == KEY_ATTRIBUTE {
+            key = Some(value)
+        } else {
+            headers.push((name, value))
+        }
+    }
+    IncomingMessage {
+        topic: topic.to_string(),
+        partition: 0,
+        offset,
+        key,
+        payload: message.body.map(String::into_bytes),
+        headers,
+    }
+}
+
+#[derive(Default)]
+struct ReadState {
+    /// Messages sought back to, read again before any new message
+    replays: VecDeque<IncomingMessage>,
+    /// The latest messages read, by topic
+    recent: HashMap<String, VecDeque<IncomingMessage>>,
+    /// Offset of the next new message, by topic
+    positions: HashMap<String, i64>,
+    /// Receipt handles of the messages read but not acknowledged yet, by topic and offset
+    handles: HashMap<(String, i64), String>,
+}
+
+struct SqsSource {
+    client: SqsClient,
+    queues: Vec<Queue>,
+    received: tokio::sync::Mutex<mpsc::Receiver<Result<Polled>>>,
+    state: Mutex<ReadState>,
+}
+
+impl MessageSource for SqsSource {
+    fn recv(&self) -> BoxFuture<'_, Result<IncomingMessage>> {
+        Box::pin(async move {
+            {
+                let mut state = self.state.lock().expect("Read state lock poisoned");
+                if let Some(message) = state.replays.pop_front() {
+                    return Ok(message);
+                }
+            }
+            let Polled {
+                message,
+                receipt_handle,
+            } = self
+                .received
+                .lock()
+                .await
+                .recv()
+                .await
+                .ok_or_else(|| anyhow!("Stopped polling SQS"))??;
+            let mut state = self.state.lock().expect("Read state lock poisoned");
+            state
+                .positions
+                .insert(message.topic.clone(), message.offset + 1);
+            if let Some(receipt_handle) = receipt_handle {
+                let read = (message.topic.clone(), message.offset);
+                state.handles.insert(read, receipt_handle);
+            }
+            let recent = state.recent.entry(message.topic.clone()).or_default();
+            if recent.len() == REPLAY_CAPACITY {
+                recent.pop_front();
+            }
+            recent.push_back(message.clone());
+            Ok(message)
+        })
+    }
+
+    /// The queue itself can't be re-read, so the messages are replayed from the latest ones
+    /// read instead.
+    fn seek(&self, topic: &str, partition: i32, offset: i64) -> Result<()> {
+        let mut state = self.state.lock().expect("Read state lock poisoned");
+        let replays: Vec<IncomingMessage> = state
+            .recent
+            .get(topic)
+            .filter(|_| partition == 0)
+            .and_then(|recent| {
+                let start = recent.iter().position(|message| message.offset == offset)?;
+                Some(recent.iter().skip(start).cloned().collect())
+            })
+            .ok_or_else(|| anyhow!("{}/{} at {} can't be read again", topic, partition, offset))?;
+        state.replays.retain(|message| message.topic != topic);
+        state.replays.extend(replays);
+        Ok(())
+    }
+
+    /// Lag is the approximate number of messages waiting in each queue.
+    fn partition_offsets(&self) -> Result<Vec<PartitionOffsets>> {
+        let runtime = tokio::runtime::Handle::current();
+        self.queues
+            .iter()
+            .map(|queue| {
+                let request = GetQueueAttributesRequest {
+                    attribute_names: Some(vec!["ApproximateNumberOfMessages".into()]),
+                    queue_url: queue.url.clone(),
+                };
+                let attributes = runtime
+                    .block_on(self.client.get_queue_attributes(request))?
+                    .attributes
+                    .unwrap_or_default();
+                let waiting = attributes
+                    .get("ApproximateNumberOfMessages")
+                    .and_then(|waiting| waiting.parse().ok())
+                    .unwrap_or_default();
+                let position = {
+                    let state = self.state.lock().expect("Read state lock poisoned");
+                    state
+                        .positions
+                        .get(&queue.topic)
+                        .copied()
+                        .unwrap_or_default()
+                };
+                Ok(PartitionOffsets {
+                    topic: queue.topic.clone(),
+                    partition: 0,
+                    position: Some(position),
+                    committed: Some(position),
+                    high_watermark: position + waiting,
+                    lag: Some(waiting),
+                })
+            })
+            .collect()
+    }
+
+    fn is_assigned(&self) -> Result<bool> {
+        Ok(!self.queues.is_empty())
+    }
+
+    /// Deletes the message from its queue. Messages read again after seeking back were already
+    /// deleted the first time.
+    fn ack<'a>(&'a self, topic: &'a str, partition: i32, offset: i64) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let receipt_handle = {
+                let mut state = self.state.lock().expect("Read state lock poisoned");
+                state.handles.remove(&(topic.to_string(), offset))
+            };
+            let (receipt_handle, queue) = match receipt_handle {
+                Some(receipt_handle) if partition == 0 => {
+                    let queue = self.queues.iter().find(|queue| queue.topic == topic);
+                    (receipt_handle, queue.context("Not reading from the topic")?)
+                }
+                _ => return Ok(()),
+            };
+            let request = DeleteMessageRequest {
+                queue_url: queue.url.clone(),
+                receipt_handle,
+            };
+            self.client
+                .delete_message(request)
+                .await
+                .with_context(|| format!("Failed to delete {} at {}", topic, offset))?;
+            Ok(())
+        })
+    }
+}
+
+struct SnsSink {
+    client: SnsClient,
+    topic_arn_prefix: String,
+}
+
+fn attribute(value: &[u8]) -> MessageAttributeValue {
+    MessageAttributeValue {
+        binary_value: Some(Bytes::copy_from_slice(value)),
+        data_type: "Binary".into(),
+        string_value: None,
+    }
+}
+
+impl MessageSink for SnsSink {
+    fn send<'a>(
+        &'a self,
+        topic: &'a str,
+        key: &'a [u8],
+        payload: &'a [u8],
+        headers: &'a [(String, Vec<u8>)],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let message = String::from_utf8(payload.to_vec())
+                .context("SNS only carries text, payloads must be JSON")?;
+            let topic_arn = format!("{}{}", self.topic_arn_prefix, topic);
+            let mut attributes: HashMap<String, MessageAttributeValue> = headers
+                .iter()
+                .map(|(name, value)| (name.clone(), attribute(value)))
+                .collect();
+            if !key.is_empty() {
+                attributes.insert(KEY_ATTRIBUTE.to_string(), attribute(key));
+            }
+            // FIFO topics keep messages with the same key in order, like a Kafka partition. They
+            // need content-based deduplication enabled.
+            let message_group_id = if !topic_arn.ends_with(".fifo") {
+                None
+            } else if key.is_empty() {
+                Some(topic.to_string())
+            } else {
+                Some(String::from_utf8_lossy(key).into_owned())
+            };
+            let input = PublishInput {
+                message,
+                topic_arn: Some(topic_arn),
+                message_attributes: Some(attributes).filter(|attributes| !attributes.is_empty()),
+                message_group_id,
+                ..Default::default()
+            };
+            self.client
+                .publish(input)
+                .await
+                .with_context(|| format!("Failed to send to {}", topic))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mockito::Matcher;
+    use rusoto_core::credential::StaticProvider;
+    use rusoto_core::HttpClient;
+
+    fn source(received: mpsc::Receiver<Result<Polled>>) -> SqsSource {
+        let region = Region::Custom {
+            name: "us-east-1".into(),
+            endpoint: mockito::server_url(),
+        };
+        let credentials = StaticProvider::new_minimal("key".into(), "secret".into());
+        let client = SqsClient::new_with(HttpClient::new().unwrap(), credentials, region);
+        SqsSource {
+            client,
+            queues: vec![Queue {
+                topic: "risk-intents".into(),
+                url: format!("{}/123456789012/risk-intents", mockito::server_url()),
+            }],
+            received: tokio::sync::Mutex::new(received),
+            state: Mutex::new(ReadState::default()),
+        }
+    }
+
+    #[tokio::test]
+    async fn deleted_once_acknowledged() {
+        let deleted = mockito::mock("POST", "/")
+            .match_body(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("Action".into(), "DeleteMessage".into()),
+                Matcher::UrlEncoded("ReceiptHandle".into(), "first".into()),
+            ]))
+            .with_body("<DeleteMessageResponse/>")
+            .expect(1)
+            .create();
+        let (sender, received) = mpsc::channel(1);
+        let source = source(received);
+        let message = Message {
+            body: Some("{}".into()),
+            ..Default::default()
+        };
+        let message = incoming("risk-intents", 0, message);
+        let polled = Polled {
+            message: message.clone(),
+            receipt_handle: Some("first".into()),
+        };
+        sender.send(Ok(polled)).await.unwrap();
+        assert_eq!(source.recv().await.unwrap(), message);
+        // Reading a message leaves it in the queue
+        assert!(!deleted.matched());
+        source.ack("risk-intents", 0, 0).await.unwrap();
+        // Replays were deleted when first acknowledged
+        source.seek("risk-intents", 0, 0).unwrap();
+        assert_eq!(source.recv().await.unwrap(), message);
+        source.ack("risk-intents", 0, 0).await.unwrap();
+        deleted.assert();
+    }
+}
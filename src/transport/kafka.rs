@@ -0,0 +1,139 @@
+use super::{IncomingMessage, MessageSink, MessageSource, Transport};
+use crate::offsets::PartitionOffsets;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use kafka_settings::KafkaSettings;
+use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::message::{Headers, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::{Message, Offset};
+use std::sync::Arc;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads and sends through the Kafka cluster in the settings, as part of its consumer group.
+#[derive(Clone, Debug)]
+pub struct KafkaTransport {
+    settings: KafkaSettings,
+}
+
+impl KafkaTransport {
+    pub fn new(settings: KafkaSettings) -> Self {
+        Self { settings }
+    }
+}
+
+impl Transport for KafkaTransport {
+    fn source(&self, topics: &[&str]) -> Result<Arc<dyn MessageSource>> {
+        let consumer = kafka_settings::consumer(&self.settings)?;
+        consumer.subscribe(topics)?;
+        Ok(Arc::new(KafkaSource(consumer)))
+    }
+
+    fn sink(&self) -> Result<Arc<dyn MessageSink>> {
+        Ok(Arc::new(KafkaSink(kafka_settings::producer(
+            &self.settings,
+        )?)))
+    }
+}
+
+struct KafkaSource(StreamConsumer);
+
+fn offset(offset: Offset) -> Option<i64> {
+    match offset {
+        Offset::Offset(offset) => Some(offset),
+        _ => None,
+    }
+}
+
+impl MessageSource for KafkaSource {
+    fn recv(&self) -> BoxFuture<'_, Result<IncomingMessage>> {
+        Box::pin(async move {
+            let message = self.0.recv().await?;
+            let headers = message
+                .headers()
+                .map(|headers| {
+                    (0..headers.count())
+                        .filter_map(|idx| headers.get(idx))
+                        .map(|(name, value)| (name.to_string(), value.to_vec()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            Ok(IncomingMessage {
+                topic: message.topic().to_string(),
+                partition: message.partition(),
+                offset: message.offset(),
+                key: message.key().map(<[u8]>::to_vec),
+                payload: message.payload().map(<[u8]>::to_vec),
+                headers,
+            })
+        })
+    }
+
+    fn seek(&self, topic: &str, partition: i32, offset: i64) -> Result<()> {
+        self.0
+            .seek(topic, partition, Offset::Offset(offset), TIMEOUT)?;
+        Ok(())
+    }
+
+    fn partition_offsets(&self) -> Result<Vec<PartitionOffsets>> {
+        let positions = self.0.position()?;
+        let committed = self.0.committed(TIMEOUT)?;
+        positions
+            .elements()
+            .iter()
+            .map(|elem| {
+                let (topic, partition) = (elem.topic(), elem.partition());
+                let (_, high_watermark) = self.0.fetch_watermarks(topic, partition, TIMEOUT)?;
+                let position = offset(elem.offset());
+                Ok(PartitionOffsets {
+                    topic: topic.to_string(),
+                    partition,
+                    position,
+                    committed: committed
+                        .find_partition(topic, partition)
+                        .and_then(|elem| offset(elem.offset())),
+                    high_watermark,
+                    lag: position.map(|position| high_watermark - position),
+                })
+            })
+            .collect()
+    }
+
+    fn is_assigned(&self) -> Result<bool> {
+        Ok(self.0.assignment()?.count() > 0)
+    }
+}
+
+struct KafkaSink(FutureProducer);
+
+impl MessageSink for KafkaSink {
+    fn send<'a>(
+        &'a self,
+        topic: &'a str,
+        key: &'a [u8],
+        payload: &'a [u8],
+        headers: &'a [(String, Vec<u8>)],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut record = FutureRecord::to(topic).key(key).payload(payload);
+            if !headers.is_empty() {
+                let headers = headers
+                    .iter()
+                    .fold(OwnedHeaders::new(), |headers, (name, value)| {
+                        headers.add(name, value.as_slice())
+                    });
+                record = record.headers(headers);
+            }
+            // The Kafka error is kept as the source, so that it can be classified for retries
+            self.0
+                .send(record, Duration::from_secs(0))
+                .await
+                .map(|_| ())
+                .map_err(|(e, _)| {
+                    anyhow::Error::new(e).context(format!("Failed to send to {}", topic))
+                })
+        })
+    }
+}
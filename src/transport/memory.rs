@@ -0,0 +1,181 @@
+use super::{IncomingMessage, MessageHeaders, MessageSink, MessageSource, Transport};
+use crate::offsets::PartitionOffsets;
+use anyhow::{anyhow, Result};
+use futures::future::{self, BoxFuture};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+#[derive(Default)]
+struct Topics {
+    messages: Mutex<HashMap<String, Vec<IncomingMessage>>>,
+    published: Notify,
+}
+
+/// Topics kept in memory, with a single partition each, to run the service without a broker.
+/// Clones share the same topics, so a test can publish inputs to the service it runs on one,
+/// and read its outputs back.
+#[derive(Clone, Default)]
+pub struct InMemoryBroker(Arc<Topics>);
+
+impl InMemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a message to a topic, creating it if needed.
+    pub fn publish(&self, topic: &str, key: &[u8], payload: &[u8], headers: MessageHeaders) {
+        let mut topics = self.0.messages.lock().expect("Topics lock poisoned");
+        let messages = topics.entry(topic.to_string()).or_default();
+        messages.push(IncomingMessage {
+            topic: topic.to_string(),
+            partition: 0,
+            offset: messages.len() as i64,
+            key: Some(key.to_vec()),
+            payload: Some(payload.to_vec()),
+            headers,
+        });
+        self.0.published.notify_waiters();
+    }
+
+    /// Every message published to a topic so far.
+    pub fn messages(&self, topic: &str) -> Vec<IncomingMessage> {
+        let topics = self.0.messages.lock().expect("Topics lock poisoned");
+        topics.get(topic).cloned().unwrap_or_default()
+    }
+
+    /// Reads the topics from their first message.
+    pub fn subscribe(&self, topics: &[&str]) -> InMemorySource {
+        InMemorySource {
+            broker: self.clone(),
+            positions: Mutex::new(topics.iter().map(|topic| (topic.to_string(), 0)).collect()),
+        }
+    }
+}
+
+/// Reads topics of an [`InMemoryBroker`], taking them in turn as messages arrive.
+pub struct InMemorySource {
+    broker: InMemoryBroker,
+    /// Offset of the next message to read, by topic
+    positions: Mutex<BTreeMap<String, i64>>,
+}
+
+impl InMemorySource {
+    fn next(&self) -> Option<IncomingMessage> {
+        let topics = self.broker.0.messages.lock().expect("Topics lock poisoned");
+        let mut positions = self.positions.lock().expect("Positions lock poisoned");
+        positions.iter_mut().find_map(|(topic, position)| {
+            let message = topics.get(topic)?.get(*position as usize)?.clone();
+            *position += 1;
+            Some(message)
+        })
+    }
+}
+
+impl MessageSource for InMemorySource {
+    fn recv(&self) -> BoxFuture<'_, Result<IncomingMessage>> {
+        Box::pin(async move {
+            loop {
+                // Waiting starts before looking, so that a message published in between isn't
+                // missed
+                let published = self.broker.0.published.notified();
+                if let Some(message) = self.next() {
+                    return Ok(message);
+                }
+                published.await;
+            }
+        })
+    }
+
+    fn seek(&self, topic: &str, partition: i32, offset: i64) -> Result<()> {
+        let mut positions = self.positions.lock().expect("Positions lock poisoned");
+        match positions.get_mut(topic) {
+            Some(position) if partition == 0 => {
+                *position = offset;
+                Ok(())
+            }
+            _ => Err(anyhow!("{}/{} is not assigned", topic, partition)),
+        }
+    }
+
+    fn partition_offsets(&self) -> Result<Vec<PartitionOffsets>> {
+        let topics = self.broker.0.messages.lock().expect("Topics lock poisoned");
+        let positions = self.positions.lock().expect("Positions lock poisoned");
+        let offsets = positions
+            .iter()
+            .map(|(topic, position)| {
+                let high_watermark = topics.get(topic).map_or(0, Vec::len) as i64;
+                PartitionOffsets {
+                    topic: topic.clone(),
+                    partition: 0,
+                    position: Some(*position),
+                    committed: Some(*position),
+                    high_watermark,
+                    lag: Some(high_watermark - position),
+                }
+            })
+            .collect();
+        Ok(offsets)
+    }
+
+    fn is_assigned(&self) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+impl MessageSink for InMemoryBroker {
+    fn send<'a>(
+        &'a self,
+        topic: &'a str,
+        key: &'a [u8],
+        payload: &'a [u8],
+        headers: &'a [(String, Vec<u8>)],
+    ) -> BoxFuture<'a, Result<()>> {
+        self.publish(topic, key, payload, headers.to_vec());
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+impl Transport for InMemoryBroker {
+    fn source(&self, topics: &[&str]) -> Result<Arc<dyn MessageSource>> {
+        Ok(Arc::new(self.subscribe(topics)))
+    }
+
+    fn sink(&self) -> Result<Arc<dyn MessageSink>> {
+        Ok(Arc::new(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_memory() {
+        let broker = InMemoryBroker::new();
+        let source = broker.source(&["lots", "time"]).unwrap();
+        broker.publish("time", b"", b"1", vec![]);
+        let waiting = tokio::spawn(async move {
+            let first = source.recv().await.unwrap();
+            let second = source.recv().await.unwrap();
+            // Seeking back reads the message again
+            source.seek("time", 0, first.offset).unwrap();
+            let again = source.recv().await.unwrap();
+            (first, second, again, source.partition_offsets().unwrap())
+        });
+        tokio::task::yield_now().await;
+        let headers = vec![("correlation-id".to_string(), b"abc".to_vec())];
+        let sink = broker.sink().unwrap();
+        sink.send("lots", b"AAPL", b"2", &headers).await.unwrap();
+        let (first, second, again, offsets) = waiting.await.unwrap();
+        assert_eq!(first.payload.as_deref(), Some(&b"1"[..]));
+        assert_eq!(second.topic, "lots");
+        assert_eq!(second.header("correlation-id"), Some(&b"abc"[..]));
+        assert_eq!(again, first);
+        assert_eq!(offsets.len(), 2);
+        assert!(offsets.iter().all(|o| o.lag == Some(0)));
+        // Topics nobody reads still keep their messages
+        sink.send("responses", b"", b"3", &[]).await.unwrap();
+        assert_eq!(broker.messages("responses").len(), 1);
+    }
+}
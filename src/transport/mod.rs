@@ -0,0 +1,89 @@
+//! What the service reads its inputs from and sends its outputs to: Kafka when deployed, or
+//! in-memory topics to run the whole service inside a test. With the `aws` feature, SQS and SNS
+//! can replace Kafka.
+#[cfg(feature = "aws")]
+mod aws;
+mod kafka;
+mod memory;
+
+#[cfg(feature = "aws")]
+pub use aws::SqsSnsTransport;
+pub use kafka::KafkaTransport;
+pub use memory::{InMemoryBroker, InMemorySource};
+
+use crate::offsets::PartitionOffsets;
+use anyhow::Result;
+use futures::future::{self, BoxFuture};
+use std::sync::Arc;
+
+/// Message headers, by name, in the order they were added.
+pub type MessageHeaders = Vec<(String, Vec<u8>)>;
+
+/// A message read from a topic.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncomingMessage {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Option<Vec<u8>>,
+    pub payload: Option<Vec<u8>>,
+    pub headers: MessageHeaders,
+}
+
+impl IncomingMessage {
+    /// Value of the first header with the given name.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        header(&self.headers, name)
+    }
+}
+
+pub fn header<'a>(headers: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    headers
+        .iter()
+        .find(|(header, _)| header == name)
+        .map(|(_, value)| value.as_slice())
+}
+
+/// Reads the input topics the source was created for.
+pub trait MessageSource: Send + Sync {
+    /// Waits for the next message.
+    fn recv(&self) -> BoxFuture<'_, Result<IncomingMessage>>;
+
+    /// Moves back to `offset`, so that the message there is the next one read from its partition.
+    fn seek(&self, topic: &str, partition: i32, offset: i64) -> Result<()>;
+
+    /// Progress on every partition being read. This may block on network calls.
+    fn partition_offsets(&self) -> Result<Vec<PartitionOffsets>>;
+
+    /// Whether any partition is assigned to the source yet.
+    fn is_assigned(&self) -> Result<bool>;
+
+    /// Marks the message at `offset` as handled, so that it isn't delivered again after a
+    /// restart. Kafka and in-memory sources track their position themselves.
+    fn ack<'a>(
+        &'a self,
+        _topic: &'a str,
+        _partition: i32,
+        _offset: i64,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Sends messages, once each. Retries are up to the caller.
+pub trait MessageSink: Send + Sync {
+    fn send<'a>(
+        &'a self,
+        topic: &'a str,
+        key: &'a [u8],
+        payload: &'a [u8],
+        headers: &'a [(String, Vec<u8>)],
+    ) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Creates the sources and sinks the service runs on.
+pub trait Transport: Send + Sync {
+    fn source(&self, topics: &[&str]) -> Result<Arc<dyn MessageSource>>;
+
+    fn sink(&self) -> Result<Arc<dyn MessageSink>>;
+}
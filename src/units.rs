@@ -0,0 +1,196 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+
+/// A number of shares, negative for short positions.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(transparent)]
+pub struct Shares(pub Decimal);
+
+/// Price of one share.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(transparent)]
+pub struct Price(pub Decimal);
+
+impl Shares {
+    pub const ZERO: Shares = Shares(Decimal::ZERO);
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn is_long(&self) -> bool {
+        self.0.is_sign_positive() && !self.0.is_zero()
+    }
+
+    pub fn is_short(&self) -> bool {
+        self.0.is_sign_negative() && !self.0.is_zero()
+    }
+
+    pub fn abs(&self) -> Shares {
+        Shares(self.0.abs())
+    }
+
+    /// Whether adding `other` would reduce the position rather than add to it.
+    pub fn is_opposite(&self, other: Shares) -> bool {
+        (self.is_long() && other.is_short()) || (self.is_short() && other.is_long())
+    }
+}
+
+impl Add for Shares {
+    type Output = Shares;
+
+    fn add(self, rhs: Shares) -> Shares {
+        Shares(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Shares {
+    fn add_assign(&mut self, rhs: Shares) {
+        self.0 += rhs.0
+    }
+}
+
+impl Sub for Shares {
+    type Output = Shares;
+
+    fn sub(self, rhs: Shares) -> Shares {
+        Shares(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Shares {
+    fn sub_assign(&mut self, rhs: Shares) {
+        self.0 -= rhs.0
+    }
+}
+
+impl Neg for Shares {
+    type Output = Shares;
+
+    fn neg(self) -> Shares {
+        Shares(-self.0)
+    }
+}
+
+/// Shares times a price is a signed amount of money.
+impl Mul<Price> for Shares {
+    type Output = Decimal;
+
+    fn mul(self, rhs: Price) -> Decimal {
+        self.0 * rhs.0
+    }
+}
+
+impl Mul<Shares> for Price {
+    type Output = Decimal;
+
+    fn mul(self, rhs: Shares) -> Decimal {
+        self.0 * rhs.0
+    }
+}
+
+impl Add for Price {
+    type Output = Price;
+
+    fn add(self, rhs: Price) -> Price {
+        Price(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Price {
+    type Output = Price;
+
+    fn sub(self, rhs: Price) -> Price {
+        Price(self.0 - rhs.0)
+    }
+}
+
+impl Neg for Price {
+    type Output = Price;
+
+    fn neg(self) -> Price {
+        Price(-self.0)
+    }
+}
+
+/// Scales a price, e.g. to add slippage.
+impl Mul<Decimal> for Price {
+    type Output = Price;
+
+    fn mul(self, rhs: Decimal) -> Price {
+        Price(self.0 * rhs)
+    }
+}
+
+impl From<Decimal> for Shares {
+    fn from(shares: Decimal) -> Self {
+        Shares(shares)
+    }
+}
+
+impl From<Shares> for Decimal {
+    fn from(shares: Shares) -> Self {
+        shares.0
+    }
+}
+
+impl From<Decimal> for Price {
+    fn from(price: Decimal) -> Self {
+        Price(price)
+    }
+}
+
+impl From<Price> for Decimal {
+    fn from(price: Price) -> Self {
+        price.0
+    }
+}
+
+impl fmt::Display for Shares {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let long = Shares(Decimal::new(10, 0));
+        let short = -Shares(Decimal::new(4, 0));
+        assert_eq!(long + short, Shares(Decimal::new(6, 0)));
+        assert_eq!(long - short, Shares(Decimal::new(14, 0)));
+        assert!(short < Shares::ZERO);
+        assert!(long.is_opposite(short));
+        assert!(!long.is_opposite(Shares::ZERO));
+        assert_eq!(short * Price(Decimal::new(25, 0)), Decimal::new(-100, 0));
+        assert_eq!(
+            Price(Decimal::new(100, 0)) * Decimal::new(101, 2),
+            Price(Decimal::new(101, 0))
+        );
+        assert_eq!(short.to_string(), "-4");
+    }
+
+    #[test]
+    fn serde() {
+        // Serialized exactly like the decimal it wraps
+        let price = Price(Decimal::new(125, 1));
+        let json = serde_json::to_string(&price).unwrap();
+        assert_eq!(json, serde_json::to_string(&price.0).unwrap());
+        assert_eq!(serde_json::from_str::<Price>(&json).unwrap(), price);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::settings::ValidationSettings;
+use crate::{PortfolioSnapshot, RiskCheckResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Resolution of the sample rate. Rates are rounded to the nearest basis point.
+const BUCKETS: u128 = 10_000;
+
+/// A granted decision together with the portfolio it was made against, used offline to check the
+/// margin model against the broker's eventual accounting.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ValidationSample {
+    pub decision: RiskCheckResponse,
+    pub snapshot: PortfolioSnapshot,
+    pub sampled_at: DateTime<Utc>,
+}
+
+pub struct DecisionSampler {
+    topic: String,
+    threshold: u128,
+}
+
+impl DecisionSampler {
+    pub fn new(settings: ValidationSettings) -> Self {
+        let rate = settings.sample_rate.clamp(0.0, 1.0);
+        Self {
+            topic: settings.topic,
+            threshold: (rate * BUCKETS as f64).round() as u128,
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Sampling is derived from the intent id, so a redelivered intent is sampled the same way.
+    pub fn should_sample(&self, id: &Uuid) -> bool {
+        id.as_u128() % BUCKETS < self.threshold
+    }
+
+    pub fn sample(
+        &self,
+        response: &RiskCheckResponse,
+        snapshot: PortfolioSnapshot,
+    ) -> Option<ValidationSample> {
+        match response {
+            RiskCheckResponse::Denied { .. } => None,
+            _ if self.should_sample(&response.intent().id) => Some(ValidationSample {
+                decision: response.clone(),
+                snapshot,
+                sampled_at: Utc::now(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sampler(sample_rate: f64) -> DecisionSampler {
+        DecisionSampler::new(ValidationSettings {
+            topic: "risk-validation".into(),
+            sample_rate,
+        })
+    }
+
+    #[test]
+    fn sample_rate() {
+        let ids: Vec<Uuid> = (0..1000).map(|_| Uuid::new_v4()).collect();
+        assert!(ids.iter().all(|id| sampler(1.0).should_sample(id)));
+        assert!(!ids.iter().any(|id| sampler(0.0).should_sample(id)));
+        let sampled = ids
+            .iter()
+            .filter(|id| sampler(0.5).should_sample(id))
+            .count();
+        assert!(sampled > 350 && sampled < 650);
+    }
+}
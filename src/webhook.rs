@@ -0,0 +1,137 @@
+use crate::events::Alert;
+use crate::settings::{WebhookFilter, WebhookSettings};
+use crate::signature::sign;
+use crate::RiskCheckResponse;
+use anyhow::Result;
+use reqwest::Client;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+pub const SIGNATURE_HEADER: &str = "X-Risk-Signature";
+
+struct Endpoint {
+    name: String,
+    settings: WebhookSettings,
+}
+
+impl Endpoint {
+    fn accepts(&self, response: &RiskCheckResponse) -> bool {
+        match self.settings.filter {
+            WebhookFilter::All => true,
+            WebhookFilter::Denied => matches!(response, RiskCheckResponse::Denied { .. }),
+            WebhookFilter::Alerts => false,
+        }
+    }
+
+    async fn post(&self, client: &Client, payload: &[u8]) -> Result<()> {
+        let mut request = client
+            .post(&self.settings.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .timeout(Duration::from_millis(self.settings.timeout_ms))
+            .body(payload.to_vec());
+        if let Some(secret) = self.settings.secret.as_ref() {
+            request = request.header(SIGNATURE_HEADER, sign(secret, payload));
+        }
+        request.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Forwards risk-check decisions and alerts to external HTTP endpoints. Delivery happens in the
+/// background so a slow endpoint never holds up the risk-check loop.
+#[derive(Clone, Default)]
+pub struct WebhookSink {
+    client: Client,
+    endpoints: Arc<Vec<Endpoint>>,
+}
+
+impl WebhookSink {
+    pub fn new(settings: HashMap<String, WebhookSettings>) -> Self {
+        let endpoints = settings
+            .into_iter()
+            .map(|(name, settings)| Endpoint { name, settings })
+            .collect();
+        Self {
+            client: Client::new(),
+            endpoints: Arc::new(endpoints),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.is_empty()
+    }
+
+    pub fn notify(&self, response: &RiskCheckResponse) {
+        self.deliver(response, |endpoint| endpoint.accepts(response))
+    }
+
+    pub fn notify_alert(&self, alert: &Alert) {
+        self.deliver(alert, |endpoint| {
+            endpoint.settings.filter == WebhookFilter::Alerts
+        })
+    }
+
+    fn deliver<T: Serialize>(&self, message: &T, accepts: impl Fn(&Endpoint) -> bool) {
+        if self.is_empty() {
+            return;
+        }
+        let payload = match serde_json::to_vec(message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!(?e, "Failed to serialize webhook payload");
+                return;
+            }
+        };
+        for idx in 0..self.endpoints.len() {
+            if !accepts(&self.endpoints[idx]) {
+                continue;
+            }
+            let client = self.client.clone();
+            let endpoints = self.endpoints.clone();
+            let payload = payload.clone();
+            tokio::spawn(async move {
+                let endpoint = &endpoints[idx];
+                let name = &endpoint.name;
+                let backoff = Duration::from_millis(endpoint.settings.initial_backoff_ms);
+                let retries = endpoint.settings.max_retries;
+                let post = || endpoint.post(&client, &payload);
+                if with_retries(name, retries, backoff, post).await {
+                    debug!(endpoint = %name, "Webhook delivered");
+                } else {
+                    error!(endpoint = %name, "Webhook retries exhausted, dropping payload");
+                }
+            });
+        }
+    }
+}
+
+/// Runs `deliver` until it succeeds, retrying up to `max_retries` times with exponential backoff
+/// from `initial_backoff`. Returns whether the delivery to `endpoint` eventually succeeded.
+pub(crate) async fn with_retries<F, Fut>(
+    endpoint: &str,
+    max_retries: usize,
+    initial_backoff: Duration,
+    mut deliver: F,
+) -> bool
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    let mut backoff = initial_backoff;
+    for attempt in 0..=max_retries {
+        match deliver().await {
+            Ok(()) => return true,
+            Err(e) => warn!(endpoint, attempt, ?e, "Delivery failed"),
+        }
+        if attempt == max_retries {
+            break;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    }
+    false
+}
@@ -0,0 +1,156 @@
+//! Runs the whole service on in-memory topics, so it needs neither Kafka nor Redis.
+use chrono::Utc;
+use mockito::mock;
+use risk_manager::transport::InMemoryBroker;
+use risk_manager::{run_with, DenyReason, Lot, RiskCheckResponse, Settings, TradeMetrics};
+use rust_decimal::Decimal;
+use std::time::Duration;
+use trading_base::{OrderType, TradeIntent};
+use uuid::Uuid;
+
+const ACCOUNT: &str = r#"{
+  "account_blocked": false,
+  "account_number": "010203ABCD",
+  "buying_power": "4000000",
+  "cash": "1000000.0",
+  "created_at": "2019-06-12T22:47:07.99658Z",
+  "currency": "USD",
+  "daytrade_count": 0,
+  "daytrading_buying_power": "4000000",
+  "equity": "1000000",
+  "id": "e6fe16f3-64a4-4921-8928-cadf02f92f98",
+  "initial_margin": "0",
+  "last_equity": "1000000",
+  "last_maintenance_margin": "0",
+  "long_market_value": "0",
+  "maintenance_margin": "0",
+  "multiplier": "4",
+  "pattern_day_trader": false,
+  "portfolio_value": "0",
+  "regt_buying_power": "2000000",
+  "short_market_value": "0",
+  "shorting_enabled": true,
+  "sma": "0",
+  "status": "ACTIVE",
+  "trade_suspended_by_user": false,
+  "trading_blocked": false,
+  "transfers_blocked": false
+}"#;
+
+fn settings() -> Settings {
+    // The Kafka settings are required, but never used to connect
+    std::env::set_var("KAFKA__BOOTSTRAP_SERVERS", "localhost:9094");
+    std::env::set_var("KAFKA__GROUP_ID", Uuid::new_v4().to_string());
+    std::env::set_var("KAFKA__SECURITY_PROTOCOL", "PLAINTEXT");
+    std::env::set_var("WEBSERVER__PORT", "0");
+    std::env::set_var("ALPACA__BASE_URL", mockito::server_url());
+    std::env::set_var("ALPACA__KEY_ID", "APCA_API_KEY_ID");
+    std::env::set_var("ALPACA__SECRET_KEY", "APCA_API_SECRET_KEY");
+    std::env::set_var("DATASTORE__BASE_URL", mockito::server_url());
+    Settings::new().unwrap()
+}
+
+/// Waits for the service to send its `count`th response.
+async fn response(broker: &InMemoryBroker, count: usize) -> RiskCheckResponse {
+    loop {
+        if let Some(message) = broker.messages("risk-check-response").get(count - 1) {
+            return serde_json::from_slice(message.payload.as_deref().unwrap()).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn in_memory() {
+    let _account = mock("GET", "/account")
+        .match_header("apca-api-key-id", "APCA_API_KEY_ID")
+        .match_header("apca-api-secret-key", "APCA_API_SECRET_KEY")
+        .with_body(ACCOUNT)
+        .create();
+    let _positions = mock("GET", "/positions")
+        .match_header("apca-api-key-id", "APCA_API_KEY_ID")
+        .match_header("apca-api-secret-key", "APCA_API_SECRET_KEY")
+        .with_body("[]")
+        .create();
+    let broker = InMemoryBroker::new();
+    let service = {
+        let broker = broker.clone();
+        let settings = settings();
+        tokio::spawn(async move { run_with(settings, &broker).await })
+    };
+
+    let intent = TradeIntent::new("AAPL", 2).order_type(OrderType::Limit {
+        limit_price: Decimal::new(100, 0),
+    });
+    let payload = serde_json::to_vec(&intent).unwrap();
+    broker.publish("risk-check-request", b"AAPL", &payload, vec![]);
+    let message = tokio::time::timeout(Duration::from_secs(10), response(&broker, 1))
+        .await
+        .unwrap();
+    // 2 shares at 100 on an account with 1,000,000 of equity and no positions
+    let metrics = Some(TradeMetrics {
+        buying_power_before: Decimal::new(2000000, 0),
+        buying_power_after: Decimal::new(1999800, 0),
+        projected_position: Decimal::new(2, 0),
+        projected_gross_exposure: Decimal::new(200, 0),
+        projected_net_exposure: Decimal::new(200, 0),
+        margin_utilization: Some(Decimal::new(6, 5)),
+    });
+    // Every response is stamped with when it was decided
+    let timing = message.timing().copied();
+    assert!(timing.is_some());
+    assert_eq!(
+        message,
+        RiskCheckResponse::Granted {
+            intent,
+            buffered_price: None,
+            price_source: None,
+            score: None,
+            metrics,
+            timing,
+        }
+    );
+
+    let lot = Lot {
+        id: Uuid::new_v4(),
+        order_id: Uuid::new_v4(),
+        ticker: "AAPL".into(),
+        fill_time: Utc::now(),
+        shares: Decimal::new(2, 0),
+        price: Decimal::new(100, 0),
+    };
+    let payload = serde_json::to_vec(&lot).unwrap();
+    broker.publish("lots", b"AAPL", &payload, vec![]);
+    let intent = TradeIntent::new("AAPL", 20000).order_type(OrderType::Limit {
+        limit_price: Decimal::new(100, 0),
+    });
+    let payload = serde_json::to_vec(&intent).unwrap();
+    broker.publish("risk-check-request", b"AAPL", &payload, vec![]);
+    let message = tokio::time::timeout(Duration::from_secs(10), response(&broker, 2))
+        .await
+        .unwrap();
+    let timing = message.timing().copied();
+    assert!(timing.is_some());
+    assert_eq!(
+        message,
+        RiskCheckResponse::Denied {
+            intent,
+            reasons: vec![DenyReason::InsufficientBuyingPower {
+                buying_power: Decimal::new(1999800, 0)
+            }],
+            buffered_price: None,
+            price_source: None,
+            score: None,
+            timing,
+        }
+    );
+
+    // The market closing for more than 12 hours shuts the service down
+    let payload = br#"{"state":"closed","next_open":63000}"#;
+    broker.publish("time", b"", payload, vec![]);
+    tokio::time::timeout(Duration::from_secs(10), service)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
@@ -1,7 +1,7 @@
 use chrono::Utc;
 use rdkafka::producer::FutureRecord;
 use rdkafka::Message;
-use risk_manager::{DenyReason, Lot, RiskCheckResponse};
+use risk_manager::{DenyReason, Lot, RiskCheckResponse, TradeMetrics};
 use rust_decimal::Decimal;
 use setup::setup;
 use teardown::teardown;
@@ -30,7 +30,29 @@ async fn main() {
         .unwrap();
     let response = consumer.recv().await.unwrap();
     let message: RiskCheckResponse = serde_json::from_slice(&response.payload().unwrap()).unwrap();
-    assert_eq!(message, RiskCheckResponse::Granted { intent });
+    // 2 shares at 100 on an account with 1,000,000 of equity and no positions
+    let metrics = Some(TradeMetrics {
+        buying_power_before: Decimal::new(2000000, 0),
+        buying_power_after: Decimal::new(1999800, 0),
+        projected_position: Decimal::new(2, 0),
+        projected_gross_exposure: Decimal::new(200, 0),
+        projected_net_exposure: Decimal::new(200, 0),
+        margin_utilization: Some(Decimal::new(6, 5)),
+    });
+    // Every response is stamped with when it was decided
+    let timing = message.timing().copied();
+    assert!(timing.is_some());
+    assert_eq!(
+        message,
+        RiskCheckResponse::Granted {
+            intent,
+            buffered_price: None,
+            price_source: None,
+            score: None,
+            metrics,
+            timing,
+        }
+    );
 
     let lot = Lot {
         id: Uuid::new_v4(),
@@ -64,13 +86,19 @@ async fn main() {
 
     let response = consumer.recv().await.unwrap();
     let message: RiskCheckResponse = serde_json::from_slice(&response.payload().unwrap()).unwrap();
+    let timing = message.timing().copied();
+    assert!(timing.is_some());
     assert_eq!(
         message,
         RiskCheckResponse::Denied {
             intent,
-            reason: DenyReason::InsufficientBuyingPower {
+            reasons: vec![DenyReason::InsufficientBuyingPower {
                 buying_power: Decimal::new(1999800, 0)
-            }
+            }],
+            buffered_price: None,
+            price_source: None,
+            score: None,
+            timing,
         }
     );
 